@@ -0,0 +1,423 @@
+use crate::platform::Platform;
+use mlua::{Lua, Result as LuaResult, Table, Value};
+
+/// Injects the global `mdot` table that configs use to query the machine.
+pub fn install(lua: &Lua, platform: &Platform) -> LuaResult<()> {
+    let mdot = lua.create_table()?;
+    install_helpers(lua, &mdot, platform)?;
+    let hostname = platform.hostname.clone();
+    mdot.set(
+        "hostname",
+        lua.create_function(move |_, ()| Ok(hostname.clone()))?,
+    )?;
+    let platform = platform.clone();
+    mdot.set(
+        "platform",
+        lua.create_function(move |lua, ()| {
+            let tbl = lua.create_table()?;
+            tbl.set("os", platform.os.as_str())?;
+            tbl.set("distro", platform.distro.as_deref())?;
+            tbl.set("family", platform.family.as_deref())?;
+            tbl.set("arch", platform.arch.as_str())?;
+            tbl.set("wsl", platform.wsl)?;
+            tbl.set("hostname", platform.hostname.as_str())?;
+            Ok(tbl)
+        })?,
+    )?;
+    let wsl = platform.wsl;
+    let windows_home = std::cell::OnceCell::new();
+    mdot.set(
+        "windows_home",
+        lua.create_function(move |_, ()| {
+            if !wsl {
+                return Ok(None);
+            }
+            Ok(windows_home
+                .get_or_init(crate::platform::windows_home)
+                .as_ref()
+                .map(|home: &std::path::PathBuf| home.display().to_string()))
+        })?,
+    )?;
+    mdot.set("vars", lua.create_table()?)?;
+    mdot.set("options", lua.create_table()?)?;
+    mdot.set("profiles", lua.create_table()?)?;
+    lua.globals().set("mdot", mdot)?;
+    Ok(())
+}
+
+/// Options `mdot.link` accepts after the source and targets.
+const LINK_OPTIONS: &[&str] = &[
+    "targets_dir",
+    "compose",
+    "comment",
+    "overwrite",
+    "backup",
+    "enabled",
+];
+
+/// A copy of `tbl` without its metatable, holding what it shows.
+fn copy(lua: &Lua, tbl: &Table) -> LuaResult<Table> {
+    let out = lua.create_table()?;
+    for (key, value) in crate::entries(tbl)? {
+        out.raw_set(key, value)?;
+    }
+    Ok(out)
+}
+
+/// Whether an `enabled` value holds, calling it if it is a function.
+fn holds(cond: &Value) -> LuaResult<bool> {
+    match cond {
+        Value::Nil => Ok(true),
+        Value::Boolean(cond) => Ok(*cond),
+        Value::Function(cond) => cond.call(()),
+        v => Err(mlua::Error::runtime(format!(
+            "a condition must be a boolean or a function, got {}",
+            v.type_name()
+        ))),
+    }
+}
+
+/// `mdot.pkg(name, spec)`: `spec` named `name`.
+fn package(lua: &Lua, name: String, spec: Option<Table>) -> LuaResult<Table> {
+    let Some(spec) = spec else {
+        return Err(mlua::Error::runtime(format!(
+            "mdot.pkg('{}') needs a spec, mdot.ref('{}') refers to a package",
+            name, name
+        )));
+    };
+    let pkg = copy(lua, &spec)?;
+    if !pkg.raw_get::<Value>(1)?.is_nil() || !pkg.raw_get::<Value>("name")?.is_nil() {
+        return Err(mlua::Error::runtime(format!(
+            "mdot.pkg('{}', spec) names the package, leave the name out of spec",
+            name
+        )));
+    }
+    pkg.raw_set("name", name)?;
+    Ok(pkg)
+}
+
+/// Registers the constructors `mdot.link(source, targets?, options?)`,
+/// `mdot.pkg(name, spec)` and `mdot.when(cond, spec)`, which check their arguments and return
+/// the tables a config would otherwise spell out, and the conditions
+/// `mdot.any(conds, spec?)`, `mdot.all(conds, spec?)`, `mdot.on_os(names,
+/// spec?)` and `mdot.on_host(patterns, spec?)`, which wrap `spec` like
+/// `mdot.when` or, without one, return the condition itself.
+fn install_helpers(lua: &Lua, mdot: &Table, platform: &Platform) -> LuaResult<()> {
+    mdot.set(
+        "link",
+        lua.create_function(
+            |lua, (source, targets, options): (String, Value, Option<Table>)| {
+                let link = lua.create_table()?;
+                link.raw_set("source", source)?;
+                match targets {
+                    Value::Nil | Value::String(_) | Value::Table(_) => {
+                        link.raw_set("targets", targets)?
+                    }
+                    v => {
+                        return Err(mlua::Error::runtime(format!(
+                            "mdot.link targets must be a path or a list of paths, got {}",
+                            v.type_name()
+                        )));
+                    }
+                }
+                for (key, value) in options
+                    .map(|o| crate::entries(&o))
+                    .transpose()?
+                    .into_iter()
+                    .flatten()
+                {
+                    let known = key
+                        .as_string()
+                        .and_then(|key| key.to_str().ok())
+                        .is_some_and(|key| LINK_OPTIONS.contains(&&*key));
+                    if !known {
+                        return Err(mlua::Error::runtime(format!(
+                            "unknown mdot.link option {:?}, expected one of {}",
+                            key.to_string()?,
+                            LINK_OPTIONS.join(", ")
+                        )));
+                    }
+                    link.raw_set(key, value)?;
+                }
+                Ok(link)
+            },
+        )?,
+    )?;
+    mdot.set(
+        "pkg",
+        lua.create_function(|lua, (name, spec): (String, Option<Table>)| package(lua, name, spec))?,
+    )?;
+    mdot.set(
+        "when",
+        lua.create_function(|lua, (cond, spec): (Value, Value)| {
+            when(lua, "mdot.when", cond, spec)
+        })?,
+    )?;
+    mdot.set(
+        "any",
+        lua.create_function(|lua, (conds, spec): (Table, Value)| {
+            let cond = combine(lua, "mdot.any", conds, true)?;
+            wrap(lua, "mdot.any", cond, spec)
+        })?,
+    )?;
+    mdot.set(
+        "all",
+        lua.create_function(|lua, (conds, spec): (Table, Value)| {
+            let cond = combine(lua, "mdot.all", conds, false)?;
+            wrap(lua, "mdot.all", cond, spec)
+        })?,
+    )?;
+    let os = [
+        Some(&platform.os),
+        platform.distro.as_ref(),
+        platform.family.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect::<Vec<String>>();
+    mdot.set(
+        "on_os",
+        lua.create_function(move |lua, (names, spec): (Value, Value)| {
+            let names = strings("mdot.on_os", names)?;
+            let cond = Value::Boolean(names.iter().any(|name| os.contains(name)));
+            wrap(lua, "mdot.on_os", cond, spec)
+        })?,
+    )?;
+    let hostname = platform.hostname.clone();
+    mdot.set(
+        "on_host",
+        lua.create_function(move |lua, (patterns, spec): (Value, Value)| {
+            let mut matched = false;
+            for pattern in strings("mdot.on_host", patterns)? {
+                let glob = globset::Glob::new(&pattern).map_err(|err| {
+                    mlua::Error::runtime(format!("mdot.on_host pattern '{}': {}", pattern, err))
+                })?;
+                matched |= glob.compile_matcher().is_match(&hostname);
+            }
+            wrap(lua, "mdot.on_host", Value::Boolean(matched), spec)
+        })?,
+    )?;
+    Ok(())
+}
+
+/// Errors unless `cond`, given to `who`, is a boolean or a function.
+fn condition(who: &str, cond: &Value) -> LuaResult<()> {
+    match cond {
+        Value::Boolean(_) | Value::Function(_) => Ok(()),
+        v => Err(mlua::Error::runtime(format!(
+            "{} expects a boolean or a function, got {}",
+            who,
+            v.type_name()
+        ))),
+    }
+}
+
+/// A name, or a list of names, given to `who`.
+fn strings(who: &str, value: Value) -> LuaResult<Vec<String>> {
+    match value {
+        Value::String(name) => Ok(vec![name.to_str()?.to_string()]),
+        Value::Table(names) => names.sequence_values::<String>().collect(),
+        v => Err(mlua::Error::runtime(format!(
+            "{} expects a name or a list of names, got {}",
+            who,
+            v.type_name()
+        ))),
+    }
+}
+
+/// A copy of the package or link `spec` that is only enabled when
+/// `cond` holds, on top of its own `enabled`.
+fn when(lua: &Lua, who: &str, cond: Value, spec: Value) -> LuaResult<Table> {
+    condition(who, &cond)?;
+    let spec = match spec {
+        Value::String(name) => lua.create_sequence_from([name])?,
+        Value::Table(spec) => copy(lua, &spec)?,
+        v => {
+            return Err(mlua::Error::runtime(format!(
+                "{} expects a package name or spec, got {}",
+                who,
+                v.type_name()
+            )));
+        }
+    };
+    let enabled = match spec.raw_get::<Value>("enabled")? {
+        Value::Nil => cond,
+        own => {
+            Value::Function(lua.create_function(move |_, ()| Ok(holds(&cond)? && holds(&own)?))?)
+        }
+    };
+    spec.raw_set("enabled", enabled)?;
+    Ok(spec)
+}
+
+/// `spec` under `cond`, or `cond` itself without a spec so that
+/// combinators nest, as in `mdot.any { mdot.on_os("macos"), ... }`.
+fn wrap(lua: &Lua, who: &str, cond: Value, spec: Value) -> LuaResult<Value> {
+    match spec {
+        Value::Nil => Ok(cond),
+        spec => when(lua, who, cond, spec).map(Value::Table),
+    }
+}
+
+/// Whether any (or all) of `conds` hold. Booleans fold right away,
+/// functions are called when the result is.
+fn combine(lua: &Lua, who: &str, conds: Table, any: bool) -> LuaResult<Value> {
+    let conds = conds
+        .sequence_values::<Value>()
+        .collect::<LuaResult<Vec<Value>>>()?;
+    for cond in &conds {
+        condition(who, cond)?;
+    }
+    if conds.iter().all(|cond| matches!(cond, Value::Boolean(_))) {
+        let held = conds.iter().map(holds).collect::<LuaResult<Vec<bool>>>()?;
+        return Ok(Value::Boolean(held.contains(&any) == any));
+    }
+    Ok(Value::Function(lua.create_function(move |_, ()| {
+        for cond in &conds {
+            if holds(cond)? == any {
+                return Ok(any);
+            }
+        }
+        Ok(!any)
+    })?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_helpers() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-helpers-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return {
+                mdot.pkg("kitty", {
+                    links = { mdot.link("kitty.conf", "~/.config/kitty/kitty.conf", { backup = true }) },
+                }),
+                mdot.when(false, "fish"),
+                mdot.when(function() return true end, { "nvim", enabled = function() return false end }),
+                mdot.when(true, mdot.pkg("git", { links = { mdot.link("bin", nil, { targets_dir = "~/bin" }) } })),
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let names: Vec<&str> = config.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["kitty", "fish", "nvim", "git"]);
+        let enabled: Vec<bool> = config.packages.iter().map(|p| p.is_enabled()).collect();
+        assert_eq!(enabled, [true, false, false, true]);
+        let link = &config.packages[0].links[0];
+        assert_eq!(link.source, PathBuf::from("kitty.conf"));
+        assert!(link.backup && !link.overwrite);
+        assert!(config.packages[3].links[0].targets_dir.is_some());
+
+        for (code, err) in [
+            (
+                r#"mdot.link("a", "b", { bakup = true })"#,
+                "unknown mdot.link option \"bakup\"",
+            ),
+            (
+                r#"mdot.link("a", 1)"#,
+                "targets must be a path or a list of paths",
+            ),
+            (r#"mdot.pkg("a", { "b" })"#, "leave the name out of spec"),
+            (r#"mdot.pkg("a")"#, "mdot.ref('a') refers to a package"),
+            (
+                r#"mdot.when("yes", "a")"#,
+                "expects a boolean or a function",
+            ),
+        ] {
+            let result = ctx.lua.load(code).exec().unwrap_err().to_string();
+            assert!(result.contains(err), "{}: {}", code, result);
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_conditions() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-conditions-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            local laptop = function() return mdot.store.get("laptop") == "yes" end
+            return {
+                mdot.on_os("linux", "fish"),
+                mdot.on_os({ "macos", "windows" }, "iterm"),
+                mdot.on_os("debian", "apt-hooks"),
+                mdot.on_host("work-*", { "vpn", links = {
+                    mdot.on_host("*-2", mdot.link("two.conf", "~/two.conf")),
+                    mdot.link("all.conf", "~/all.conf"),
+                } }),
+                mdot.any({ mdot.on_os("macos"), mdot.on_host("home") }, "games"),
+                mdot.all({ mdot.on_os("linux"), laptop }, "tlp"),
+                mdot.any({ false, laptop }, "powertop"),
+            }
+            "#,
+        )
+        .unwrap();
+        let platform = platform::Platform {
+            os: "linux".to_string(),
+            distro: Some("ubuntu".to_string()),
+            family: Some("debian".to_string()),
+            hostname: "work-1".to_string(),
+            ..platform::Platform::detect()
+        };
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .platform(platform)
+            .build();
+        let config = config::load(&ctx);
+        let enabled = |config: &config::Config| -> Vec<String> {
+            config
+                .packages
+                .iter()
+                .filter(|p| p.is_enabled())
+                .map(|p| p.name.clone())
+                .collect()
+        };
+        assert_eq!(enabled(&config), ["fish", "apt-hooks", "vpn"]);
+        let vpn = config.packages.iter().find(|p| p.name == "vpn").unwrap();
+        let sources: Vec<&std::path::Path> = vpn.links.iter().map(|l| l.source.as_path()).collect();
+        assert_eq!(sources, [std::path::Path::new("all.conf")]);
+
+        ctx.lua
+            .load("mdot.store.set('laptop', 'yes')")
+            .exec()
+            .unwrap();
+        assert_eq!(
+            enabled(&config),
+            ["fish", "apt-hooks", "vpn", "tlp", "powertop"]
+        );
+
+        for (code, err) in [
+            (
+                r#"mdot.any({ "yes" }, "a")"#,
+                "mdot.any expects a boolean or a function",
+            ),
+            (
+                r#"mdot.on_os(1, "a")"#,
+                "mdot.on_os expects a name or a list of names",
+            ),
+            (r#"mdot.on_host("[", "a")"#, "mdot.on_host pattern '['"),
+        ] {
+            let result = ctx.lua.load(code).exec().unwrap_err().to_string();
+            assert!(result.contains(err), "{}: {}", code, result);
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,451 @@
+use crate::config::Config;
+use crate::deploy::{self, resolve_target};
+use crate::state::{escape, unescape};
+use crate::template::{self, Vars};
+use crate::{Context, Package, attrs, exports, lua_str_to_str, lua_value_to_str, ordered_pairs};
+use log::info;
+use mlua::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+
+/// `assets = { ["walls/forest.jpg"] = "~/Pictures/wall.jpg", apply = "swww img {{target}}" }`
+///
+/// Large binary files that are copied rather than linked, never rendered,
+/// and only rewritten when their content differs, backing up what was
+/// there. `apply` runs once for each target that was written, with
+/// `{{target}}` set to its path, already quoted for the shell.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Assets {
+    pub files: Vec<(PathBuf, Vec<PathBuf>)>,
+    pub apply: Option<String>,
+}
+
+impl Assets {
+    pub fn from_value(value: &Value) -> Assets {
+        let Value::Table(tbl) = value else {
+            fatal!("'assets' expected type 'Table', got {}", value.type_name());
+        };
+        let mut assets = Assets::default();
+        for (key, value) in ordered_pairs(tbl, "assets") {
+            let key = lua_value_to_str(&key);
+            if key == "apply" {
+                let Value::String(apply) = value else {
+                    fatal!(
+                        "'assets.apply' expected type 'String', got {}",
+                        value.type_name()
+                    );
+                };
+                assets.apply = Some(lua_str_to_str(&apply));
+            } else {
+                let targets = Package::parse_target_list(&format!("assets.{}", key), value);
+                assets.files.push((PathBuf::from(key), targets));
+            }
+        }
+        assets.files.sort();
+        assets
+    }
+}
+
+/// Hashes recorded across runs in the cache dir, `path`, size,
+/// modification time in nanoseconds and hash on each line.
+pub const HASHES_FILE: &str = "hashes";
+
+/// The size and modification time, in nanoseconds since the epoch, of a
+/// file.
+type Stamp = (u64, u128);
+
+/// Hashes known in this run, with the stamp of the file when hashed.
+static HASHES: OnceLock<Mutex<HashMap<PathBuf, (Stamp, u64)>>> = OnceLock::new();
+/// Whether [`HASHES`] holds hashes not saved yet.
+static DIRTY: AtomicBool = AtomicBool::new(false);
+static PARANOID: AtomicBool = AtomicBool::new(false);
+
+/// `--paranoid`: hash every file compared instead of trusting a recorded
+/// hash whose file kept its size and modification time.
+pub fn set_paranoid() {
+    PARANOID.store(true, Ordering::Relaxed);
+}
+
+fn hashes() -> &'static Mutex<HashMap<PathBuf, (Stamp, u64)>> {
+    HASHES.get_or_init(Default::default)
+}
+
+fn stamp(meta: &fs::Metadata) -> io::Result<Stamp> {
+    let modified = meta.modified()?.duration_since(UNIX_EPOCH);
+    Ok((meta.len(), modified.map_or(0, |d| d.as_nanos())))
+}
+
+/// Identifies the hash function, which may change between Rust releases;
+/// hashes recorded with another one are dropped.
+fn hasher_tag() -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(b"mdot");
+    format!("# mdot hashes v1 {:016x}", hasher.finish())
+}
+
+/// Reads the hashes recorded by earlier runs in `cache_dir`.
+pub fn load_hashes(cache_dir: &Path) {
+    let Ok(content) = fs::read_to_string(cache_dir.join(HASHES_FILE)) else {
+        return;
+    };
+    let mut lines = content.lines();
+    if lines.next() != Some(hasher_tag().as_str()) {
+        return;
+    }
+    let mut hashes = hashes().lock().unwrap();
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [path, len, modified, hash] = fields[..] else {
+            continue;
+        };
+        let (Ok(len), Ok(modified), Ok(hash)) =
+            (len.parse(), modified.parse(), u64::from_str_radix(hash, 16))
+        else {
+            continue;
+        };
+        hashes.insert(PathBuf::from(unescape(path)), ((len, modified), hash));
+    }
+}
+
+/// Records the hashes known in this run in `cache_dir`, when some were
+/// computed.
+pub fn save_hashes(cache_dir: &Path) -> io::Result<()> {
+    if !DIRTY.swap(false, Ordering::Relaxed) {
+        return Ok(());
+    }
+    let hashes = hashes().lock().unwrap();
+    let mut out = format!("{}\n", hasher_tag());
+    let mut paths: Vec<&PathBuf> = hashes.keys().collect();
+    paths.sort();
+    for path in paths {
+        let ((len, modified), hash) = hashes[path];
+        out.push_str(&format!(
+            "{}\t{}\t{}\t{:016x}\n",
+            escape(&path.to_string_lossy()),
+            len,
+            modified,
+            hash
+        ));
+    }
+    fs::create_dir_all(cache_dir)?;
+    let path = cache_dir.join(HASHES_FILE);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, out)?;
+    fs::rename(tmp, path)
+}
+
+/// The hash of the content of `path`. A file whose size and modification
+/// time did not change since it was hashed, in this run or a recorded
+/// one, is not read again unless `--paranoid` is given.
+fn hash(path: &Path) -> io::Result<u64> {
+    let stamp = stamp(&fs::metadata(path)?)?;
+    if !PARANOID.load(Ordering::Relaxed)
+        && let Some(&(hashed, hash)) = hashes().lock().unwrap().get(path)
+        && hashed == stamp
+    {
+        return Ok(hash);
+    }
+    let hash = hash_content(path)?;
+    hashes()
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (stamp, hash));
+    DIRTY.store(true, Ordering::Relaxed);
+    Ok(hash)
+}
+
+fn hash_content(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..n]);
+    }
+}
+
+/// Whether `target` holds the same content as `source`.
+pub fn same_content(source: &Path, target: &Path) -> bool {
+    fs::symlink_metadata(target).is_ok_and(|meta| meta.is_file())
+        && fs::metadata(source)
+            .and_then(|s| fs::metadata(target).map(|t| s.len() == t.len()))
+            .unwrap_or(false)
+        && hash(source).ok() == hash(target).ok()
+}
+
+/// The permission bits of `path`.
+pub fn mode(path: &Path) -> io::Result<u32> {
+    Ok(fs::metadata(path)?.permissions().mode() & 0o7777)
+}
+
+/// Whether `target` allows nothing that `source` does not. A copy may be
+/// tighter than its source: git keeps little more than the executable
+/// bit, so a `chmod 600` of the copy is the user's to keep.
+pub fn no_looser(source: &Path, target: &Path) -> bool {
+    matches!((mode(source), mode(target)), (Ok(a), Ok(b)) if b & !a == 0)
+}
+
+/// The permissions a copy with `mode` gets over `target`: `mode` without
+/// the bits an existing `target` lacks, so that replacing a copy never
+/// loosens it.
+pub fn kept_mode(mode: u32, target: &Path) -> u32 {
+    match fs::symlink_metadata(target) {
+        Ok(meta) if meta.is_file() => mode & meta.permissions().mode(),
+        _ => mode,
+    }
+}
+
+/// Whether `target` is a copy of `source`, no looser than it. A
+/// directory is compared entry by entry.
+pub fn up_to_date(source: &Path, target: &Path) -> bool {
+    if !source.is_dir() {
+        return same_content(source, target) && no_looser(source, target);
+    }
+    let entries = |dir: &Path| -> io::Result<Vec<_>> {
+        let mut names: Vec<_> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<_>>()?;
+        names.sort();
+        Ok(names)
+    };
+    fs::symlink_metadata(target).is_ok_and(|meta| meta.is_dir())
+        && match (entries(source), entries(target)) {
+            (Ok(a), Ok(b)) => {
+                a == b
+                    && a.iter()
+                        .all(|name| up_to_date(&source.join(name), &target.join(name)))
+            }
+            _ => false,
+        }
+}
+
+/// Copies `source` to `target`, a directory with everything below it.
+pub fn copy_tree(source: &Path, target: &Path) -> io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, target).map(|_| ());
+    }
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        copy_tree(&entry.path(), &target.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// The status of a copy of `source` at `target`: `missing`, `ok`,
+/// `drifted` when only its permissions are looser, or `changed`.
+pub fn status(source: &Path, target: &Path) -> &'static str {
+    if fs::symlink_metadata(target).is_err() {
+        "missing"
+    } else if !same_content(source, target) {
+        "changed"
+    } else if !no_looser(source, target) {
+        "drifted"
+    } else {
+        "ok"
+    }
+}
+
+/// Each asset source of `pkg` with its resolved targets.
+pub fn plan(ctx: &Context, pkg: &Package, vars: &Vars) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    let Some(assets) = &pkg.assets else {
+        return Ok(Vec::new());
+    };
+    let dir = ctx.package_dir(pkg);
+    let mut out = Vec::new();
+    for (source, targets) in &assets.files {
+        for target in targets {
+            out.push((dir.join(source), resolve_target(target, vars)?));
+        }
+    }
+    Ok(out)
+}
+
+fn run_apply(command: &str, target: &Path, vars: &Vars) -> Result<(), String> {
+    let mut vars = vars.clone();
+    vars.insert(
+        "target".to_string(),
+        exports::single_quote(&target.to_string_lossy(), false),
+    );
+    let command = template::render(command, &vars)?;
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|err| format!("failed to run '{}': {}", command, err))?;
+    if !status.success() {
+        return Err(format!("'{}' exited with {}", command, status));
+    }
+    Ok(())
+}
+
+/// Copies the assets of `pkg` whose target is missing or differs, running
+/// `apply` for each one copied. Targets in `held_back` are left alone.
+/// Returns the targets written.
+pub fn sync(
+    ctx: &Context,
+    config: &Config,
+    pkg: &Package,
+    held_back: &BTreeSet<PathBuf>,
+) -> Result<Vec<PathBuf>, String> {
+    let Some(assets) = &pkg.assets else {
+        return Ok(Vec::new());
+    };
+    let vars = template::package_vars(config, pkg, &ctx.platform);
+    let mut written = Vec::new();
+    for (source, target) in plan(ctx, pkg, &vars)? {
+        if up_to_date(&source, &target) {
+            continue;
+        }
+        if held_back.contains(&target) {
+            info!("[{}] skipped sensitive {}", pkg.name, target.display());
+            continue;
+        }
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        let kept = mode(&source).map(|mode| kept_mode(mode, &target));
+        match fs::symlink_metadata(&target) {
+            Ok(meta) if meta.is_symlink() => {
+                fs::remove_file(&target).map_err(|err| err.to_string())?;
+            }
+            Ok(_) => deploy::back_up(&pkg.name, &target, &ctx.backup_dir())
+                .map_err(|err| format!("failed to back up {}: {}", target.display(), err))?,
+            Err(_) => {}
+        }
+        fs::copy(&source, &target)
+            .and_then(|_| fs::set_permissions(&target, fs::Permissions::from_mode(kept?)))
+            .map_err(|err| {
+                format!(
+                    "failed to copy {} to {}: {}",
+                    source.display(),
+                    target.display(),
+                    attrs::explain(&target, err)
+                )
+            })?;
+        info!("[{}] copied {}", pkg.name, target.display());
+        if let Some(apply) = &assets.apply {
+            run_apply(apply, &target, &vars)?;
+        }
+        written.push(target);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    #[test]
+    fn test_recorded_hashes() {
+        let root = env::temp_dir().join(format!("mdot-hashes-{}", std::process::id()));
+        let cache = root.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let file = root.join("wall.jpg");
+        fs::write(&file, "pixels").unwrap();
+        let (len, modified) = assets::stamp(&fs::metadata(&file).unwrap()).unwrap();
+        fs::write(
+            cache.join(assets::HASHES_FILE),
+            format!(
+                "{}\n{}\t{}\t{}\t{:016x}\n",
+                assets::hasher_tag(),
+                file.display(),
+                len,
+                modified,
+                42
+            ),
+        )
+        .unwrap();
+        assets::load_hashes(&cache);
+        // Unchanged since recorded, so the file is not read.
+        assert_eq!(assets::hash(&file).unwrap(), 42);
+
+        fs::write(&file, "more pixels").unwrap();
+        let hash = assets::hash(&file).unwrap();
+        assert_ne!(hash, 42);
+        assets::save_hashes(&cache).unwrap();
+        let saved = fs::read_to_string(cache.join(assets::HASHES_FILE)).unwrap();
+        assert!(saved.contains(&format!("{}\t11\t", file.display())));
+        assert!(saved.contains(&format!("{:016x}", hash)));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_assets() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-assets-{}", std::process::id()));
+        fs::create_dir_all(root.join("walls")).unwrap();
+        fs::write(root.join("walls/forest.jpg"), [0xff, 0xd8, 0xff, 0x00]).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            format!(
+                r#"return {{ {{ "walls", assets = {{
+                    ["forest.jpg"] = "{out}/wall.jpg",
+                    apply = "echo {{{{target}}}} >> {out}/applied",
+                }} }} }}"#,
+                out = root.join("out").display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .build();
+        let config = config::load(&ctx);
+        let pkg = &config.packages[0];
+        let target = root.join("out/wall.jpg");
+
+        assert_eq!(
+            assets::sync(&ctx, &config, pkg, &BTreeSet::new()).unwrap(),
+            std::slice::from_ref(&target)
+        );
+        assert_eq!(fs::read(&target).unwrap(), [0xff, 0xd8, 0xff, 0x00]);
+        assert!(
+            assets::sync(&ctx, &config, pkg, &BTreeSet::new())
+                .unwrap()
+                .is_empty()
+        );
+
+        fs::write(root.join("walls/forest.jpg"), [0x01]).unwrap();
+        assert_eq!(
+            assets::sync(&ctx, &config, pkg, &BTreeSet::new())
+                .unwrap()
+                .len(),
+            1
+        );
+        let applied = fs::read_to_string(root.join("out/applied")).unwrap();
+        assert_eq!(applied.lines().count(), 2);
+        assert_eq!(
+            applied.lines().next().unwrap(),
+            target.display().to_string()
+        );
+
+        // What was there is backed up, and quoting keeps spaces together.
+        let backups = backup::index(&ctx.backup_dir()).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].target, target);
+        let mut spaced = pkg.clone();
+        let spaced_target = root.join("out/my wall.jpg");
+        spaced.assets.as_mut().unwrap().files[0].1 = vec![spaced_target.clone()];
+        spaced.assets.as_mut().unwrap().apply = Some("touch {{target}}.done".to_string());
+        assets::sync(&ctx, &config, &spaced, &BTreeSet::new()).unwrap();
+        assert!(root.join("out/my wall.jpg.done").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
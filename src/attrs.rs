@@ -0,0 +1,125 @@
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Whether SELinux is enabled on this system.
+fn selinux_enabled() -> bool {
+    Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+/// Whether SELinux denies what its policy does not allow.
+fn selinux_enforcing() -> bool {
+    fs::read_to_string("/sys/fs/selinux/enforce").is_ok_and(|mode| mode.trim() == "1")
+}
+
+/// The flags `lsattr -d` prints for `path`, e.g. `----i---------e-------`.
+fn flags(path: &Path) -> Option<String> {
+    let output = Command::new("lsattr")
+        .arg("-d")
+        .arg("--")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    output
+        .status
+        .success()
+        .then(|| stdout.split_whitespace().next().map(String::from))
+        .flatten()
+}
+
+/// How to clear the `lsattr` flags of `path` that stop its entries being
+/// replaced, if it has any.
+fn remedy(path: &Path, flags: &str) -> Option<String> {
+    let (attribute, flag) = if flags.contains('i') {
+        ("immutable", 'i')
+    } else if flags.contains('a') {
+        ("append-only", 'a')
+    } else {
+        return None;
+    };
+    Some(format!(
+        "{} is {}, clear it with 'sudo chattr -{} {}' and deploy again",
+        path.display(),
+        attribute,
+        flag,
+        path.display()
+    ))
+}
+
+/// Explains a failure to write `target`: an immutable or append-only
+/// target or parent directory, or an SELinux denial, with how to fix it.
+/// Other errors are returned as they are.
+pub fn explain(target: &Path, err: io::Error) -> String {
+    if err.kind() != io::ErrorKind::PermissionDenied {
+        return err.to_string();
+    }
+    let candidates = [Some(target), target.parent()];
+    let flagged = candidates
+        .into_iter()
+        .flatten()
+        .filter(|path| fs::symlink_metadata(path).is_ok_and(|meta| !meta.is_symlink()))
+        .find_map(|path| remedy(path, &flags(path)?));
+    if let Some(remedy) = flagged {
+        return remedy;
+    }
+    if selinux_enforcing() {
+        let dir = target.parent().unwrap_or(target);
+        return format!(
+            "{} (SELinux is enforcing: look for denials with 'ausearch -m avc -ts recent' \
+             and relabel with 'restorecon -Rv {}')",
+            err,
+            dir.display()
+        );
+    }
+    err.to_string()
+}
+
+/// Restores the default SELinux labels of `paths` with `restorecon`, for
+/// `mdot.options.restore_labels`. Does nothing without SELinux.
+pub fn restore_labels(paths: &[PathBuf]) {
+    if paths.is_empty() || !selinux_enabled() {
+        return;
+    }
+    let status = Command::new("restorecon")
+        .arg("--")
+        .args(paths)
+        .stdin(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => info!("restored the labels of {} file(s)", paths.len()),
+        Ok(status) => warn!("restorecon exited with {}", status),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            warn!("restorecon is not installed, cannot restore SELinux labels")
+        }
+        Err(err) => warn!("failed to run restorecon: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attrs::*;
+
+    #[test]
+    fn test_remedy() {
+        let path = Path::new("/home/me/.bashrc");
+        assert_eq!(
+            remedy(path, "----i---------e-------").unwrap(),
+            "/home/me/.bashrc is immutable, clear it with 'sudo chattr -i /home/me/.bashrc' \
+             and deploy again"
+        );
+        assert!(
+            remedy(path, "-----a--------e-------")
+                .unwrap()
+                .contains("chattr -a")
+        );
+        assert_eq!(remedy(path, "--------------e-------"), None);
+
+        let err = io::Error::new(io::ErrorKind::NotFound, "gone");
+        assert_eq!(explain(path, err), "gone");
+    }
+}
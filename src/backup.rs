@@ -0,0 +1,221 @@
+use crate::state::{escape, unescape};
+use crate::{fetch, journal, platform};
+use log::{info, warn};
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Backed up files by the SHA-256 of their content, below the backup dir.
+pub const BLOBS_DIR: &str = "blobs";
+/// Which file was backed up where and when, in the backup dir: time,
+/// host, package, target, content hash and mode on each line.
+pub const INDEX_FILE: &str = "index";
+const HEADER: &str = "# mdot backups v1";
+
+/// One backup recorded in the index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub host: String,
+    pub package: String,
+    pub target: PathBuf,
+    pub hash: String,
+    pub mode: u32,
+}
+
+/// Where `target` is backed up below `backups` when it is not a regular
+/// file, mirroring its absolute path. Earlier backups of the same target
+/// are kept by numbering the new one.
+fn mirrored_path(backups: &Path, target: &Path) -> PathBuf {
+    let rel: PathBuf = target
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    let path = backups.join(rel);
+    let mut candidate = path.clone();
+    let mut n = 1;
+    while fs::symlink_metadata(&candidate).is_ok() {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(format!(".{}", n));
+        candidate = PathBuf::from(name);
+        n += 1;
+    }
+    candidate
+}
+
+/// Moves `from` to `to`, copying when they are on different file systems.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices && from.is_file() => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+fn append(backups: &Path, entry: &Entry) -> io::Result<()> {
+    let path = backups.join(INDEX_FILE);
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", HEADER)?;
+    }
+    let fields = [
+        entry.time.to_string(),
+        escape(&entry.host),
+        escape(&entry.package),
+        escape(&entry.target.to_string_lossy()),
+        entry.hash.clone(),
+        format!("{:o}", entry.mode),
+    ];
+    writeln!(file, "{}", fields.join("\t"))
+}
+
+/// The backups recorded in the index of `backups`, oldest first.
+pub fn index(backups: &Path) -> io::Result<Vec<Entry>> {
+    let content = match fs::read_to_string(backups.join(INDEX_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let entries = content
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<String> = line.split('\t').map(unescape).collect();
+            let [time, host, package, target, hash, mode] = &fields[..] else {
+                return None;
+            };
+            Some(Entry {
+                time: time.parse().ok()?,
+                host: host.clone(),
+                package: package.clone(),
+                target: PathBuf::from(target),
+                hash: hash.clone(),
+                mode: u32::from_str_radix(mode, 8).ok()?,
+            })
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Moves `target` into `backups`, returning where it went. A regular file
+/// is stored once per content as `blobs/<sha256>`, and recorded in the
+/// index, so backing up the same file again takes no more space and
+/// stores synced between machines share what they hold in common. Other
+/// targets, and files that cannot be hashed, mirror their path.
+pub fn store(package: &str, target: &Path, backups: &Path) -> io::Result<PathBuf> {
+    let meta = fs::symlink_metadata(target)?;
+    let hash = match meta.is_file() {
+        true => match fs::read(target) {
+            Ok(content) => Some(fetch::sha256(content)),
+            Err(err) => {
+                warn!(
+                    "cannot hash {} ({}), backing it up by its path",
+                    target.display(),
+                    err
+                );
+                None
+            }
+        },
+        false => None,
+    };
+    let Some(hash) = hash else {
+        let backup = mirrored_path(backups, target);
+        move_file(target, &backup)?;
+        return Ok(backup);
+    };
+    let blob = backups.join(BLOBS_DIR).join(&hash);
+    if blob.exists() {
+        info!("{} is already backed up as {}", target.display(), hash);
+        fs::remove_file(target)?;
+    } else {
+        move_file(target, &blob)?;
+    }
+    append(
+        backups,
+        &Entry {
+            time: journal::now(),
+            host: platform::hostname(),
+            package: package.to_string(),
+            target: target.to_path_buf(),
+            hash,
+            mode: meta.permissions().mode() & 0o7777,
+        },
+    )?;
+    Ok(blob)
+}
+
+/// Puts the backup of `target` at `backup` back in place. A blob is
+/// copied, as other backups may share it, with the mode `target` had.
+pub fn restore(backup: &Path, target: &Path) -> io::Result<()> {
+    let blobs = backup.parent();
+    if blobs.and_then(Path::file_name) != Some(BLOBS_DIR.as_ref()) {
+        return fs::rename(backup, target);
+    }
+    let hash = backup.file_name().unwrap_or_default().to_string_lossy();
+    let backups = blobs.and_then(Path::parent).unwrap_or(Path::new(""));
+    let mode = index(backups)?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.target == target && entry.hash == hash)
+        .map(|entry| entry.mode);
+    fs::copy(backup, target)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(target, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::*;
+    use std::env;
+
+    #[test]
+    fn test_store() {
+        let root = env::temp_dir().join(format!("mdot-blobs-{}", std::process::id()));
+        let (home, backups) = (root.join("home"), root.join("backups"));
+        fs::create_dir_all(&home).unwrap();
+        let target = home.join(".zshrc");
+
+        let mut blobs = Vec::new();
+        for (content, mode) in [("old", 0o600), ("older", 0o644), ("old", 0o644)] {
+            fs::write(&target, content).unwrap();
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode)).unwrap();
+            blobs.push(store("zsh", &target, &backups).unwrap());
+            assert!(!target.exists());
+        }
+        assert_eq!(blobs[0], blobs[2]);
+        assert_ne!(blobs[0], blobs[1]);
+        assert!(blobs[0].starts_with(backups.join(BLOBS_DIR)));
+        assert_eq!(fs::read_dir(backups.join(BLOBS_DIR)).unwrap().count(), 2);
+        let index = index(&backups).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index[0].package, "zsh");
+        assert_eq!(index[0].target, target);
+        assert_eq!(index[1].mode, 0o644);
+
+        restore(&blobs[0], &target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+        assert_eq!(
+            fs::metadata(&target).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+        assert!(blobs[0].exists());
+
+        let dir = home.join(".config/nvim");
+        fs::create_dir_all(&dir).unwrap();
+        let backup = store("nvim", &dir, &backups).unwrap();
+        assert_eq!(backup, backups.join(dir.strip_prefix("/").unwrap()));
+        restore(&backup, &dir).unwrap();
+        assert!(dir.is_dir());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use crate::state::{escape, unescape};
+use crate::{Context, Package, fetch, git, net};
+use log::{info, warn};
+use mlua::{Table, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Checkouts of base repos, in the cache dir.
+const BASE_DIR: &str = "base";
+/// The commit of the base repo, in the repo root so that every machine
+/// deploying it gets the same one.
+pub const BASE_FILE: &str = "extends";
+const HEADER: &str = "# mdot extends v1, written by `mdot update-base`";
+const GIT_PREFIX: &str = "git+";
+
+/// Reads and removes the `extends` key of the returned package list.
+pub fn take_extends(tbl: &Table) -> Result<Option<String>, String> {
+    let url = match tbl
+        .raw_get::<Value>("extends")
+        .map_err(|err| err.to_string())?
+    {
+        Value::Nil => return Ok(None),
+        Value::String(url) => url.to_string_lossy(),
+        v => {
+            return Err(format!(
+                "'extends' expected a string, got {}",
+                v.type_name()
+            ));
+        }
+    };
+    tbl.raw_set("extends", Value::Nil)
+        .map_err(|err| err.to_string())?;
+    match url.strip_prefix(GIT_PREFIX) {
+        Some(url) => Ok(Some(url.to_string())),
+        None => Err(format!(
+            "'extends' only supports git repos, e.g. \"git+https://...\", got '{}'",
+            url
+        )),
+    }
+}
+
+/// Where the base repo at `url` is checked out.
+pub fn dir(ctx: &Context, url: &str) -> PathBuf {
+    ctx.cache_dir.join(BASE_DIR).join(&fetch::sha256(url)[..16])
+}
+
+/// The commit recorded for the base repo at `url` in the repo at `root`.
+pub fn pinned(root: &Path, url: &str) -> Result<Option<String>, String> {
+    let path = root.join(BASE_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+    };
+    for (n, line) in contents.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        match fields.as_slice() {
+            [recorded, commit] if recorded == url => return Ok(Some(commit.clone())),
+            [_, _] => {}
+            _ => return Err(format!("{}:{}: invalid base record", path.display(), n + 1)),
+        }
+    }
+    Ok(None)
+}
+
+/// Records `commit` as the one of the base repo at `url`.
+fn pin(root: &Path, url: &str, commit: &str) -> Result<(), String> {
+    let path = root.join(BASE_FILE);
+    let out = format!("{}\n{}\t{}\n", HEADER, escape(url), escape(commit));
+    fs::write(&path, out).map_err(|err| format!("failed to write {}: {}", path.display(), err))
+}
+
+/// The checkout of the base repo at `url`, cloned on first use, at the
+/// commit the repo records. Without one it stays at the commit cloned,
+/// which a deploy or `mdot update-base` records.
+pub fn checkout(ctx: &Context, url: &str, retries: u32) -> Result<PathBuf, String> {
+    let dir = dir(ctx, url);
+    if !dir.join(".git").exists() {
+        git::clone(url, &dir, retries)?;
+    }
+    let head = git::head(&dir)?;
+    match pinned(&ctx.config_path, url)? {
+        Some(commit) if commit == head => {}
+        Some(commit) => {
+            if net::offline() {
+                return Err(format!("{} at {} is not fetched yet", url, commit));
+            }
+            info!("checking out {} at {}", url, commit);
+            net::retry(&format!("fetching {}", url), retries, || {
+                git::git(
+                    &dir,
+                    &["fetch", "--quiet", "--depth", "1", "origin", &commit],
+                )
+            })?;
+            git::git(&dir, &["checkout", "--quiet", "--detach", &commit])?;
+        }
+        None => warn!(
+            "{} is not pinned in {} yet, deploy or run `mdot update-base` to record its commit",
+            url, BASE_FILE
+        ),
+    }
+    Ok(dir)
+}
+
+/// Records the commit the base repo at `url` is checked out at, unless
+/// the repo records one already.
+pub fn pin_checkout(ctx: &Context, url: &str) -> Result<(), String> {
+    if pinned(&ctx.config_path, url)?.is_some() {
+        return Ok(());
+    }
+    let commit = git::head(&dir(ctx, url))?;
+    info!("pinning {} at {}", url, commit);
+    pin(&ctx.config_path, url, &commit)
+}
+
+/// Moves the checkout of the base repo at `url` to the latest commit of
+/// its upstream, and records that commit.
+pub fn update(ctx: &Context, url: &str, retries: u32) -> Result<(), String> {
+    let dir = checkout(ctx, url, retries)?;
+    if net::skip(&format!("updating {}", url)) {
+        return Ok(());
+    }
+    info!("updating {}", url);
+    net::retry(&format!("fetching {}", url), retries, || {
+        git::git(
+            &dir,
+            &["fetch", "--quiet", "--depth", "1", "origin", "HEAD"],
+        )
+    })?;
+    git::git(&dir, &["checkout", "--quiet", "--detach", "FETCH_HEAD"])?;
+    pin(&ctx.config_path, url, &git::head(&dir)?)
+}
+
+/// The packages of the base underneath `packages`: base packages come
+/// first, and a package of the same name replaces the base's.
+pub fn merge(base: Vec<Package>, packages: Vec<Package>) -> Vec<Package> {
+    let own: BTreeSet<&str> = packages.iter().map(|pkg| pkg.name.as_str()).collect();
+    let mut merged: Vec<Package> = base
+        .into_iter()
+        .filter(|pkg| !own.contains(pkg.name.as_str()))
+        .collect();
+    merged.extend(packages);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn test_extends() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-base-{}", std::process::id()));
+        let (upstream, repo) = (root.join("upstream"), root.join("repo"));
+        fs::create_dir_all(upstream.join("git")).unwrap();
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(upstream.join("git/gitconfig"), "[user]\n").unwrap();
+        fs::write(
+            upstream.join("main.lua"),
+            r#"return { { "git", tags = "base" }, { "kitty", tags = "base" } }"#,
+        )
+        .unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&upstream)
+                .args(["-c", "user.name=mdot", "-c", "user.email=mdot@example.com"])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success());
+        };
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "base"]);
+        fs::write(
+            repo.join("main.lua"),
+            format!(
+                r#"return {{ extends = "git+file://{}", {{ "kitty", tags = "mine" }} }}"#,
+                upstream.display()
+            ),
+        )
+        .unwrap();
+
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let packages: Vec<(&str, &[String])> = config
+            .packages
+            .iter()
+            .map(|pkg| (pkg.name.as_str(), pkg.tags.as_slice()))
+            .collect();
+        assert_eq!(
+            packages,
+            [
+                ("git", ["base".to_string()].as_slice()),
+                ("kitty", ["mine".to_string()].as_slice())
+            ]
+        );
+        let dir = base::dir(&ctx, &format!("file://{}", upstream.display()));
+        assert_eq!(ctx.package_dir(&config.packages[0]), dir.join("git"));
+        assert_eq!(ctx.package_dir(&config.packages[1]), repo.join("kitty"));
+        assert!(dir.join("git/gitconfig").is_file());
+
+        // Loading the config leaves the repo alone, a deploy pins the base
+        // and another machine checks out that commit, not the latest.
+        let url = format!("file://{}", upstream.display());
+        assert_eq!(base::pinned(&repo, &url), Ok(None));
+        base::pin_checkout(&ctx, &url).unwrap();
+        let first = base::pinned(&repo, &url).unwrap().unwrap();
+        assert_eq!(git::head(&dir).unwrap(), first);
+        fs::write(upstream.join("git/gitconfig"), "[core]\n").unwrap();
+        git(&["commit", "--quiet", "-am", "core"]);
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .cache_dir(root.join("other"))
+            .build();
+        let dir = base::checkout(&ctx, &url, 0).unwrap();
+        assert_eq!(git::head(&dir).unwrap(), first);
+        base::update(&ctx, &url, 0).unwrap();
+        let latest = base::pinned(&repo, &url).unwrap().unwrap();
+        assert_ne!(latest, first);
+        assert_eq!(git::head(&dir).unwrap(), latest);
+        assert_eq!(
+            fs::read_to_string(dir.join("git/gitconfig")).unwrap(),
+            "[core]\n"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use crate::Context;
+use log::{info, warn};
+use std::env;
+use std::fs;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::Path;
+use std::process::Command;
+
+/// Top directory of the tarball.
+const BUNDLE_DIR: &str = "mdot-bundle";
+/// The repo inside the bundle.
+const REPO_DIR: &str = "repo";
+
+/// Copies `from` into `to`, keeping symlinks and leaving out `.git`.
+fn copy_tree(from: &Path, to: &Path) -> Result<(), String> {
+    fs::create_dir_all(to).map_err(|err| format!("failed to create {}: {}", to.display(), err))?;
+    let entries =
+        fs::read_dir(from).map_err(|err| format!("failed to read {}: {}", from.display(), err))?;
+    for entry in entries {
+        let entry = entry.map_err(|err| err.to_string())?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let (source, dest) = (entry.path(), to.join(entry.file_name()));
+        let kind = entry.file_type().map_err(|err| err.to_string())?;
+        let copied = if kind.is_symlink() {
+            fs::read_link(&source).and_then(|link| symlink(link, &dest))
+        } else if kind.is_dir() {
+            copy_tree(&source, &dest)?;
+            Ok(())
+        } else {
+            fs::copy(&source, &dest).map(|_| ())
+        };
+        copied.map_err(|err| format!("failed to copy {}: {}", source.display(), err))?;
+    }
+    Ok(())
+}
+
+/// Deploys the bundled repo with the bundled binary, or the `mdot` on
+/// `PATH` when the bundle has none. Targets are copies, so they outlive
+/// the extracted bundle.
+fn script(entry: &Path) -> String {
+    format!(
+        r#"#!/bin/sh
+# Generated by `mdot bundle`
+set -e
+BUNDLE_DIR="$(cd "$(dirname "$0")" && pwd)"
+MDOT=mdot
+if [ -x "$BUNDLE_DIR/mdot" ]; then
+    MDOT="$BUNDLE_DIR/mdot"
+fi
+exec "$MDOT" --config "$BUNDLE_DIR/{repo}/{entry}" deploy --copy "$@"
+"#,
+        repo = REPO_DIR,
+        entry = entry.display()
+    )
+}
+
+/// Writes a gzipped tarball of the repo to `output`, with the running
+/// mdot binary when `binary` is set, and an `install.sh` deploying it.
+/// The binary is only self-contained when built with the `bootstrap`
+/// profile for a musl target.
+pub fn create(ctx: &Context, output: &Path, binary: bool) -> Result<(), String> {
+    let staging = env::temp_dir().join(format!("mdot-bundle-{}", std::process::id()));
+    let top = staging.join(BUNDLE_DIR);
+    let _ = fs::remove_dir_all(&staging);
+    let result = (|| {
+        copy_tree(&ctx.config_path, &top.join(REPO_DIR))?;
+        if binary {
+            let exe = env::current_exe().map_err(|err| err.to_string())?;
+            fs::copy(&exe, top.join("mdot"))
+                .map_err(|err| format!("failed to copy {}: {}", exe.display(), err))?;
+        }
+        let entry = ctx
+            .entry
+            .strip_prefix(&ctx.config_path)
+            .unwrap_or(&ctx.entry);
+        let install = top.join("install.sh");
+        fs::write(&install, script(entry))
+            .and_then(|_| fs::set_permissions(&install, fs::Permissions::from_mode(0o755)))
+            .map_err(|err| format!("failed to write {}: {}", install.display(), err))?;
+        let output = env::current_dir()
+            .map(|cwd| cwd.join(output))
+            .map_err(|err| err.to_string())?;
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(&output)
+            .arg("-C")
+            .arg(&staging)
+            .arg(BUNDLE_DIR)
+            .status()
+            .map_err(|err| format!("failed to run tar: {}", err))?;
+        if !status.success() {
+            return Err(format!("tar exited with {}", status));
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(&staging);
+    result?;
+    if ctx.config_path.join(crate::remote::REMOTES_FILE).exists() {
+        warn!("remote packages are not bundled and are cloned on the first deploy");
+    }
+    info!(
+        "bundled {} into {}, deploy it with {}/install.sh",
+        ctx.config_path.display(),
+        output.display(),
+        BUNDLE_DIR
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bundle::*;
+    use crate::*;
+
+    #[test]
+    fn test_bundle() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-bundle-test-{}", std::process::id()));
+        let repo = root.join("repo");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::create_dir_all(repo.join("kitty")).unwrap();
+        fs::write(repo.join("home.lua"), r#"return { "kitty" }"#).unwrap();
+        fs::write(repo.join("kitty/kitty.conf"), "font_size 11\n").unwrap();
+        symlink("kitty.conf", repo.join("kitty/current.conf")).unwrap();
+        let ctx = Context::builder().entry(repo.join("home.lua")).build();
+
+        let output = root.join("out.tar.gz");
+        create(&ctx, &output, true).unwrap();
+        let listing = Command::new("tar")
+            .arg("-tzvf")
+            .arg(&output)
+            .output()
+            .unwrap();
+        let listing = String::from_utf8_lossy(&listing.stdout);
+        let names: Vec<&str> = listing
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(5))
+            .collect();
+        for name in [
+            "mdot-bundle/mdot",
+            "mdot-bundle/install.sh",
+            "mdot-bundle/repo/home.lua",
+            "mdot-bundle/repo/kitty/kitty.conf",
+            "mdot-bundle/repo/kitty/current.conf",
+        ] {
+            assert!(names.contains(&name), "{} missing from {:?}", name, names);
+        }
+        assert!(!names.iter().any(|name| name.contains(".git")));
+        assert!(listing.contains("current.conf -> kitty.conf"));
+        assert!(
+            script(Path::new("home.lua")).contains("\"$BUNDLE_DIR/repo/home.lua\" deploy --copy")
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use crate::cli::Filter;
+use crate::config::{self, Config, MissingBins};
+use crate::events::{self, Event};
+use crate::platform::Platform;
+use crate::select::{self, find_package};
+use crate::{Context, Package, deploy, template};
+use log::{error, info, warn};
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+/// Looks `bin` up in the directories of `PATH`, or as given when it
+/// contains a `/`.
+pub fn find_bin(bin: &str) -> Option<PathBuf> {
+    if bin.contains('/') {
+        let path = PathBuf::from(bin);
+        return is_executable(&path).then_some(path);
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(bin))
+        .find(|path| is_executable(path))
+}
+
+/// A `requires_bin` entry that is not on `PATH`.
+#[derive(Debug, PartialEq)]
+pub struct MissingBin {
+    pub package: String,
+    pub bin: String,
+    /// OS package that provides the binary, when known.
+    pub provider: Option<String>,
+}
+
+/// Checks the `requires_bin` of `packages`.
+///
+/// The provider of a missing binary is the package named in
+/// `{ rg = "ripgrep" }`, or else the `package_name` of a config package
+/// called like the binary.
+pub fn missing_bins(config: &Config, packages: &[Package], platform: &Platform) -> Vec<MissingBin> {
+    let mut missing = Vec::new();
+    for pkg in packages {
+        for (bin, provider) in &pkg.requires_bin {
+            if find_bin(bin).is_some() {
+                continue;
+            }
+            let provider = match provider {
+                Some(provider) => find_package(&config.packages, provider)
+                    .map_or(Some(provider.clone()), |p| p.os_package(platform)),
+                None => find_package(&config.packages, bin).and_then(|p| p.os_package(platform)),
+            };
+            missing.push(MissingBin {
+                package: pkg.name.clone(),
+                bin: bin.clone(),
+                provider,
+            });
+        }
+    }
+    missing
+}
+
+/// Reports missing binaries according to `mdot.options.missing_bins`.
+/// Returns `false` when the policy says the run failed.
+pub fn report_bins(config: &Config, packages: &[Package], platform: &Platform) -> bool {
+    let missing = missing_bins(config, packages, platform);
+    if missing.is_empty() {
+        info!("all required binaries were found");
+        return true;
+    }
+    let fail = config.options.missing_bins == MissingBins::Fail;
+    for m in &missing {
+        let hint = match &m.provider {
+            Some(provider) => format!(" (provided by package '{}')", provider),
+            None => String::new(),
+        };
+        let message = format!(
+            "[{}] required binary '{}' was not found on PATH{}",
+            m.package, m.bin, hint
+        );
+        events::emit(Event::BinMissing, &[&m.package, &m.bin]);
+        if fail {
+            error!("{}", message);
+        } else {
+            warn!("{}", message);
+        }
+    }
+    !fail
+}
+
+/// What a config evaluates to, one line per selected package, planned
+/// link and variable.
+fn resolved(ctx: &Context, config: &Config, filter: &Filter) -> BTreeSet<String> {
+    let selection = select::select(config, &[], ctx.profile.as_deref(), filter);
+    let mut lines = BTreeSet::new();
+    for pkg in &selection.packages {
+        lines.insert(format!("package {}", pkg.name));
+    }
+    for link in deploy::plan(ctx, config, &selection.packages) {
+        lines.insert(format!(
+            "link {} -> {}",
+            link.target.display(),
+            link.source.display()
+        ));
+    }
+    for pkg in &selection.packages {
+        for (key, value) in template::package_vars(config, pkg, &ctx.platform) {
+            lines.insert(format!("[{}] {} = {}", pkg.name, key, value));
+        }
+    }
+    lines
+}
+
+/// Evaluates the config a second time and describes what came out
+/// differently. Any difference means `enabled` functions, vars or targets
+/// depend on the time, randomness or other state that changes between
+/// runs, so a reviewed plan may not be what gets applied.
+pub fn determinism(ctx: &Context, config: &Config, filter: &Filter) -> Vec<String> {
+    let first = resolved(ctx, config, filter);
+    let ctx = ctx.reload();
+    let second = resolved(&ctx, &config::load(&ctx), filter);
+    let mut diff: Vec<String> = first
+        .difference(&second)
+        .map(|line| format!("first run only: {}", line))
+        .collect();
+    diff.extend(
+        second
+            .difference(&first)
+            .map(|line| format!("second run only: {}", line)),
+    );
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_missing_bins() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-check-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return {
+                { "tools", requires_bin = { "sh", "mdot-missing-a", ["mdot-missing-b"] = "b-tools" } },
+                { "mdot-missing-a", package_name = "a-tools" },
+                { "lone", requires_bin = "mdot-missing-c" },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        assert!(check::find_bin("sh").is_some());
+
+        let mut missing = check::missing_bins(&config, &config.packages, &ctx.platform);
+        missing.sort_by(|a, b| a.bin.cmp(&b.bin));
+        let found: Vec<(&str, &str, Option<&str>)> = missing
+            .iter()
+            .map(|m| (m.package.as_str(), m.bin.as_str(), m.provider.as_deref()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("tools", "mdot-missing-a", Some("a-tools")),
+                ("tools", "mdot-missing-b", Some("b-tools")),
+                ("lone", "mdot-missing-c", None),
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_determinism() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-determinism-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let counter = root.join("runs");
+        fs::write(
+            root.join("main.lua"),
+            format!(
+                r#"
+                local file = io.open("{0}")
+                local runs = file and tonumber(file:read("*a")) or 0
+                if file then file:close() end
+                file = io.open("{0}", "w")
+                file:write(runs + 1)
+                file:close()
+                mdot.vars = {{ stable = "yes" }}
+                return {{
+                    "fish",
+                    {{ "flaky", enabled = function() return runs % 2 == 0 end }},
+                }}
+                "#,
+                counter.display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let diff = check::determinism(&ctx, &config, &cli::Filter::default());
+        assert!(diff.contains(&"first run only: package flaky".to_string()));
+        assert!(!diff.iter().any(|line| line.contains("fish")));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
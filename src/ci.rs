@@ -0,0 +1,378 @@
+use crate::cli::{CiFormat, Filter};
+use crate::config::{self, Config};
+use crate::reload::Reload;
+use crate::{
+    Context, HookAction, catch_fatal, compose, deploy, hosts, lint, plugins, render, select,
+};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// One check of `mdot ci`, reported as a test case.
+#[derive(Debug, PartialEq)]
+pub struct Case {
+    pub suite: &'static str,
+    pub name: String,
+    pub failures: Vec<String>,
+}
+
+/// The config as one machine sees it.
+struct Target {
+    name: String,
+    ctx: Context,
+}
+
+/// The machines the config is planned for: this one, this one with each
+/// profile, each hostname `hosts.lua` names and each `--simulate` spec.
+fn targets(ctx: &Context, config: &Config, simulate: &[String]) -> Result<Vec<Target>, String> {
+    let mut targets = vec![Target {
+        name: "default".to_string(),
+        ctx: ctx.reload(),
+    }];
+    for profile in config.profiles.keys() {
+        let mut target = ctx.reload();
+        target.profile = Some(profile.clone());
+        targets.push(Target {
+            name: format!("profile {}", profile),
+            ctx: target,
+        });
+    }
+    for host in hosts::load(&ctx.lua, &ctx.config_path) {
+        if host.pattern.contains(['*', '?', '[', '{']) {
+            continue;
+        }
+        let mut target = ctx.reload();
+        target.platform.hostname = host.pattern.clone();
+        targets.push(Target {
+            name: format!("host {}", host.pattern),
+            ctx: target,
+        });
+    }
+    for spec in simulate {
+        let mut target = ctx.reload();
+        target.platform = ctx
+            .platform
+            .simulate(spec)
+            .map_err(|err| format!("invalid --simulate '{}': {}", spec, err))?;
+        targets.push(Target {
+            name: format!("simulate {}", spec),
+            ctx: target,
+        });
+    }
+    Ok(targets)
+}
+
+/// Plans `target`, and renders its composed files under `dir`. A config
+/// that fails to load for `target` fails its plan case.
+fn plan(target: &Target, dir: &Path, cases: &mut Vec<Case>) {
+    let ctx = &target.ctx;
+    let config = match catch_fatal(|| config::load(ctx)) {
+        Ok(config) => config,
+        Err(err) => {
+            cases.push(Case {
+                suite: "plan",
+                name: target.name.clone(),
+                failures: vec![err],
+            });
+            return;
+        }
+    };
+    let profile = ctx.profile.as_ref().or(config.host.profile.as_ref());
+    let selection = select::select(
+        &config,
+        &[],
+        profile.map(String::as_str),
+        &Filter::default(),
+    );
+    let mut failures: Vec<String> = deploy::plan_errors(ctx, &config, &selection.packages)
+        .into_iter()
+        .map(|(package, err)| format!("[{}] {}", package, err))
+        .collect();
+    let mut rendered = Vec::new();
+    match compose::plan(ctx, &config, &selection.packages) {
+        Ok(composed) => {
+            for composed in composed {
+                let out = render::under(dir, &composed.target);
+                let written = compose::build(&composed).and_then(|content| {
+                    fs::create_dir_all(out.parent().unwrap_or(dir))
+                        .and_then(|_| fs::write(&out, content))
+                        .map_err(|err| format!("failed to write {}: {}", out.display(), err))
+                });
+                if let Err(err) = written {
+                    rendered.push(format!("{}: {}", composed.target.display(), err));
+                }
+            }
+        }
+        Err(err) => failures.push(err),
+    }
+    cases.push(Case {
+        suite: "plan",
+        name: target.name.clone(),
+        failures,
+    });
+    cases.push(Case {
+        suite: "templates",
+        name: target.name.clone(),
+        failures: rendered,
+    });
+}
+
+/// Parses the command hooks and reloads with `sh -n` and looks up the
+/// plugin actions of every package.
+fn hooks(ctx: &Context, config: &Config) -> Case {
+    let mut failures = Vec::new();
+    let mut parse =
+        |pkg: &str, cmd: &str| match Command::new("sh").arg("-n").arg("-c").arg(cmd).output() {
+            Ok(out) if out.status.success() => (),
+            Ok(out) => failures.push(format!(
+                "[{}] '{}' does not parse: {}",
+                pkg,
+                cmd,
+                String::from_utf8_lossy(&out.stderr).trim()
+            )),
+            Err(err) => failures.push(format!("failed to run sh: {}", err)),
+        };
+    let mut unknown = Vec::new();
+    for pkg in select::all_packages(config) {
+        for action in pkg
+            .on_install
+            .iter()
+            .chain(&pkg.actions)
+            .chain(&pkg.on_deploy)
+        {
+            match action {
+                HookAction::Command(cmd) | HookAction::Sandboxed(cmd) => parse(&pkg.name, cmd),
+                HookAction::Action { name, .. } if !plugins::has_action(ctx, name) => unknown.push(
+                    format!("[{}] no plugin registers the '{}' action", pkg.name, name),
+                ),
+                _ => (),
+            }
+        }
+        for reload in &pkg.reload {
+            if let Reload::Command(cmd) = reload {
+                parse(&pkg.name, cmd);
+            }
+        }
+    }
+    failures.extend(unknown);
+    Case {
+        suite: "hooks",
+        name: "parse".to_string(),
+        failures,
+    }
+}
+
+/// Runs every check: the lints of `mdot check`, a plan and the composed
+/// files of each target, and the hooks.
+pub fn run(ctx: &Context, config: &Config, simulate: &[String]) -> Result<Vec<Case>, String> {
+    let mut cases = vec![Case {
+        suite: "check",
+        name: "lint".to_string(),
+        failures: lint::check(ctx, config)
+            .iter()
+            .map(|lint| lint.to_string())
+            .collect(),
+    }];
+    let dir = env::temp_dir().join(format!("mdot-ci-{}", std::process::id()));
+    for target in targets(ctx, config, simulate)? {
+        let slug: String = target
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        plan(&target, &dir.join(slug), &mut cases);
+    }
+    let _ = fs::remove_dir_all(&dir);
+    cases.push(hooks(ctx, config));
+    Ok(cases)
+}
+
+/// Escapes `s` for an XML attribute or text.
+fn xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escapes `s` for the message of a GitHub workflow command.
+fn workflow(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// The report of `cases` in `format`.
+pub fn report(cases: &[Case], format: CiFormat) -> String {
+    let mut out = String::new();
+    match format {
+        CiFormat::Text => {
+            for case in cases {
+                let status = if case.failures.is_empty() {
+                    "ok"
+                } else {
+                    "FAIL"
+                };
+                out.push_str(&format!("{:<4} {}: {}\n", status, case.suite, case.name));
+                for failure in &case.failures {
+                    out.push_str(&format!("     {}\n", failure));
+                }
+            }
+        }
+        CiFormat::Junit => {
+            let failed = |cases: &[&Case]| cases.iter().filter(|c| !c.failures.is_empty()).count();
+            let all: Vec<&Case> = cases.iter().collect();
+            out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            out.push_str(&format!(
+                "<testsuites name=\"mdot ci\" tests=\"{}\" failures=\"{}\">\n",
+                all.len(),
+                failed(&all)
+            ));
+            let mut suites: Vec<&str> = Vec::new();
+            for case in cases {
+                if !suites.contains(&case.suite) {
+                    suites.push(case.suite);
+                }
+            }
+            for suite in suites {
+                let cases: Vec<&Case> = cases.iter().filter(|c| c.suite == suite).collect();
+                out.push_str(&format!(
+                    "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n",
+                    suite,
+                    cases.len(),
+                    failed(&cases)
+                ));
+                for case in cases {
+                    let name = format!("classname=\"{}\" name=\"{}\"", suite, xml(&case.name));
+                    if case.failures.is_empty() {
+                        out.push_str(&format!("    <testcase {}/>\n", name));
+                        continue;
+                    }
+                    out.push_str(&format!("    <testcase {}>\n", name));
+                    for failure in &case.failures {
+                        out.push_str(&format!("      <failure message=\"{}\"/>\n", xml(failure)));
+                    }
+                    out.push_str("    </testcase>\n");
+                }
+                out.push_str("  </testsuite>\n");
+            }
+            out.push_str("</testsuites>\n");
+        }
+        CiFormat::Github => {
+            for case in cases {
+                for failure in &case.failures {
+                    out.push_str(&format!(
+                        "::error title=mdot {}: {}::{}\n",
+                        case.suite,
+                        workflow(&case.name),
+                        workflow(failure)
+                    ));
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ci::*;
+    use crate::*;
+
+    #[test]
+    fn test_ci() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-ci-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("git")).unwrap();
+        fs::create_dir_all(root.join("shell")).unwrap();
+        fs::write(root.join("git/gitconfig"), "").unwrap();
+        fs::write(
+            root.join("shell/env.sh"),
+            "export EDITOR={{ vars.editor }}\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("hosts.lua"),
+            r#"return { mbp = { facts = { editor = "zed" } }, ["work-*"] = "work", ["mdot-broken"] = {} }"#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.profiles.work = { "git", "shell" }
+            if mdot.fact("editor") ~= "zed" then mdot.vars.editor = "nvim" end
+            if mdot.hostname() == "mdot-broken" then error("not set up yet") end
+            return {
+                { "git", links = { { source = "gitconfig", targets = "~/.gitconfig" } },
+                  on_deploy = "git config --global init.defaultBranch main", reload = "git fsck (" },
+                { "shell", links = { { source = "env.sh", targets = "~/.env.sh", compose = "fragments" } },
+                  on_install = "if true; then echo", actions = { gsettings = {} } },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        let cases = run(&ctx, &config, &["os=macos,hostname=mbp".to_string()]).unwrap();
+        let names: Vec<String> = cases
+            .iter()
+            .map(|c| format!("{}: {}", c.suite, c.name))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "check: lint",
+                "plan: default",
+                "templates: default",
+                "plan: profile work",
+                "templates: profile work",
+                "plan: host mbp",
+                "templates: host mbp",
+                "plan: host mdot-broken",
+                "plan: simulate os=macos,hostname=mbp",
+                "templates: simulate os=macos,hostname=mbp",
+                "hooks: parse",
+            ]
+        );
+        let failed: Vec<&str> = cases
+            .iter()
+            .filter(|c| !c.failures.is_empty())
+            .map(|c| c.name.as_str())
+            .collect();
+        // mbp has the `editor` fact, so its fragments miss `vars.editor`.
+        assert_eq!(
+            failed,
+            [
+                "host mbp",
+                "host mdot-broken",
+                "simulate os=macos,hostname=mbp",
+                "parse"
+            ]
+        );
+        let broken = &cases[7].failures;
+        assert_eq!(broken.len(), 1);
+        assert!(broken[0].contains("not set up yet"), "{:?}", broken);
+        let hooks = &cases.last().unwrap().failures;
+        assert_eq!(hooks.len(), 3, "{:?}", hooks);
+        assert!(hooks[0].starts_with("[git] 'git fsck (' does not parse"));
+        assert!(hooks[1].starts_with("[shell] 'if true; then echo' does not parse"));
+        assert_eq!(
+            hooks[2],
+            "[shell] no plugin registers the 'gsettings' action"
+        );
+
+        let junit = report(&cases, CiFormat::Junit);
+        assert!(junit.contains("<testsuites name=\"mdot ci\" tests=\"11\" failures=\"4\">"));
+        assert!(junit.contains("<testsuite name=\"hooks\" tests=\"1\" failures=\"1\">"));
+        assert!(junit.contains("<testcase classname=\"check\" name=\"lint\"/>"));
+        let github = report(&cases, CiFormat::Github);
+        assert_eq!(github.lines().count(), 6);
+        assert!(github.contains("::error title=mdot hooks: parse::[shell] no plugin"));
+        assert!(report(&cases, CiFormat::Text).starts_with("ok   check: lint\n"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
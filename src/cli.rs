@@ -0,0 +1,539 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+/// The Lua mdot embeds, chosen with the `lua54`, `lua51` and `luajit`
+/// cargo features.
+#[cfg(feature = "lua54")]
+macro_rules! lua_flavor {
+    () => {
+        "Lua 5.4"
+    };
+}
+#[cfg(feature = "lua51")]
+macro_rules! lua_flavor {
+    () => {
+        "Lua 5.1"
+    };
+}
+#[cfg(feature = "luajit")]
+macro_rules! lua_flavor {
+    () => {
+        "LuaJIT"
+    };
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "mdot",
+    about = "Declarative dotfiles manager configured in Lua",
+    version = concat!(env!("CARGO_PKG_VERSION"), " (", lua_flavor!(), ")")
+)]
+pub struct Cli {
+    /// Entry config file; relative paths are looked up in the working
+    /// directory first, then in the config directory
+    #[arg(short, long, global = true, env = "MDOT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Only deploy the packages selected by this profile from `mdot.profiles`
+    #[arg(short, long, global = true, env = "MDOT_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Print stable tab separated events on stdout and logs on stderr
+    #[arg(long, global = true)]
+    pub porcelain: bool,
+
+    /// Override a variable for this run, e.g. `--set font.size=11`; wins
+    /// over every other layer
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = parse_set)]
+    pub set: Vec<(String, String)>,
+
+    /// Answer yes to questions, e.g. back up and replace conflicting files
+    #[arg(short = 'y', long, global = true, conflicts_with = "assume_no")]
+    pub assume_yes: bool,
+
+    /// Answer no to questions, e.g. skip conflicting files
+    #[arg(long, global = true)]
+    pub assume_no: bool,
+
+    /// Skip package installs and git pulls instead of waiting on the
+    /// network; `mdot.net` checks report no connection
+    #[arg(long, global = true)]
+    pub offline: bool,
+
+    /// Evaluate the config as the machine named HOSTNAME would, for its
+    /// `hosts.lua` entries, host file and `mdot.hostname()`; only for
+    /// commands that change nothing, such as plan, status and export
+    #[arg(long, global = true, value_name = "HOSTNAME")]
+    pub as_host: Option<String>,
+
+    /// Hash every copied file compared, instead of trusting the recorded
+    /// hash of a file whose size and modification time did not change
+    #[arg(long, global = true)]
+    pub paranoid: bool,
+
+    /// Seconds Lua code may run: one evaluation of the config, or one call
+    /// of an `enabled`, hook or template function (default: 10)
+    #[arg(long, global = true, value_name = "SECONDS", env = "MDOT_LUA_TIMEOUT")]
+    pub lua_timeout: Option<u64>,
+
+    /// Megabytes the Lua state may allocate (default: 512)
+    #[arg(long, global = true, value_name = "MB", env = "MDOT_LUA_MEMORY")]
+    pub lua_memory: Option<usize>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Parses `--set key=value` into the `vars.key` variable.
+fn parse_set(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", arg))?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("missing variable name in '{}'", arg));
+    }
+    let key = match key.starts_with("vars.") {
+        true => key.to_string(),
+        false => format!("vars.{}", key),
+    };
+    Ok((key, value.to_string()))
+}
+
+/// Filters narrowing which packages are deployed.
+#[derive(Args, Debug, Default)]
+pub struct Filter {
+    /// Only deploy packages carrying one of these tags
+    #[arg(long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+    /// Never deploy this package, even when others depend on it
+    #[arg(long, value_name = "PACKAGE")]
+    pub exclude: Vec<String>,
+    /// Never deploy packages carrying this tag
+    #[arg(long = "exclude-tag", value_name = "TAG")]
+    pub exclude_tags: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Link packages into place
+    Deploy {
+        /// Packages to deploy, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+        /// Skip installing the OS packages before linking
+        #[arg(long)]
+        no_install: bool,
+        /// Remove the links of packages that are gone from the config
+        #[arg(long)]
+        prune: bool,
+        /// Copy the sources instead of linking them
+        #[arg(long)]
+        copy: bool,
+        /// Provision a container: implies --no-install and --copy, skips
+        /// packages tagged `gui` and only logs warnings. Implied by the
+        /// `container` profile
+        #[arg(long)]
+        minimal: bool,
+        /// Download `fetch` entries that have no checksum or signature
+        #[arg(long)]
+        insecure_fetch: bool,
+        /// Print each hook and ask before running it, unless it only runs
+        /// a program from the `allowed_hooks` file of the state dir
+        #[arg(long)]
+        confirm_hooks: bool,
+        /// Link into `~/.ssh`, `~/.gnupg` and shell startup files, and
+        /// link executables, without asking
+        #[arg(long)]
+        allow_sensitive: bool,
+        /// Refuse to deploy packages, hooks or downloads missing from
+        /// `mdot.lock`
+        #[arg(long)]
+        locked: bool,
+        /// Carry on an interrupted deploy, skipping the steps it completed
+        /// and the links it made that are still in place
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Install the OS packages of the selected packages
+    Install {
+        /// Packages to install, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+    },
+    /// Verify that the binaries and PATH entries of the selected packages exist
+    #[command(alias = "doctor")]
+    Check {
+        /// Packages to check, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+        /// Rewrite the config to fix what can be fixed automatically
+        #[arg(long)]
+        fix: bool,
+        /// Evaluate the config twice and report what differs between runs
+        #[arg(long)]
+        determinism: bool,
+    },
+    /// Show whether the planned links are in place
+    Status {
+        /// Packages to inspect, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+        /// Show what changed in the config since the last deploy instead
+        #[arg(long)]
+        since_last: bool,
+    },
+    /// Print the OS packages and links a deploy would create, without
+    /// looking at the targets
+    Plan {
+        /// Packages to plan, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+        /// Resolve the config as on another platform, e.g.
+        /// `os=macos,hostname=mbp`; also takes distro, family, arch and wsl
+        #[arg(long, value_name = "KEY=VALUE,...")]
+        simulate: Option<String>,
+    },
+    /// Lint the config, plan it for every profile, host and simulated
+    /// platform, render its composed files and parse its hooks, for pull
+    /// request checks
+    Ci {
+        /// Also plan as on this platform, e.g. `os=macos,hostname=mbp`
+        #[arg(long, value_name = "KEY=VALUE,...")]
+        simulate: Vec<String>,
+        #[arg(long, value_enum, default_value_t = CiFormat::Text)]
+        format: CiFormat,
+        /// Write the report to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Render the templates of the selected packages into a private
+    /// directory in the cache dir, reporting undefined variables and syntax
+    /// errors
+    Render {
+        /// Only render the templates of this package
+        package: Option<String>,
+        /// Only report the problems, without keeping the rendered files
+        #[arg(long)]
+        check: bool,
+        /// Print this template rendered instead, a path in the repo
+        #[arg(long, value_name = "FILE", conflicts_with = "check")]
+        stdout: Option<String>,
+        /// Write every target a deploy would create below this directory
+        /// instead, at its absolute path: sources copied, templates
+        /// rendered
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["check", "stdout"])]
+        out: Option<PathBuf>,
+    },
+    /// Hand the recorded links of a renamed package over to its new name
+    Mv { old: String, new: String },
+    /// Keep the config loaded and serve a JSON API on a Unix socket
+    Daemon,
+    /// Work with the dotfiles repo through git
+    Git {
+        #[command(subcommand)]
+        command: GitCommand,
+    },
+    /// Move the cached checkout of the repo the config `extends` to its
+    /// latest commit, and record that commit in the repo
+    UpdateBase,
+    /// Fetch a community package repo, e.g. `github:user/mdot-kitty`, and add its packages
+    AddRemote {
+        /// `github:user/repo`, `gitlab:user/repo` or a git url
+        spec: String,
+        /// Times to retry cloning and pulling this remote, instead of
+        /// `mdot.options.retries`
+        #[arg(long)]
+        retries: Option<u32>,
+    },
+    /// Pull the latest commits of the remote package repos
+    UpdateRemote {
+        /// Only update this remote
+        name: Option<String>,
+    },
+    /// Forget a remote package repo and delete its checkout
+    RemoveRemote { name: String },
+    /// Inspect the fleet inventory in `hosts.lua`
+    Hosts {
+        #[command(subcommand)]
+        action: HostsAction,
+    },
+    /// Run `mdot git sync` periodically through systemd or launchd
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+    /// Open the source of a package or target in $EDITOR, then render and
+    /// link again the links of what was edited
+    Edit {
+        /// Package name or deployed target path
+        target: String,
+    },
+    /// Print the repo file backing a deployed target
+    SourcePath { target: String },
+    /// Print the targets a repo file is deployed to
+    TargetPath {
+        /// Repo path, relative to the working directory or the repo
+        source: String,
+    },
+    /// Print the resolved links, packages or variables
+    Query {
+        what: QueryKind,
+        /// Print a single line of JSON instead of `path = value` lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the OS packages or links of the selected packages for other
+    /// tools
+    Export {
+        #[command(subcommand)]
+        what: ExportKind,
+    },
+    /// Record the selected packages, their hook hashes and downloads in
+    /// `mdot.lock` at the repo root, for `deploy --locked`
+    Lock {
+        /// Packages to lock, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+    },
+    /// Pack the repo and an `install.sh` deploying it into a tarball, for
+    /// machines without mdot
+    Bundle {
+        #[arg(short, long, default_value = "mdot-bundle.tar.gz")]
+        output: PathBuf,
+        /// Include this mdot binary; build it with `just bootstrap` to
+        /// have it run anywhere
+        #[arg(long)]
+        binary: bool,
+    },
+    /// Revert the most recent deploy
+    Undo,
+    /// List past runs recorded in the journal
+    History {
+        #[command(subcommand)]
+        action: Option<HistoryAction>,
+    },
+    /// Print the path of the dotfiles repo
+    Root,
+    /// Print where mdot keeps its config, state, data and cache
+    Paths,
+    /// Print shell integration (completions, `mdot cd`, a prompt segment)
+    /// to eval from the rc file of bash, zsh or fish
+    ShellInit { shell: String },
+    /// Print the man page in roff, e.g. `mdot man > mdot.1`
+    Man {
+        /// Write it and a page per subcommand, e.g. `mdot-deploy.1`, into
+        /// this directory instead
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Print the completion candidates used by the shell integration
+    #[command(hide = true)]
+    Complete { what: CompleteKind },
+    /// List the packages defined by the config
+    List {
+        /// Also print descriptions, link and hook counts and dependencies
+        #[arg(short, long)]
+        long: bool,
+    },
+    /// Print everything about one package: decision, dependencies,
+    /// resolved links and their status, templates, hooks and README
+    Info { package: String },
+    /// Print every resolved fact
+    Facts,
+    /// Print the template variables with their values and the layer that
+    /// set them: mdot.vars < package vars < profile vars < host file <
+    /// local.lua < --set
+    Vars {
+        /// Show what this package's templates see
+        #[arg(long)]
+        package: Option<String>,
+    },
+    /// Explain whether a package will be deployed, and why
+    Why {
+        package: String,
+        #[command(flatten)]
+        filter: Filter,
+    },
+    /// A command registered by a plugin with `mdot.register_command`
+    #[command(external_subcommand)]
+    Plugin(Vec<String>),
+}
+
+impl Command {
+    /// Commands that change files and are recorded in the journal.
+    pub fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Command::Deploy { .. } | Command::Git { .. } | Command::Edit { .. } | Command::Undo
+        )
+    }
+
+    /// Commands that only report, and so may evaluate the config of
+    /// another machine with `--as-host`.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Check { .. }
+                | Command::Status { .. }
+                | Command::Plan { .. }
+                | Command::Ci { .. }
+                | Command::Render { .. }
+                | Command::Hosts { .. }
+                | Command::SourcePath { .. }
+                | Command::TargetPath { .. }
+                | Command::Query { .. }
+                | Command::Export { .. }
+                | Command::History { .. }
+                | Command::Root
+                | Command::Paths
+                | Command::ShellInit { .. }
+                | Command::Complete { .. }
+                | Command::List { .. }
+                | Command::Info { .. }
+                | Command::Facts
+                | Command::Vars { .. }
+                | Command::Why { .. }
+        )
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GitCommand {
+    /// Fast-forward the repo from its upstream, then link every package
+    Sync,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ExportKind {
+    /// A Brewfile for `brew bundle`, with the names resolved for macOS
+    Brewfile {
+        /// Packages to export, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+    },
+    /// A Home Manager module linking the resolved targets through
+    /// `home.file` and `xdg.configFile`
+    Nix {
+        /// Packages to export, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+    },
+    /// A devcontainer feature in DIR that installs the CLI packages and
+    /// copies the dotfiles, leaving out packages tagged `gui`
+    Devcontainer {
+        dir: PathBuf,
+        /// Packages to export, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+        /// Package manager of the container image (default: apt)
+        #[arg(long, value_enum)]
+        format: Option<PackageManager>,
+    },
+    /// One name per line, e.g. for `pacman -S --needed - < pkglist`
+    Pkglist {
+        /// Packages to export, along with their dependencies (default: all)
+        packages: Vec<String>,
+        #[command(flatten)]
+        filter: Filter,
+        /// Package manager to resolve the names for (default: this
+        /// platform's)
+        #[arg(long, value_enum)]
+        format: Option<PackageManager>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PackageManager {
+    Pacman,
+    Apt,
+    Dnf,
+    Apk,
+    Brew,
+    Nix,
+    Winget,
+    Scoop,
+    Choco,
+}
+
+impl PackageManager {
+    pub fn name(self) -> &'static str {
+        match self {
+            PackageManager::Pacman => "pacman",
+            PackageManager::Apt => "apt",
+            PackageManager::Dnf => "dnf",
+            PackageManager::Apk => "apk",
+            PackageManager::Brew => "brew",
+            PackageManager::Nix => "nix",
+            PackageManager::Winget => "winget",
+            PackageManager::Scoop => "scoop",
+            PackageManager::Choco => "choco",
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryAction {
+    /// Print every change a run made
+    Show { id: u64 },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HostsAction {
+    /// Print every entry, marking those matching this machine, and the
+    /// profile and tags they resolve to
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ScheduleAction {
+    /// Write and enable a user timer running `mdot git sync`
+    Install {
+        /// Time between runs, e.g. `30m`, `1h` or `1d`
+        #[arg(long, default_value = "1h")]
+        interval: String,
+    },
+    /// Disable and delete the timer
+    Remove,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CompleteKind {
+    /// Package names
+    Packages,
+    /// Profile names from `mdot.profiles`
+    Profiles,
+    /// Tags of packages and profiles
+    Tags,
+    /// Deployed targets, from the state
+    Targets,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CiFormat {
+    /// One line per check, then its failures
+    Text,
+    /// JUnit XML, for test report viewers
+    Junit,
+    /// `::error` workflow commands, shown as GitHub annotations
+    Github,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum QueryKind {
+    /// Planned links with their status
+    Links,
+    /// Every package and whether it is deployed
+    Packages,
+    /// Template variables, globally and per package
+    Vars,
+}
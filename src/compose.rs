@@ -0,0 +1,343 @@
+use crate::config::Config;
+use crate::deploy::{self, resolve_target};
+use crate::state::{escape, unescape};
+use crate::template::{self, Settings, Vars};
+use crate::{Context, Package, assets, attrs};
+use log::info;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The targets mdot composed, one per line in the state dir, so that only
+/// a file it did not write is backed up before it is replaced.
+pub const COMPOSED_FILE: &str = "composed";
+const HEADER: &str = "# mdot composed v1";
+
+/// How a link whose target several packages contribute to is assembled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compose {
+    /// The fragments joined in package order.
+    Concat,
+    /// The fragments rendered as templates and joined in package order,
+    /// each between marker comments naming its package.
+    Fragments,
+}
+
+impl Compose {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "concat" => Some(Compose::Concat),
+            "fragments" => Some(Compose::Fragments),
+            _ => None,
+        }
+    }
+}
+
+/// One fragment of a composed target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragment {
+    pub package: String,
+    pub source: PathBuf,
+    pub vars: Vars,
+    /// The `template_delims` of the package, for `compose = "fragments"`.
+    pub settings: Settings,
+}
+
+/// A target generated from the fragments of one or more packages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Composed {
+    pub target: PathBuf,
+    pub mode: Compose,
+    /// Line comment prefix of the target's format, for markers.
+    pub comment: String,
+    pub fragments: Vec<Fragment>,
+}
+
+/// Collects the `compose` links of `packages` by target, keeping the
+/// fragments in package order.
+pub fn plan(ctx: &Context, config: &Config, packages: &[Package]) -> Result<Vec<Composed>, String> {
+    let mut composed: BTreeMap<PathBuf, Composed> = BTreeMap::new();
+    for pkg in packages {
+        let vars = template::package_vars(config, pkg, &ctx.platform);
+        let dir = ctx.package_dir(pkg);
+        for link in &pkg.links {
+            let Some(mode) = link.compose else {
+                continue;
+            };
+            for target in &link.targets {
+                let target = resolve_target(target, &vars)?;
+                let entry = composed.entry(target.clone()).or_insert_with(|| Composed {
+                    target: target.clone(),
+                    mode,
+                    comment: link.comment.clone().unwrap_or_else(|| "#".to_string()),
+                    fragments: Vec::new(),
+                });
+                if entry.mode != mode {
+                    return Err(format!(
+                        "{} is composed in different modes",
+                        target.display()
+                    ));
+                }
+                entry.fragments.push(Fragment {
+                    package: pkg.name.clone(),
+                    source: dir.join(&link.source),
+                    vars: vars.clone(),
+                    settings: template::package_settings(config, pkg),
+                });
+            }
+        }
+    }
+    Ok(composed.into_values().collect())
+}
+
+/// The content of `composed` from its fragments as they are now.
+pub fn build(composed: &Composed) -> Result<String, String> {
+    let mut out = String::new();
+    for fragment in &composed.fragments {
+        let content = fs::read_to_string(&fragment.source)
+            .map_err(|err| format!("failed to read {}: {}", fragment.source.display(), err))?;
+        match composed.mode {
+            Compose::Concat => out.push_str(&content),
+            Compose::Fragments => {
+                let rendered = template::render_with(&content, &fragment.vars, &fragment.settings)
+                    .map_err(|err| format!("{}: {}", fragment.source.display(), err))?;
+                let marker = format!("{} mdot: {}", composed.comment, fragment.package);
+                out.push_str(&format!("{} >>>\n{}", marker, rendered));
+                if !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str(&format!("{} <<<\n", marker));
+            }
+        }
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+/// The loosest permissions the target of `composed` gets: those of its
+/// first fragment, so that a `0600` fragment makes a `0600` target.
+fn mode(composed: &Composed) -> Option<u32> {
+    assets::mode(&composed.fragments.first()?.source).ok()
+}
+
+/// Whether the target of `composed` holds what its fragments build.
+fn same_content(composed: &Composed) -> bool {
+    let target = &composed.target;
+    !fs::symlink_metadata(target).is_ok_and(|meta| meta.is_symlink())
+        && build(composed).is_ok_and(|content| fs::read_to_string(target).ok() == Some(content))
+}
+
+/// Whether the target of `composed` holds what its fragments build, with
+/// permissions no looser than theirs.
+pub fn up_to_date(composed: &Composed) -> bool {
+    same_content(composed)
+        && matches!(
+            (mode(composed), assets::mode(&composed.target)),
+            (Some(mode), Ok(target)) if target & !mode == 0
+        )
+}
+
+/// The status of the target of `composed`: `missing`, `ok`, `drifted`
+/// when only its permissions are looser, or `changed`.
+pub fn status(composed: &Composed) -> &'static str {
+    if !composed.target.exists() {
+        "missing"
+    } else if !same_content(composed) {
+        "changed"
+    } else if !up_to_date(composed) {
+        "drifted"
+    } else {
+        "ok"
+    }
+}
+
+/// The targets composed by earlier deploys.
+fn written(state_dir: &Path) -> io::Result<BTreeSet<PathBuf>> {
+    match fs::read_to_string(state_dir.join(COMPOSED_FILE)) {
+        Ok(content) => Ok(content
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| PathBuf::from(unescape(line)))
+            .collect()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(err) => Err(err),
+    }
+}
+
+fn save_written(state_dir: &Path, targets: &BTreeSet<PathBuf>) -> io::Result<()> {
+    let mut out = format!("{}\n", HEADER);
+    for target in targets {
+        out.push_str(&format!("{}\n", escape(&target.to_string_lossy())));
+    }
+    fs::create_dir_all(state_dir)?;
+    fs::write(state_dir.join(COMPOSED_FILE), out)
+}
+
+/// Regenerates every composed target of `packages` that is missing or
+/// whose fragments changed, returning those written. A file mdot did not
+/// compose before is backed up first, under the first package composing it.
+/// Targets in `held_back` are left alone.
+pub fn sync(
+    ctx: &Context,
+    config: &Config,
+    packages: &[Package],
+    held_back: &BTreeSet<PathBuf>,
+) -> Result<Vec<Composed>, String> {
+    let mut ours = written(&ctx.state_dir).map_err(|err| err.to_string())?;
+    let mut written = Vec::new();
+    for composed in plan(ctx, config, packages)? {
+        if up_to_date(&composed) {
+            continue;
+        }
+        if held_back.contains(&composed.target) {
+            info!("skipped sensitive {}", composed.target.display());
+            continue;
+        }
+        let content = build(&composed)?;
+        let target = &composed.target;
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        match fs::symlink_metadata(target) {
+            Ok(meta) if meta.is_symlink() => {
+                fs::remove_file(target).map_err(|err| err.to_string())?;
+            }
+            Ok(_) if !ours.contains(target) => {
+                let package = &composed.fragments[0].package;
+                deploy::back_up(package, target, &ctx.backup_dir())
+                    .map_err(|err| format!("failed to back up {}: {}", target.display(), err))?;
+            }
+            _ => {}
+        }
+        let kept = mode(&composed).map(|mode| assets::kept_mode(mode, target));
+        fs::write(target, content)
+            .and_then(|_| match kept {
+                Some(mode) => fs::set_permissions(target, fs::Permissions::from_mode(mode)),
+                None => Ok(()),
+            })
+            .map_err(|err| {
+                format!(
+                    "failed to write {}: {}",
+                    target.display(),
+                    attrs::explain(target, err)
+                )
+            })?;
+        let packages: Vec<&str> = composed
+            .fragments
+            .iter()
+            .map(|f| f.package.as_str())
+            .collect();
+        info!("composed {} from {}", target.display(), packages.join(", "));
+        if ours.insert(target.clone()) {
+            save_written(&ctx.state_dir, &ours).map_err(|err| err.to_string())?;
+        }
+        written.push(composed);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    #[test]
+    fn test_concat() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-compose-{}", std::process::id()));
+        fs::create_dir_all(root.join("base")).unwrap();
+        fs::create_dir_all(root.join("work")).unwrap();
+        fs::write(root.join("base/ssh.conf"), "Host *\n  AddKeysToAgent yes").unwrap();
+        fs::write(root.join("work/ssh.conf"), "Host work\n  User me\n").unwrap();
+        let target = root.join("home/.ssh/config");
+        fs::write(
+            root.join("main.lua"),
+            format!(
+                r#"
+                local link = {{ source = "ssh.conf", targets = "{}", compose = "concat" }}
+                return {{ {{ "base", links = {{ link }} }}, {{ "work", links = {{ link }} }} }}
+                "#,
+                target.display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .build();
+        let config = config::load(&ctx);
+        assert!(deploy::plan(&ctx, &config, &config.packages).is_empty());
+
+        // A config the user wrote is backed up before it is first replaced.
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(&target, "Host mine\n").unwrap();
+        let written = compose::sync(&ctx, &config, &config.packages, &BTreeSet::new()).unwrap();
+        let written: Vec<&PathBuf> = written.iter().map(|c| &c.target).collect();
+        assert_eq!(written, [&target]);
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "Host *\n  AddKeysToAgent yes\nHost work\n  User me\n"
+        );
+        assert!(
+            compose::sync(&ctx, &config, &config.packages, &BTreeSet::new())
+                .unwrap()
+                .is_empty()
+        );
+
+        fs::write(root.join("work/ssh.conf"), "Host work\n  User you\n").unwrap();
+        assert_eq!(
+            compose::sync(&ctx, &config, &config.packages, &BTreeSet::new())
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(fs::read_to_string(&target).unwrap().ends_with("User you\n"));
+        let backups = backup::index(&ctx.backup_dir()).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(
+            (backups[0].package.as_str(), &backups[0].target),
+            ("base", &target)
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_fragments() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-fragments-{}", std::process::id()));
+        fs::create_dir_all(root.join("git")).unwrap();
+        fs::create_dir_all(root.join("nvim")).unwrap();
+        fs::write(root.join("git/aliases"), "alias g=git\n").unwrap();
+        fs::write(root.join("nvim/aliases"), "alias v={{ name }}").unwrap();
+        let target = root.join("home/.bash_aliases");
+        fs::write(
+            root.join("main.lua"),
+            format!(
+                r#"
+                local link = {{ source = "aliases", targets = "{}", compose = "fragments" }}
+                return {{ {{ "git", links = {{ link }} }}, {{ "nvim", links = {{ link }} }} }}
+                "#,
+                target.display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        compose::sync(&ctx, &config, &config.packages, &BTreeSet::new()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "# mdot: git >>>\nalias g=git\n# mdot: git <<<\n\
+             # mdot: nvim >>>\nalias v=nvim\n# mdot: nvim <<<\n"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,1016 @@
+use crate::deploy::resolve_target;
+use crate::template::{self, HOSTS_DIR, LOCAL_FILE, Layer, Vars};
+use crate::{
+    Context, Package, api, base, facts, field, hooks, hosts, install, limits, lua_value_to_str,
+    macos, net, ordered_pairs, plugins, remote, schema, select, store, walk, xdg,
+};
+use log::warn;
+use mlua::{Lua, Result as LuaResult, Table, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Tables returned by files pulled in through `include()`, in call order.
+#[derive(Default)]
+struct Included {
+    tables: Vec<Table>,
+    stack: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    /// Root that paths are relative to instead of the config root, while
+    /// an `extends` base is evaluated.
+    root: Option<PathBuf>,
+}
+
+pub fn eval_file(lua: &Lua, path: &Path) -> LuaResult<Value> {
+    if !path.is_file() {
+        return Err(mlua::Error::runtime(format!(
+            "config file '{}' does not exist",
+            path.display()
+        )));
+    }
+    limits::budgeted(|| lua.load(path).eval::<Value>())
+}
+
+/// A package as `mdot.ref(name)` shows it to config code, with paths
+/// resolved.
+#[derive(Debug, Clone, Default)]
+struct PackageRef {
+    dir: String,
+    description: String,
+    default_target: String,
+    links: Vec<(String, Vec<String>)>,
+}
+
+/// Whether the config called `mdot.ref(name)`.
+#[derive(Default)]
+struct PackageRefs {
+    used: bool,
+}
+
+/// Stands for a value of `mdot.ref(name)` in the strings of the config
+/// until the packages are known: the name and field between `REF_START`,
+/// a separator and `REF_END`.
+const REF_START: &str = "\u{1}mdot.ref\u{1}";
+const REF_SEP: char = '\u{1}';
+const REF_END: char = '\u{2}';
+/// Links, and targets of a link, a reference may index, so that `ipairs`
+/// over one stops with an error instead of running forever.
+const MAX_REF_INDEX: i64 = 64;
+
+fn reference(name: &str, field: &str) -> String {
+    format!("{}{}{}{}{}", REF_START, name, REF_SEP, field, REF_END)
+}
+
+/// A table of references to the values of `mdot.ref(name)` at `path`,
+/// each indexed on demand by `item`. Their number is not known yet.
+fn ref_list<F>(lua: &Lua, path: String, item: F) -> LuaResult<Table>
+where
+    F: Fn(&Lua, i64) -> LuaResult<Value> + 'static,
+{
+    let meta = lua.create_table()?;
+    let len_path = path.clone();
+    meta.set(
+        "__index",
+        lua.create_function(move |lua, (_, key): (Table, Value)| {
+            let Value::Integer(i) = key else {
+                return Ok(Value::Nil);
+            };
+            if !(1..=MAX_REF_INDEX).contains(&i) {
+                return Err(mlua::Error::runtime(format!(
+                    "{} cannot be iterated while the config is evaluated, index it instead",
+                    path
+                )));
+            }
+            item(lua, i)
+        })?,
+    )?;
+    meta.set(
+        "__len",
+        lua.create_function(move |_, _: Table| -> LuaResult<i64> {
+            Err(mlua::Error::runtime(format!(
+                "the length of {} is not known while the config is evaluated",
+                len_path
+            )))
+        })?,
+    )?;
+    let list = lua.create_table()?;
+    list.set_metatable(Some(meta))?;
+    Ok(list)
+}
+
+/// Registers `mdot.ref(name)`, returning `{ name, dir, description,
+/// default_target, links = { { source, targets = {...} } } }`.
+///
+/// The packages are only known once the config returned them, so the
+/// values are references that strings built from them carry along. They
+/// are resolved in link sources, targets and `default_target` after the
+/// config is evaluated, once; used anywhere else they are an error.
+fn install_ref(lua: &Lua) -> LuaResult<()> {
+    let mdot: Table = lua.globals().get("mdot")?;
+    let reference = lua.create_function(|lua, name: String| {
+        if let Some(mut refs) = lua.app_data_mut::<PackageRefs>() {
+            refs.used = true;
+        }
+        let path = format!("mdot.ref('{}').links", name);
+        let links_name = name.clone();
+        let links = ref_list(lua, path, move |lua, i| {
+            let link = lua.create_table()?;
+            let name = links_name.clone();
+            link.set("source", reference(&name, &format!("links.{}.source", i)))?;
+            let path = format!("mdot.ref('{}').links[{}].targets", name, i);
+            let targets = ref_list(lua, path, move |lua, j| {
+                let field = format!("links.{}.targets.{}", i, j);
+                lua.create_string(reference(&name, &field))
+                    .map(Value::String)
+            })?;
+            link.set("targets", targets)?;
+            Ok(Value::Table(link))
+        })?;
+        let tbl = lua.create_table()?;
+        tbl.set("name", name.clone())?;
+        for field in ["dir", "description", "default_target"] {
+            tbl.set(field, reference(&name, field))?;
+        }
+        tbl.set("links", links)?;
+        Ok(tbl)
+    })?;
+    mdot.set("ref", reference)?;
+    Ok(())
+}
+
+/// The value the reference to `field` of package `name` stands for.
+fn lookup(refs: &BTreeMap<String, PackageRef>, name: &str, field: &str) -> Result<String, String> {
+    let pkg = refs
+        .get(name)
+        .ok_or_else(|| format!("mdot.ref('{}'): package '{}' is not defined", name, name))?;
+    let parts: Vec<&str> = field.split('.').collect();
+    let index = |i: &str| i.parse::<usize>().ok().and_then(|i| i.checked_sub(1));
+    let found = match parts.as_slice() {
+        ["dir"] => Some(pkg.dir.clone()),
+        ["description"] => Some(pkg.description.clone()),
+        ["default_target"] => Some(pkg.default_target.clone()),
+        ["links", i, "source"] => index(i)
+            .and_then(|i| pkg.links.get(i))
+            .map(|(source, _)| source.clone()),
+        ["links", i, "targets", j] => index(i)
+            .and_then(|i| pkg.links.get(i))
+            .and_then(|(_, targets)| targets.get(index(j)?))
+            .cloned(),
+        _ => None,
+    };
+    let path = parts
+        .iter()
+        .map(|part| match part.parse::<usize>() {
+            Ok(_) => format!("[{}]", part),
+            Err(_) => format!(".{}", part),
+        })
+        .collect::<String>();
+    found.ok_or_else(|| format!("mdot.ref('{}'){} does not exist", name, path))
+}
+
+/// `text` with the references in it replaced by what they stand for.
+fn substitute(text: &str, refs: &BTreeMap<String, PackageRef>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(REF_START) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + REF_START.len()..];
+        let end = after
+            .find(REF_END)
+            .ok_or("a malformed mdot.ref() reference")?;
+        let (name, field) = after[..end]
+            .split_once(REF_SEP)
+            .ok_or("a malformed mdot.ref() reference")?;
+        out.push_str(&lookup(refs, name, field)?);
+        rest = &after[end + REF_END.len_utf8()..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Replaces the references in the link sources, targets and
+/// `default_target` of `packages` and their inline `depends`. Returns
+/// whether any were left.
+fn substitute_packages(
+    packages: &mut [Package],
+    refs: &BTreeMap<String, PackageRef>,
+) -> Result<bool, String> {
+    let mut left = false;
+    let mut path = |path: &Path| -> Result<PathBuf, String> {
+        let path = substitute(&path.to_string_lossy(), refs)?;
+        left |= path.contains(REF_START);
+        Ok(PathBuf::from(path))
+    };
+    for pkg in packages.iter_mut() {
+        for link in &mut pkg.links {
+            link.source = path(&link.source)?;
+            for target in &mut link.targets {
+                *target = path(target)?;
+            }
+            if let Some(dir) = &link.targets_dir {
+                link.targets_dir = Some(path(dir)?);
+            }
+        }
+        if let Some(target) = &pkg.default_target {
+            pkg.default_target = Some(path(target)?);
+        }
+    }
+    for pkg in packages {
+        left |= substitute_packages(&mut pkg.depends, refs)?;
+    }
+    Ok(left)
+}
+
+/// Resolves the `mdot.ref()` references of `config`. A reference may
+/// lead to another, so this goes on while that makes progress.
+fn resolve_refs(ctx: &Context, config: &mut Config) -> Result<(), String> {
+    let rounds = select::all_packages(config).len() + 1;
+    for _ in 0..rounds {
+        let refs = package_refs(ctx, config);
+        if !substitute_packages(&mut config.packages, &refs)? {
+            break;
+        }
+    }
+    // Debug output escapes the separators of a reference.
+    let escaped = REF_START.escape_debug().to_string();
+    for pkg in select::all_packages(config) {
+        if format!("{:?}", pkg).contains(&escaped) {
+            return Err(format!(
+                "[{}] uses mdot.ref() references outside link sources, targets and \
+                 default_target, or references that refer to each other",
+                pkg.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn package_refs(ctx: &Context, config: &Config) -> BTreeMap<String, PackageRef> {
+    let mut refs = BTreeMap::new();
+    for pkg in select::all_packages(config) {
+        let vars = template::package_vars(config, pkg, &ctx.platform);
+        let resolve = |path: &Path| resolve_target(path, &vars).unwrap_or(path.to_path_buf());
+        let dir = ctx.package_dir(pkg);
+        let root = resolve(pkg.default_target.as_deref().unwrap_or(Path::new("~")));
+        let links = pkg
+            .links
+            .iter()
+            .map(|link| {
+                let targets = match link.targets.is_empty() {
+                    true => vec![root.join(walk::glob_base(&link.source))],
+                    false => link.targets.iter().map(|t| resolve(t)).collect(),
+                };
+                (
+                    dir.join(&link.source).display().to_string(),
+                    targets.iter().map(|t| t.display().to_string()).collect(),
+                )
+            })
+            .collect();
+        refs.insert(
+            pkg.name.clone(),
+            PackageRef {
+                dir: dir.display().to_string(),
+                description: pkg.description.clone().unwrap_or_default(),
+                default_target: root.display().to_string(),
+                links,
+            },
+        );
+    }
+    refs
+}
+
+/// Registers the global `include(path [, { optional = true }])` function.
+///
+/// Paths are relative to the config root, or to the root of the base
+/// repo for the files of an `extends` base. The included file returns a
+/// package list just like the entry file, and its packages are appended
+/// after the ones returned by the entry file.
+fn install_include(lua: &Lua, root: &Path) -> LuaResult<()> {
+    let root = root.to_path_buf();
+    let include = lua.create_function(move |lua, (path, opts): (String, Option<Table>)| {
+        let optional = match &opts {
+            Some(opts) => opts.get::<Option<bool>>("optional")?.unwrap_or(false),
+            None => false,
+        };
+        let path = match &lua.app_data_ref::<Included>().unwrap().root {
+            Some(base) => base.join(path),
+            None => root.join(path),
+        };
+        if optional && !path.is_file() {
+            return Ok(());
+        }
+        {
+            let mut included = lua.app_data_mut::<Included>().unwrap();
+            if included.stack.contains(&path) {
+                return Err(mlua::Error::runtime(format!(
+                    "include cycle detected at '{}'",
+                    path.display()
+                )));
+            }
+            included.stack.push(path.clone());
+            included.files.push(path.clone());
+        }
+        let result = eval_file(lua, &path);
+        let mut included = lua.app_data_mut::<Included>().unwrap();
+        included.stack.pop();
+        match result? {
+            Value::Table(tbl) => included.tables.push(tbl),
+            Value::Nil => (),
+            v => {
+                return Err(mlua::Error::runtime(format!(
+                    "'{}' must return a package list, got {}",
+                    path.display(),
+                    v.type_name()
+                )));
+            }
+        }
+        Ok(())
+    })?;
+    lua.globals().set("include", include)?;
+    Ok(())
+}
+
+/// What to do when a `requires_bin` binary is missing after install.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingBins {
+    Warn,
+    Fail,
+}
+
+/// Global switches set through `mdot.options`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Skip git-ignored files when discovering package sources.
+    pub gitignore: bool,
+    /// Link whole directories in tree mode when a package owns them.
+    pub fold: bool,
+    /// Descend into symlinked directories when discovering package sources.
+    pub follow_symlinks: bool,
+    /// Deepest path, in components below the package directory, that
+    /// source discovery accepts before giving up.
+    pub max_depth: usize,
+    /// Policy for missing `requires_bin` binaries: `"warn"` or `"fail"`.
+    pub missing_bins: MissingBins,
+    /// Warn about top-level dotfiles in `$HOME` that have an XDG location.
+    pub xdg: bool,
+    /// Package manager installing `package_name`s instead of the
+    /// platform's, e.g. `"nix"`.
+    pub package_manager: Option<String>,
+    /// Times a failed package install or git pull is tried again.
+    pub retries: u32,
+    /// Programs that hook commands may run without asking; when set,
+    /// other hooks only run once confirmed with `--confirm-hooks`. Read
+    /// from the allowlist in the state dir, never from the repo.
+    pub allowed_hooks: Option<Vec<String>>,
+    /// Engine of the templates of packages not setting their own.
+    pub template_engine: template::Engine,
+    /// Restore the SELinux labels of the files a deploy writes.
+    pub restore_labels: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            gitignore: false,
+            fold: true,
+            follow_symlinks: false,
+            max_depth: 32,
+            missing_bins: MissingBins::Warn,
+            xdg: false,
+            package_manager: None,
+            retries: 0,
+            allowed_hooks: None,
+            template_engine: template::Engine::Builtin,
+            restore_labels: false,
+        }
+    }
+}
+
+impl Options {
+    fn from_table(tbl: &Table) -> Self {
+        let mut options = Options::default();
+        for (key, value) in ordered_pairs(tbl, "mdot.options") {
+            let key = lua_value_to_str(&key);
+            match (key.as_str(), value) {
+                ("gitignore", Value::Boolean(v)) => options.gitignore = v,
+                ("fold", Value::Boolean(v)) => options.fold = v,
+                ("follow_symlinks", Value::Boolean(v)) => options.follow_symlinks = v,
+                ("xdg", Value::Boolean(v)) => options.xdg = v,
+                ("restore_labels", Value::Boolean(v)) => options.restore_labels = v,
+                (
+                    key @ ("gitignore" | "fold" | "follow_symlinks" | "xdg" | "restore_labels"),
+                    v,
+                ) => {
+                    fatal!(
+                        "'mdot.options.{}' expected type 'Boolean', got {:?}",
+                        key,
+                        v
+                    )
+                }
+                ("max_depth", Value::Integer(n)) if n > 0 => options.max_depth = n as usize,
+                ("max_depth", v) => {
+                    fatal!(
+                        "'mdot.options.max_depth' expected a positive 'Integer', got {:?}",
+                        v
+                    )
+                }
+                ("missing_bins", Value::String(policy)) => {
+                    options.missing_bins = match policy.to_string_lossy().as_str() {
+                        "warn" => MissingBins::Warn,
+                        "fail" => MissingBins::Fail,
+                        p => fatal!(
+                            "'mdot.options.missing_bins' must be \"warn\" or \"fail\", got '{}'",
+                            p
+                        ),
+                    }
+                }
+                ("missing_bins", v) => {
+                    fatal!(
+                        "'mdot.options.missing_bins' expected type 'String', got {:?}",
+                        v
+                    )
+                }
+                ("package_manager", Value::String(name)) => {
+                    let name = name.to_string_lossy();
+                    if !install::is_known(&name) {
+                        fatal!("'mdot.options.package_manager' '{}' is not supported", name);
+                    }
+                    options.package_manager = Some(name);
+                }
+                ("package_manager", v) => {
+                    fatal!(
+                        "'mdot.options.package_manager' expected type 'String', got {:?}",
+                        v
+                    )
+                }
+                ("retries", Value::Integer(n)) if n >= 0 => options.retries = n as u32,
+                ("retries", v) => {
+                    fatal!(
+                        "'mdot.options.retries' expected a non-negative 'Integer', got {:?}",
+                        v
+                    )
+                }
+                ("allowed_hooks", _) => warn!(
+                    "'mdot.options.allowed_hooks' is ignored: a repo cannot allow its own \
+                     hooks, list the programs in the '{}' file of the state dir",
+                    hooks::ALLOWLIST_FILE
+                ),
+                ("template_engine", Value::String(name)) => {
+                    options.template_engine = template::Engine::parse(
+                        "mdot.options.template_engine",
+                        &name.to_string_lossy(),
+                    )
+                }
+                ("template_engine", v) => {
+                    fatal!(
+                        "'mdot.options.template_engine' expected type 'String', got {:?}",
+                        v
+                    )
+                }
+                (key, _) => warn!("option '{}' is ignored", key),
+            }
+        }
+        options
+    }
+}
+
+/// A named package selection from `mdot.profiles`.
+///
+/// `mdot.profiles.desktop = { "hypr", "waybar", tags = { "gui" } }` selects
+/// the listed packages plus every package tagged `gui`. The list may also
+/// be given as a `packages` field, and `vars` override `mdot.vars` while
+/// the profile is active.
+///
+/// `extends = "base"` (or a list of profiles) inherits from other profiles:
+/// their packages and tags come first and are added to, never removed,
+/// and vars are merged key by key, the profile's own winning over those of
+/// later parents, which win over earlier ones.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Profile {
+    pub packages: Vec<String>,
+    pub tags: Vec<String>,
+    pub vars: Vars,
+    /// The profiles this one extends, already merged into it.
+    pub extends: Vec<String>,
+}
+
+impl Profile {
+    fn from_table(name: &str, tbl: &Table) -> Self {
+        let mut profile = Profile::default();
+        let path = &format!("mdot.profiles.{}", name);
+        for (key, value) in ordered_pairs(tbl, path) {
+            match key {
+                Value::Integer(_) => profile.packages.push(crate::lua_value_to_str(&value)),
+                Value::String(key) => match key.to_string_lossy().as_str() {
+                    "packages" => profile.packages.extend(Package::extract_strings(
+                        &format!("{}.packages", path),
+                        &value,
+                    )),
+                    "tags" => {
+                        profile.tags = Package::extract_strings(&format!("{}.tags", path), &value)
+                    }
+                    "extends" => {
+                        profile.extends =
+                            Package::extract_strings(&format!("{}.extends", path), &value)
+                    }
+                    "vars" => match &value {
+                        Value::Table(tbl) => template::flatten("vars", tbl, &mut profile.vars),
+                        v => fatal!(
+                            "'vars' of profile '{}' expected type 'Table', got {}",
+                            name,
+                            v.type_name()
+                        ),
+                    },
+                    key => warn!("key '{}' of profile '{}' is ignored", key, name),
+                },
+                k => fatal!("invalid key {:?} in profile '{}'", k, name),
+            }
+        }
+        profile
+    }
+
+    /// Merges `self` over `parent`.
+    fn inherit(&mut self, parent: &Profile) {
+        let mut packages = parent.packages.clone();
+        packages.extend(
+            self.packages
+                .drain(..)
+                .filter(|p| !parent.packages.contains(p)),
+        );
+        self.packages = packages;
+        let mut tags = parent.tags.clone();
+        tags.extend(self.tags.drain(..).filter(|t| !parent.tags.contains(t)));
+        self.tags = tags;
+        let mut vars = parent.vars.clone();
+        vars.append(&mut self.vars);
+        self.vars = vars;
+    }
+}
+
+/// Merges the parents of profile `name` into it, and theirs into them.
+/// `chain` holds the profiles being resolved, to report cycles.
+fn resolve_profile(
+    name: &str,
+    raw: &BTreeMap<String, Profile>,
+    resolved: &mut BTreeMap<String, Profile>,
+    chain: &mut Vec<String>,
+) -> Profile {
+    if let Some(profile) = resolved.get(name) {
+        return profile.clone();
+    }
+    if chain.iter().any(|n| n == name) {
+        chain.push(name.to_string());
+        fatal!("profiles extend each other: {}", chain.join(" -> "));
+    }
+    chain.push(name.to_string());
+    let own = &raw[name];
+    let mut parents = Profile::default();
+    for parent in &own.extends {
+        if !raw.contains_key(parent) {
+            fatal!("profile '{}' extends unknown profile '{}'", name, parent);
+        }
+        let mut parent = resolve_profile(parent, raw, resolved, chain);
+        parent.inherit(&parents);
+        parents = parent;
+    }
+    let mut profile = own.clone();
+    profile.inherit(&parents);
+    chain.pop();
+    resolved.insert(name.to_string(), profile.clone());
+    profile
+}
+
+fn profiles_from_table(tbl: &Table) -> BTreeMap<String, Profile> {
+    let mut profiles = BTreeMap::new();
+    for (name, value) in ordered_pairs(tbl, "mdot.profiles") {
+        let name = lua_value_to_str(&name);
+        match value {
+            Value::Table(tbl) => {
+                let profile = Profile::from_table(&name, &tbl);
+                profiles.insert(name, profile);
+            }
+            v => fatal!("profile '{}' expected type 'Table', got {:?}", name, v),
+        }
+    }
+    let mut resolved = BTreeMap::new();
+    for name in profiles.keys() {
+        resolve_profile(name, &profiles, &mut resolved, &mut Vec::new());
+    }
+    resolved
+}
+
+/// Everything the config evaluated to.
+pub struct Config {
+    pub packages: Vec<Package>,
+    /// Global template variables assigned to `mdot.vars`, keyed as `vars.*`.
+    pub vars: Vars,
+    /// Overrides of `mdot.vars` that also win over package vars, lowest
+    /// precedence first: the active profile, the host file, `local.lua` and
+    /// `--set`.
+    pub var_layers: Vec<(Layer, Vars)>,
+    /// Resolved `mdot.facts`, keyed as `facts.*`.
+    pub facts: Vars,
+    pub options: Options,
+    pub profiles: BTreeMap<String, Profile>,
+    /// The entry file followed by every included file.
+    pub files: Vec<PathBuf>,
+    /// Url of the repo the config `extends`.
+    pub base: Option<String>,
+    /// What `hosts.lua` says about this machine.
+    pub host: hosts::Resolution,
+}
+
+/// Evaluates a file returning a table of variables, keyed as `vars.*`.
+/// Returns `None` when the file does not exist.
+fn vars_file(lua: &Lua, path: &Path) -> Option<Vars> {
+    if !path.is_file() {
+        return None;
+    }
+    let mut vars = Vars::new();
+    match eval_file(lua, path) {
+        Ok(Value::Table(tbl)) => template::flatten("vars", &tbl, &mut vars),
+        Ok(Value::Nil) => (),
+        Ok(v) => fatal!(
+            "'{}' must return a table of variables, got {}",
+            path.display(),
+            v.type_name()
+        ),
+        Err(err) => fatal!("{}", err),
+    }
+    Some(vars)
+}
+
+/// Evaluates the entry config (plus everything it includes) into packages.
+pub fn load(ctx: &Context) -> Config {
+    let (mut config, refs_used) = evaluate(ctx);
+    if refs_used {
+        resolve_refs(ctx, &mut config).unwrap_or_else(|err| fatal!("{}", err));
+    }
+    config
+}
+
+/// The packages of a returned package list and the lists it included.
+fn parse_packages(root: Table, included: Vec<Table>) -> Vec<Package> {
+    let version = schema::take_version(&root).unwrap_or_else(|err| fatal!("{}", err));
+    let mut packages = Vec::new();
+    for tbl in std::iter::once(root).chain(included) {
+        // Included files default to the schema of the entry file.
+        let version = match schema::take_version(&tbl) {
+            Ok(own) => own.or(version).unwrap_or(1),
+            Err(err) => fatal!("{}", err),
+        };
+        if version < schema::CURRENT {
+            schema::upgrade(&tbl, version);
+        }
+        for (key, value) in ordered_pairs(&tbl, "packages") {
+            if let Some(pkg) = Package::from_pair((&key, &value)) {
+                packages.push(pkg);
+            }
+        }
+    }
+    packages
+}
+
+/// The packages of the checkout of another repo, an `extends` base or a
+/// remote package. Only its packages count: the repo is evaluated after
+/// this config read `mdot.vars` and `mdot.options`.
+fn evaluate_repo(ctx: &Context, dir: PathBuf) -> Vec<Package> {
+    let lua = &ctx.lua;
+    let entry = dir.join("main.lua");
+    lua.set_app_data(Included {
+        root: Some(dir.clone()),
+        ..Included::default()
+    });
+    let root = match eval_file(lua, &entry) {
+        Ok(Value::Table(tbl)) => tbl,
+        Ok(v) => fatal!(
+            "'{}' must return a package list, got {}",
+            entry.display(),
+            v.type_name()
+        ),
+        Err(err) => fatal!("{}", err),
+    };
+    let included = lua.remove_app_data::<Included>().unwrap_or_default();
+    let mut packages = parse_packages(root, included.tables);
+    for pkg in &mut packages {
+        pkg.base = Some(dir.clone());
+    }
+    packages
+}
+
+/// Evaluates the config, returning it and whether it called
+/// `mdot.ref()`.
+fn evaluate(ctx: &Context) -> (Config, bool) {
+    let lua = &ctx.lua;
+    lua.set_app_data(Included::default());
+    lua.set_app_data(PackageRefs::default());
+    if let Err(err) =
+        limits::install(lua, &ctx.limits).and_then(|_| api::install(lua, &ctx.platform))
+    {
+        fatal!("failed to set up the Lua environment: {}", err);
+    }
+    let host = hosts::resolve(&hosts::load(lua, &ctx.config_path), &ctx.platform.hostname);
+    if let Err(err) = install_include(lua, &ctx.config_path)
+        .and_then(|_| install_ref(lua))
+        .and_then(|_| facts::install(lua, &ctx.config_path, &ctx.platform, &host))
+        .and_then(|_| xdg::install(lua))
+        .and_then(|_| macos::install(lua))
+        .and_then(|_| store::install(lua, &ctx.state_dir))
+        .and_then(|_| net::install(lua, &ctx.cache_dir))
+        .and_then(|_| plugins::install(lua))
+    {
+        fatal!("failed to set up the Lua environment: {}", err);
+    }
+    plugins::load(lua, &ctx.plugins_dir).unwrap_or_else(|err| fatal!("{}", err));
+
+    let root = match eval_file(lua, &ctx.entry) {
+        Ok(Value::Table(tbl)) => tbl,
+        Ok(v) => fatal!(
+            "'{}' must return a package list, got {}",
+            ctx.entry.display(),
+            v.type_name()
+        ),
+        Err(err) => fatal!("{}", err),
+    };
+
+    let included = lua.remove_app_data::<Included>().unwrap_or_default();
+    let refs = lua.remove_app_data::<PackageRefs>().unwrap_or_default();
+    let base = base::take_extends(&root).unwrap_or_else(|err| fatal!("{}", err));
+    let mut packages = parse_packages(root, included.tables);
+
+    let mut vars = Vars::new();
+    let mdot: Table = match lua.globals().get("mdot") {
+        Ok(Value::Table(mdot)) => mdot,
+        v => fatal!("the config replaced the 'mdot' table with {:?}", v),
+    };
+    match field(&mdot, "mdot", "vars") {
+        Value::Table(tbl) => template::flatten("vars", &tbl, &mut vars),
+        Value::Nil => (),
+        v => fatal!("'mdot.vars' expected type 'Table', got {}", v.type_name()),
+    }
+    let mut options = match field(&mdot, "mdot", "options") {
+        Value::Table(tbl) => Options::from_table(&tbl),
+        Value::Nil => Options::default(),
+        v => fatal!(
+            "'mdot.options' expected type 'Table', got {}",
+            v.type_name()
+        ),
+    };
+    options.allowed_hooks = hooks::allowlist(&ctx.state_dir);
+    let mut profiles = match field(&mdot, "mdot", "profiles") {
+        Value::Table(tbl) => profiles_from_table(&tbl),
+        Value::Nil => BTreeMap::new(),
+        v => fatal!(
+            "'mdot.profiles' expected type 'Table', got {}",
+            v.type_name()
+        ),
+    };
+    let facts = facts::collect(lua).unwrap_or_else(|err| fatal!("invalid 'mdot.facts': {}", err));
+    if let Some(url) = &base {
+        let dir = base::checkout(ctx, url, options.retries).unwrap_or_else(|err| fatal!("{}", err));
+        packages = base::merge(evaluate_repo(ctx, dir), packages);
+    }
+    let remotes = remote::load(&ctx.config_path).unwrap_or_else(|err| fatal!("{}", err));
+    for remote in &remotes {
+        let dir =
+            remote::checkout(ctx, remote, options.retries).unwrap_or_else(|err| fatal!("{}", err));
+        packages = base::merge(evaluate_repo(ctx, dir), packages);
+    }
+
+    if let Some(name) = &host.profile
+        && !profiles.contains_key(name)
+    {
+        fatal!(
+            "'{}' maps host '{}' to unknown profile '{}'",
+            hosts::HOSTS_FILE,
+            ctx.platform.hostname,
+            name
+        );
+    }
+    if let Some(name) = ctx.profile.as_ref().or(host.profile.as_ref())
+        && let Some(profile) = profiles.get_mut(name)
+    {
+        for tag in &host.tags {
+            if !profile.tags.contains(tag) {
+                profile.tags.push(tag.clone());
+            }
+        }
+    }
+
+    let mut var_layers = Vec::new();
+    if let Some(name) = ctx.profile.as_ref().or(host.profile.as_ref())
+        && let Some(profile) = profiles.get(name)
+    {
+        var_layers.push((Layer::Profile(name.clone()), profile.vars.clone()));
+    }
+    let hostname = &ctx.platform.hostname;
+    let host_file = ctx
+        .config_path
+        .join(HOSTS_DIR)
+        .join(format!("{}.lua", hostname));
+    if let Some(vars) = vars_file(lua, &host_file) {
+        var_layers.push((Layer::Host(hostname.clone()), vars));
+    }
+    if let Some(vars) = vars_file(lua, &ctx.config_path.join(LOCAL_FILE)) {
+        var_layers.push((Layer::Local, vars));
+    }
+    if !ctx.overrides.is_empty() {
+        var_layers.push((Layer::Cli, ctx.overrides.clone()));
+    }
+    let config = Config {
+        packages,
+        vars,
+        var_layers,
+        facts,
+        options,
+        profiles,
+        files: std::iter::once(ctx.entry.clone())
+            .chain(included.files)
+            .collect(),
+        base,
+        host,
+    };
+    (config, refs.used)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_include_appends_packages() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-include-{}", std::process::id()));
+        fs::create_dir_all(root.join("machines")).unwrap();
+        fs::write(
+            root.join("work.lua"),
+            r#"
+            include("machines/" .. mdot.hostname() .. ".lua")
+            include("machines/missing.lua", { optional = true })
+            return { "fish" }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("work.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        assert_eq!(ctx.config_path, root);
+        fs::write(
+            root.join("machines")
+                .join(format!("{}.lua", ctx.platform.hostname)),
+            r#"return { "hypr" }"#,
+        )
+        .unwrap();
+        let names: Vec<String> = config::load(&ctx)
+            .packages
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(names, vec!["fish", "hypr"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_package_refs() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-pkg-refs-{}", std::process::id()));
+        fs::create_dir_all(root.join("nvim")).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            EVALUATED = (EVALUATED or 0) + 1
+            local nvim = mdot.ref("nvim")
+            return {
+                { "backup", links = { ["init.lua"] = nvim.links[1] and nvim.links[1].targets[1] .. ".bak" or "~/x" } },
+                { "nvim", default_target = "~/.config/nvim", links = { "init.lua" } },
+                { "notes", links = { [nvim.dir .. "/README.md"] = "~/notes.md" } },
+                { "lsp", default_target = mdot.ref("backup").links[1].targets[1] .. ".d" },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        assert_eq!(ctx.lua.globals().get::<i64>("EVALUATED").unwrap(), 1);
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            config.packages[0].links[0].targets,
+            [PathBuf::from(format!(
+                "{}/.config/nvim/init.lua.bak",
+                home.display()
+            ))]
+        );
+        assert_eq!(
+            config.packages[2].links[0].source,
+            root.join("nvim/README.md")
+        );
+        assert_eq!(
+            config.packages[3].default_target,
+            Some(PathBuf::from(format!(
+                "{}/.config/nvim/init.lua.bak.d",
+                home.display()
+            )))
+        );
+
+        let refs = config::package_refs(&ctx, &config);
+        let missing = config::reference("nvim", "links.2.targets.1");
+        assert_eq!(
+            config::substitute(&format!("{}/x", missing), &refs).unwrap_err(),
+            "mdot.ref('nvim').links[2].targets[1] does not exist"
+        );
+        assert!(
+            ctx.lua
+                .load(r#"return #mdot.ref("nvim").links"#)
+                .exec()
+                .unwrap_err()
+                .to_string()
+                .contains("is not known while the config is evaluated")
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_ordered_pairs() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-ordered-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return {
+                "fish",
+                zsh = { links = { zshrc = "~/.zshrc", ["zshenv"] = "~/.zshenv", "aliases" } },
+                bash = {},
+                "git",
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        for _ in 0..3 {
+            let config = config::load(&ctx);
+            let names: Vec<&str> = config.packages.iter().map(|p| p.name.as_str()).collect();
+            assert_eq!(names, ["fish", "git", "bash", "zsh"]);
+            let sources: Vec<_> = config.packages[3]
+                .links
+                .iter()
+                .map(|l| l.source.display().to_string())
+                .collect();
+            assert_eq!(sources, ["aliases", "zshenv", "zshrc"]);
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_profile_extends() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-extends-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.profiles.base = { "git", "fish", tags = { "cli" }, vars = { theme = "dark", font = "mono" } }
+            mdot.profiles.gui = { "kitty", tags = { "gui" }, vars = { font = "sans" } }
+            mdot.profiles.work = {
+                extends = { "base", "gui" },
+                packages = { "vpn", "git" },
+                vars = { theme = "light" },
+            }
+            mdot.profiles.laptop = { extends = "work", "tlp" }
+            return { "git", "fish", "kitty", "vpn", "tlp" }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let work = &config.profiles["work"];
+        assert_eq!(work.packages, ["git", "fish", "kitty", "vpn"]);
+        assert_eq!(work.tags, ["cli", "gui"]);
+        assert_eq!(work.vars["vars.theme"], "light");
+        assert_eq!(work.vars["vars.font"], "sans");
+        assert_eq!(work.extends, ["base", "gui"]);
+        let laptop = &config.profiles["laptop"];
+        assert_eq!(laptop.packages, ["git", "fish", "kitty", "vpn", "tlp"]);
+        assert_eq!(laptop.vars, work.vars);
+        assert_eq!(config.profiles["base"].packages, ["git", "fish"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+use crate::config::{self, Config};
+use crate::json::Json;
+use crate::progress::Progress;
+use crate::state::State;
+use crate::{Context, catch_fatal, cli, deploy, journal, query, select};
+use log::{info, warn};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+pub const SOCKET_NAME: &str = "mdot.sock";
+const POLL: Duration = Duration::from_millis(200);
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long an answer may wait for a client that does not read it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `$XDG_RUNTIME_DIR/mdot.sock`, or the socket in the state dir.
+pub fn socket_path(ctx: &Context) -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(|| ctx.state_dir.clone())
+        .join(SOCKET_NAME)
+}
+
+/// Newest modification time of anything in the repo, `.git` aside.
+fn repo_stamp(repo: &Path) -> Option<SystemTime> {
+    ignore::WalkBuilder::new(repo)
+        .standard_filters(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build()
+        .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+        .max()
+}
+
+/// A connected client, with what it sent that is not a full line yet.
+struct Client {
+    stream: UnixStream,
+    pending: Vec<u8>,
+}
+
+impl Client {
+    fn new(stream: UnixStream) -> io::Result<Client> {
+        stream.set_nonblocking(true)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(Client {
+            stream,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Reads what the client sent so far, returning false once it hung up.
+    fn receive(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.pending.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// The next full line the client sent, if any.
+    fn line(&mut self) -> Option<String> {
+        let end = self.pending.iter().position(|&b| b == b'\n')?;
+        let line: Vec<u8> = self.pending.drain(..=end).collect();
+        Some(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    fn send(&mut self, response: &Json) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let written = writeln!(self.stream, "{}", response);
+        self.stream.set_nonblocking(true)?;
+        written
+    }
+}
+
+struct Daemon {
+    ctx: Context,
+    config: Config,
+    profile: Option<String>,
+    stamp: Option<SystemTime>,
+}
+
+impl Daemon {
+    /// Evaluates the config again. A broken config is an error, and the
+    /// previous one stays loaded.
+    fn reload(&mut self) -> Result<(), String> {
+        let ctx = self.ctx.reload();
+        self.config = catch_fatal(|| config::load(&ctx))?;
+        self.ctx = ctx;
+        self.stamp = repo_stamp(&self.ctx.config_path);
+        info!("config reloaded");
+        Ok(())
+    }
+
+    fn select(&self, packages: &[String]) -> Result<select::Selection, String> {
+        let all = select::select(
+            &self.config,
+            &[],
+            self.profile.as_deref(),
+            &cli::Filter::default(),
+        );
+        if let Some(unknown) = packages.iter().find(|p| !all.decisions.contains_key(*p)) {
+            return Err(format!(
+                "package '{}' is not defined in the config",
+                unknown
+            ));
+        }
+        Ok(select::select(
+            &self.config,
+            packages,
+            self.profile.as_deref(),
+            &cli::Filter::default(),
+        ))
+    }
+
+    fn status(&self) -> Result<Json, String> {
+        let selection = self.select(&[])?;
+        let links = deploy::plan(&self.ctx, &self.config, &selection.packages);
+        Ok(Json::object([("links", query::links(&links))]))
+    }
+
+    fn handle(&mut self, request: &Json) -> Result<Json, String> {
+        let packages: Vec<String> = match request.get("packages") {
+            None => Vec::new(),
+            Some(packages) => packages
+                .as_array()
+                .ok_or("'packages' must be an array")?
+                .iter()
+                .map(|p| p.as_str().map(String::from))
+                .collect::<Option<_>>()
+                .ok_or("'packages' must only hold strings")?,
+        };
+        match request.get("command").and_then(Json::as_str) {
+            Some("status") => self.status(),
+            Some("deploy") => {
+                let selection = self.select(&packages)?;
+                // Checked here, as a deploy exits on a state it cannot read.
+                State::load(&State::path(&self.ctx))?;
+                let command = format!("daemon deploy {}", packages.join(" "));
+                if let Err(err) = journal::begin(&self.ctx.state_dir, command.trim_end()) {
+                    warn!("failed to start the journal: {}", err);
+                }
+                let applied = deploy::apply(
+                    &self.ctx,
+                    &self.config,
+                    &selection,
+                    false,
+                    &Progress::default(),
+                );
+                journal::finish(true);
+                Ok(Json::object([("linked", Json::from(applied.len()))]))
+            }
+            Some("reload") => self.reload().map(|_| Json::object::<&str>([])),
+            Some(command) => Err(format!("unknown command '{}'", command)),
+            None => Err("request needs a 'command'".to_string()),
+        }
+    }
+
+    /// The answer to the request `line`, errors included. A fatal error
+    /// while handling it fails the request, not the daemon.
+    fn respond(&mut self, line: &str) -> Json {
+        let handled = Json::parse(line).and_then(|req| {
+            catch_fatal(|| self.handle(&req)).unwrap_or_else(|err| {
+                journal::finish(false);
+                Err(err)
+            })
+        });
+        match handled {
+            Ok(Json::Object(mut map)) => {
+                map.insert("ok".to_string(), Json::Bool(true));
+                Json::Object(map)
+            }
+            Ok(value) => value,
+            Err(err) => Json::object([("ok", Json::Bool(false)), ("error", err.into())]),
+        }
+    }
+
+    /// Answers the requests `client` sent in full so far, returning false
+    /// once it hung up.
+    fn serve(&mut self, client: &mut Client) -> io::Result<bool> {
+        let open = client.receive()?;
+        while let Some(line) = client.line() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = self.respond(&line);
+            client.send(&response)?;
+        }
+        Ok(open)
+    }
+}
+
+/// Serves the line based JSON API on the daemon socket until killed.
+///
+/// Every request is one JSON object per line, answered with one line:
+/// `{"command":"status"}`, `{"command":"deploy","packages":["kitty"]}`
+/// (all packages when `packages` is left out) and `{"command":"reload"}`.
+/// Answers carry `"ok"`, plus `"error"` when it is false. The repo is
+/// polled for changes and the config reloaded when something changed.
+pub fn run(ctx: Context, config: Config, profile: Option<String>) -> io::Result<()> {
+    let socket = socket_path(&ctx);
+    if UnixStream::connect(&socket).is_ok() {
+        return Err(io::Error::other(format!(
+            "a daemon is already listening on {}",
+            socket.display()
+        )));
+    }
+    let _ = fs::remove_file(&socket);
+    if let Some(parent) = socket.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(&socket)?;
+    listener.set_nonblocking(true)?;
+    info!("listening on {}", socket.display());
+
+    let mut daemon = Daemon {
+        stamp: repo_stamp(&ctx.config_path),
+        ctx,
+        config,
+        profile,
+    };
+    let mut clients: Vec<Client> = Vec::new();
+    let mut watched = Instant::now();
+    loop {
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => match Client::new(stream) {
+                    Ok(client) => clients.push(client),
+                    Err(err) => warn!("client connection failed: {}", err),
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        clients.retain_mut(|client| match daemon.serve(client) {
+            Ok(open) => open,
+            Err(err) => {
+                warn!("client connection failed: {}", err);
+                false
+            }
+        });
+        std::thread::sleep(POLL);
+        if watched.elapsed() >= WATCH_INTERVAL {
+            watched = Instant::now();
+            if repo_stamp(&daemon.ctx.config_path) != daemon.stamp {
+                info!("repo changed, reloading");
+                if let Err(err) = daemon.reload() {
+                    daemon.stamp = repo_stamp(&daemon.ctx.config_path);
+                    warn!("keeping the previous config, reload failed:\n{}", err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::*;
+    use crate::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_serve() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-daemon-{}", std::process::id()));
+        fs::create_dir_all(root.join("kitty")).unwrap();
+        fs::write(root.join("kitty/kitty.conf"), "").unwrap();
+        fs::write(
+            root.join("main.lua"),
+            format!(
+                r#"return {{ {{ "kitty", default_target = "{}" }} }}"#,
+                root.join("home").display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        let mut daemon = Daemon {
+            stamp: None,
+            ctx,
+            config,
+            profile: None,
+        };
+
+        // Two clients at once, one of them sending a request in pieces.
+        let (mut first, server) = UnixStream::pair().unwrap();
+        let mut first_client = Client::new(server).unwrap();
+        let (mut second, server) = UnixStream::pair().unwrap();
+        let mut second_client = Client::new(server).unwrap();
+        first
+            .write_all(b"{\"command\":\"status\"}\n{\"command\":\"dep")
+            .unwrap();
+        second
+            .write_all(b"not json\n{\"command\":\"deploy\",\"packages\":[\"nope\"]}\n")
+            .unwrap();
+        assert!(daemon.serve(&mut first_client).unwrap());
+        assert!(daemon.serve(&mut second_client).unwrap());
+        first.write_all(b"loy\"}\n").unwrap();
+        assert!(daemon.serve(&mut first_client).unwrap());
+
+        let answers = |stream: &UnixStream, n: usize| -> Vec<Json> {
+            BufReader::new(stream)
+                .lines()
+                .take(n)
+                .map(|line| Json::parse(&line.unwrap()).unwrap())
+                .collect()
+        };
+        let answered = answers(&first, 2);
+        assert_eq!(answered[0].get("ok"), Some(&Json::Bool(true)));
+        assert!(answered[0].get("links").is_some());
+        assert_eq!(answered[1].get("linked"), Some(&Json::from(1usize)));
+        drop(first);
+        assert!(!daemon.serve(&mut first_client).unwrap());
+        let second = answers(&second, 2);
+        assert_eq!(second[0].get("ok"), Some(&Json::Bool(false)));
+        assert_eq!(
+            second[1].get("error").and_then(Json::as_str),
+            Some("package 'nope' is not defined in the config")
+        );
+        assert!(root.join("home/kitty.conf").is_symlink());
+
+        // A config that fails to load, or a request that hits a fatal
+        // error, leaves the daemon running on the previous config.
+        fs::write(root.join("main.lua"), "return 1").unwrap();
+        assert!(daemon.reload().is_err());
+        assert_eq!(daemon.config.packages.len(), 1);
+        fs::write(
+            root.join("main.lua"),
+            r#"return { { "kitty", enabled = function() error("boom") end } }"#,
+        )
+        .unwrap();
+        daemon.reload().unwrap();
+        let answer = daemon.respond(r#"{"command":"deploy"}"#);
+        assert_eq!(answer.get("ok"), Some(&Json::Bool(false)));
+        assert_eq!(daemon.ctx.state_dir, root.join("state"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
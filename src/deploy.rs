@@ -0,0 +1,1659 @@
+use crate::config::{Config, Options};
+use crate::events::{self, Event};
+use crate::journal::{self, Action};
+use crate::progress::{self, Progress};
+use crate::render::{self, Template};
+use crate::select::{self, Selection};
+use crate::state::{PackageState, State};
+use crate::template::{self, Vars};
+use crate::walk::{self, Excludes};
+use crate::{
+    Context, LinkObject, Package, assets, attrs, backup, base, compose, exports, fetch, fonts,
+    fscaps, health, hooks, interactive, keys, platform, reload, remote, sensitive, settings, ssh,
+    xdg,
+};
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Expands a leading `~` to the home directory.
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => dirs::home_dir().unwrap_or_default().join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Renders `{{ }}` placeholders in a target and expands `~`.
+pub fn resolve_target(target: &Path, vars: &Vars) -> Result<PathBuf, String> {
+    let rendered = template::render(&target.to_string_lossy(), vars)?;
+    Ok(expand_tilde(Path::new(&rendered)))
+}
+
+/// Moves `target` of `package` into `backups`, journaling the move.
+pub fn back_up(package: &str, target: &Path, backups: &Path) -> io::Result<()> {
+    let backup = backup::store(package, target, backups)?;
+    info!(
+        "[{}] backed up {} to {}",
+        package,
+        target.display(),
+        backup.display()
+    );
+    journal::record(Action::BackedUp {
+        package: package.to_string(),
+        target: target.to_path_buf(),
+        backup: backup.clone(),
+    });
+    events::emit(
+        Event::BackupCreated,
+        &[&package, &target.display(), &backup.display()],
+    );
+    Ok(())
+}
+
+fn remove(target: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(target)?;
+    if meta.is_dir() {
+        fs::remove_dir_all(target)
+    } else {
+        fs::remove_file(target)
+    }
+}
+
+/// A single symlink the deploy will create.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedLink {
+    pub package: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub overwrite: bool,
+    pub backup: bool,
+    /// Copy the source instead of linking it.
+    pub copy: bool,
+    /// The template in the repo that `source` is rendered from, by the
+    /// deploy, to be copied.
+    pub rendered_from: Option<PathBuf>,
+}
+
+impl PlannedLink {
+    fn new(pkg: &Package, source: PathBuf, target: PathBuf, link: Option<&LinkObject>) -> Self {
+        Self {
+            package: pkg.name.clone(),
+            source,
+            target,
+            overwrite: link.is_some_and(|l| l.overwrite),
+            backup: link.is_some_and(|l| l.backup),
+            copy: false,
+            rendered_from: None,
+        }
+    }
+}
+
+/// Everything needed to expand one package's sources into links.
+struct Planner<'a> {
+    pkg: &'a Package,
+    dir: PathBuf,
+    vars: Vars,
+    excludes: Excludes,
+    options: &'a Options,
+    /// The templates of the package, which are linked file by file.
+    templates: Vec<Template>,
+}
+
+impl Planner<'_> {
+    fn files(&self, dir: &Path) -> Result<Vec<PathBuf>, String> {
+        walk::files(&self.dir, dir, &self.excludes, self.options).map_err(|err| err.to_string())
+    }
+
+    /// Expands one `links` entry.
+    ///
+    /// Glob sources link every match below each target, keeping the path
+    /// relative to the glob's literal prefix. Directories containing
+    /// excluded entries are linked file by file so the excluded ones stay
+    /// behind. An explicitly named file is always linked, even if it
+    /// matches `excludes`. A link without targets mirrors its source path
+    /// below the package root. Composed links are left to `compose`.
+    fn link(&self, link: &LinkObject) -> Result<Vec<PlannedLink>, String> {
+        let pkg = self.pkg;
+        if link.compose.is_some() {
+            return Ok(Vec::new());
+        }
+        if let Some(dir) = &link.targets_dir {
+            return self.link_into(link, &resolve_target(dir, &self.vars)?);
+        }
+        let targets = if link.targets.is_empty() {
+            vec![self.root()?.join(walk::glob_base(&link.source))]
+        } else {
+            link.targets
+                .iter()
+                .map(|t| resolve_target(t, &self.vars))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        let mut planned = Vec::new();
+        if walk::is_glob(&link.source) {
+            let base = walk::glob_base(&link.source);
+            let matches = walk::glob(&link.source, self.files(&self.dir)?)?;
+            if matches.is_empty() {
+                warn!("[{}] '{}' matches nothing", pkg.name, link.source.display());
+            }
+            for rel in matches {
+                for target in &targets {
+                    let dest = target.join(rel.strip_prefix(&base).unwrap());
+                    planned.push(PlannedLink::new(pkg, self.dir.join(&rel), dest, Some(link)));
+                }
+            }
+            return Ok(planned);
+        }
+
+        let source = self.dir.join(&link.source);
+        if !source.exists() {
+            return Err(format!("source {} does not exist", source.display()));
+        }
+        let split = source.is_dir()
+            && (self.templates.iter().any(|t| t.source.starts_with(&source))
+                || walk::has_excluded(&self.dir, &source, &self.excludes, self.options)
+                    .map_err(|err| err.to_string())?);
+        for target in &targets {
+            if !split {
+                planned.push(PlannedLink::new(
+                    pkg,
+                    source.clone(),
+                    target.clone(),
+                    Some(link),
+                ));
+                continue;
+            }
+            for rel in self.files(&source)? {
+                let dest = target.join(rel.strip_prefix(&link.source).unwrap());
+                planned.push(PlannedLink::new(pkg, self.dir.join(rel), dest, Some(link)));
+            }
+        }
+        Ok(planned)
+    }
+
+    /// Links every match of the source into `dir`, keeping only file
+    /// names.
+    fn link_into(&self, link: &LinkObject, dir: &Path) -> Result<Vec<PlannedLink>, String> {
+        let sources = if walk::is_glob(&link.source) {
+            walk::glob(&link.source, self.files(&self.dir)?)?
+        } else {
+            vec![link.source.clone()]
+        };
+        if sources.is_empty() {
+            warn!(
+                "[{}] '{}' matches nothing",
+                self.pkg.name,
+                link.source.display()
+            );
+        }
+        let mut planned: Vec<PlannedLink> = Vec::new();
+        for rel in sources {
+            let source = self.dir.join(&rel);
+            if !source.exists() {
+                return Err(format!("source {} does not exist", source.display()));
+            }
+            let Some(name) = rel.file_name() else {
+                return Err(format!(
+                    "source {} has no name to link into {}",
+                    source.display(),
+                    dir.display()
+                ));
+            };
+            let dest = dir.join(name);
+            if let Some(other) = planned.iter().find(|p| p.target == dest) {
+                return Err(format!(
+                    "{} and {} both link to {}",
+                    other.source.display(),
+                    source.display(),
+                    dest.display()
+                ));
+            }
+            planned.push(PlannedLink::new(self.pkg, source, dest, Some(link)));
+        }
+        Ok(planned)
+    }
+
+    /// `default_target`, or `~` unless set.
+    fn root(&self) -> Result<PathBuf, String> {
+        match &self.pkg.default_target {
+            Some(root) => resolve_target(root, &self.vars),
+            None => Ok(expand_tilde(Path::new("~"))),
+        }
+    }
+
+    /// Tree mode: a package without `links` mirrors its whole directory
+    /// into the package root.
+    fn tree(&self) -> Result<(PathBuf, Vec<PlannedLink>), String> {
+        let root = self.root()?;
+        let links = self
+            .files(&self.dir)?
+            .into_iter()
+            .map(|rel| PlannedLink::new(self.pkg, self.dir.join(&rel), root.join(rel), None))
+            .collect();
+        Ok((root, links))
+    }
+}
+
+/// Links planned for one package. `tree` holds the source and target
+/// roots when the package is linked in tree mode, and `errors` the links
+/// that could not be planned.
+struct PackagePlan {
+    links: Vec<PlannedLink>,
+    tree: Option<(PathBuf, PathBuf)>,
+    errors: Vec<String>,
+}
+
+fn plan_package(ctx: &Context, config: &Config, pkg: &Package) -> Result<PackagePlan, String> {
+    let dir = ctx.package_dir(pkg);
+    let planner = Planner {
+        pkg,
+        vars: template::package_vars(config, pkg, &ctx.platform),
+        excludes: Excludes::load(&ctx.config_path, &dir, &pkg.excludes)
+            .map_err(|err| err.to_string())?,
+        options: &config.options,
+        templates: render::package_templates(ctx, config, pkg)?,
+        dir,
+    };
+    let mut plan = if pkg.links.is_empty() {
+        if !planner.dir.is_dir() {
+            return Ok(PackagePlan {
+                links: Vec::new(),
+                tree: None,
+                errors: Vec::new(),
+            });
+        }
+        let (root, links) = planner.tree()?;
+        PackagePlan {
+            links,
+            tree: Some((planner.dir.clone(), root)),
+            errors: Vec::new(),
+        }
+    } else {
+        let mut links = Vec::new();
+        let mut errors = Vec::new();
+        for link in &pkg.links {
+            match planner.link(link) {
+                Ok(planned) => links.extend(planned),
+                Err(err) => errors.push(err),
+            }
+        }
+        PackagePlan {
+            links,
+            tree: None,
+            errors,
+        }
+    };
+    // Templates are copied from where the deploy renders them.
+    for link in &mut plan.links {
+        if let Some(template) = planner.templates.iter().find(|t| t.source == link.source) {
+            link.rendered_from = Some(link.source.clone());
+            link.source = render::rendered_path(ctx, template);
+            link.copy = true;
+        }
+    }
+    Ok(plan)
+}
+
+/// Renders the templates of `pkg`, or only those below `only`, to where
+/// its links copy them from.
+fn render_templates(
+    ctx: &Context,
+    config: &Config,
+    pkg: &Package,
+    only: Option<&Path>,
+) -> Result<(), String> {
+    let mut templates = render::package_templates(ctx, config, pkg)?;
+    templates.retain(|template| only.is_none_or(|only| template.source.starts_with(only)));
+    let problems = render::render_all(&templates, &ctx.data_dir.join(render::RENDERED_DIR))?;
+    let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems.join("\n")),
+    }
+}
+
+/// Whether the tree-mode package `owner` may link `target` as a whole to
+/// `source`: nobody else claims anything below it, every file in `source`
+/// is part of the plan, and the target is free or already folded.
+fn foldable(
+    owner: usize,
+    source: &Path,
+    target: &Path,
+    claims: &BTreeMap<PathBuf, usize>,
+    owned: usize,
+    options: &Options,
+) -> bool {
+    match fs::symlink_metadata(target) {
+        Ok(meta) if !(meta.is_symlink() && fs::read_link(target).is_ok_and(|l| l == source)) => {
+            return false;
+        }
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return false,
+        _ => (),
+    }
+    let mut below = claims
+        .range(target.to_path_buf()..)
+        .take_while(|(t, _)| t.starts_with(target));
+    below.all(|(_, &pkg)| pkg == owner)
+        && walk::count_files(source, options).is_ok_and(|n| n == owned)
+}
+
+/// Stow-style folding: replaces the links of every file below a directory
+/// with a single link of the directory itself, choosing the topmost
+/// directory that `foldable` allows.
+fn fold(plans: &mut [PackagePlan], options: &Options) {
+    let mut claims = BTreeMap::new();
+    for (i, plan) in plans.iter().enumerate() {
+        for link in &plan.links {
+            claims.insert(link.target.clone(), i);
+        }
+    }
+    for (i, plan) in plans.iter_mut().enumerate() {
+        let Some((src_root, dst_root)) = &plan.tree else {
+            continue;
+        };
+        let mut folded: Vec<PathBuf> = Vec::new();
+        let mut links = Vec::new();
+        for link in &plan.links {
+            if folded.iter().any(|dir| link.target.starts_with(dir)) {
+                continue;
+            }
+            if link.copy {
+                links.push(link.clone());
+                continue;
+            }
+            let rel = link.target.strip_prefix(dst_root).unwrap();
+            let mut dirs: Vec<&Path> = rel.ancestors().skip(1).collect();
+            dirs.pop();
+            let fold_dir = dirs.into_iter().rev().find(|dir| {
+                let target = dst_root.join(dir);
+                let owned = plan
+                    .links
+                    .iter()
+                    .filter(|l| l.target.starts_with(&target))
+                    .count();
+                let copied = plan
+                    .links
+                    .iter()
+                    .any(|l| l.copy && l.target.starts_with(&target));
+                !copied && foldable(i, &src_root.join(dir), &target, &claims, owned, options)
+            });
+            match fold_dir {
+                Some(dir) => {
+                    let target = dst_root.join(dir);
+                    folded.push(target.clone());
+                    links.push(PlannedLink {
+                        source: src_root.join(dir),
+                        target,
+                        ..link.clone()
+                    });
+                }
+                None => links.push(link.clone()),
+            }
+        }
+        plan.links = links;
+    }
+}
+
+/// A target a package plans but leaves to a package with a higher
+/// priority.
+#[derive(Debug, PartialEq)]
+pub struct Shadowed {
+    pub target: PathBuf,
+    pub package: String,
+    pub by: String,
+}
+
+/// Drops the links whose target another package with a higher `priority`
+/// also plans. Packages of equal priority keep their links and conflict.
+fn prioritize(plans: &mut [PackagePlan], packages: &[Package]) -> Vec<Shadowed> {
+    let priority: BTreeMap<&str, i64> = packages
+        .iter()
+        .map(|pkg| (pkg.name.as_str(), pkg.priority))
+        .collect();
+    let mut winners: BTreeMap<&Path, &str> = BTreeMap::new();
+    for link in plans.iter().flat_map(|plan| &plan.links) {
+        let winner = winners.entry(&link.target).or_insert(&link.package);
+        if priority[link.package.as_str()] > priority[*winner] {
+            *winner = &link.package;
+        }
+    }
+    let mut shadowed = Vec::new();
+    for plan in plans.iter() {
+        for link in &plan.links {
+            let winner = winners[link.target.as_path()];
+            if priority[link.package.as_str()] < priority[winner] {
+                shadowed.push(Shadowed {
+                    target: link.target.clone(),
+                    package: link.package.clone(),
+                    by: winner.to_string(),
+                });
+            }
+        }
+    }
+    for plan in plans.iter_mut() {
+        plan.links.retain(|link| {
+            !shadowed
+                .iter()
+                .any(|s| s.target == link.target && s.package == link.package)
+        });
+    }
+    shadowed
+}
+
+/// Longest path Windows accepts without the `\\?\` prefix: MAX_PATH
+/// less the terminating NUL.
+const MAX_PATH: usize = 259;
+
+/// The length of `target`, on a Windows drive mounted into WSL, as the
+/// Windows path `C:\...` it is.
+fn windows_len(target: &Path) -> usize {
+    let len = target.as_os_str().to_string_lossy().encode_utf16().count();
+    len - "/mnt/c".len() + "C:".len()
+}
+
+/// The sets of distinct targets among `targets` that are the same path
+/// once case-folded.
+fn case_collisions<'a>(targets: impl IntoIterator<Item = &'a Path>) -> Vec<BTreeSet<&'a Path>> {
+    let mut folded: BTreeMap<String, BTreeSet<&Path>> = BTreeMap::new();
+    for target in targets {
+        let key = target.to_string_lossy().to_lowercase();
+        folded.entry(key).or_default().insert(target);
+    }
+    folded.into_values().filter(|set| set.len() > 1).collect()
+}
+
+/// Drops the links that would come out wrong, making each an error of
+/// its package: targets differing only in case on a case-insensitive
+/// filesystem, where one would replace the other, and targets on a
+/// Windows drive longer than MAX_PATH.
+fn check_paths(ctx: &Context, plans: &mut [PackagePlan]) {
+    let mut failed: BTreeMap<PathBuf, String> = BTreeMap::new();
+    let targets = plans
+        .iter()
+        .flat_map(|plan| &plan.links)
+        .map(|link| link.target.as_path());
+    for set in case_collisions(targets) {
+        let first = set.first().expect("collisions are not empty");
+        if fscaps::probe(first).is_none_or(|caps| caps.case_sensitive) {
+            continue;
+        }
+        let names: Vec<String> = set.iter().map(|t| t.display().to_string()).collect();
+        for target in set {
+            let err = format!(
+                "{} are the same file on this case-insensitive filesystem, rename the sources \
+                 of all but one",
+                names.join(" and ")
+            );
+            failed.insert(target.to_path_buf(), err);
+        }
+    }
+    for link in plans.iter().flat_map(|plan| &plan.links) {
+        if ctx.platform.wsl
+            && platform::on_windows_drive(&link.target)
+            && windows_len(&link.target) > MAX_PATH
+        {
+            let err = format!(
+                "{} is {} characters long as a Windows path, over the {} MAX_PATH allows",
+                link.target.display(),
+                windows_len(&link.target),
+                MAX_PATH
+            );
+            failed.insert(link.target.clone(), err);
+        }
+    }
+    for plan in plans {
+        plan.links.retain(|link| match failed.get(&link.target) {
+            Some(err) => {
+                plan.errors.push(err.clone());
+                false
+            }
+            None => true,
+        });
+    }
+}
+
+/// Resolves packages into the concrete links a deploy would create, and
+/// the targets lost to higher-priority packages.
+pub fn plan_with_shadowed(
+    ctx: &Context,
+    config: &Config,
+    packages: &[Package],
+) -> (Vec<PlannedLink>, Vec<Shadowed>) {
+    let mut plans = Vec::new();
+    let mut names = Vec::new();
+    for pkg in packages {
+        match plan_package(ctx, config, pkg) {
+            Ok(plan) => {
+                plans.push(plan);
+                names.push(&pkg.name);
+            }
+            Err(err) => warn!("[{}] {}", pkg.name, err),
+        }
+    }
+    let shadowed = prioritize(&mut plans, packages);
+    check_paths(ctx, &mut plans);
+    for (name, plan) in names.iter().zip(&plans) {
+        for err in &plan.errors {
+            warn!("[{}] {}", name, err);
+        }
+    }
+    for link in plans.iter_mut().flat_map(|plan| &mut plan.links) {
+        link.copy = link.copy
+            || ctx.copy
+            || (ctx.platform.wsl && platform::on_windows_drive(&link.target))
+            || fscaps::needs_copy(&link.target);
+    }
+    if config.options.fold {
+        fold(&mut plans, &config.options);
+    }
+    let links = plans.into_iter().flat_map(|plan| plan.links).collect();
+    (links, shadowed)
+}
+
+/// The packages whose links cannot be planned, with the reasons a deploy
+/// would warn about.
+pub fn plan_errors(ctx: &Context, config: &Config, packages: &[Package]) -> Vec<(String, String)> {
+    let mut errors = Vec::new();
+    let mut plans = Vec::new();
+    let mut names = Vec::new();
+    for pkg in packages {
+        match plan_package(ctx, config, pkg) {
+            Ok(plan) => {
+                plans.push(plan);
+                names.push(&pkg.name);
+            }
+            Err(err) => errors.push((pkg.name.clone(), err)),
+        }
+    }
+    check_paths(ctx, &mut plans);
+    for (name, plan) in names.into_iter().zip(plans) {
+        errors.extend(plan.errors.into_iter().map(|err| (name.clone(), err)));
+    }
+    errors
+}
+
+/// Resolves packages into the concrete links a deploy would create.
+pub fn plan(ctx: &Context, config: &Config, packages: &[Package]) -> Vec<PlannedLink> {
+    plan_with_shadowed(ctx, config, packages).0
+}
+
+/// The directories packages are linked from: the repo, and the checkouts
+/// of the `extends` base and of the remotes.
+fn package_roots(ctx: &Context, config: &Config) -> Vec<PathBuf> {
+    let mut roots = vec![ctx.config_path.clone()];
+    roots.extend(config.base.iter().map(|url| base::dir(ctx, url)));
+    roots.extend(
+        remote::load(&ctx.config_path)
+            .unwrap_or_default()
+            .iter()
+            .map(|remote| remote::dir(ctx, remote)),
+    );
+    roots
+}
+
+/// Splits directories folded into one of `roots` along the path to `dir`:
+/// each such symlink becomes a real directory holding one link per entry,
+/// so links from other packages can be placed next to them.
+fn unfold(dir: &Path, roots: &[PathBuf]) -> io::Result<()> {
+    let mut ancestors: Vec<&Path> = dir.ancestors().collect();
+    ancestors.reverse();
+    for path in ancestors {
+        let Ok(meta) = fs::symlink_metadata(path) else {
+            break;
+        };
+        if !meta.is_symlink() {
+            continue;
+        }
+        let source = fs::read_link(path)?;
+        if !roots.iter().any(|root| source.starts_with(root)) || !source.is_dir() {
+            continue;
+        }
+        info!("splitting folded directory {}", path.display());
+        events::emit(Event::DirSplit, &[&path.display()]);
+        fs::remove_file(path)?;
+        journal::record(Action::Split {
+            dir: path.to_path_buf(),
+            source: source.clone(),
+        });
+        fs::create_dir(path)?;
+        for entry in fs::read_dir(&source)? {
+            let entry = entry?;
+            std::os::unix::fs::symlink(entry.path(), path.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// What [`link_one`] did with a target.
+#[derive(Debug, PartialEq)]
+enum Linked {
+    Unchanged,
+    Changed,
+    /// The target is in the way and was left alone, as the user chose.
+    Skipped,
+}
+
+/// Creates one link. `recorded` is the source the state says this package
+/// last linked the target to; a link still pointing there is ours and is
+/// replaced without needing `overwrite` or `backup`.
+fn link_one(
+    link: &PlannedLink,
+    roots: &[PathBuf],
+    backups: &Path,
+    recorded: Option<&Path>,
+) -> io::Result<Linked> {
+    let (source, target) = (&link.source, &link.target);
+    if let Some(parent) = target.parent() {
+        unfold(parent, roots)?;
+    }
+    // A copy replacing a file keeps the permissions that file had removed.
+    let replaced_mode = match fs::symlink_metadata(target) {
+        Ok(meta) if link.copy && meta.is_file() => {
+            Some(assets::kept_mode(assets::mode(source)?, target))
+        }
+        _ => None,
+    };
+    if let Ok(meta) = fs::symlink_metadata(target) {
+        let current = meta
+            .is_symlink()
+            .then(|| fs::read_link(target))
+            .transpose()?;
+        if current.as_ref() == Some(source) || (link.copy && is_deployed(target, source)) {
+            info!("[{}] {} is up to date", link.package, target.display());
+            events::emit(Event::LinkUnchanged, &[&link.package, &target.display()]);
+            return Ok(Linked::Unchanged);
+        }
+        if link.copy && assets::same_content(source, target) {
+            let mode = assets::kept_mode(assets::mode(source)?, target);
+            fs::set_permissions(target, fs::Permissions::from_mode(mode))?;
+            info!(
+                "[{}] tightened the permissions of {}",
+                link.package,
+                target.display()
+            );
+            return Ok(Linked::Changed);
+        }
+        if let Some(current) = current.as_ref().filter(|c| Some(c.as_path()) == recorded) {
+            fs::remove_file(target)?;
+            journal::record(Action::Removed {
+                package: link.package.clone(),
+                target: target.clone(),
+                source: current.clone(),
+            });
+        } else if link.copy && !meta.is_symlink() && recorded == Some(source) {
+            // An earlier copy of a source that changed since, which may
+            // have been edited where it is.
+            back_up(&link.package, target, backups)?;
+        } else if link.backup {
+            back_up(&link.package, target, backups)?;
+        } else if link.overwrite {
+            remove(target)?;
+            journal::record(match current {
+                Some(source) => Action::Removed {
+                    package: link.package.clone(),
+                    target: target.clone(),
+                    source,
+                },
+                None => Action::Deleted {
+                    package: link.package.clone(),
+                    target: target.clone(),
+                },
+            });
+        } else {
+            let question = format!(
+                "[{}] {} already exists, back it up and link it?",
+                link.package,
+                target.display()
+            );
+            if !interactive::confirm(&question).map_err(io::Error::other)? {
+                warn!(
+                    "[{}] {} already exists, skipping (set 'overwrite' or 'backup')",
+                    link.package,
+                    target.display()
+                );
+                events::emit(Event::LinkSkipped, &[&link.package, &target.display()]);
+                return Ok(Linked::Skipped);
+            }
+            back_up(&link.package, target, backups)?;
+        }
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if link.copy {
+        assets::copy_tree(source, target)?;
+        if let Some(mode) = replaced_mode {
+            fs::set_permissions(target, fs::Permissions::from_mode(mode))?;
+        }
+    } else {
+        std::os::unix::fs::symlink(source, target)?;
+    }
+    journal::record(Action::Created {
+        package: link.package.clone(),
+        target: target.clone(),
+        source: source.clone(),
+    });
+    info!(
+        "[{}] {} {} -> {}",
+        link.package,
+        if link.copy { "copied" } else { "linked" },
+        target.display(),
+        source.display()
+    );
+    events::emit(
+        Event::LinkCreated,
+        &[&link.package, &target.display(), &source.display()],
+    );
+    Ok(Linked::Changed)
+}
+
+/// Links the sources of `packages` into their targets, returning the
+/// planned links that did not fail and were not skipped, the packages
+/// whose targets changed and the sensitive targets to leave alone, links
+/// or not. `state` is what the last deploy recorded. The links that
+/// `progress`, the interrupted deploy being resumed, made and that are
+/// still in place count as applied without being looked at again.
+pub fn deploy(
+    ctx: &Context,
+    config: &Config,
+    packages: &[Package],
+    state: &State,
+    progress: &Progress,
+) -> (Vec<PlannedLink>, BTreeSet<String>, BTreeSet<PathBuf>) {
+    let mut applied = Vec::new();
+    let mut changed = BTreeSet::new();
+    let mut written = Vec::new();
+    let home = dirs::home_dir().unwrap_or_default();
+    for pkg in packages {
+        if let Err(err) = render_templates(ctx, config, pkg, None) {
+            warn!("[{}] {}", pkg.name, err);
+        }
+    }
+    let mut links = plan(ctx, config, packages);
+    if !progress.links.is_empty() {
+        let (done, rest): (Vec<PlannedLink>, _) =
+            links.into_iter().partition(|link| progress.done(link));
+        if !done.is_empty() {
+            info!("{} link(s) were made before the interruption", done.len());
+        }
+        changed.extend(done.iter().map(|link| link.package.clone()));
+        applied.extend(done);
+        links = rest;
+    }
+    let deployed = deployed_packages(config, packages, state);
+    let pending = sensitive::pending(ctx, config, packages, &deployed, &links);
+    let held_back = sensitive::review(ctx, &pending);
+    let roots = package_roots(ctx, config);
+    for link in links {
+        if held_back.contains(&link.target) {
+            info!(
+                "[{}] skipped sensitive {}",
+                link.package,
+                link.target.display()
+            );
+            events::emit(Event::LinkSkipped, &[&link.package, &link.target.display()]);
+            continue;
+        }
+        if config.options.xdg
+            && let Some(xdg) = xdg::suggestion(&link.target, &home)
+        {
+            warn!(
+                "[{}] {} could live in {}",
+                link.package,
+                link.target.display(),
+                xdg.display()
+            );
+        }
+        let recorded = state
+            .packages
+            .get(&link.package)
+            .and_then(|pkg| pkg.links.get(&link.target));
+        match link_one(
+            &link,
+            &roots,
+            &ctx.backup_dir(),
+            recorded.map(PathBuf::as_path),
+        ) {
+            Ok(Linked::Skipped) => (),
+            Ok(linked) => {
+                if linked == Linked::Changed {
+                    changed.insert(link.package.clone());
+                    written.push(link.target.clone());
+                }
+                progress::linked(&link);
+                applied.push(link)
+            }
+            Err(err) => {
+                let err = attrs::explain(&link.target, err);
+                warn!(
+                    "[{}] failed to link {}: {}",
+                    link.package,
+                    link.target.display(),
+                    err
+                );
+                events::emit(
+                    Event::LinkFailed,
+                    &[&link.package, &link.target.display(), &err],
+                );
+            }
+        }
+    }
+    if config.options.restore_labels {
+        attrs::restore_labels(&written);
+    }
+    (applied, changed, held_back)
+}
+
+/// Renders and links again only the links of `pkg` whose source is
+/// `edited`, holds it or lies below it, for `mdot edit`. Returns the links
+/// whose targets changed.
+pub fn redeploy(ctx: &Context, config: &Config, pkg: &Package, edited: &Path) -> Vec<PlannedLink> {
+    let state = State::load(&State::path(ctx)).unwrap_or_else(|err| fatal!("{}", err));
+    if let Err(err) = render_templates(ctx, config, pkg, Some(edited)) {
+        warn!("[{}] {}", pkg.name, err);
+    }
+    let mut changed = Vec::new();
+    let roots = package_roots(ctx, config);
+    for link in plan(ctx, config, std::slice::from_ref(pkg)) {
+        let source = link.rendered_from.as_ref().unwrap_or(&link.source);
+        if !edited.starts_with(source) && !source.starts_with(edited) {
+            continue;
+        }
+        let recorded = state
+            .packages
+            .get(&link.package)
+            .and_then(|pkg| pkg.links.get(&link.target));
+        match link_one(
+            &link,
+            &roots,
+            &ctx.backup_dir(),
+            recorded.map(PathBuf::as_path),
+        ) {
+            Ok(Linked::Changed) => changed.push(link),
+            Ok(_) => (),
+            Err(err) => warn!(
+                "[{}] failed to link {}: {}",
+                link.package,
+                link.target.display(),
+                attrs::explain(&link.target, err)
+            ),
+        }
+    }
+    changed
+}
+
+/// The packages deployed on this machine once `packages` are: those
+/// given and those an earlier deploy recorded, in config order. Outputs
+/// several packages contribute to are built from all of them, so that
+/// deploying one package keeps what the others contributed.
+fn deployed_packages(config: &Config, packages: &[Package], state: &State) -> Vec<Package> {
+    select::all_packages(config)
+        .into_iter()
+        .filter(|pkg| {
+            state.packages.contains_key(&pkg.name) || packages.iter().any(|p| p.name == pkg.name)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Deploys a selection and records it in the state: takes over the links
+/// of `renamed_from` packages, links everything, then prunes (or warns
+/// about) packages that left the config. Packages whose targets changed
+/// are reloaded and health checked last. What `progress` says the
+/// interrupted deploy did is skipped.
+pub fn apply(
+    ctx: &Context,
+    config: &Config,
+    selection: &Selection,
+    prune: bool,
+    progress: &Progress,
+) -> Vec<PlannedLink> {
+    let path = State::path(ctx);
+    let mut state = State::load(&path).unwrap_or_else(|err| fatal!("{}", err));
+    for pkg in &selection.packages {
+        for old in &pkg.renamed_from {
+            if state.packages.contains_key(old) && !state.packages.contains_key(&pkg.name) {
+                info!("[{}] taking over the links of '{}'", pkg.name, old);
+                state.rename(old, &pkg.name).unwrap();
+            }
+        }
+    }
+    if let Some(url) = &config.base
+        && let Err(err) = base::pin_checkout(ctx, url)
+    {
+        warn!("{}", err);
+    }
+    let (applied, mut changed, held_back) =
+        deploy(ctx, config, &selection.packages, &state, progress);
+    for pkg in &selection.packages {
+        let links: Vec<PlannedLink> = applied
+            .iter()
+            .filter(|link| link.package == pkg.name)
+            .cloned()
+            .collect();
+        let name = pkg.name.as_str();
+        // Targets written before the interruption were never reloaded.
+        if progress.finished(name, "assets") || progress.finished(name, "fetch") {
+            changed.insert(pkg.name.clone());
+        }
+        if let Err(err) = step(progress, name, "provision", || keys::provision(ctx, pkg))
+            .and_then(|_| {
+                step(progress, name, "assets", || {
+                    assets::sync(ctx, config, pkg, &held_back)
+                })
+            })
+            .and_then(|mut written| {
+                written.extend(step(progress, name, "fetch", || {
+                    fetch::sync(ctx, config, pkg, &held_back)
+                })?);
+                if !written.is_empty() {
+                    changed.insert(pkg.name.clone());
+                }
+                if config.options.restore_labels {
+                    attrs::restore_labels(&written);
+                }
+                Ok(())
+            })
+            .and_then(|_| {
+                step(progress, name, "fonts", || {
+                    fonts::install(ctx, &config.options, pkg)
+                })
+            })
+            .and_then(|_| step(progress, name, "settings", || settings::apply(ctx, pkg)))
+            .and_then(|_| {
+                step(progress, name, "hooks", || {
+                    hooks::run(
+                        ctx,
+                        &config.options,
+                        pkg,
+                        state.packages.get(&pkg.name),
+                        &links,
+                    )
+                })
+            })
+        {
+            warn!("[{}] {}", pkg.name, err);
+        }
+    }
+    let deployed = deployed_packages(config, &selection.packages, &state);
+    match step(progress, "", "compose", || {
+        compose::sync(ctx, config, &deployed, &held_back)
+    }) {
+        Ok(written) => {
+            if config.options.restore_labels {
+                let targets: Vec<PathBuf> = written.iter().map(|c| c.target.clone()).collect();
+                attrs::restore_labels(&targets);
+            }
+            changed.extend(
+                written
+                    .into_iter()
+                    .flat_map(|composed| composed.fragments)
+                    .map(|fragment| fragment.package),
+            )
+        }
+        Err(err) => warn!("{}", err),
+    }
+    if let Err(err) = step(progress, "", "exports", || exports::write(&deployed)) {
+        warn!("{}", err);
+    }
+    match ssh::changes(&deployed) {
+        Some(path) if held_back.contains(&path) => {
+            info!("skipped sensitive {}", path.display());
+        }
+        _ => {
+            if let Err(err) = step(progress, "", "ssh", || ssh::sync(&deployed)) {
+                warn!("{}", err);
+            }
+        }
+    }
+    let vanished = state.vanished(selection);
+    if prune {
+        for name in vanished {
+            self::prune(&name, &state.packages[&name]);
+            state.packages.remove(&name);
+        }
+    } else if !vanished.is_empty() {
+        warn!(
+            "packages removed from the config still have links: {} (run with --prune to remove them)",
+            vanished.join(", ")
+        );
+    }
+    state.merge(State::from_plan(&selection.packages, &applied));
+    if let Err(err) = state.save(&path) {
+        warn!("failed to save state to {}: {}", path.display(), err);
+    }
+    reload::run(ctx, &config.options, &selection.packages, &changed);
+    health::report(ctx, &config.options, &selection.packages, &changed);
+    applied
+}
+
+/// Runs the `name` step of `package` unless `progress` says it was done
+/// before the interruption, recording it once it succeeded.
+fn step<T: Default>(
+    progress: &Progress,
+    package: &str,
+    name: &str,
+    run: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    if progress.finished(package, name) {
+        return Ok(T::default());
+    }
+    let value = run()?;
+    progress::done(package, name);
+    Ok(value)
+}
+
+/// Whether `target` is a link to `source`, or a copy of it.
+pub fn is_deployed(target: &Path, source: &Path) -> bool {
+    let linked = fs::symlink_metadata(target).is_ok_and(|meta| meta.is_symlink())
+        && fs::read_link(target).is_ok_and(|l| l == source);
+    linked || assets::up_to_date(source, target)
+}
+
+/// Removes the recorded links of a package that left the config. Targets
+/// that no longer point at the recorded source are left alone.
+pub fn prune(name: &str, pkg: &PackageState) {
+    for (target, source) in &pkg.links {
+        if !is_deployed(target, source) {
+            info!(
+                "[{}] {} is no longer ours, keeping it",
+                name,
+                target.display()
+            );
+            continue;
+        }
+        match fs::remove_file(target) {
+            Ok(()) => {
+                journal::record(Action::Removed {
+                    package: name.to_string(),
+                    target: target.clone(),
+                    source: source.clone(),
+                });
+                info!("[{}] removed {}", name, target.display());
+                events::emit(Event::LinkRemoved, &[&name, &target.display()]);
+            }
+            Err(err) => warn!("[{}] failed to remove {}: {}", name, target.display(), err),
+        }
+    }
+}
+
+/// How a planned link compares to what is on disk. A copy whose content
+/// matches but whose permissions changed is `drifted`.
+pub fn link_status(link: &PlannedLink) -> &'static str {
+    match fs::symlink_metadata(&link.target) {
+        Err(_) => "missing",
+        Ok(meta) if meta.is_symlink() => match fs::read_link(&link.target) {
+            Ok(source) if source == link.source => "linked",
+            _ => "conflict",
+        },
+        Ok(_) if assets::up_to_date(&link.source, &link.target) => "linked",
+        Ok(_) if link.copy && assets::same_content(&link.source, &link.target) => "drifted",
+        Ok(_) => "conflict",
+    }
+}
+
+/// The [`link_status`] of each of `links`, in order, looked up over
+/// several threads so that thousands of targets take little longer than
+/// the slowest of them.
+pub fn link_statuses(links: &[PlannedLink]) -> Vec<&'static str> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk = links.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = links
+            .chunks(chunk)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(link_status).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("link status panicked"))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    fn deploy_with(repo: &Path, lua: &str) -> state::State {
+        fs::write(repo.join("main.lua"), lua).unwrap();
+        let root = repo.parent().unwrap();
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let (applied, _, _) = deploy::deploy(
+            &ctx,
+            &config,
+            &config.packages,
+            &state::State::default(),
+            &progress::Progress::default(),
+        );
+        state::State::from_plan(&config.packages, &applied)
+    }
+
+    #[test]
+    fn test_fold_and_split() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-fold-{}", std::process::id()));
+        let (repo, home) = (root.join("repo"), root.join("home"));
+        for file in [
+            "nvim/.config/nvim/init.lua",
+            "nvim/.config/nvim/lua/x.lua",
+            "extra/.config/nvim/lua/extra.lua",
+        ] {
+            fs::create_dir_all(repo.join(file).parent().unwrap()).unwrap();
+            fs::write(repo.join(file), "").unwrap();
+        }
+        let nvim = format!(r#"nvim = {{ default_target = "{}" }}"#, home.display());
+        let extra = format!(r#"extra = {{ default_target = "{}" }}"#, home.display());
+
+        deploy_with(&repo, &format!("return {{ {} }}", nvim));
+        assert_eq!(
+            fs::read_link(home.join(".config")).unwrap(),
+            repo.join("nvim/.config")
+        );
+
+        deploy_with(&repo, &format!("return {{ {}, {} }}", nvim, extra));
+        let lua_dir = home.join(".config/nvim/lua");
+        assert!(!fs::symlink_metadata(&lua_dir).unwrap().is_symlink());
+        assert_eq!(
+            fs::read_link(lua_dir.join("x.lua")).unwrap(),
+            repo.join("nvim/.config/nvim/lua/x.lua")
+        );
+        assert_eq!(
+            fs::read_link(lua_dir.join("extra.lua")).unwrap(),
+            repo.join("extra/.config/nvim/lua/extra.lua")
+        );
+
+        // Directories folded into a checkout outside the repo split too.
+        let base = root.join("cache/base/nvim/.config");
+        fs::create_dir_all(base.join("nvim")).unwrap();
+        fs::write(base.join("nvim/init.lua"), "").unwrap();
+        let other = root.join("other");
+        fs::create_dir_all(&other).unwrap();
+        std::os::unix::fs::symlink(&base, other.join(".config")).unwrap();
+        let roots = [repo.clone(), root.join("cache/base")];
+        deploy::unfold(&other.join(".config/nvim"), &roots).unwrap();
+        assert!(!other.join(".config").is_symlink());
+        assert!(!other.join(".config/nvim").is_symlink());
+        assert_eq!(
+            fs::read_link(other.join(".config/nvim/init.lua")).unwrap(),
+            base.join("nvim/init.lua")
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_templates() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-deploy-templates-{}", std::process::id()));
+        let (repo, home) = (root.join("repo"), root.join("home"));
+        fs::create_dir_all(repo.join("kitty/.config/kitty")).unwrap();
+        fs::write(
+            repo.join("kitty/.config/kitty/kitty.conf"),
+            "include {{ name }}\n",
+        )
+        .unwrap();
+        fs::write(repo.join("kitty/.config/kitty/theme.conf"), "").unwrap();
+        fs::write(
+            repo.join("main.lua"),
+            format!(
+                r#"return {{ kitty = {{ default_target = "{}", templates = ".config/kitty/kitty.conf" }} }}"#,
+                home.display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .build();
+        let config = config::load(&ctx);
+        let (applied, _, _) = deploy::deploy(
+            &ctx,
+            &config,
+            &config.packages,
+            &state::State::default(),
+            &progress::Progress::default(),
+        );
+        assert_eq!(applied.len(), 2);
+        let conf = home.join(".config/kitty/kitty.conf");
+        assert!(!conf.is_symlink());
+        assert_eq!(fs::read_to_string(&conf).unwrap(), "include kitty\n");
+        assert!(home.join(".config/kitty/theme.conf").is_symlink());
+        let link = applied.iter().find(|link| link.target == conf).unwrap();
+        assert_eq!(
+            link.rendered_from.as_deref(),
+            Some(repo.join("kitty/.config/kitty/kitty.conf").as_path())
+        );
+        assert_eq!(deploy::link_status(link), "linked");
+
+        // An edit renders and copies again only the edited template.
+        state::State::from_plan(&config.packages, &applied)
+            .save(&state::State::path(&ctx))
+            .unwrap();
+        let source = repo.join("kitty/.config/kitty/kitty.conf");
+        fs::write(&source, "include {{ name }}.d\n").unwrap();
+        fs::remove_file(home.join(".config/kitty/theme.conf")).unwrap();
+        let changed = deploy::redeploy(&ctx, &config, &config.packages[0], &source);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(fs::read_to_string(&conf).unwrap(), "include kitty.d\n");
+        assert!(!home.join(".config/kitty/theme.conf").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-prune-{}", std::process::id()));
+        let (repo, home) = (root.join("repo"), root.join("home"));
+        for file in ["bash/.bashrc", "bash/.profile", "fish/.fishrc"] {
+            fs::create_dir_all(repo.join(file).parent().unwrap()).unwrap();
+            fs::write(repo.join(file), "").unwrap();
+        }
+        let bash = format!(r#"bash = {{ default_target = "{}" }}"#, home.display());
+        let fish = format!(r#"fish = {{ default_target = "{}" }}"#, home.display());
+        let state = deploy_with(&repo, &format!("return {{ {}, {} }}", bash, fish));
+        assert!(home.join(".bashrc").is_symlink());
+
+        fs::write(repo.join("main.lua"), format!("return {{ {} }}", fish)).unwrap();
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let selection = select::select(&config, &[], None, &cli::Filter::default());
+        assert_eq!(state.vanished(&selection), vec!["bash"]);
+
+        fs::remove_file(home.join(".profile")).unwrap();
+        fs::write(home.join(".profile"), "mine").unwrap();
+        deploy::prune("bash", &state.packages["bash"]);
+        assert!(!home.join(".bashrc").exists());
+        assert_eq!(fs::read_to_string(home.join(".profile")).unwrap(), "mine");
+        assert!(home.join(".fishrc").is_symlink());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_rename_takes_over_links() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-rename-{}", std::process::id()));
+        let (repo, home) = (root.join("repo"), root.join("home"));
+        fs::create_dir_all(repo.join("zsh")).unwrap();
+        fs::write(repo.join("zsh/.zshrc"), "").unwrap();
+        let mut state = deploy_with(
+            &repo,
+            &format!(
+                r#"return {{ zsh = {{ default_target = "{}" }} }}"#,
+                home.display()
+            ),
+        );
+
+        fs::rename(repo.join("zsh"), repo.join("shell")).unwrap();
+        fs::write(
+            repo.join("main.lua"),
+            format!(
+                r#"return {{ shell = {{ default_target = "{}", renamed_from = "zsh" }} }}"#,
+                home.display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        assert_eq!(config.packages[0].renamed_from, vec!["zsh"]);
+        state.rename("zsh", "shell").unwrap();
+        assert!(state.rename("zsh", "shell").is_err());
+        deploy::deploy(
+            &ctx,
+            &config,
+            &config.packages,
+            &state,
+            &progress::Progress::default(),
+        );
+        assert_eq!(
+            fs::read_link(home.join(".zshrc")).unwrap(),
+            repo.join("shell/.zshrc")
+        );
+        assert!(!home.join(".zshrc.bak").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_mirrored_targets() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-mirror-{}", std::process::id()));
+        let (repo, home) = (root.join("repo"), root.join("home"));
+        for file in [
+            "git/.gitconfig",
+            "git/.config/git/ignore",
+            "git/.config/git/attributes",
+        ] {
+            fs::create_dir_all(repo.join(file).parent().unwrap()).unwrap();
+            fs::write(repo.join(file), "").unwrap();
+        }
+        fs::write(
+            repo.join("main.lua"),
+            format!(
+                r#"return {{ git = {{ default_target = "{}", links = {{
+                    ".gitconfig",
+                    {{ source = ".config/git/*" }},
+                }} }} }}"#,
+                home.display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let mut targets: Vec<_> = deploy::plan(&ctx, &config, &config.packages)
+            .into_iter()
+            .map(|link| link.target)
+            .collect();
+        targets.sort();
+        assert_eq!(
+            targets,
+            [
+                home.join(".config/git/attributes"),
+                home.join(".config/git/ignore"),
+                home.join(".gitconfig"),
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_check_paths() {
+        let targets = [
+            Path::new("/home/me/.config/nvim/init.lua"),
+            Path::new("/home/me/.config/NVIM/init.lua"),
+            Path::new("/home/me/.config/nvim/init.lua"),
+            Path::new("/home/me/.bashrc"),
+        ];
+        let collisions = deploy::case_collisions(targets);
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].len(), 2);
+
+        let short = PathBuf::from("/mnt/c/Users/me/.gitconfig");
+        assert_eq!(deploy::windows_len(&short), "C:/Users/me/.gitconfig".len());
+        let long = Path::new("/mnt/c/Users/me").join("a".repeat(300));
+        let link = |target: &Path| deploy::PlannedLink {
+            package: "git".to_string(),
+            source: PathBuf::from("/repo/git/x"),
+            target: target.to_path_buf(),
+            overwrite: false,
+            backup: false,
+            copy: false,
+            rendered_from: None,
+        };
+        let mut plans = [deploy::PackagePlan {
+            links: vec![link(&short), link(&long)],
+            tree: None,
+            errors: Vec::new(),
+        }];
+        let ctx = Context::builder()
+            .platform(platform::Platform {
+                os: "linux".to_string(),
+                distro: None,
+                family: None,
+                arch: "x86_64".to_string(),
+                wsl: true,
+                hostname: "box".to_string(),
+            })
+            .build();
+        deploy::check_paths(&ctx, &mut plans);
+        assert_eq!(plans[0].links, [link(&short)]);
+        assert_eq!(plans[0].errors.len(), 1);
+        assert!(plans[0].errors[0].ends_with("over the 259 MAX_PATH allows"));
+    }
+
+    #[test]
+    fn test_targets_dir() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-targets-dir-{}", std::process::id()));
+        let (repo, plugins) = (root.join("repo"), root.join("home/plugins"));
+        for file in [
+            "nvim/lsp/lsp.lua",
+            "nvim/ui/theme.lua",
+            "nvim/ui/dup.lua",
+            "nvim/dup.lua",
+        ] {
+            fs::create_dir_all(repo.join(file).parent().unwrap()).unwrap();
+            fs::write(repo.join(file), "").unwrap();
+        }
+        let config_with = |source: &str| {
+            fs::write(
+                repo.join("main.lua"),
+                format!(
+                    r#"return {{ nvim = {{ links = {{
+                        {{ source = "{}", targets_dir = "{}" }},
+                    }} }} }}"#,
+                    source,
+                    plugins.display()
+                ),
+            )
+            .unwrap();
+            let ctx = Context::builder()
+                .entry(repo.join("main.lua"))
+                .state_dir(root.join("state"))
+                .data_dir(root.join("data"))
+                .cache_dir(root.join("cache"))
+                .build();
+            let config = config::load(&ctx);
+            let mut links: Vec<_> = deploy::plan(&ctx, &config, &config.packages)
+                .into_iter()
+                .map(|link| (link.target, link.source))
+                .collect();
+            links.sort();
+            links
+        };
+        assert_eq!(
+            config_with("{lsp,ui}/*.lua"),
+            [
+                (plugins.join("dup.lua"), repo.join("nvim/ui/dup.lua")),
+                (plugins.join("lsp.lua"), repo.join("nvim/lsp/lsp.lua")),
+                (plugins.join("theme.lua"), repo.join("nvim/ui/theme.lua")),
+            ]
+        );
+        assert!(config_with("**/dup.lua").is_empty());
+        assert!(config_with(".").is_empty());
+        assert!(config_with("..").is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_priority() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-priority-{}", std::process::id()));
+        let (repo, home) = (root.join("repo"), root.join("home"));
+        for file in ["base/gitconfig", "base/ignore", "work/gitconfig"] {
+            fs::create_dir_all(repo.join(file).parent().unwrap()).unwrap();
+            fs::write(repo.join(file), "").unwrap();
+        }
+        let base = format!(
+            r#"base = {{ links = {{ gitconfig = "{0}/.gitconfig", ignore = "{0}/.gitignore" }} }}"#,
+            home.display()
+        );
+        let work = format!(
+            r#"work = {{ priority = 10, links = {{ gitconfig = "{}/.gitconfig" }} }}"#,
+            home.display()
+        );
+        for lua in [
+            format!("return {{ {}, {} }}", base, work),
+            format!("return {{ {}, {} }}", work, base),
+        ] {
+            fs::write(repo.join("main.lua"), lua).unwrap();
+            let ctx = Context::builder()
+                .entry(repo.join("main.lua"))
+                .state_dir(root.join("state"))
+                .data_dir(root.join("data"))
+                .cache_dir(root.join("cache"))
+                .build();
+            let config = config::load(&ctx);
+            let (links, shadowed) = deploy::plan_with_shadowed(&ctx, &config, &config.packages);
+            let gitconfig: Vec<_> = links
+                .iter()
+                .filter(|link| link.target == home.join(".gitconfig"))
+                .collect();
+            assert_eq!(gitconfig.len(), 1);
+            assert_eq!(gitconfig[0].source, repo.join("work/gitconfig"));
+            assert_eq!(links.len(), 2);
+            assert_eq!(
+                shadowed,
+                [deploy::Shadowed {
+                    target: home.join(".gitconfig"),
+                    package: "base".to_string(),
+                    by: "work".to_string(),
+                }]
+            );
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_backup_dir() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-backup-{}", std::process::id()));
+        let (repo, home) = (root.join("repo"), root.join("home"));
+        fs::create_dir_all(repo.join("zsh")).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        fs::write(repo.join("zsh/zshrc"), "new").unwrap();
+        fs::write(
+            repo.join("main.lua"),
+            format!(
+                r#"return {{ zsh = {{ links = {{ {{ source = "zshrc", targets = "{}", backup = true }} }} }} }}"#,
+                home.join(".zshrc").display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .data_dir(root.join("data"))
+            .build();
+        let config = config::load(&ctx);
+        for old in ["first", "second", "first"] {
+            let _ = fs::remove_file(home.join(".zshrc"));
+            fs::write(home.join(".zshrc"), old).unwrap();
+            deploy::deploy(
+                &ctx,
+                &config,
+                &config.packages,
+                &state::State::default(),
+                &progress::Progress::default(),
+            );
+            assert!(home.join(".zshrc").is_symlink());
+        }
+        let index = backup::index(&ctx.backup_dir()).unwrap();
+        assert_eq!(index.len(), 3);
+        assert!(
+            index
+                .iter()
+                .all(|entry| entry.target == home.join(".zshrc"))
+        );
+        assert_eq!(index[0].hash, index[2].hash);
+        let blobs = ctx.backup_dir().join(backup::BLOBS_DIR);
+        assert_eq!(fs::read_dir(&blobs).unwrap().count(), 2);
+        for (entry, old) in index.iter().zip(["first", "second"]) {
+            assert_eq!(fs::read_to_string(blobs.join(&entry.hash)).unwrap(), old);
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_copy() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-copy-{}", std::process::id()));
+        fs::create_dir_all(root.join("repo/git")).unwrap();
+        let source = root.join("repo/git/gitconfig");
+        fs::write(&source, "[user]\n").unwrap();
+        let link = deploy::PlannedLink {
+            package: "git".to_string(),
+            source: source.clone(),
+            target: root.join("win/.gitconfig"),
+            overwrite: false,
+            backup: false,
+            copy: true,
+            rendered_from: None,
+        };
+        let backups = root.join("backups");
+        deploy::link_one(&link, &[root.join("repo")], &backups, None).unwrap();
+        assert!(!link.target.is_symlink());
+        assert_eq!(fs::read_to_string(&link.target).unwrap(), "[user]\n");
+        assert!(deploy::is_deployed(&link.target, &source));
+        assert_eq!(deploy::link_status(&link), "linked");
+
+        fs::write(&source, "[user]\n  name = me\n").unwrap();
+        assert_eq!(deploy::link_status(&link), "conflict");
+        deploy::link_one(&link, &[root.join("repo")], &backups, Some(&source)).unwrap();
+        assert!(deploy::is_deployed(&link.target, &source));
+
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(deploy::link_status(&link), "drifted");
+        assert_eq!(
+            deploy::link_one(&link, &[root.join("repo")], &backups, Some(&source)).unwrap(),
+            deploy::Linked::Changed
+        );
+        assert_eq!(assets::mode(&link.target).unwrap(), 0o600);
+        assert_eq!(deploy::link_status(&link), "linked");
+
+        // A copy the user made tighter is neither drifted nor loosened.
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(deploy::link_status(&link), "linked");
+        fs::write(&source, "[user]\n  name = you\n").unwrap();
+        deploy::link_one(&link, &[root.join("repo")], &backups, Some(&source)).unwrap();
+        assert!(deploy::is_deployed(&link.target, &source));
+        assert_eq!(assets::mode(&link.target).unwrap(), 0o600);
+
+        // Directories are copied whole, and an edited copy is backed up.
+        let dir = deploy::PlannedLink {
+            source: root.join("repo/git/hooks"),
+            target: root.join("win/hooks"),
+            ..link.clone()
+        };
+        fs::create_dir_all(dir.source.join("lib")).unwrap();
+        fs::write(dir.source.join("lib/common.sh"), "set -e\n").unwrap();
+        deploy::link_one(&dir, &[root.join("repo")], &backups, None).unwrap();
+        assert_eq!(deploy::link_status(&dir), "linked");
+        fs::write(dir.target.join("lib/common.sh"), "set -eu\n").unwrap();
+        fs::write(dir.source.join("pre-commit"), "").unwrap();
+        assert_eq!(deploy::link_status(&dir), "conflict");
+        deploy::link_one(&dir, &[root.join("repo")], &backups, Some(&dir.source)).unwrap();
+        assert_eq!(deploy::link_status(&dir), "linked");
+        let backup = backups.join(dir.target.strip_prefix("/").unwrap());
+        assert_eq!(
+            fs::read_to_string(backup.join("lib/common.sh")).unwrap(),
+            "set -eu\n"
+        );
+
+        let missing = deploy::PlannedLink {
+            target: root.join("win/.gitignore"),
+            ..link.clone()
+        };
+        let links = [vec![link.clone(); 20], vec![missing]].concat();
+        let statuses = deploy::link_statuses(&links);
+        assert_eq!(statuses.len(), 21);
+        assert!(statuses[..20].iter().all(|status| *status == "linked"));
+        assert_eq!(statuses[20], "missing");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
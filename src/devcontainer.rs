@@ -0,0 +1,179 @@
+use crate::deploy::PlannedLink;
+use crate::json::Json;
+use crate::render::{self, Template};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Directory of the feature holding the files copied into the home of the
+/// container user.
+const HOME_DIR: &str = "home";
+/// The metadata of the feature, which also marks a directory as one
+/// written by mdot.
+const METADATA_FILE: &str = "devcontainer-feature.json";
+const ID: &str = "mdot-dotfiles";
+
+/// The shell command installing `names` with `manager` as root in a
+/// fresh container.
+fn install_command(manager: &str, names: &[String]) -> Result<Option<String>, String> {
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let command = match manager {
+        "apt" => "apt-get update && apt-get install -y --no-install-recommends",
+        "apk" => "apk add --no-cache",
+        "dnf" => "dnf install -y",
+        "pacman" => "pacman -Sy --needed --noconfirm",
+        _ => return Err(format!("'{}' is not available in devcontainers", manager)),
+    };
+    Ok(Some(format!("{} {}", command, names.join(" "))))
+}
+
+fn metadata() -> Json {
+    Json::object([
+        ("id", Json::String(ID.to_string())),
+        ("version", Json::String("1.0.0".to_string())),
+        ("name", Json::String("mdot dotfiles".to_string())),
+        (
+            "description",
+            Json::String("CLI packages and dotfiles exported by mdot".to_string()),
+        ),
+    ])
+}
+
+fn script(install: Option<&str>) -> String {
+    let mut out = String::from(
+        r#"#!/bin/sh
+# Generated by `mdot export devcontainer`
+set -e
+FEATURE_DIR="$(cd "$(dirname "$0")" && pwd)"
+TARGET_HOME="${_REMOTE_USER_HOME:-$HOME}"
+"#,
+    );
+    if let Some(install) = install {
+        out.push_str(install);
+        out.push('\n');
+    }
+    out.push_str(&format!(
+        r#"mkdir -p "$TARGET_HOME"
+cp -R "$FEATURE_DIR/{home}/." "$TARGET_HOME/"
+if [ -n "$_REMOTE_USER" ] && [ "$_REMOTE_USER" != root ]; then
+    (cd "$FEATURE_DIR/{home}" && find . -mindepth 1) | while read -r path; do
+        chown "$_REMOTE_USER" "$TARGET_HOME/$path"
+    done
+fi
+"#,
+        home = HOME_DIR
+    ));
+    out
+}
+
+/// Whether `dir` can be written to: it is missing, empty, or holds a
+/// feature exported before, whose files are replaced.
+fn replaceable(dir: &Path) -> bool {
+    let written_before = fs::read_to_string(dir.join(METADATA_FILE))
+        .is_ok_and(|json| json.contains(&format!(r#""id":"{}""#, ID)));
+    written_before || fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none())
+}
+
+/// Writes a devcontainer feature to `dir` that installs `names` with
+/// `manager` and copies the sources of `links` below `home` into the home
+/// of the container user, rendering those that are `templates`. Returns
+/// the targets outside `home`, which are left out. A `dir` holding
+/// anything but an earlier export is left alone.
+pub fn write(
+    dir: &Path,
+    links: &[PlannedLink],
+    templates: &[Template],
+    home: &Path,
+    names: &[String],
+    manager: &str,
+) -> Result<Vec<PathBuf>, String> {
+    let install = install_command(manager, names)?;
+    if !replaceable(dir) {
+        return Err(format!(
+            "{} is not empty and holds no feature exported by mdot",
+            dir.display()
+        ));
+    }
+    let files = dir.join(HOME_DIR);
+    if files.exists() {
+        fs::remove_dir_all(&files).map_err(|err| err.to_string())?;
+    }
+    fs::create_dir_all(&files).map_err(|err| err.to_string())?;
+    let mut skipped = Vec::new();
+    let mut problems = Vec::new();
+    for link in links {
+        let Ok(rel) = link.target.strip_prefix(home) else {
+            skipped.push(link.target.clone());
+            continue;
+        };
+        let source = link.rendered_from.as_ref().unwrap_or(&link.source);
+        render::materialize_file(source, &files.join(rel), templates, &mut problems)?;
+    }
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+        return Err(problems.join("\n"));
+    }
+    fs::write(dir.join(METADATA_FILE), format!("{}\n", metadata()))
+        .map_err(|err| err.to_string())?;
+    let path = dir.join("install.sh");
+    fs::write(&path, script(install.as_deref())).map_err(|err| err.to_string())?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).map_err(|err| err.to_string())?;
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::PlannedLink;
+    use crate::devcontainer::*;
+    use std::env;
+
+    #[test]
+    fn test_write() {
+        let root = env::temp_dir().join(format!("mdot-devcontainer-{}", std::process::id()));
+        fs::create_dir_all(root.join("repo/zsh")).unwrap();
+        fs::write(root.join("repo/zsh/zshrc"), "setopt autocd\n").unwrap();
+        let link = |target: &str| PlannedLink {
+            package: "zsh".to_string(),
+            source: root.join("repo/zsh/zshrc"),
+            target: PathBuf::from(target),
+            overwrite: false,
+            backup: false,
+            copy: true,
+            rendered_from: None,
+        };
+        let links = [link("/home/me/.config/zsh/.zshrc"), link("/etc/zshrc")];
+        let names = ["zsh".to_string(), "ripgrep".to_string()];
+        let dir = root.join("feature");
+
+        let skipped = write(&dir, &links, &[], Path::new("/home/me"), &names, "apt").unwrap();
+        assert_eq!(skipped, [PathBuf::from("/etc/zshrc")]);
+        assert_eq!(
+            fs::read_to_string(dir.join("home/.config/zsh/.zshrc")).unwrap(),
+            "setopt autocd\n"
+        );
+        let script = fs::read_to_string(dir.join("install.sh")).unwrap();
+        assert!(script.contains("apt-get install -y --no-install-recommends zsh ripgrep\n"));
+        assert!(
+            fs::read_to_string(dir.join("devcontainer-feature.json"))
+                .unwrap()
+                .contains(r#""id":"mdot-dotfiles""#)
+        );
+        assert!(write(&dir, &links, &[], Path::new("/home/me"), &names, "brew").is_err());
+        assert!(write(&dir, &links, &[], Path::new("/home/me"), &names, "apk").is_ok());
+        assert!(
+            write(
+                &root.join("repo"),
+                &links,
+                &[],
+                Path::new("/home/me"),
+                &names,
+                "apk"
+            )
+            .is_err()
+        );
+        assert!(root.join("repo/zsh/zshrc").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
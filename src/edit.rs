@@ -0,0 +1,139 @@
+use crate::deploy::{self, PlannedLink};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Finds the planned link providing `target`, directly or through a linked
+/// parent directory, along with the repo path behind `target`.
+pub fn source_for<'a>(
+    links: &'a [PlannedLink],
+    target: &Path,
+) -> Option<(&'a PlannedLink, PathBuf)> {
+    links
+        .iter()
+        .filter_map(|link| {
+            let rel = target.strip_prefix(&link.target).ok()?;
+            let source = link.rendered_from.as_ref().unwrap_or(&link.source);
+            Some((link, source.join(rel)))
+        })
+        .max_by_key(|(link, _)| link.target.components().count())
+}
+
+/// The inverse of `source_for`: every target a repo path is deployed to.
+pub fn targets_for(links: &[PlannedLink], source: &Path) -> Vec<PathBuf> {
+    links
+        .iter()
+        .filter_map(|link| {
+            let linked = link.rendered_from.as_ref().unwrap_or(&link.source);
+            Some(link.target.join(source.strip_prefix(linked).ok()?))
+        })
+        .collect()
+}
+
+/// Makes a user supplied target path absolute.
+pub fn target_arg(path: &str) -> PathBuf {
+    let target = deploy::expand_tilde(Path::new(path));
+    std::path::absolute(&target).unwrap_or(target)
+}
+
+/// Makes a user supplied repo path absolute: relative paths are looked up
+/// in the working directory first, then in the repo.
+pub fn source_arg(repo: &Path, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let path = match path.exists() {
+        true => path.to_path_buf(),
+        false => repo.join(path),
+    };
+    path.canonicalize()
+        .unwrap_or_else(|_| std::path::absolute(&path).unwrap_or(path))
+}
+
+/// Resolves what `mdot edit` opens: a package name opens its only source,
+/// or the package directory when it has several; anything else is taken
+/// as a target path. Returns the owning package and the repo path.
+pub fn resolve(
+    repo: &Path,
+    links: &[PlannedLink],
+    packages: &[String],
+    what: &str,
+) -> Result<(String, PathBuf), String> {
+    if packages.iter().any(|p| p == what) {
+        let mut sources = links
+            .iter()
+            .filter(|l| l.package == what)
+            .map(|l| l.rendered_from.as_ref().unwrap_or(&l.source));
+        let path = match (sources.next(), sources.next()) {
+            (Some(source), None) => source.clone(),
+            _ => repo.join(what),
+        };
+        return Ok((what.to_string(), path));
+    }
+    source_for(links, &target_arg(what))
+        .map(|(link, source)| (link.package.clone(), source))
+        .ok_or_else(|| format!("'{}' is neither a package nor a managed target", what))
+}
+
+/// Opens `path` in `$VISUAL`/`$EDITOR` (falling back to `vi`) and waits.
+pub fn open_editor(path: &Path) -> Result<(), String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg("sh")
+        .arg(path)
+        .status()
+        .map_err(|err| format!("failed to run {}: {}", editor, err))?;
+    if !status.success() {
+        return Err(format!("{} exited with {}", editor, status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::PlannedLink;
+    use crate::edit::*;
+
+    #[test]
+    fn test_resolve() {
+        let link = |package: &str, source: &str, target: &str| PlannedLink {
+            package: package.to_string(),
+            source: PathBuf::from(source),
+            target: PathBuf::from(target),
+            overwrite: false,
+            backup: false,
+            copy: false,
+            rendered_from: None,
+        };
+        let links = vec![
+            link("kitty", "/r/kitty/.config/kitty", "/h/.config/kitty"),
+            link("nvim", "/r/nvim/init.lua", "/h/.config/nvim/init.lua"),
+            link("nvim", "/r/nvim/lua", "/h/.config/nvim/lua"),
+        ];
+        let packages = vec!["kitty".to_string(), "nvim".to_string()];
+        let repo = Path::new("/r");
+        assert_eq!(
+            resolve(repo, &links, &packages, "/h/.config/kitty/kitty.conf"),
+            Ok((
+                "kitty".to_string(),
+                PathBuf::from("/r/kitty/.config/kitty/kitty.conf")
+            ))
+        );
+        assert_eq!(
+            resolve(repo, &links, &packages, "kitty"),
+            Ok(("kitty".to_string(), PathBuf::from("/r/kitty/.config/kitty")))
+        );
+        assert_eq!(
+            resolve(repo, &links, &packages, "nvim"),
+            Ok(("nvim".to_string(), PathBuf::from("/r/nvim")))
+        );
+        assert!(resolve(repo, &links, &packages, "/h/.bashrc").is_err());
+        assert_eq!(
+            targets_for(&links, Path::new("/r/nvim/lua/plugins.lua")),
+            vec![PathBuf::from("/h/.config/nvim/lua/plugins.lua")]
+        );
+        assert!(targets_for(&links, Path::new("/r/fish/config.fish")).is_empty());
+    }
+}
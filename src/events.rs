@@ -0,0 +1,88 @@
+use crate::state::escape;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PORCELAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    PORCELAIN.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    PORCELAIN.load(Ordering::Relaxed)
+}
+
+/// Machine readable events printed with `--porcelain`.
+///
+/// Each event is one line on stdout: the event name followed by its fields,
+/// separated by tabs, with tabs, newlines and backslashes in fields escaped
+/// as `\t`, `\n` and `\\`. Human oriented logs go to stderr, so stdout
+/// carries nothing else. The names and field order below are stable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// `<pkg> <target> <source>`
+    LinkCreated,
+    /// `<pkg> <target>`
+    LinkUnchanged,
+    /// `<pkg> <target>`: something else is in the way.
+    LinkSkipped,
+    /// `<pkg> <target> <error>`
+    LinkFailed,
+    /// `<pkg> <target>`
+    LinkRemoved,
+    /// `<pkg> <target> <backup>`
+    BackupCreated,
+    /// `<target>`: a folded directory became a real one.
+    DirSplit,
+    /// `<backend> <os package>`
+    PackageInstalled,
+    /// `<pkg> <bin>`
+    BinMissing,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Event::LinkCreated => "LINK_CREATED",
+            Event::LinkUnchanged => "LINK_UNCHANGED",
+            Event::LinkSkipped => "LINK_SKIPPED",
+            Event::LinkFailed => "LINK_FAILED",
+            Event::LinkRemoved => "LINK_REMOVED",
+            Event::BackupCreated => "BACKUP_CREATED",
+            Event::DirSplit => "DIR_SPLIT",
+            Event::PackageInstalled => "PACKAGE_INSTALLED",
+            Event::BinMissing => "BIN_MISSING",
+        })
+    }
+}
+
+/// Formats an event line.
+pub fn format(event: Event, fields: &[&dyn fmt::Display]) -> String {
+    let mut line = event.to_string();
+    for field in fields {
+        line.push('\t');
+        line.push_str(&escape(&field.to_string()));
+    }
+    line
+}
+
+/// Prints an event when `--porcelain` is active.
+pub fn emit(event: Event, fields: &[&dyn fmt::Display]) {
+    if enabled() {
+        println!("{}", format(event, fields));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            format(Event::LinkCreated, &[&"kitty", &"/h/a\tb", &"/r/kitty"]),
+            "LINK_CREATED\tkitty\t/h/a\\tb\t/r/kitty"
+        );
+        assert_eq!(format(Event::DirSplit, &[]), "DIR_SPLIT");
+    }
+}
@@ -0,0 +1,466 @@
+use crate::deploy::expand_tilde;
+use crate::{Package, field, lua_value_to_str, ordered_pairs, sequence, xdg};
+use log::{info, warn};
+use mlua::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn valid_name(field: &str, name: &str) -> bool {
+    match field {
+        "env" => name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric()),
+        _ => {
+            !name.starts_with('-')
+                && !name.contains(|c: char| c.is_whitespace() || "=/'\"\\$`".contains(c))
+        }
+    }
+}
+
+/// Parses `env = { EDITOR = "nvim", GOPATH = "~/go" }`, and `aliases` of
+/// the same shape.
+pub fn from_value(field: &str, value: &Value) -> Vec<(String, String)> {
+    let Value::Table(tbl) = value else {
+        fatal!(
+            "'{}' expected type 'Table', got {}",
+            field,
+            value.type_name()
+        );
+    };
+    let mut vars: Vec<(String, String)> = ordered_pairs(tbl, field)
+        .into_iter()
+        .map(|(name, value)| {
+            let name = lua_value_to_str(&name);
+            if name.is_empty() || !valid_name(field, &name) {
+                fatal!("'{}' has invalid name '{}'", field, name);
+            }
+            (name, lua_value_to_str(&value))
+        })
+        .collect();
+    vars.sort();
+    vars
+}
+
+/// A `path` entry: `"~/.local/bin"` is prepended to `PATH`,
+/// `{ "/opt/bin", append = true }` appended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathEntry {
+    pub dir: String,
+    pub append: bool,
+}
+
+/// Parses `path = { "~/.local/bin", { "/opt/bin", append = true } }`.
+pub fn path_from_value(value: &Value) -> Vec<PathEntry> {
+    let entry = |value: Value| match value {
+        Value::String(dir) => PathEntry {
+            dir: dir.to_string_lossy(),
+            append: false,
+        },
+        Value::Table(tbl) => {
+            let dir: String = tbl
+                .get(1)
+                .unwrap_or_else(|err| fatal!("'path' entry expects a directory: {}", err));
+            let append = match field(&tbl, "path", "append") {
+                Value::Boolean(append) => append,
+                Value::Nil => false,
+                v => fatal!("'path' 'append' expected type 'Boolean', got {:?}", v),
+            };
+            PathEntry { dir, append }
+        }
+        v => fatal!(
+            "'path' entries expected type 'String' or 'Table', got {}",
+            v.type_name()
+        ),
+    };
+    match value {
+        Value::String(_) => vec![entry(value.clone())],
+        Value::Table(tbl) => sequence(tbl, "path").into_iter().map(entry).collect(),
+        v => fatal!(
+            "'path' expected type 'String' or 'Table', got {}",
+            v.type_name()
+        ),
+    }
+}
+
+/// The `path` entries of `packages`, deduplicated, as the directories to
+/// put before and after the inherited `PATH`. Packages with a higher
+/// `priority` come first, then packages in order.
+pub fn path_entries(packages: &[Package]) -> (Vec<String>, Vec<String>) {
+    let mut ordered: Vec<&Package> = packages.iter().collect();
+    ordered.sort_by_key(|pkg| std::cmp::Reverse(pkg.priority));
+    let (mut prepend, mut append) = (Vec::new(), Vec::new());
+    for entry in ordered.iter().flat_map(|pkg| &pkg.path) {
+        if prepend.contains(&entry.dir) || append.contains(&entry.dir) {
+            continue;
+        }
+        match entry.append {
+            false => prepend.push(entry.dir.clone()),
+            true => append.push(entry.dir.clone()),
+        }
+    }
+    (prepend, append)
+}
+
+/// `path` entries of `packages` whose directory does not exist.
+pub fn missing_path_dirs(packages: &[Package]) -> Vec<(String, String)> {
+    packages
+        .iter()
+        .flat_map(|pkg| pkg.path.iter().map(move |entry| (pkg, entry)))
+        .filter(|(_, entry)| !expand_tilde(Path::new(&entry.dir)).is_dir())
+        .map(|(pkg, entry)| (pkg.name.clone(), entry.dir.clone()))
+        .collect()
+}
+
+/// The `env` or `aliases` of every package, in package order. A name set
+/// by more than one package keeps the last value.
+pub fn collect(packages: &[Package], field: &str) -> BTreeMap<String, String> {
+    let mut out: BTreeMap<String, (String, &str)> = BTreeMap::new();
+    for pkg in packages {
+        let values = match field {
+            "env" => &pkg.env,
+            _ => &pkg.aliases,
+        };
+        for (name, value) in values {
+            if let Some((old, owner)) = out.get(name)
+                && old != value
+            {
+                warn!(
+                    "[{}] {} {} overrides the value set by '{}'",
+                    pkg.name, field, name, owner
+                );
+            }
+            out.insert(name.clone(), (value.clone(), &pkg.name));
+        }
+    }
+    out.into_iter()
+        .map(|(name, (value, _))| (name, value))
+        .collect()
+}
+
+/// `~/...` becomes `$HOME/...`, and characters special inside double
+/// quotes are escaped, leaving `$VAR` references to the shell.
+fn quote(value: &str, special: &[char]) -> String {
+    let value = match value.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("$HOME{}", rest),
+        _ => value.to_string(),
+    };
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        if special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+const HEADER: &str = "# Generated by mdot from the 'env' of deployed packages. Do not edit.\n";
+
+pub fn render_sh(vars: &BTreeMap<String, String>, path: &(Vec<String>, Vec<String>)) -> String {
+    let mut out = HEADER.to_string();
+    let (prepend, append) = path;
+    for dir in prepend.iter().rev() {
+        let dir = quote(dir, &['"', '\\', '`']);
+        out.push_str(&format!(
+            "case \":$PATH:\" in *:{}:*) ;; *) PATH={}:$PATH ;; esac\n",
+            dir, dir
+        ));
+    }
+    for dir in append {
+        let dir = quote(dir, &['"', '\\', '`']);
+        out.push_str(&format!(
+            "case \":$PATH:\" in *:{}:*) ;; *) PATH=$PATH:{} ;; esac\n",
+            dir, dir
+        ));
+    }
+    if !prepend.is_empty() || !append.is_empty() {
+        out.push_str("export PATH\n");
+    }
+    for (name, value) in vars {
+        out.push_str(&format!(
+            "export {}={}\n",
+            name,
+            quote(value, &['"', '\\', '`'])
+        ));
+    }
+    out
+}
+
+pub fn render_fish(vars: &BTreeMap<String, String>, path: &(Vec<String>, Vec<String>)) -> String {
+    let mut out = HEADER.to_string();
+    let (prepend, append) = path;
+    for dir in prepend.iter().rev() {
+        let dir = quote(dir, &['"', '\\']);
+        out.push_str(&format!(
+            "contains -- {} $PATH; or set -gx PATH {} $PATH\n",
+            dir, dir
+        ));
+    }
+    for dir in append {
+        let dir = quote(dir, &['"', '\\']);
+        out.push_str(&format!(
+            "contains -- {} $PATH; or set -gx PATH $PATH {}\n",
+            dir, dir
+        ));
+    }
+    for (name, value) in vars {
+        out.push_str(&format!(
+            "set -gx {} {}\n",
+            name,
+            quote(value, &['"', '\\'])
+        ));
+    }
+    out
+}
+
+/// `value` in single quotes, which neither shell expands. Inside them sh
+/// can only end the quote to add a `'`, fish escapes it.
+pub fn single_quote(value: &str, fish: bool) -> String {
+    if fish {
+        format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// Aliases for bash and zsh.
+pub fn render_aliases_sh(aliases: &BTreeMap<String, String>) -> String {
+    let mut out = HEADER.to_string();
+    for (name, command) in aliases {
+        out.push_str(&format!(
+            "alias {}={}\n",
+            name,
+            single_quote(command, false)
+        ));
+    }
+    out
+}
+
+/// Aliases for fish, as abbreviations that expand when typed.
+pub fn render_aliases_fish(aliases: &BTreeMap<String, String>) -> String {
+    let mut out = HEADER.to_string();
+    for (name, command) in aliases {
+        out.push_str(&format!(
+            "abbr --add --global {} {}\n",
+            name,
+            single_quote(command, true)
+        ));
+    }
+    out
+}
+
+/// Where the snippets are written, `$XDG_CONFIG_HOME/mdot`.
+pub fn dir() -> PathBuf {
+    xdg::config_home().join("mdot")
+}
+
+fn write_in(dir: &Path, packages: &[Package]) -> Result<Vec<PathBuf>, String> {
+    let vars = collect(packages, "env");
+    let path = path_entries(packages);
+    let aliases = collect(packages, "aliases");
+    let has_env = !vars.is_empty() || !path.0.is_empty() || !path.1.is_empty();
+    let mut written = Vec::new();
+    for (file, content, wanted) in [
+        ("env.sh", render_sh(&vars, &path), has_env),
+        ("env.fish", render_fish(&vars, &path), has_env),
+        (
+            "aliases.sh",
+            render_aliases_sh(&aliases),
+            !aliases.is_empty(),
+        ),
+        (
+            "aliases.fish",
+            render_aliases_fish(&aliases),
+            !aliases.is_empty(),
+        ),
+    ] {
+        let path = dir.join(file);
+        if !wanted {
+            // Nothing left to export: drop what an earlier deploy wrote.
+            if fs::read_to_string(&path).is_ok_and(|old| old.starts_with(HEADER)) {
+                fs::remove_file(&path)
+                    .map_err(|err| format!("failed to remove {}: {}", path.display(), err))?;
+                info!("removed {}", path.display());
+            }
+            continue;
+        }
+        if fs::read_to_string(&path).is_ok_and(|old| old == content) {
+            continue;
+        }
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        fs::write(&path, content)
+            .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+        info!("wrote {}", path.display());
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Regenerates `env.sh`/`env.fish` from the `env` and `path` of
+/// `packages`, and `aliases.sh`/`aliases.fish` from their `aliases`.
+/// Snippets with nothing to export are not written, and removed when an
+/// earlier deploy wrote them.
+pub fn write(packages: &[Package]) -> Result<Vec<PathBuf>, String> {
+    write_in(&dir(), packages)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::exports::*;
+    use crate::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    #[test]
+    fn test_env() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-env-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return {
+                { "nvim", env = { EDITOR = "nvim", VISUAL = "nvim" } },
+                { "go", env = { GOPATH = "~/go", GOFLAGS = 'say "hi" $USER' } },
+                { "helix", env = { EDITOR = "hx" } },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let vars = collect(&config.packages, "env");
+        assert_eq!(vars["EDITOR"], "hx");
+        let no_path = (Vec::new(), Vec::new());
+        assert_eq!(
+            render_sh(&vars, &no_path)
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>(),
+            [
+                r#"export EDITOR="hx""#,
+                r#"export GOFLAGS="say \"hi\" $USER""#,
+                r#"export GOPATH="$HOME/go""#,
+                r#"export VISUAL="nvim""#,
+            ]
+        );
+        assert!(render_fish(&vars, &no_path).contains("set -gx GOPATH \"$HOME/go\"\n"));
+
+        let out = root.join("out");
+        assert_eq!(
+            write_in(&out, &config.packages).unwrap(),
+            [out.join("env.sh"), out.join("env.fish")]
+        );
+        assert!(write_in(&out, &config.packages).unwrap().is_empty());
+        assert!(!out.join("aliases.sh").exists());
+        assert!(write_in(&out, &[]).unwrap().is_empty());
+        assert!(!out.join("env.sh").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_path() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-path-{}", std::process::id()));
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            format!(
+                r#"
+                return {{
+                    {{ "cargo", path = {{ "~/.cargo/bin", {{ "/opt/bin", append = true }} }} }},
+                    {{ "local", priority = 1, path = {{ "~/.local/bin", "{}" }} }},
+                    {{ "rust", path = "~/.cargo/bin" }},
+                }}
+                "#,
+                root.join("bin").display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let bin = root.join("bin").display().to_string();
+        let path = path_entries(&config.packages);
+        assert_eq!(
+            path,
+            (
+                vec![
+                    "~/.local/bin".to_string(),
+                    bin.clone(),
+                    "~/.cargo/bin".to_string()
+                ],
+                vec!["/opt/bin".to_string()]
+            )
+        );
+        let sh = render_sh(&BTreeMap::new(), &path);
+        let lines: Vec<&str> = sh.lines().skip(1).collect();
+        assert_eq!(
+            lines[0],
+            r#"case ":$PATH:" in *:"$HOME/.cargo/bin":*) ;; *) PATH="$HOME/.cargo/bin":$PATH ;; esac"#
+        );
+        assert_eq!(
+            lines[3],
+            r#"case ":$PATH:" in *:"/opt/bin":*) ;; *) PATH=$PATH:"/opt/bin" ;; esac"#
+        );
+        assert_eq!(lines[4], "export PATH");
+        assert!(
+            render_fish(&BTreeMap::new(), &path)
+                .contains("contains -- \"/opt/bin\" $PATH; or set -gx PATH $PATH \"/opt/bin\"\n")
+        );
+        let missing = missing_path_dirs(&config.packages);
+        assert!(missing.contains(&("cargo".to_string(), "/opt/bin".to_string())));
+        assert!(!missing.iter().any(|(_, dir)| *dir == bin));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_aliases() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-aliases-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return {
+                { "git", aliases = { gs = "git status", gl = "git log --format='%h %s'" } },
+                { "ls", aliases = { ll = "ls -l" } },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let aliases = collect(&config.packages, "aliases");
+        assert_eq!(
+            render_aliases_sh(&aliases)
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>(),
+            [
+                r#"alias gl='git log --format='\''%h %s'\'''"#,
+                "alias gs='git status'",
+                "alias ll='ls -l'",
+            ]
+        );
+        assert_eq!(
+            render_aliases_fish(&aliases).lines().nth(1).unwrap(),
+            r#"abbr --add --global gl 'git log --format=\'%h %s\''"#
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use crate::hosts::Resolution;
+use crate::platform::Platform;
+use crate::template::{self, Vars};
+use mlua::{Lua, Result as LuaResult, Table, Value};
+use std::env;
+use std::path::Path;
+
+pub const FACTS_FILE: &str = "facts.lua";
+const ENV_PREFIX: &str = "MDOT_FACT_";
+
+fn env_value(lua: &Lua, value: &str) -> LuaResult<Value> {
+    Ok(match value {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => Value::String(lua.create_string(value)?),
+    })
+}
+
+/// Populates `mdot.facts` and registers `mdot.fact(name)`.
+///
+/// Facts are layered, later layers winning: the builtin platform facts,
+/// the table returned by `facts.lua` in the config root, the `facts` of
+/// the `hosts.lua` entries of this machine, then `MDOT_FACT_<NAME>`
+/// environment variables. The config itself may still assign
+/// `mdot.facts.<name>` while it is evaluated.
+pub fn install(lua: &Lua, root: &Path, platform: &Platform, host: &Resolution) -> LuaResult<()> {
+    let mdot: Table = lua.globals().get("mdot")?;
+    let facts = lua.create_table()?;
+    facts.set("os", platform.os.as_str())?;
+    facts.set("distro", platform.distro.as_deref())?;
+    facts.set("family", platform.family.as_deref())?;
+    facts.set("arch", platform.arch.as_str())?;
+    facts.set("wsl", platform.wsl)?;
+    facts.set("hostname", platform.hostname.as_str())?;
+    mdot.set("facts", &facts)?;
+    mdot.set(
+        "fact",
+        lua.create_function(|lua, name: String| {
+            let mdot: Table = lua.globals().get("mdot")?;
+            mdot.get::<Table>("facts")?.get::<Value>(name)
+        })?,
+    )?;
+
+    let file = root.join(FACTS_FILE);
+    if file.is_file() {
+        match lua.load(file.as_path()).eval::<Value>()? {
+            Value::Table(tbl) => {
+                for pair in tbl.pairs::<Value, Value>() {
+                    let (key, value) = pair?;
+                    facts.set(key, value)?;
+                }
+            }
+            Value::Nil => (),
+            v => {
+                return Err(mlua::Error::runtime(format!(
+                    "'{}' must return a table of facts, got {}",
+                    file.display(),
+                    v.type_name()
+                )));
+            }
+        }
+    }
+
+    host.apply_facts(lua)?;
+    for (key, value) in env::vars() {
+        if let Some(name) = key.strip_prefix(ENV_PREFIX) {
+            facts.set(name.to_lowercase(), env_value(lua, &value)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the final `mdot.facts` as template variables keyed `facts.*`.
+pub fn collect(lua: &Lua) -> LuaResult<Vars> {
+    let mdot: Table = lua.globals().get("mdot")?;
+    let mut facts = Vars::new();
+    template::flatten("facts", &mdot.get::<Table>("facts")?, &mut facts);
+    Ok(facts)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_fact_layers() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-facts-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join(facts::FACTS_FILE),
+            r#"return { gpu = "nvidia", work_machine = false }"#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.facts.gpu = mdot.fact("gpu") .. "-open"
+            return { { "cuda", vars = { os = mdot.fact("os") } } }
+            "#,
+        )
+        .unwrap();
+
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        assert_eq!(config.facts["facts.gpu"], "nvidia-open");
+        assert_eq!(config.facts["facts.work_machine"], "false");
+        assert_eq!(config.facts["facts.os"], ctx.platform.os);
+        assert_eq!(config.packages[0].vars["vars.os"], ctx.platform.os);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+use crate::config::Config;
+use crate::deploy::{self, resolve_target};
+use crate::template;
+use crate::{Context, Package, lua_str_to_str, lua_value_to_str, net, ordered_pairs};
+use log::info;
+use mlua::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Where downloads wait in the cache dir until they are verified.
+const FETCH_DIR: &str = "fetch";
+
+/// How a download is signed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signature {
+    /// A minisign public key, e.g. `RWQf6LRC...`.
+    Minisign(String),
+    /// An armored public key file in the package directory.
+    Gpg(PathBuf),
+}
+
+/// `fetch = { ["~/.local/bin/tool"] = { url = "https://...", sha256 = "..." } }`
+///
+/// A file downloaded into place on deploy unless the target already holds
+/// it, backing up what was there. Downloads are checked against `sha256`/`sha512` and, with
+/// `minisign` or `gpg`, against the signature at `signature`, by default
+/// the url followed by `.minisig` or `.asc`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fetch {
+    pub target: PathBuf,
+    pub url: String,
+    pub sha256: Option<String>,
+    pub sha512: Option<String>,
+    pub signature: Option<Signature>,
+    pub signature_url: Option<String>,
+    pub executable: bool,
+    /// Overrides `mdot.options.retries` for this download.
+    pub retries: Option<u32>,
+}
+
+impl Fetch {
+    fn new(target: PathBuf, url: String) -> Fetch {
+        Fetch {
+            target,
+            url,
+            sha256: None,
+            sha512: None,
+            signature: None,
+            signature_url: None,
+            executable: false,
+            retries: None,
+        }
+    }
+
+    fn from_pair(target: &Value, value: &Value) -> Fetch {
+        let target = PathBuf::from(lua_value_to_str(target));
+        let tbl = match value {
+            Value::String(url) => return Fetch::new(target, lua_str_to_str(url)),
+            Value::Table(tbl) => tbl,
+            v => fatal!(
+                "'fetch' entries expect a url or a table, got {}",
+                v.type_name()
+            ),
+        };
+        let path = &format!("fetch.{}", target.display());
+        let mut fetch = Fetch::new(target, String::new());
+        for (key, value) in ordered_pairs(tbl, path) {
+            let key = lua_value_to_str(&key);
+            match key.as_str() {
+                "url" => fetch.url = lua_value_to_str(&value),
+                "sha256" => fetch.sha256 = Some(lua_value_to_str(&value).to_lowercase()),
+                "sha512" => fetch.sha512 = Some(lua_value_to_str(&value).to_lowercase()),
+                "minisign" => fetch.signature = Some(Signature::Minisign(lua_value_to_str(&value))),
+                "gpg" => {
+                    fetch.signature = Some(Signature::Gpg(PathBuf::from(lua_value_to_str(&value))))
+                }
+                "signature" => fetch.signature_url = Some(lua_value_to_str(&value)),
+                "executable" => {
+                    fetch.executable = match value {
+                        Value::Boolean(executable) => executable,
+                        v => fatal!("'fetch.executable' expected type 'Boolean', got {:?}", v),
+                    }
+                }
+                "retries" => {
+                    fetch.retries = match value {
+                        Value::Integer(n) if n >= 0 => Some(n as u32),
+                        v => fatal!(
+                            "'fetch.retries' expected a non-negative 'Integer', got {:?}",
+                            v
+                        ),
+                    }
+                }
+                key => fatal!("unknown 'fetch' key '{}'", key),
+            }
+        }
+        if fetch.url.is_empty() {
+            fatal!("'fetch' entry '{}' has no 'url'", fetch.target.display());
+        }
+        fetch
+    }
+
+    /// Whether the download can be checked at all.
+    pub fn is_verified(&self) -> bool {
+        self.sha256.is_some() || self.sha512.is_some() || self.signature.is_some()
+    }
+
+    fn signature_url(&self) -> Option<String> {
+        let extension = match self.signature.as_ref()? {
+            Signature::Minisign(_) => "minisig",
+            Signature::Gpg(_) => "asc",
+        };
+        Some(
+            self.signature_url
+                .clone()
+                .unwrap_or_else(|| format!("{}.{}", self.url, extension)),
+        )
+    }
+}
+
+/// Parses the `fetch` table, keyed by target.
+pub fn from_value(value: &Value) -> Vec<Fetch> {
+    let Value::Table(tbl) = value else {
+        fatal!("'fetch' expected type 'Table', got {}", value.type_name());
+    };
+    ordered_pairs(tbl, "fetch")
+        .iter()
+        .map(|(target, value)| Fetch::from_pair(target, value))
+        .collect()
+}
+
+fn run(argv: &[&str]) -> Result<String, String> {
+    let out = Command::new(argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| format!("failed to run {}: {}", argv[0], err))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{} exited with {}: {}",
+            argv[0],
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// The hex SHA-256 of `data`.
+pub fn sha256(data: impl AsRef<[u8]>) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The hex digest of `path` with SHA-`bits`, from coreutils or `shasum`.
+pub fn digest(bits: u32, path: &Path) -> Result<String, String> {
+    let path = path.to_string_lossy();
+    let tool = format!("sha{}sum", bits);
+    let bits = bits.to_string();
+    let out = run(&[&tool, &path]).or_else(|_| run(&["shasum", "-a", &bits, &path]))?;
+    out.split_whitespace()
+        .next()
+        .map(str::to_lowercase)
+        .ok_or_else(|| format!("no digest for {}", path))
+}
+
+fn download(url: &str, dest: &Path, retries: u32) -> Result<(), String> {
+    let dest = dest.to_string_lossy();
+    net::retry(&format!("fetching {}", url), retries, || {
+        run(&["curl", "-fsSL", "-o", &dest, url]).map(|_| ())
+    })
+}
+
+/// Checks the downloaded `file` against the checksums and signature of
+/// `fetch`, fetching the signature next to it. Gpg keys are looked up in
+/// `pkg_dir`.
+fn verify(fetch: &Fetch, pkg_dir: &Path, file: &Path, retries: u32) -> Result<(), String> {
+    for (bits, expected) in [(256, &fetch.sha256), (512, &fetch.sha512)] {
+        let Some(expected) = expected else {
+            continue;
+        };
+        let actual = digest(bits, file)?;
+        if &actual != expected {
+            return Err(format!(
+                "sha{} mismatch for {}: expected {}, got {}",
+                bits, fetch.url, expected, actual
+            ));
+        }
+    }
+    let (Some(signature), Some(url)) = (&fetch.signature, fetch.signature_url()) else {
+        return Ok(());
+    };
+    let sig = file.with_extension("sig");
+    download(&url, &sig, retries)?;
+    let file_name = file.to_string_lossy();
+    let sig = sig.to_string_lossy();
+    match signature {
+        Signature::Minisign(key) => {
+            run(&["minisign", "-Vq", "-P", key, "-m", &file_name, "-x", &sig])
+        }
+        Signature::Gpg(key) => {
+            let keyring = file.with_file_name("keyring.gpg");
+            let keyring = keyring.to_string_lossy();
+            let key = pkg_dir.join(key);
+            let key = key.to_string_lossy();
+            let gpg = [
+                "gpg",
+                "--batch",
+                "--no-default-keyring",
+                "--keyring",
+                &keyring,
+            ];
+            run(&[&gpg[..], &["--import", &key]].concat())
+                .and_then(|_| run(&[&gpg[..], &["--verify", &sig, &file_name]].concat()))
+        }
+    }
+    .map(|_| ())
+    .map_err(|err| format!("bad signature for {}: {}", fetch.url, err))
+}
+
+/// Whether `target` already holds what `fetch` downloads. Without a
+/// checksum to compare, any existing file counts.
+pub fn is_current(fetch: &Fetch, target: &Path) -> bool {
+    if !target.is_file() {
+        return false;
+    }
+    [(256, &fetch.sha256), (512, &fetch.sha512)]
+        .iter()
+        .all(|(bits, expected)| match expected {
+            Some(expected) => digest(*bits, target).is_ok_and(|actual| &actual == expected),
+            None => true,
+        })
+}
+
+fn fetch_one(
+    ctx: &Context,
+    config: &Config,
+    pkg: &Package,
+    fetch: &Fetch,
+    target: &Path,
+) -> Result<(), String> {
+    let dir = ctx.cache_dir.join(FETCH_DIR).join(sha256(&fetch.url));
+    fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+    let file = dir.join("download");
+    let retries = fetch.retries.unwrap_or(config.options.retries);
+    let result = download(&fetch.url, &file, retries)
+        .and_then(|_| {
+            let pkg_dir = ctx.package_dir(pkg);
+            verify(fetch, &pkg_dir, &file, retries)
+        })
+        .and_then(|_| {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+            }
+            match fs::symlink_metadata(target) {
+                Ok(meta) if meta.is_symlink() => {
+                    fs::remove_file(target).map_err(|err| err.to_string())?;
+                }
+                Ok(_) => deploy::back_up(&pkg.name, target, &ctx.backup_dir())
+                    .map_err(|err| format!("failed to back up {}: {}", target.display(), err))?,
+                Err(_) => {}
+            }
+            fs::copy(&file, target)
+                .map_err(|err| format!("failed to write {}: {}", target.display(), err))?;
+            let mode = if fetch.executable { 0o755 } else { 0o644 };
+            fs::set_permissions(target, fs::Permissions::from_mode(mode))
+                .map_err(|err| err.to_string())
+        });
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Downloads the `fetch` entries of `pkg` whose target is missing or
+/// differs from its checksum. Entries that cannot be verified are refused
+/// unless the run allows them with `--insecure-fetch`. Targets in
+/// `held_back` are left alone. Returns the targets written.
+pub fn sync(
+    ctx: &Context,
+    config: &Config,
+    pkg: &Package,
+    held_back: &BTreeSet<PathBuf>,
+) -> Result<Vec<PathBuf>, String> {
+    let vars = template::package_vars(config, pkg, &ctx.platform);
+    let mut written = Vec::new();
+    for fetch in &pkg.fetch {
+        let target = resolve_target(&fetch.target, &vars)?;
+        if is_current(fetch, &target) {
+            continue;
+        }
+        if held_back.contains(&target) {
+            info!("[{}] skipped sensitive {}", pkg.name, target.display());
+            continue;
+        }
+        if !fetch.is_verified() && !ctx.insecure_fetch {
+            return Err(format!(
+                "refusing to fetch {} without 'sha256', 'sha512' or a signature to verify it \
+                 (pass --insecure-fetch to allow it)",
+                fetch.url
+            ));
+        }
+        if net::skip(&format!("fetching {}", fetch.url)) {
+            continue;
+        }
+        fetch_one(ctx, config, pkg, fetch, &target)?;
+        info!("[{}] fetched {}", pkg.name, target.display());
+        written.push(target);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_fetch() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-fetch-{}", std::process::id()));
+        fs::create_dir_all(root.join("dist")).unwrap();
+        fs::write(root.join("dist/tool"), "#!/bin/sh\necho tool\n").unwrap();
+        let sha256 = fetch::digest(256, &root.join("dist/tool")).unwrap();
+        let write_config = |entry: &str| {
+            fs::write(
+                root.join("main.lua"),
+                format!(
+                    r#"return {{ {{ "tool", fetch = {{ ["{out}/tool"] = {} }} }} }}"#,
+                    entry,
+                    out = root.join("out").display()
+                ),
+            )
+            .unwrap();
+            let ctx = Context::builder()
+                .entry(root.join("main.lua"))
+                .cache_dir(root.join("cache"))
+                .state_dir(root.join("state"))
+                .data_dir(root.join("data"))
+                .build();
+            let config = config::load(&ctx);
+            (ctx, config)
+        };
+        let url = format!("file://{}", root.join("dist/tool").display());
+        let target = root.join("out/tool");
+
+        let (mut ctx, config) = write_config(&format!(r#""{}""#, url));
+        let pkg = &config.packages[0];
+        assert!(fetch::sync(&ctx, &config, pkg, &BTreeSet::new()).is_err());
+        ctx.insecure_fetch = true;
+        assert_eq!(
+            fetch::sync(&ctx, &config, pkg, &BTreeSet::new()).unwrap(),
+            std::slice::from_ref(&target)
+        );
+
+        let (ctx, config) = write_config(&format!(
+            r#"{{ url = "{}", sha256 = "{}", executable = true, retries = 0 }}"#,
+            url,
+            sha256.to_uppercase()
+        ));
+        let pkg = &config.packages[0];
+        assert_eq!(pkg.fetch[0].retries, Some(0));
+        assert!(
+            fetch::sync(&ctx, &config, pkg, &BTreeSet::new())
+                .unwrap()
+                .is_empty()
+        );
+        fs::write(&target, "stale").unwrap();
+        assert_eq!(
+            fetch::sync(&ctx, &config, pkg, &BTreeSet::new())
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "#!/bin/sh\necho tool\n"
+        );
+        assert_eq!(
+            fs::metadata(&target).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        // What was there is backed up first, a directory too.
+        fs::remove_file(&target).unwrap();
+        fs::create_dir_all(target.join("old")).unwrap();
+        fetch::sync(&ctx, &config, pkg, &BTreeSet::new()).unwrap();
+        assert!(target.is_file());
+        let backups = ctx.backup_dir();
+        assert_eq!(backup::index(&backups).unwrap()[0].target, target);
+        assert!(
+            backups
+                .join(target.strip_prefix("/").unwrap())
+                .join("old")
+                .is_dir()
+        );
+
+        let (ctx, config) = write_config(&format!(
+            r#"{{ url = "{}", sha256 = "{}" }}"#,
+            url,
+            "0".repeat(64)
+        ));
+        let err = fetch::sync(&ctx, &config, &config.packages[0], &BTreeSet::new()).unwrap_err();
+        assert!(err.starts_with("sha256 mismatch"));
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "#!/bin/sh\necho tool\n"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use crate::config::Options;
+use crate::platform::Platform;
+use crate::{Context, Package, check, walk, xdg};
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Where user fonts are installed: `~/Library/Fonts` on macOS, else
+/// `$XDG_DATA_HOME/fonts`.
+pub fn font_dir(platform: &Platform) -> PathBuf {
+    if platform.os == "macos" {
+        dirs::home_dir().unwrap_or_default().join("Library/Fonts")
+    } else {
+        xdg::data_home().join("fonts")
+    }
+}
+
+/// The font files `fonts` of `pkg` names, expanding globs against the
+/// package directory.
+fn sources(ctx: &Context, options: &Options, pkg: &Package) -> Result<Vec<PathBuf>, String> {
+    let dir = ctx.package_dir(pkg);
+    let mut out = Vec::new();
+    for pattern in &pkg.fonts {
+        if walk::is_glob(pattern) {
+            let files = walk::all_files(&dir, options).map_err(|err| err.to_string())?;
+            let matches = walk::glob(pattern, files)?;
+            if matches.is_empty() {
+                return Err(format!("'{}' matches no fonts", pattern.display()));
+            }
+            out.extend(matches.into_iter().map(|rel| dir.join(rel)));
+        } else {
+            out.push(dir.join(pattern));
+        }
+    }
+    Ok(out)
+}
+
+/// Each source with where it is installed in `dir`, below a directory
+/// named after the package.
+fn destinations(
+    ctx: &Context,
+    options: &Options,
+    pkg: &Package,
+    dir: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    Ok(sources(ctx, options, pkg)?
+        .into_iter()
+        .map(|source| {
+            let dest = dir.join(&pkg.name).join(source.file_name().unwrap());
+            (source, dest)
+        })
+        .collect())
+}
+
+fn installed(source: &Path, dest: &Path) -> bool {
+    match (fs::read(source), fs::read(dest)) {
+        (Ok(source), Ok(dest)) => source == dest,
+        _ => false,
+    }
+}
+
+/// The families `fc-scan` finds in `file`, when it is available.
+fn families(file: &Path) -> Vec<String> {
+    let Ok(out) = Command::new("fc-scan")
+        .args(["--format", "%{family[0]}\n"])
+        .arg(file)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    else {
+        return Vec::new();
+    };
+    let mut families: Vec<String> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+    families.dedup();
+    families
+}
+
+/// A font file of a package, and whether it is installed.
+#[derive(Debug, PartialEq)]
+pub struct FontStatus {
+    pub package: String,
+    pub file: PathBuf,
+    pub installed: bool,
+    pub families: Vec<String>,
+}
+
+fn status_in(
+    ctx: &Context,
+    options: &Options,
+    pkg: &Package,
+    dir: &Path,
+) -> Result<Vec<FontStatus>, String> {
+    Ok(destinations(ctx, options, pkg, dir)?
+        .into_iter()
+        .map(|(source, dest)| FontStatus {
+            package: pkg.name.clone(),
+            installed: installed(&source, &dest),
+            families: families(&source),
+            file: dest,
+        })
+        .collect())
+}
+
+/// The fonts of `pkg` with their families.
+pub fn status(ctx: &Context, options: &Options, pkg: &Package) -> Result<Vec<FontStatus>, String> {
+    status_in(ctx, options, pkg, &font_dir(&ctx.platform))
+}
+
+/// Copies the fonts of `pkg` that are missing or differ into `dir`,
+/// returning how many were copied.
+fn install_into(
+    ctx: &Context,
+    options: &Options,
+    pkg: &Package,
+    dir: &Path,
+) -> Result<usize, String> {
+    let mut changed = 0;
+    for (source, dest) in destinations(ctx, options, pkg, dir)? {
+        if installed(&source, &dest) {
+            continue;
+        }
+        fs::create_dir_all(dest.parent().unwrap()).map_err(|err| err.to_string())?;
+        fs::copy(&source, &dest)
+            .map_err(|err| format!("failed to install {}: {}", source.display(), err))?;
+        info!("[{}] installed font {}", pkg.name, dest.display());
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+/// Installs the fonts of `pkg`, refreshing the font cache with `fc-cache`
+/// only when a font was copied.
+pub fn install(ctx: &Context, options: &Options, pkg: &Package) -> Result<(), String> {
+    if pkg.fonts.is_empty() {
+        return Ok(());
+    }
+    let dir = font_dir(&ctx.platform);
+    if install_into(ctx, options, pkg, &dir)? > 0 && check::find_bin("fc-cache").is_some() {
+        let status = Command::new("fc-cache")
+            .arg(&dir)
+            .stdin(Stdio::null())
+            .status()
+            .map_err(|err| format!("failed to run fc-cache: {}", err))?;
+        if !status.success() {
+            return Err("fc-cache failed".to_string());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fonts::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_fonts() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-fonts-{}", std::process::id()));
+        fs::create_dir_all(root.join("fira/assets/FiraCode")).unwrap();
+        fs::write(root.join("fira/assets/FiraCode/Regular.ttf"), "regular").unwrap();
+        fs::write(root.join("fira/assets/FiraCode/Bold.ttf"), "bold").unwrap();
+        fs::write(root.join("fira/assets/FiraCode/LICENSE"), "").unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"return { { "fira", fonts = { "assets/FiraCode/*.ttf" } } }"#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let pkg = &config.packages[0];
+        let dir = root.join("fonts");
+
+        let status = status_in(&ctx, &config.options, pkg, &dir).unwrap();
+        assert_eq!(status.len(), 2);
+        assert!(status.iter().all(|font| !font.installed));
+
+        assert_eq!(install_into(&ctx, &config.options, pkg, &dir).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.join("fira/Bold.ttf")).unwrap(),
+            "bold"
+        );
+        assert_eq!(install_into(&ctx, &config.options, pkg, &dir).unwrap(), 0);
+
+        fs::write(root.join("fira/assets/FiraCode/Bold.ttf"), "bolder").unwrap();
+        assert_eq!(install_into(&ctx, &config.options, pkg, &dir).unwrap(), 1);
+        let status = status_in(&ctx, &config.options, pkg, &dir).unwrap();
+        assert!(status.iter().all(|font| font.installed));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use log::info;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// What a filesystem holding targets supports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Caps {
+    pub symlinks: bool,
+    pub hardlinks: bool,
+    /// Whether `a` and `A` are different names, false on exFAT or the
+    /// default APFS.
+    pub case_sensitive: bool,
+}
+
+impl Caps {
+    pub fn describe(&self) -> String {
+        let yes = |b: bool| if b { "yes" } else { "no" };
+        format!(
+            "symlinks={} hardlinks={} case_sensitive={}",
+            yes(self.symlinks),
+            yes(self.hardlinks),
+            yes(self.case_sensitive)
+        )
+    }
+}
+
+thread_local! {
+    /// The filesystems probed so far by device, with the directory probed
+    /// and what was found, `None` when it could not be written to.
+    static PROBED: RefCell<BTreeMap<u64, (PathBuf, Option<Caps>)>> =
+        const { RefCell::new(BTreeMap::new()) };
+    /// Whether filesystems not probed yet may be, see [`allow_probing`].
+    static ALLOWED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Lets [`probe`] write its scratch directory next to targets, for the
+/// commands that write targets anyway and a plan of this machine. Until
+/// then, a filesystem not probed yet is reported as `None`.
+pub fn allow_probing() {
+    ALLOWED.set(true);
+}
+
+/// Tries each capability in a scratch directory created in `dir`.
+fn probe_in(dir: &Path) -> io::Result<Caps> {
+    let scratch = dir.join(format!(".mdot-probe-{}", std::process::id()));
+    fs::create_dir(&scratch)?;
+    let probe = || {
+        let file = scratch.join("probe");
+        fs::write(&file, "")?;
+        Ok(Caps {
+            symlinks: std::os::unix::fs::symlink(&file, scratch.join("symlink")).is_ok(),
+            hardlinks: fs::hard_link(&file, scratch.join("hardlink")).is_ok(),
+            case_sensitive: fs::symlink_metadata(scratch.join("PROBE")).is_err(),
+        })
+    };
+    let caps = probe();
+    fs::remove_dir_all(&scratch)?;
+    caps
+}
+
+/// The deepest directory of `target` that exists, with the device of its
+/// filesystem.
+fn filesystem(target: &Path) -> Option<(u64, &Path)> {
+    let dir = target.ancestors().skip(1).find(|dir| dir.is_dir())?;
+    Some((fs::metadata(dir).ok()?.dev(), dir))
+}
+
+/// What the filesystem that would hold `target` supports, probed once
+/// per filesystem in the deepest directory of `target` that exists.
+/// `None` when that directory cannot be written to, or probing is not
+/// allowed.
+pub fn probe(target: &Path) -> Option<Caps> {
+    let (dev, dir) = filesystem(target)?;
+    if let Some((_, caps)) = PROBED.with_borrow(|probed| probed.get(&dev).cloned()) {
+        return caps;
+    }
+    if !ALLOWED.get() {
+        return None;
+    }
+    let caps = probe_in(dir).ok();
+    if let Some(caps) = caps.filter(|caps| !caps.symlinks) {
+        info!(
+            "{} does not support symlinks ({}), copying targets there",
+            dir.display(),
+            caps.describe()
+        );
+    }
+    PROBED.with_borrow_mut(|probed| probed.insert(dev, (dir.to_path_buf(), caps)));
+    caps
+}
+
+/// Whether a target is copied because its filesystem has no symlinks.
+pub fn needs_copy(target: &Path) -> bool {
+    probe(target).is_some_and(|caps| !caps.symlinks)
+}
+
+/// The filesystems holding `targets`, each once as the directory probed
+/// with what it supports.
+pub fn probed<'a>(targets: impl IntoIterator<Item = &'a Path>) -> Vec<(PathBuf, Option<Caps>)> {
+    let mut found = BTreeMap::new();
+    for target in targets {
+        let Some((dev, dir)) = filesystem(target) else {
+            continue;
+        };
+        found.entry(dev).or_insert_with(|| {
+            let caps = probe(target);
+            PROBED
+                .with_borrow(|probed| probed.get(&dev).cloned())
+                .unwrap_or((dir.to_path_buf(), caps))
+        });
+    }
+    found.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fscaps::*;
+    use std::env;
+
+    #[test]
+    fn test_probe() {
+        let root = env::temp_dir().join(format!("mdot-fscaps-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        assert_eq!(probe(&root.join(".bashrc")), None);
+        assert_eq!(
+            probed([root.join(".bashrc").as_path()]),
+            [(root.clone(), None)]
+        );
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+
+        allow_probing();
+        let caps = probe(&root.join("a/b/.bashrc")).unwrap();
+        assert!(caps.symlinks && caps.hardlinks && caps.case_sensitive);
+        assert_eq!(
+            caps.describe(),
+            "symlinks=yes hardlinks=yes case_sensitive=yes"
+        );
+        assert!(!needs_copy(&root.join(".zshrc")));
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+        assert_eq!(
+            probed([root.join(".zshrc").as_path(), &root.join("a/.vimrc")]),
+            [(root.clone(), Some(caps))]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
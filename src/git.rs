@@ -0,0 +1,68 @@
+use crate::net;
+use log::info;
+use std::path::Path;
+use std::process::Command;
+
+/// Runs git inside the repo.
+pub fn git(repo: &Path, args: &[&str]) -> Result<(), String> {
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .status()
+        .map_err(|err| format!("failed to run git: {}", err))?;
+    if !status.success() {
+        return Err(format!("git {} exited with {}", args.join(" "), status));
+    }
+    Ok(())
+}
+
+/// The commit checked out in the repo.
+pub fn head(repo: &Path) -> Result<String, String> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .map_err(|err| format!("failed to run git: {}", err))?;
+    if !out.status.success() {
+        return Err(format!(
+            "no commit checked out in {}: git rev-parse exited with {}",
+            repo.display(),
+            out.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// Shallow-clones `url` into `dir`, trying again up to `retries` times.
+pub fn clone(url: &str, dir: &Path, retries: u32) -> Result<(), String> {
+    if net::offline() {
+        return Err(format!("{} is not cloned yet", url));
+    }
+    info!("cloning {}", url);
+    net::retry(&format!("cloning {}", url), retries, || {
+        let status = Command::new("git")
+            .args(["clone", "--quiet", "--depth", "1", url])
+            .arg(dir)
+            .status()
+            .map_err(|err| format!("failed to run git: {}", err))?;
+        match status.success() {
+            true => Ok(()),
+            false => Err(format!("git clone exited with {}", status)),
+        }
+    })
+}
+
+/// Fast-forwards the repo to its upstream; local changes are never merged.
+/// A failed pull is tried again up to `retries` times.
+pub fn pull(repo: &Path, retries: u32) -> Result<(), String> {
+    if !repo.join(".git").exists() {
+        return Err(format!("{} is not a git repository", repo.display()));
+    }
+    if net::skip(&format!("pulling {}", repo.display())) {
+        return Ok(());
+    }
+    info!("pulling {}", repo.display());
+    net::retry("git pull", retries, || git(repo, &["pull", "--ff-only"]))
+}
@@ -0,0 +1,204 @@
+use crate::config::Options;
+use crate::{Context, HookAction, Package, hooks};
+use log::{info, warn};
+use mlua::Value;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The Ex command `nvim_health = true` runs.
+const CHECKHEALTH: &str = "checkhealth";
+/// Where Neovim writes its health reports, in the cache dir, readable by
+/// the user alone.
+const HEALTH_DIR: &str = "health";
+
+/// Parses `nvim_health = true`, or the Ex command to run instead of
+/// `checkhealth`, e.g. `"checkhealth lazy"`.
+pub fn from_value(path: &str, value: &Value) -> Option<String> {
+    match value {
+        Value::Boolean(true) => Some(CHECKHEALTH.to_string()),
+        Value::Boolean(false) => None,
+        Value::String(command) => Some(command.to_string_lossy()),
+        v => fatal!(
+            "'{}' expected type 'Boolean' or 'String', got {}",
+            path,
+            v.type_name()
+        ),
+    }
+}
+
+/// The failures in what Neovim wrote: the `ERROR` lines of the health
+/// report, and the errors it printed while loading the config.
+fn failures(report: &str, stderr: &str) -> Vec<String> {
+    let reported = report.lines().filter_map(|line| {
+        let line = line.trim().trim_start_matches(['-', ' ']);
+        let line = line.strip_prefix('❌').unwrap_or(line).trim_start();
+        line.starts_with("ERROR").then(|| line.to_string())
+    });
+    let code = |line: &str| {
+        line.strip_prefix('E')
+            .and_then(|rest| rest.split_once(':'))
+            .is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    };
+    let printed = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.contains("Error") || code(line));
+    reported.chain(printed.map(String::from)).collect()
+}
+
+/// Runs `command` in a headless Neovim, returning its failures, or `None`
+/// when Neovim is not installed. The report is written below `cache_dir`.
+fn run(cache_dir: &Path, command: &str) -> Result<Option<Vec<String>>, String> {
+    let dir = cache_dir.join(HEALTH_DIR);
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .and_then(|_| fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)))
+        .map_err(|err| format!("failed to create {}: {}", dir.display(), err))?;
+    let report = dir.join(format!("report-{}.txt", std::process::id()));
+    let _ = fs::remove_file(&report);
+    let output = Command::new("nvim")
+        .arg("--headless")
+        .args(["-c", command])
+        .args([
+            "-c",
+            "lua vim.cmd.write({ args = { vim.env.MDOT_HEALTH_REPORT }, bang = true })",
+        ])
+        .args(["-c", "qa!"])
+        .env("MDOT_HEALTH_REPORT", &report)
+        .stdin(Stdio::null())
+        .output();
+    let output = match output {
+        Ok(output) => output,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("failed to run nvim: {}", err)),
+    };
+    let written = fs::read_to_string(&report).unwrap_or_default();
+    let _ = fs::remove_file(&report);
+    let mut found = failures(&written, &String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() && found.is_empty() {
+        found.push(format!("nvim exited with {}", output.status));
+    }
+    Ok(Some(found))
+}
+
+/// Runs the `nvim_health` checks of the `changed` packages among
+/// `packages`, then lists what they found. An Ex command other than
+/// `checkhealth` is a hook, so the allowlist of hooks applies to it.
+pub fn report(ctx: &Context, options: &Options, packages: &[Package], changed: &BTreeSet<String>) {
+    for pkg in packages.iter().filter(|pkg| changed.contains(&pkg.name)) {
+        let Some(command) = &pkg.nvim_health else {
+            continue;
+        };
+        if command != CHECKHEALTH {
+            let action = HookAction::Command(command.clone());
+            match hooks::permitted(ctx, options, pkg, "nvim_health", &action) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    warn!("[{}] nvim health: {}", pkg.name, err);
+                    continue;
+                }
+            }
+        }
+        info!("[{}] nvim health: {}", pkg.name, command);
+        match run(&ctx.cache_dir, command) {
+            Ok(None) => info!(
+                "[{}] nvim is not installed, skipping its health check",
+                pkg.name
+            ),
+            Ok(Some(found)) if found.is_empty() => info!("[{}] nvim health: ok", pkg.name),
+            Ok(Some(found)) => {
+                warn!(
+                    "[{}] nvim health found {} problem(s):",
+                    pkg.name,
+                    found.len()
+                );
+                for failure in found {
+                    warn!("  {}", failure);
+                }
+            }
+            Err(err) => warn!("[{}] nvim health: {}", pkg.name, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::*;
+    use crate::*;
+
+    #[test]
+    fn test_failures() {
+        let report = "
+==============================================================================
+vim.lsp: require(\"vim.lsp.health\").check()
+
+- OK no active clients
+- ERROR Failed to run healthcheck for \"foo\" plugin.
+- ❌ ERROR lua-language-server: not executable
+- WARNING tree-sitter CLI not found
+";
+        let stderr = "Error detected while processing /home/me/.config/nvim/init.lua:\n\
+                      E5113: Error while calling lua chunk: module 'lazy' not found\n\
+                      E492: Not an editor command: Lazy\nEverything: fine\n";
+        assert_eq!(
+            failures(report, stderr),
+            [
+                "ERROR Failed to run healthcheck for \"foo\" plugin.",
+                "ERROR lua-language-server: not executable",
+                "Error detected while processing /home/me/.config/nvim/init.lua:",
+                "E5113: Error while calling lua chunk: module 'lazy' not found",
+                "E492: Not an editor command: Lazy",
+            ]
+        );
+        assert!(failures("- OK all good\n", "").is_empty());
+    }
+
+    #[test]
+    fn test_nvim_health() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-health-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("main.lua"),
+            r#"return {
+                { "nvim", nvim_health = true },
+                { "lazy", nvim_health = "checkhealth lazy" },
+                { "vim", nvim_health = false },
+            }"#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let health: Vec<Option<&str>> = config
+            .packages
+            .iter()
+            .map(|pkg| pkg.nvim_health.as_deref())
+            .collect();
+        assert_eq!(
+            health,
+            [Some("checkhealth"), Some("checkhealth lazy"), None]
+        );
+
+        // The report is written to a directory of the user's own.
+        assert!(run(&root.join("cache"), CHECKHEALTH).is_ok());
+        let dir = root.join("cache").join(HEALTH_DIR);
+        assert_eq!(
+            fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
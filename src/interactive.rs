@@ -0,0 +1,96 @@
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How questions such as "replace this conflicting file?" are answered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Ask on the terminal.
+    Ask,
+    /// `--assume-yes`
+    Yes,
+    /// `--assume-no`, and the behaviour when nothing set a policy.
+    No,
+    /// Nobody to ask: fail the run instead.
+    Fail,
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// The policy for the flags given and whether stdin and stdout are
+/// terminals.
+pub fn detect(assume_yes: bool, assume_no: bool, terminal: bool) -> Policy {
+    match (assume_yes, assume_no, terminal) {
+        (true, _, _) => Policy::Yes,
+        (_, true, _) => Policy::No,
+        (_, _, true) => Policy::Ask,
+        _ => Policy::Fail,
+    }
+}
+
+/// Sets up the run for the flags given. Without a terminal on stdout,
+/// colors are turned off unless `CLICOLOR_FORCE` is set.
+pub fn init(assume_yes: bool, assume_no: bool) {
+    let terminal = io::stdin().is_terminal() && io::stdout().is_terminal();
+    let _ = POLICY.set(detect(assume_yes, assume_no, terminal));
+    if !io::stdout().is_terminal() && env::var_os("CLICOLOR_FORCE").is_none() {
+        colored::control::set_override(false);
+    }
+}
+
+fn policy() -> Policy {
+    POLICY.get().copied().unwrap_or(Policy::No)
+}
+
+/// Whether questions are asked on a terminal rather than answered by the
+/// policy.
+pub fn can_ask() -> bool {
+    policy() == Policy::Ask
+}
+
+/// Answers a yes/no `question` according to the policy. Errors when there
+/// is nobody to ask, and marks the run as failed.
+pub fn confirm(question: &str) -> Result<bool, String> {
+    match policy() {
+        Policy::Yes => Ok(true),
+        Policy::No => Ok(false),
+        Policy::Fail => {
+            FAILED.store(true, Ordering::Relaxed);
+            Err(format!(
+                "{} (not a terminal, run with --assume-yes or --assume-no)",
+                question
+            ))
+        }
+        Policy::Ask => {
+            eprint!("{} [y/N] ", question);
+            io::stderr().flush().map_err(|err| err.to_string())?;
+            let mut answer = String::new();
+            io::stdin()
+                .lock()
+                .read_line(&mut answer)
+                .map_err(|err| err.to_string())?;
+            Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+        }
+    }
+}
+
+/// Whether a question went unanswered for lack of a terminal.
+pub fn failed() -> bool {
+    FAILED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interactive::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(true, false, false), Policy::Yes);
+        assert_eq!(detect(false, true, true), Policy::No);
+        assert_eq!(detect(false, false, true), Policy::Ask);
+        assert_eq!(detect(false, false, false), Policy::Fail);
+        assert_eq!(confirm("replace?"), Ok(false));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::json::Json;
+use log::info;
+use std::env;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long a compositor may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// The magic string starting every message of the i3/sway protocol.
+const I3_MAGIC: &[u8] = b"i3-ipc";
+/// The i3/sway message running a command.
+const RUN_COMMAND: u32 = 0;
+
+/// A compositor reloaded over its IPC socket, `reload = { ipc = "hyprland" }`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compositor {
+    Hyprland,
+    Sway,
+}
+
+impl Compositor {
+    pub fn parse(path: &str, name: &str) -> Self {
+        match name {
+            "hyprland" => Compositor::Hyprland,
+            "sway" => Compositor::Sway,
+            name => fatal!(
+                "'{}' must be \"hyprland\" or \"sway\", got '{}'",
+                path,
+                name
+            ),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compositor::Hyprland => "hyprland",
+            Compositor::Sway => "sway",
+        }
+    }
+
+    /// The socket of the instance this session runs, if any.
+    fn socket(&self) -> Option<PathBuf> {
+        match self {
+            Compositor::Hyprland => {
+                let signature = env::var("HYPRLAND_INSTANCE_SIGNATURE").ok()?;
+                let runtime = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+                runtime
+                    .into_iter()
+                    .chain([PathBuf::from("/tmp")])
+                    .map(|dir| dir.join("hypr").join(&signature).join(".socket.sock"))
+                    .find(|socket| socket.exists())
+            }
+            Compositor::Sway => env::var_os("SWAYSOCK").map(PathBuf::from),
+        }
+    }
+}
+
+fn connect(socket: &Path) -> io::Result<UnixStream> {
+    let stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Sends `command` to Hyprland, which answers and closes the connection.
+fn hyprland_request(socket: &Path, command: &str) -> io::Result<String> {
+    let mut stream = connect(socket)?;
+    stream.write_all(command.as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// Sends a message of `kind` to sway and reads its answer.
+fn sway_request(socket: &Path, kind: u32, payload: &str) -> io::Result<String> {
+    let mut stream = connect(socket)?;
+    let mut message = I3_MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(kind.to_ne_bytes());
+    message.extend(payload.as_bytes());
+    stream.write_all(&message)?;
+    let mut header = [0; 14];
+    stream.read_exact(&mut header)?;
+    if &header[..6] != I3_MAGIC {
+        return Err(io::Error::other("not an i3-ipc reply"));
+    }
+    let len = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+    let mut reply = vec![0; len as usize];
+    stream.read_exact(&mut reply)?;
+    Ok(String::from_utf8_lossy(&reply).into_owned())
+}
+
+/// The errors Hyprland reports for `reload` and in `j/configerrors`.
+fn hyprland_errors(reload: &str, config: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    if reload.trim() != "ok" {
+        errors.push(reload.trim().to_string());
+    }
+    match Json::parse(config) {
+        Ok(Json::Array(items)) => errors.extend(
+            items
+                .iter()
+                .filter_map(Json::as_str)
+                .flat_map(str::lines)
+                .filter(|line| !line.trim().is_empty())
+                .map(String::from),
+        ),
+        _ => errors.push(format!(
+            "unexpected config errors reply '{}'",
+            config.trim()
+        )),
+    }
+    errors
+}
+
+/// The errors of the commands in a sway `RUN_COMMAND` reply.
+fn sway_errors(reply: &str) -> Vec<String> {
+    let Ok(Json::Array(results)) = Json::parse(reply) else {
+        return vec![format!("unexpected reply '{}'", reply.trim())];
+    };
+    results
+        .iter()
+        .filter(|result| result.get("success") != Some(&Json::Bool(true)))
+        .map(|result| {
+            result
+                .get("error")
+                .and_then(Json::as_str)
+                .unwrap_or("the command failed")
+                .to_string()
+        })
+        .collect()
+}
+
+/// Reloads the config of `compositor` listening on `socket`, returning
+/// the errors it reports.
+fn reload_at(compositor: Compositor, socket: &Path) -> Result<(), String> {
+    let failed = |err: io::Error| format!("{} IPC: {}", compositor.name(), err);
+    let errors = match compositor {
+        Compositor::Hyprland => {
+            let reload = hyprland_request(socket, "reload").map_err(failed)?;
+            let config = hyprland_request(socket, "j/configerrors").map_err(failed)?;
+            hyprland_errors(&reload, &config)
+        }
+        Compositor::Sway => {
+            sway_errors(&sway_request(socket, RUN_COMMAND, "reload").map_err(failed)?)
+        }
+    };
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join("; ")),
+    }
+}
+
+/// Reloads the config of `compositor` when this session runs it.
+pub fn reload(compositor: Compositor) -> Result<(), String> {
+    match compositor.socket() {
+        Some(socket) => reload_at(compositor, &socket),
+        None => {
+            info!("{} is not running", compositor.name());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ipc::*;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn test_errors() {
+        assert!(hyprland_errors("ok", r#"[""]"#).is_empty());
+        assert_eq!(
+            hyprland_errors(
+                "ok",
+                r#"["Config error in file hyprland.conf at line 3: invalid field\nsecond"]"#
+            ),
+            [
+                "Config error in file hyprland.conf at line 3: invalid field",
+                "second"
+            ]
+        );
+        assert_eq!(
+            hyprland_errors("unknown request", "[]"),
+            ["unknown request"]
+        );
+        assert!(sway_errors(r#"[{"success": true}]"#).is_empty());
+        assert_eq!(
+            sway_errors(r#"[{"success": false, "parse_error": true, "error": "bad"}]"#),
+            ["bad"]
+        );
+    }
+
+    #[test]
+    fn test_reload_at() {
+        let dir = env::temp_dir().join(format!("mdot-ipc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let hypr = dir.join("hypr.sock");
+        let listener = UnixListener::bind(&hypr).unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for reply in ["ok", r#"["error at line 2"]"#] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 64];
+                let n = stream.read(&mut request).unwrap();
+                requests.push(String::from_utf8_lossy(&request[..n]).into_owned());
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            requests
+        });
+        assert_eq!(
+            reload_at(Compositor::Hyprland, &hypr),
+            Err("error at line 2".to_string())
+        );
+        assert_eq!(server.join().unwrap(), ["reload", "j/configerrors"]);
+
+        let sway = dir.join("sway.sock");
+        let listener = UnixListener::bind(&sway).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0; 14];
+            stream.read_exact(&mut header).unwrap();
+            let len = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+            let mut payload = vec![0; len as usize];
+            stream.read_exact(&mut payload).unwrap();
+            let reply = br#"[{"success": true}]"#;
+            let mut message = I3_MAGIC.to_vec();
+            message.extend((reply.len() as u32).to_ne_bytes());
+            message.extend(RUN_COMMAND.to_ne_bytes());
+            message.extend(reply);
+            stream.write_all(&message).unwrap();
+            String::from_utf8(payload).unwrap()
+        });
+        assert_eq!(reload_at(Compositor::Sway, &sway), Ok(()));
+        assert_eq!(server.join().unwrap(), "reload");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
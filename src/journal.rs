@@ -0,0 +1,528 @@
+use crate::state::{State, escape, unescape};
+use crate::{backup, deploy};
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const JOURNAL_DIR: &str = "journal";
+/// Append-only summary of every mutating run, one line each.
+pub const AUDIT_LOG: &str = "history.log";
+const HEADER: &str = "# mdot journal v2";
+
+thread_local! {
+    /// Journal file of the run in progress, if it records one.
+    static ACTIVE: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// A filesystem change made by a run, with what is needed to revert it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Created {
+        package: String,
+        target: PathBuf,
+        source: PathBuf,
+    },
+    BackedUp {
+        package: String,
+        target: PathBuf,
+        backup: PathBuf,
+    },
+    /// A symlink to `source` was removed.
+    Removed {
+        package: String,
+        target: PathBuf,
+        source: PathBuf,
+    },
+    /// A file or directory was deleted by `overwrite`; it cannot come back.
+    Deleted { package: String, target: PathBuf },
+    /// The folded directory link `dir -> source` became a real directory.
+    Split { dir: PathBuf, source: PathBuf },
+}
+
+impl Action {
+    fn fields(&self) -> Vec<String> {
+        let p = |path: &Path| path.display().to_string();
+        match self {
+            Action::Created {
+                package,
+                target,
+                source,
+            } => vec!["created".into(), package.clone(), p(target), p(source)],
+            Action::BackedUp {
+                package,
+                target,
+                backup,
+            } => vec!["backup".into(), package.clone(), p(target), p(backup)],
+            Action::Removed {
+                package,
+                target,
+                source,
+            } => vec!["removed".into(), package.clone(), p(target), p(source)],
+            Action::Deleted { package, target } => {
+                vec!["deleted".into(), package.clone(), p(target)]
+            }
+            Action::Split { dir, source } => vec!["split".into(), p(dir), p(source)],
+        }
+    }
+
+    fn from_fields(fields: &[String]) -> Option<Action> {
+        let path = |i: usize| PathBuf::from(&fields[i]);
+        Some(match (fields[0].as_str(), fields.len()) {
+            ("created", 4) => Action::Created {
+                package: fields[1].clone(),
+                target: path(2),
+                source: path(3),
+            },
+            ("backup", 4) => Action::BackedUp {
+                package: fields[1].clone(),
+                target: path(2),
+                backup: path(3),
+            },
+            ("removed", 4) => Action::Removed {
+                package: fields[1].clone(),
+                target: path(2),
+                source: path(3),
+            },
+            ("deleted", 3) => Action::Deleted {
+                package: fields[1].clone(),
+                target: path(2),
+            },
+            ("split", 3) => Action::Split {
+                dir: path(1),
+                source: path(2),
+            },
+            _ => return None,
+        })
+    }
+
+    fn package(&self) -> Option<&str> {
+        match self {
+            Action::Created { package, .. }
+            | Action::BackedUp { package, .. }
+            | Action::Removed { package, .. }
+            | Action::Deleted { package, .. } => Some(package),
+            Action::Split { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Created {
+                package,
+                target,
+                source,
+            } => write!(
+                f,
+                "linked   [{}] {} -> {}",
+                package,
+                target.display(),
+                source.display()
+            ),
+            Action::BackedUp {
+                package,
+                target,
+                backup,
+            } => write!(
+                f,
+                "backup   [{}] {} to {}",
+                package,
+                target.display(),
+                backup.display()
+            ),
+            Action::Removed {
+                package,
+                target,
+                source,
+            } => write!(
+                f,
+                "unlinked [{}] {} -> {}",
+                package,
+                target.display(),
+                source.display()
+            ),
+            Action::Deleted { package, target } => {
+                write!(f, "deleted  [{}] {}", package, target.display())
+            }
+            Action::Split { dir, source } => write!(
+                f,
+                "split    {} (was -> {})",
+                dir.display(),
+                source.display()
+            ),
+        }
+    }
+}
+
+/// One recorded run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    pub id: u64,
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub command: String,
+    pub user: String,
+    pub actions: Vec<Action>,
+    /// `None` while running, or when the run was killed.
+    pub ok: Option<bool>,
+    pub undone: bool,
+}
+
+impl Run {
+    /// Names of the packages whose files the run changed.
+    pub fn packages(&self) -> BTreeSet<&str> {
+        self.actions.iter().filter_map(Action::package).collect()
+    }
+
+    pub fn result(&self) -> &'static str {
+        match self.ok {
+            Some(true) => "ok",
+            Some(false) => "failed",
+            None => "unfinished",
+        }
+    }
+}
+
+fn dir(state_dir: &Path) -> PathBuf {
+    state_dir.join(JOURNAL_DIR)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Who ran mdot, looking through sudo.
+fn user() -> String {
+    env::var("SUDO_USER")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn append(path: &Path, fields: &[String]) -> io::Result<()> {
+    let line: Vec<String> = fields.iter().map(|f| escape(f)).collect();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line.join("\t"))
+}
+
+/// Starts journaling a run of `command`; returns its id.
+pub fn begin(state_dir: &Path, command: &str) -> io::Result<u64> {
+    let dir = dir(state_dir);
+    fs::create_dir_all(&dir)?;
+    let id = ids(&dir).last().map_or(1, |id| id + 1);
+    let path = dir.join(id.to_string());
+    fs::write(&path, format!("{}\n", HEADER))?;
+    append(
+        &path,
+        &["run".into(), now().to_string(), user(), command.into()],
+    )?;
+    ACTIVE.set(Some(path));
+    Ok(id)
+}
+
+/// Stops journaling, recording whether the run succeeded, and adds the run
+/// to the audit log next to the journal.
+pub fn finish(ok: bool) {
+    let Some(path) = ACTIVE.take() else {
+        return;
+    };
+    let result = if ok { "ok" } else { "failed" };
+    if let Err(err) = append(&path, &["end".into(), now().to_string(), result.into()]) {
+        warn!("failed to write journal {}: {}", path.display(), err);
+    }
+    let (Some(dir), Some(id)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let Ok(run) = load(&path, id.to_string_lossy().parse().unwrap_or_default()) else {
+        return;
+    };
+    let packages: Vec<&str> = run.packages().into_iter().collect();
+    let log = dir.with_file_name(AUDIT_LOG);
+    let fields = [
+        format_time(run.time),
+        run.id.to_string(),
+        run.user.clone(),
+        result.to_string(),
+        packages.join(","),
+        run.command.clone(),
+    ];
+    if let Err(err) = append(&log, &fields) {
+        warn!("failed to write {}: {}", log.display(), err);
+    }
+}
+
+/// Records an action of the run in progress, if any.
+pub fn record(action: Action) {
+    ACTIVE.with_borrow(|path| {
+        if let Some(path) = path
+            && let Err(err) = append(path, &action.fields())
+        {
+            warn!("failed to write journal {}: {}", path.display(), err);
+        }
+    });
+}
+
+fn ids(dir: &Path) -> Vec<u64> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    ids.sort();
+    ids
+}
+
+fn load(path: &Path, id: u64) -> io::Result<Run> {
+    let mut run = Run {
+        id,
+        time: 0,
+        command: String::new(),
+        user: String::new(),
+        actions: Vec::new(),
+        ok: None,
+        undone: false,
+    };
+    for line in fs::read_to_string(path)?.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        match fields[0].as_str() {
+            "run" if fields.len() == 4 => {
+                run.time = fields[1].parse().unwrap_or_default();
+                run.user = fields[2].clone();
+                run.command = fields[3].clone();
+            }
+            // Journals before v2 did not record the user.
+            "run" if fields.len() == 3 => {
+                run.time = fields[1].parse().unwrap_or_default();
+                run.user = "unknown".to_string();
+                run.command = fields[2].clone();
+            }
+            "end" if fields.len() == 3 => run.ok = Some(fields[2] == "ok"),
+            "undone" => run.undone = true,
+            _ => match Action::from_fields(&fields) {
+                Some(action) => run.actions.push(action),
+                None => warn!("{}: ignoring invalid record '{}'", path.display(), line),
+            },
+        }
+    }
+    Ok(run)
+}
+
+/// The run with this id.
+pub fn run(state_dir: &Path, id: u64) -> io::Result<Run> {
+    load(&dir(state_dir).join(id.to_string()), id)
+}
+
+/// Every recorded run, oldest first.
+pub fn runs(state_dir: &Path) -> io::Result<Vec<Run>> {
+    let dir = dir(state_dir);
+    ids(&dir)
+        .into_iter()
+        .map(|id| load(&dir.join(id.to_string()), id))
+        .collect()
+}
+
+fn is_link_to(path: &Path, source: &Path) -> bool {
+    fs::read_link(path).is_ok_and(|l| l == source)
+}
+
+fn revert(action: &Action, state: &mut State) -> io::Result<()> {
+    match action {
+        Action::Created {
+            package,
+            target,
+            source,
+        } => {
+            if !deploy::is_deployed(target, source) {
+                warn!("{} changed since, leaving it alone", target.display());
+                return Ok(());
+            }
+            fs::remove_file(target)?;
+            if let Some(pkg) = state.packages.get_mut(package) {
+                pkg.links.remove(target);
+            }
+            info!("[{}] removed {}", package, target.display());
+        }
+        Action::BackedUp {
+            package,
+            target,
+            backup,
+        } => {
+            if fs::symlink_metadata(target).is_ok() {
+                warn!(
+                    "{} is in the way of restoring {}",
+                    target.display(),
+                    backup.display()
+                );
+                return Ok(());
+            }
+            backup::restore(backup, target)?;
+            info!("[{}] restored {}", package, target.display());
+        }
+        Action::Removed {
+            package,
+            target,
+            source,
+        } => {
+            if !source.exists() || fs::symlink_metadata(target).is_ok() {
+                warn!("cannot re-create {}", target.display());
+                return Ok(());
+            }
+            std::os::unix::fs::symlink(source, target)?;
+            state
+                .packages
+                .entry(package.clone())
+                .or_default()
+                .links
+                .insert(target.clone(), source.clone());
+            info!("[{}] re-created {}", package, target.display());
+        }
+        Action::Deleted { package, target } => {
+            warn!(
+                "[{}] {} was overwritten and cannot be restored",
+                package,
+                target.display()
+            );
+        }
+        Action::Split { dir, source } => {
+            let folded_back = fs::read_dir(dir)?.all(|entry| {
+                entry.is_ok_and(|e| is_link_to(&e.path(), &source.join(e.file_name())))
+            });
+            if !folded_back {
+                warn!("{} holds other files now, keeping it split", dir.display());
+                return Ok(());
+            }
+            fs::remove_dir_all(dir)?;
+            std::os::unix::fs::symlink(source, dir)?;
+            info!("folded {} again", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// Reverts the most recent run that changed something and is not undone
+/// yet, newest action first, and marks it undone. Returns the id of the
+/// reverted run.
+pub fn undo(state_dir: &Path, state: &mut State) -> Result<Option<u64>, String> {
+    let runs = runs(state_dir).map_err(|err| err.to_string())?;
+    let Some(run) = runs
+        .iter()
+        .rev()
+        .find(|run| !run.undone && !run.actions.is_empty())
+    else {
+        return Ok(None);
+    };
+    for action in run.actions.iter().rev() {
+        if let Err(err) = revert(action, state) {
+            warn!("failed to revert {:?}: {}", action, err);
+        }
+    }
+    let path = dir(state_dir).join(run.id.to_string());
+    append(&path, &["undone".into(), now().to_string()]).map_err(|err| err.to_string())?;
+    Ok(Some(run.id))
+}
+
+/// Formats Unix seconds as a UTC `YYYY-MM-DD HH:MM:SS` timestamp.
+pub fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_undo_deploy() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-undo-{}", std::process::id()));
+        let (repo, home) = (root.join("repo"), root.join("home"));
+        fs::create_dir_all(repo.join("bash")).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        fs::write(repo.join("bash/.bashrc"), "").unwrap();
+        fs::write(home.join(".bashrc"), "old").unwrap();
+        fs::write(
+            repo.join("main.lua"),
+            format!(
+                r#"return {{ {{ "bash", links = {{ {{ source = ".bashrc", targets = "{}", backup = true }} }} }} }}"#,
+                home.join(".bashrc").display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .build();
+        let config = config::load(&ctx);
+        let selection = select::select(&config, &[], None, &cli::Filter::default());
+
+        assert_eq!(journal::begin(&ctx.state_dir, "deploy").unwrap(), 1);
+        deploy::apply(
+            &ctx,
+            &config,
+            &selection,
+            false,
+            &progress::Progress::default(),
+        );
+        journal::finish(true);
+        assert!(home.join(".bashrc").is_symlink());
+
+        let mut state = state::State::load(&state::State::path(&ctx)).unwrap();
+        assert_eq!(journal::undo(&ctx.state_dir, &mut state), Ok(Some(1)));
+        assert_eq!(fs::read_to_string(home.join(".bashrc")).unwrap(), "old");
+        assert!(state.packages["bash"].links.is_empty());
+        assert_eq!(journal::undo(&ctx.state_dir, &mut state), Ok(None));
+
+        let runs = journal::runs(&ctx.state_dir).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].undone);
+        assert_eq!(runs[0].actions.len(), 2);
+        assert_eq!(runs[0].ok, Some(true));
+        assert_eq!(runs[0].packages().into_iter().collect::<Vec<_>>(), ["bash"]);
+        let log = fs::read_to_string(ctx.state_dir.join(journal::AUDIT_LOG)).unwrap();
+        assert!(log.contains("\t1\t") && log.ends_with("\tok\tbash\tdeploy\n"));
+        assert_eq!(journal::format_time(951782400), "2000-02-29 00:00:00");
+
+        let v1 = ctx.state_dir.join(journal::JOURNAL_DIR).join("2");
+        fs::write(&v1, "# mdot journal v1\nrun\t951782400\tdeploy\n").unwrap();
+        let run = journal::run(&ctx.state_dir, 2).unwrap();
+        assert_eq!(
+            (run.user.as_str(), run.command.as_str()),
+            ("unknown", "deploy")
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use crate::deploy::expand_tilde;
+use crate::{Context, Package, lua_value_to_str, ordered_pairs};
+use log::info;
+use mlua::Value;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// `ssh_keygen = { type = "ed25519", comment = "me@host", path = "~/.ssh/id_ed25519" }`,
+/// or `ssh_keygen = true` for an ed25519 key at the default path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshKey {
+    pub kind: String,
+    pub comment: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+impl SshKey {
+    pub fn from_value(value: &Value) -> Option<SshKey> {
+        let mut key = SshKey {
+            kind: "ed25519".to_string(),
+            comment: None,
+            path: None,
+        };
+        match value {
+            Value::Boolean(false) => return None,
+            Value::Boolean(true) => (),
+            Value::Table(tbl) => {
+                for (k, v) in ordered_pairs(tbl, "ssh_keygen") {
+                    match lua_value_to_str(&k).as_str() {
+                        "type" => key.kind = lua_value_to_str(&v),
+                        "comment" => key.comment = Some(lua_value_to_str(&v)),
+                        "path" => key.path = Some(PathBuf::from(lua_value_to_str(&v))),
+                        k => fatal!("unknown 'ssh_keygen' key '{}'", k),
+                    }
+                }
+            }
+            v => fatal!(
+                "'ssh_keygen' expected type 'Boolean' or 'Table', got {}",
+                v.type_name()
+            ),
+        }
+        Some(key)
+    }
+
+    /// The private key file, `~/.ssh/id_<type>` unless `path` is set.
+    pub fn path(&self) -> PathBuf {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => PathBuf::from(format!("~/.ssh/id_{}", self.kind)),
+        };
+        expand_tilde(&path)
+    }
+}
+
+fn output(program: &str, args: &[&str]) -> Result<String, String> {
+    let out = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| format!("failed to run {}: {}", program, err))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+fn ssh_fingerprint(path: &Path) -> Option<String> {
+    let public = format!("{}.pub", path.display());
+    let out = output("ssh-keygen", &["-l", "-f", &public]).ok()?;
+    out.split_whitespace().nth(1).map(String::from)
+}
+
+/// Fingerprints of the primary keys in an armored or binary key file.
+fn gpg_fingerprints(file: &Path) -> Result<Vec<String>, String> {
+    let out = output(
+        "gpg",
+        &["--show-keys", "--with-colons", &file.to_string_lossy()],
+    )?;
+    let mut fingerprints = Vec::new();
+    let mut primary = false;
+    for line in out.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[0] {
+            "pub" | "sec" => primary = true,
+            "fpr" if primary => {
+                fingerprints.extend(fields.get(9).map(|f| f.to_string()));
+                primary = false;
+            }
+            _ => (),
+        }
+    }
+    Ok(fingerprints)
+}
+
+fn gpg_has(fingerprint: &str) -> bool {
+    output("gpg", &["--list-keys", "--with-colons", fingerprint]).is_ok()
+}
+
+/// A key a package provisions, and whether it is in place.
+#[derive(Debug, PartialEq)]
+pub struct KeyStatus {
+    pub package: String,
+    pub description: String,
+    pub present: bool,
+    pub fingerprint: Option<String>,
+}
+
+fn gpg_files(ctx: &Context, pkg: &Package) -> Vec<PathBuf> {
+    let dir = ctx.package_dir(pkg);
+    pkg.gpg_import.iter().map(|file| dir.join(file)).collect()
+}
+
+/// The keys of `pkg`, with their fingerprints when present.
+pub fn status(ctx: &Context, pkg: &Package) -> Vec<KeyStatus> {
+    let mut out = Vec::new();
+    if let Some(key) = &pkg.ssh_keygen {
+        let path = key.path();
+        let fingerprint = ssh_fingerprint(&path);
+        out.push(KeyStatus {
+            package: pkg.name.clone(),
+            description: format!("ssh key {}", path.display()),
+            present: path.exists(),
+            fingerprint,
+        });
+    }
+    for file in gpg_files(ctx, pkg) {
+        let fingerprints = gpg_fingerprints(&file).unwrap_or_default();
+        out.push(KeyStatus {
+            package: pkg.name.clone(),
+            description: format!("gpg key {}", file.display()),
+            present: !fingerprints.is_empty() && fingerprints.iter().all(|f| gpg_has(f)),
+            fingerprint: (!fingerprints.is_empty()).then(|| fingerprints.join(",")),
+        });
+    }
+    out
+}
+
+/// Generates the ssh key and imports the gpg keys of `pkg` that are
+/// missing. Existing keys are never touched.
+pub fn provision(ctx: &Context, pkg: &Package) -> Result<(), String> {
+    if let Some(key) = &pkg.ssh_keygen {
+        let path = key.path();
+        if !path.exists() {
+            if let Some(dir) = path.parent()
+                && !dir.exists()
+            {
+                fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+                fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+                    .map_err(|err| err.to_string())?;
+            }
+            let comment = key.comment.clone().unwrap_or_default();
+            let path_arg = path.to_string_lossy();
+            output(
+                "ssh-keygen",
+                &[
+                    "-q", "-t", &key.kind, "-N", "", "-C", &comment, "-f", &path_arg,
+                ],
+            )?;
+            let fingerprint = ssh_fingerprint(&path).unwrap_or_default();
+            info!(
+                "[{}] generated ssh key {} {}",
+                pkg.name,
+                path.display(),
+                fingerprint
+            );
+        }
+    }
+    for file in gpg_files(ctx, pkg) {
+        let fingerprints = gpg_fingerprints(&file)?;
+        if fingerprints.is_empty() || !fingerprints.iter().all(|f| gpg_has(f)) {
+            output("gpg", &["--batch", "--import", &file.to_string_lossy()])?;
+            info!(
+                "[{}] imported gpg key {} {}",
+                pkg.name,
+                file.display(),
+                fingerprints.join(",")
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_ssh_keygen() {
+        let _ = setup_logger();
+        if check::find_bin("ssh-keygen").is_none() {
+            return;
+        }
+        let root = env::temp_dir().join(format!("mdot-keys-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            format!(
+                r#"return {{ {{ "ssh", ssh_keygen = {{ comment = "me@box", path = "{}" }} }} }}"#,
+                root.join("keys/id").display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let pkg = &config.packages[0];
+        assert_eq!(pkg.ssh_keygen.as_ref().unwrap().kind, "ed25519");
+        assert!(!keys::status(&ctx, pkg)[0].present);
+
+        keys::provision(&ctx, pkg).unwrap();
+        let status = keys::status(&ctx, pkg);
+        assert!(status[0].present);
+        let fingerprint = status[0].fingerprint.clone().unwrap();
+        assert!(fingerprint.starts_with("SHA256:"));
+        let public = fs::read_to_string(root.join("keys/id.pub")).unwrap();
+        assert!(public.trim_end().ends_with("me@box"));
+
+        keys::provision(&ctx, pkg).unwrap();
+        assert_eq!(keys::status(&ctx, pkg)[0].fingerprint, Some(fingerprint));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,2291 @@
+use clap::Parser;
+use colored::*; // 1. Import the Colorize trait
+use log::{error, info, warn};
+use mlua::{Function, Lua, Result as LuaResult, Table, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+// alias Command string
+// alias HookAction Command | fun() | (Command | fun())[] | { [integer]: Command, sandbox: boolean }
+//
+// alias OSPackageName string | table<string, string | table<string, string>>
+// alias PathString string
+// alias ReloadSpec Command | { signal: string, process: string } | { ipc: "hyprland" | "sway" }
+// alias TargetList PathString | PathString[]
+//
+// class LinkObject
+// field source? PathString
+// field targets? TargetList
+// field targets_dir? PathString
+// field compose? "concat" | "fragments"
+// field comment? string
+// field overwrite? boolean
+// field backup? boolean
+// field enabled? boolean | fun(): boolean
+//
+// alias LinkEntrySpec LinkObject | PathString | table<PathString, TargetList>
+// alias LinksArraySpec LinkEntrySpec[]
+//
+// class SshHost
+// field host? string
+// field hostname? string
+// field user? string
+// field port? integer
+// field identity? PathString | { command: Command }
+// field jump? string
+// field options? table<string, string>
+//
+// class FetchObject
+// field url string
+// field sha256? string
+// field sha512? string
+// field minisign? string
+// field gpg? PathString
+// field signature? string
+// field executable? boolean
+// field retries? integer
+//
+// class PackageSchema
+// field name? string
+// field description? string
+// field docs? PathString
+// field package_name? OSPackageName
+// field enabled? boolean | fun(): boolean
+// field depends? PackageList
+// field wants? string | string[]
+// field requires_bin? string | (string | table<string, string>)[]
+// field tags? string | string[]
+// field renamed_from? string | string[]
+// field links? LinksArraySpec
+// field excludes? TargetList
+// field templates? PathString | (PathString | { [1]: PathString, lenient?: boolean, delims?: string[] })[]
+// field template_delims? string[]
+// field template_engine? "builtin" | "lua"
+// field default_target? PathString
+// field root? boolean
+// field priority? integer
+// field retries? integer
+// field ssh_keygen? boolean | { type?: string, comment?: string, path?: PathString }
+// field gpg_import? TargetList
+// field defaults? table<string, table<string, boolean | number | string>>
+// field fonts? TargetList
+// field assets? table<PathString, TargetList> | { apply?: string }
+// field fetch? table<PathString, string | FetchObject>
+// field env? table<string, string>
+// field aliases? table<string, string>
+// field path? PathString | (PathString | { [1]: PathString, append?: boolean })[]
+// field settings? table<string, table<string, boolean | number | string>>
+// field ssh_hosts? table<string, SshHost> | SshHost[]
+// field on_install? HookAction
+// field on_deploy? HookAction
+// field reload? ReloadSpec | ReloadSpec[]
+// field tmux_reload? boolean | PathString
+// field nvim_health? boolean | string
+// field actions? table<string, any>
+// field vars? table<string, any>
+//
+// alias PackageItemSpec string | PackageSchema
+// alias PackageList PackageItemSpec[]
+//
+// class ConfigSchema : PackageList
+// field schema? integer
+// field extends? string
+
+const APP_NAME: &str = "mdot";
+
+macro_rules! fatal {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        if $crate::catching_fatal() {
+            std::panic::resume_unwind(Box::new($crate::Fatal(message)));
+        }
+        log::error!("{}", message);
+        $crate::journal::finish(false);
+        std::process::exit(1);
+    }};
+}
+
+thread_local! {
+    /// Whether [`catch_fatal`] is running on this thread.
+    static CATCH_FATAL: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// The message of a `fatal!` error unwinding out of [`catch_fatal`].
+struct Fatal(String);
+
+fn catching_fatal() -> bool {
+    CATCH_FATAL.get()
+}
+
+/// Runs `f`, turning a `fatal!` error in it into `Err` with its message
+/// instead of exiting, for commands that report a broken config along
+/// with other checks.
+fn catch_fatal<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    let catching = CATCH_FATAL.replace(true);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    CATCH_FATAL.set(catching);
+    result.map_err(|payload| match payload.downcast::<Fatal>() {
+        Ok(fatal) => fatal.0,
+        Err(payload) => std::panic::resume_unwind(payload),
+    })
+}
+
+mod api;
+mod assets;
+mod attrs;
+mod backup;
+mod base;
+mod bundle;
+mod check;
+mod ci;
+mod cli;
+mod compose;
+mod config;
+mod daemon;
+mod deploy;
+mod devcontainer;
+mod edit;
+mod events;
+mod exports;
+mod facts;
+mod fetch;
+mod fonts;
+mod fscaps;
+mod git;
+mod health;
+mod hooks;
+mod hosts;
+mod info;
+mod install;
+mod interactive;
+mod ipc;
+mod journal;
+mod json;
+mod keys;
+mod limits;
+mod lint;
+mod lock;
+mod macos;
+mod man;
+mod markdown;
+mod net;
+mod nix;
+mod plan;
+mod platform;
+mod plugins;
+mod progress;
+mod query;
+mod reload;
+mod remote;
+mod render;
+mod schedule;
+mod schema;
+mod select;
+mod sensitive;
+mod settings;
+mod shell;
+mod ssh;
+mod state;
+mod store;
+mod template;
+mod tmux;
+mod walk;
+mod xdg;
+
+pub use limits::Limits;
+pub use platform::Platform;
+pub use plugins::ActionProvider;
+
+fn lua_value_to_str(val: &Value) -> String {
+    match val {
+        Value::String(_) => val
+            .to_string()
+            .map_err(|_| fatal!("Field contains invalid UTF-8 bytes"))
+            .unwrap()
+            .to_string(),
+        _ => fatal!("expected type 'String', got {:#?}", val),
+    }
+}
+
+/// The entries of `tbl` as config code sees them, read through its
+/// metatable: a `__pairs` metamethod lists them when set, otherwise the
+/// entries of an `__index` table, and of its own `__index` table and so
+/// on, show up below the table's own. Functions and `__`-prefixed keys of
+/// an `__index` table are left out, as a class-style table keeps its
+/// methods and metamethods there. Keys answered by an `__index` function
+/// cannot be listed, only looked up with [`field`].
+fn entries(tbl: &Table) -> LuaResult<Vec<(Value, Value)>> {
+    let mut out: Vec<(Value, Value)> = Vec::new();
+    let mut visited = Vec::new();
+    let mut next_tbl = Some(tbl.clone());
+    while let Some(tbl) = next_tbl.take() {
+        if visited.contains(&tbl.to_pointer()) {
+            break;
+        }
+        visited.push(tbl.to_pointer());
+        let meta = tbl.metatable();
+        let mut own = Vec::new();
+        match meta
+            .as_ref()
+            .map(|meta| meta.raw_get("__pairs"))
+            .transpose()?
+        {
+            Some(Value::Function(pairs)) => {
+                let (next, state, mut key): (Function, Value, Value) = pairs.call(&tbl)?;
+                loop {
+                    let (k, v): (Value, Value) = next.call((&state, key))?;
+                    if k.is_nil() {
+                        break;
+                    }
+                    key = k.clone();
+                    own.push((k, v));
+                }
+            }
+            _ => {
+                own = tbl.pairs::<Value, Value>().collect::<LuaResult<_>>()?;
+                if let Some(Value::Table(index)) =
+                    meta.map(|meta| meta.raw_get("__index")).transpose()?
+                {
+                    next_tbl = Some(index);
+                }
+            }
+        }
+        if visited.len() == 1 {
+            out = own;
+        } else {
+            own.retain(|(key, value)| {
+                let meta_key = key
+                    .as_string()
+                    .is_some_and(|key| key.as_bytes().starts_with(b"__"));
+                !value.is_function() && !meta_key && !out.iter().any(|(k, _)| k == key)
+            });
+            out.extend(own);
+        }
+    }
+    Ok(out)
+}
+
+/// The pairs of `tbl` in a stable order: the sequence entries first, by
+/// index, then the other keys sorted. `pairs` alone visits hash keys in
+/// an order that changes from run to run. Metatables are read through as
+/// [`entries`] describes.
+///
+/// `path` names the table in the error when it cannot be read.
+fn ordered_pairs(tbl: &Table, path: &str) -> Vec<(Value, Value)> {
+    let (mut sequence, mut named): (Vec<_>, Vec<_>) = entries(tbl)
+        .unwrap_or_else(|err| fatal!("failed to read '{}': {}", path, err))
+        .into_iter()
+        .partition(|(key, _)| key.is_integer());
+    sequence.sort_by_key(|(key, _)| key.as_integer());
+    named.sort_by_cached_key(|(key, _)| key.to_string().unwrap_or_default());
+    sequence.extend(named);
+    sequence
+}
+
+/// The sequence entries of `tbl`, the table at `path`, up to the first
+/// nil. Like `ipairs`, this goes through `__index`.
+fn sequence(tbl: &Table, path: &str) -> Vec<Value> {
+    let mut values = Vec::new();
+    for i in 1.. {
+        match tbl.get::<Value>(i) {
+            Ok(Value::Nil) => break,
+            Ok(value) => values.push(value),
+            Err(err) => fatal!("failed to read '{}[{}]': {}", path, i, err),
+        }
+    }
+    values
+}
+
+/// `tbl[key]`, where `tbl` is the table at `path`, through `__index`.
+fn field(tbl: &Table, path: &str, key: &str) -> Value {
+    tbl.get(key)
+        .unwrap_or_else(|err| fatal!("failed to read '{}.{}': {}", path, key, err))
+}
+
+fn lua_str_to_str(val: &mlua::String) -> String {
+    val.to_str()
+        .map_err(|_| fatal!("Field contains invalid UTF-8 bytes"))
+        .unwrap()
+        .to_string()
+}
+
+type OSPackage = BTreeMap<String, String>;
+#[derive(Debug, PartialEq, Clone)]
+enum OSPackageName {
+    AsPackage(bool),
+    Name(String),
+    Package(OSPackage),
+}
+
+impl OSPackageName {
+    fn from_value(path: &str, value: &Value) -> Self {
+        match value {
+            Value::Boolean(b) => OSPackageName::AsPackage(*b),
+            Value::String(name) => OSPackageName::Name(lua_str_to_str(name)),
+            Value::Table(tbl) => {
+                let mut map = OSPackage::new();
+                for pair in ordered_pairs(tbl, path) {
+                    match pair {
+                        (Value::String(key), Value::String(name)) => {
+                            map.insert(lua_str_to_str(&key), lua_str_to_str(&name));
+                        }
+                        // `windows = { winget = "..", scoop = ".." }` names the
+                        // package per package manager.
+                        (Value::String(os), Value::Table(managers)) => {
+                            let path = format!("{}.{}", path, lua_str_to_str(&os));
+                            for pair in ordered_pairs(&managers, &path) {
+                                match pair {
+                                    (Value::String(manager), Value::String(name)) => {
+                                        map.insert(lua_str_to_str(&manager), lua_str_to_str(&name));
+                                    }
+                                    (k, v) => {
+                                        fatal!("invalid 'package_name' entry: [{:?}] = {:?}", k, v)
+                                    }
+                                }
+                            }
+                        }
+                        (k, v) => fatal!("invalid 'package_name' entry: [{:?}] = {:?}", k, v),
+                    }
+                }
+                OSPackageName::Package(map)
+            }
+            v => fatal!(
+                "'package_name' expected type 'Boolean', 'String' or 'Table', got {:?}",
+                v
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Enabled {
+    Enable(bool),
+    Hook(Function),
+}
+
+impl Default for Enabled {
+    fn default() -> Self {
+        Enabled::Enable(true)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum HookAction {
+    Command(String),
+    Function(Function),
+    /// A command run in a sandbox, from a hook list with `sandbox = true`.
+    Sandboxed(String),
+    /// An action registered by a plugin with `mdot.register_action`, and
+    /// the value the package gives it.
+    Action {
+        name: String,
+        args: Value,
+    },
+}
+
+impl HookAction {
+    fn from_value(key: &str, value: &Value) -> Vec<HookAction> {
+        match value {
+            Value::String(cmd) => vec![HookAction::Command(lua_str_to_str(cmd))],
+            Value::Function(hook) => vec![HookAction::Function(hook.clone())],
+            Value::Table(actions) => {
+                let sandbox = match field(actions, key, "sandbox") {
+                    Value::Nil => false,
+                    Value::Boolean(sandbox) => sandbox,
+                    v => fatal!("'{}.sandbox' expected type 'Boolean', got {:?}", key, v),
+                };
+                sequence(actions, key)
+                    .into_iter()
+                    .map(|v| match v {
+                        Value::String(cmd) if sandbox => {
+                            HookAction::Sandboxed(lua_str_to_str(&cmd))
+                        }
+                        Value::String(cmd) => HookAction::Command(lua_str_to_str(&cmd)),
+                        Value::Function(_) if sandbox => {
+                            fatal!(
+                                "'{}' functions run inside mdot and cannot be sandboxed",
+                                key
+                            )
+                        }
+                        Value::Function(hook) => HookAction::Function(hook),
+                        v => fatal!(
+                            "'{}' entries expected type 'String' or 'Function', got {:?}",
+                            key,
+                            v
+                        ),
+                    })
+                    .collect()
+            }
+            v => fatal!(
+                "'{}' expected type 'String', 'Function' or 'Table', got {:?}",
+                key,
+                v
+            ),
+        }
+    }
+
+    /// The `actions = { [name] = args }` of a package.
+    fn actions_from_value(path: &str, value: &Value) -> Vec<HookAction> {
+        let Value::Table(actions) = value else {
+            fatal!("'{}' expected type 'Table', got {:?}", path, value);
+        };
+        ordered_pairs(actions, path)
+            .into_iter()
+            .map(|pair| match pair {
+                (Value::String(name), args) => HookAction::Action {
+                    name: lua_str_to_str(&name),
+                    args,
+                },
+                (k, _) => fatal!("'{}' keys expected type 'String', got {:?}", path, k),
+            })
+            .collect()
+    }
+
+    /// A stable description of the hook, used to notice when it changes
+    /// between runs. Functions are described by where they are defined and
+    /// a hash of their bytecode.
+    fn describe(&self) -> String {
+        match self {
+            HookAction::Command(cmd) => cmd.clone(),
+            HookAction::Sandboxed(cmd) => format!("{} (sandboxed)", cmd),
+            HookAction::Action { name, .. } => format!("{} (plugin action)", name),
+            HookAction::Function(hook) => {
+                let info = hook.info();
+                format!(
+                    "function {}:{} #{}",
+                    info.short_src.unwrap_or_default(),
+                    info.line_defined.unwrap_or_default(),
+                    fetch::sha256(hook.dump(true))
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct LinkObject {
+    source: PathBuf,
+    targets: Vec<PathBuf>,
+    /// Links every source match into this directory by file name.
+    targets_dir: Option<PathBuf>,
+    /// Assembles the target from this and other packages' fragments
+    /// instead of linking it.
+    compose: Option<compose::Compose>,
+    /// Comment prefix for the markers of `compose = "fragments"`.
+    comment: Option<String>,
+    overwrite: bool,
+    backup: bool,
+}
+
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct Package {
+    name: String,
+    description: Option<String>,
+    /// README in the package directory, shown by `mdot info`.
+    docs: Option<PathBuf>,
+    package_name: Option<OSPackageName>,
+    // enabled: bool,
+    enabled: Enabled,
+    depends: Vec<Package>,
+    wants: Vec<String>,
+    /// Binaries that must be on `PATH` once installed, with the package
+    /// providing them when it is given as `{ rg = "ripgrep" }`.
+    requires_bin: Vec<(String, Option<String>)>,
+    links: Vec<LinkObject>,
+    excludes: Vec<PathBuf>,
+    /// Files rendered by the deploy and copied into their targets.
+    templates: Vec<render::TemplateFile>,
+    /// Placeholder delimiters of the package's templates and fragments,
+    /// instead of `{{` and `}}`.
+    template_delims: Option<(String, String)>,
+    /// Engine of the package's templates and fragments, instead of
+    /// `mdot.options.template_engine`.
+    template_engine: Option<template::Engine>,
+    default_target: Option<PathBuf>,
+    /// Set on packages that link system files outside `$HOME`.
+    root: bool,
+    /// Wins targets also claimed by packages with a lower priority.
+    priority: i64,
+    /// Checkout of the `extends` base repo the package comes from.
+    base: Option<PathBuf>,
+    /// Times a failed install of the OS package is tried again, instead
+    /// of `mdot.options.retries`.
+    retries: Option<u32>,
+    vars: template::Vars,
+    tags: Vec<String>,
+    /// Former names whose recorded links this package takes over.
+    renamed_from: Vec<String>,
+    /// Ssh key generated on deploy when it does not exist yet.
+    ssh_keygen: Option<keys::SshKey>,
+    /// Key files in the package directory imported into gpg when missing.
+    gpg_import: Vec<PathBuf>,
+    /// macOS preferences written with `defaults write` when they differ.
+    defaults: Vec<macos::Setting>,
+    /// Desktop settings applied with gsettings or dconf, or `defaults` on
+    /// macOS, keyed by schema.
+    settings: Vec<macos::Setting>,
+    /// Font files, or globs of them, installed into the user font dir.
+    fonts: Vec<PathBuf>,
+    /// Environment variables written to the generated `env.sh`/`env.fish`.
+    env: Vec<(String, String)>,
+    /// Shell aliases written to the generated `aliases.sh`/`aliases.fish`.
+    aliases: Vec<(String, String)>,
+    /// Directories added to `PATH` in the generated snippets.
+    path: Vec<exports::PathEntry>,
+    /// Binary files copied instead of linked.
+    assets: Option<assets::Assets>,
+    /// Files downloaded into place, keyed by target.
+    fetch: Vec<fetch::Fetch>,
+    /// `Host` entries written to `~/.ssh/config.d/mdot.conf`.
+    ssh_hosts: Vec<ssh::SshHost>,
+    on_install: Vec<HookAction>,
+    on_deploy: Vec<HookAction>,
+    /// Reloads programs once a deploy changed the package's targets,
+    /// from `reload` and `tmux_reload`.
+    reload: Vec<reload::Reload>,
+    /// Ex command checking the Neovim config once a deploy changed it.
+    nvim_health: Option<String>,
+    /// Plugin actions, run between `on_install` and `on_deploy`.
+    actions: Vec<HookAction>,
+}
+
+impl Package {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The OS package installing this package on `platform`, if any.
+    ///
+    /// Tables are searched by distro ID, distro family, OS and finally a
+    /// `default` key, so a `debian` entry also applies on Ubuntu.
+    fn os_package(&self, platform: &platform::Platform) -> Option<String> {
+        match &self.package_name {
+            None | Some(OSPackageName::AsPackage(true)) => Some(self.name.clone()),
+            Some(OSPackageName::AsPackage(false)) => None,
+            Some(OSPackageName::Name(name)) => Some(name.clone()),
+            Some(OSPackageName::Package(map)) => platform
+                .package_keys()
+                .into_iter()
+                .find_map(|key| map.get(key).cloned()),
+        }
+    }
+
+    fn has_name(tbl: &Table) -> bool {
+        tbl.get::<String>(1).is_ok() || tbl.get::<String>("name").is_ok()
+    }
+
+    fn extract_name(tbl: &Table) -> Result<String, String> {
+        let idx_1: Option<String> = tbl.get(1).ok();
+        let name_key: Option<String> = tbl.get("name").ok();
+
+        match (idx_1, name_key) {
+            (Some(_), Some(_)) => Err("provide 'name' OR [1] but not both.".to_string()),
+            (Some(name), None) | (None, Some(name)) => Ok(name),
+            (None, None) => {
+                Err("package must have a name (at index [1] or as 'name' field)".to_string())
+            }
+        }
+    }
+
+    fn parse_target_list(path: &str, targets: Value) -> Vec<PathBuf> {
+        match targets {
+            Value::String(target) => vec![PathBuf::from(lua_str_to_str(&target))],
+            Value::Table(target_list) => {
+                let mut links: Vec<PathBuf> = Vec::new();
+                for pair in ordered_pairs(&target_list, path) {
+                    match pair {
+                        (Value::Integer(_), Value::String(target)) => {
+                            links.push(PathBuf::from(lua_str_to_str(&target)));
+                        }
+                        (k, v) => {
+                            fatal!("Link invalid target element: [{:#?}] = {:#?}", k, v);
+                        }
+                    }
+                }
+                links
+            }
+            v => fatal!(
+                "Link 'targets' expected type 'String' or 'Table', got {:?}",
+                v
+            ),
+        }
+    }
+
+    /// Whether the link table at `path` is on, per its `enabled` field.
+    fn link_enabled(path: &str, tbl: &Table) -> bool {
+        match field(tbl, path, "enabled") {
+            Value::Nil => true,
+            Value::Boolean(enabled) => enabled,
+            Value::Function(hook) => limits::budgeted(|| hook.call::<bool>(()))
+                .unwrap_or_else(|err| fatal!("'{}.enabled' function failed: {}", path, err)),
+            v => fatal!(
+                "Link 'enabled' expected type 'Boolean' or 'Function', got {:?}",
+                v
+            ),
+        }
+    }
+
+    fn extract_links(path: &str, tbl: &Table) -> Vec<LinkObject> {
+        ordered_pairs(tbl, path)
+            .into_iter()
+            .filter(|(key, value)| match (key, value) {
+                (Value::Integer(i), Value::Table(tbl)) => {
+                    Package::link_enabled(&format!("{}[{}]", path, i), tbl)
+                }
+                _ => true,
+            })
+            .map(|(key, value)| match (key, value) {
+                (Value::Integer(i), Value::Table(tbl)) => {
+                    let path = &format!("{}[{}]", path, i);
+                    let source: String = match field(&tbl, path, "source") {
+                        Value::String(s) => lua_str_to_str(&s),
+                        Value::Nil => fatal!("Link must contain 'source'"),
+                        v => fatal!("Link 'source' expected type 'String', got {:?}", v),
+                    };
+                    let targets = match field(&tbl, path, "targets") {
+                        Value::Nil => Vec::new(),
+                        v => Package::parse_target_list(&format!("{}.targets", path), v),
+                    };
+                    let targets_dir = match field(&tbl, path, "targets_dir") {
+                        Value::String(dir) => Some(PathBuf::from(lua_str_to_str(&dir))),
+                        Value::Nil => None,
+                        v => fatal!("Link 'targets_dir' expected type 'String', got {:?}", v),
+                    };
+                    if targets_dir.is_some() && !targets.is_empty() {
+                        fatal!("Link '{}' sets both 'targets' and 'targets_dir'", source);
+                    }
+                    let compose = match field(&tbl, path, "compose") {
+                        Value::String(mode) => {
+                            let mode = lua_str_to_str(&mode);
+                            let compose = compose::Compose::parse(&mode).unwrap_or_else(|| {
+                                fatal!("Link 'compose' has unknown mode '{}'", mode)
+                            });
+                            if targets.is_empty() {
+                                fatal!("Link '{}' with 'compose' must contain 'targets'", source);
+                            }
+                            Some(compose)
+                        }
+                        Value::Nil => None,
+                        v => fatal!("Link 'compose' expected type 'String', got {:?}", v),
+                    };
+                    let comment = match field(&tbl, path, "comment") {
+                        Value::String(comment) => Some(lua_str_to_str(&comment)),
+                        Value::Nil => None,
+                        v => fatal!("Link 'comment' expected type 'String', got {:?}", v),
+                    };
+                    let overwrite = match field(&tbl, path, "overwrite") {
+                        Value::Boolean(v) => v,
+                        Value::Nil => false,
+                        v => fatal!("Link 'overwrite' expected type 'Boolean', got {:?}", v),
+                    };
+                    let backup = match field(&tbl, path, "backup") {
+                        Value::Boolean(v) => v,
+                        Value::Nil => false,
+                        v => fatal!("Link 'backup' expected type 'Boolean', got {:?}", v),
+                    };
+                    LinkObject {
+                        source: PathBuf::from(source),
+                        targets,
+                        targets_dir,
+                        compose,
+                        comment,
+                        overwrite,
+                        backup,
+                    }
+                }
+                (Value::Integer(_), Value::String(source)) => LinkObject {
+                    source: PathBuf::from(lua_str_to_str(&source)),
+                    targets: Vec::new(),
+                    targets_dir: None,
+                    compose: None,
+                    comment: None,
+                    overwrite: false,
+                    backup: false,
+                },
+                (Value::String(source), v) => LinkObject {
+                    targets: Package::parse_target_list(
+                        &format!("{}.{}", path, source.to_string_lossy()),
+                        v,
+                    ),
+                    source: PathBuf::from(lua_str_to_str(&source)),
+                    targets_dir: None,
+                    compose: None,
+                    comment: None,
+                    overwrite: false,
+                    backup: false,
+                },
+                (key, value) => {
+                    fatal!("expected Link element, found {:#?} = {:#?}", key, value);
+                }
+            })
+            .collect()
+    }
+
+    fn extract_strings(path: &str, value: &Value) -> Vec<String> {
+        match value {
+            Value::String(_) => vec![lua_value_to_str(value)],
+            Value::Table(items) => sequence(items, path)
+                .into_iter()
+                .map(|v| match v {
+                    Value::String(item) => lua_str_to_str(&item),
+                    v => {
+                        fatal!("'{}' expected 'String' entries, found {:#?}", path, v);
+                    }
+                })
+                .collect(),
+            _ => {
+                fatal!(
+                    "'{}' expected 'String' or 'Table', found {:#?}",
+                    path,
+                    value
+                );
+            }
+        }
+    }
+
+    fn extract_bins(path: &str, value: &Value) -> Vec<(String, Option<String>)> {
+        match value {
+            Value::String(_) => vec![(lua_value_to_str(value), None)],
+            Value::Table(tbl) => ordered_pairs(tbl, path)
+                .into_iter()
+                .map(|pair| match pair {
+                    (Value::Integer(_), Value::String(bin)) => (lua_str_to_str(&bin), None),
+                    (Value::String(bin), Value::String(owner)) => {
+                        (lua_str_to_str(&bin), Some(lua_str_to_str(&owner)))
+                    }
+                    (k, v) => fatal!("invalid 'requires_bin' entry: [{:?}] = {:?}", k, v),
+                })
+                .collect(),
+            v => fatal!(
+                "'requires_bin' expected type 'String' or 'Table', got {:?}",
+                v
+            ),
+        }
+    }
+
+    fn extract_targets(path: &str, value: &Value) -> Vec<PathBuf> {
+        Package::extract_strings(path, value)
+            .into_iter()
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    fn extract_packages(path: &str, value: &Value) -> Vec<Package> {
+        match value {
+            Value::Table(tbl) => ordered_pairs(tbl, path)
+                .into_iter()
+                .filter_map(|(key, value)| Package::from_pair((&key, &value)))
+                .collect(),
+            v => fatal!("expected 'Table', found {:?}", v),
+        }
+    }
+
+    /// Evaluates `enabled`, calling it if it is a function.
+    fn is_enabled(&self) -> bool {
+        match &self.enabled {
+            Enabled::Enable(enabled) => *enabled,
+            Enabled::Hook(hook) => limits::budgeted(|| hook.call::<bool>(()))
+                .unwrap_or_else(|err| fatal!("[{}] 'enabled' function failed: {}", self.name, err)),
+        }
+    }
+
+    fn from_table(name: Option<String>, tbl: &Table) -> Self {
+        // todo!(); // Table -> Package
+        let package: Option<Package>;
+        if let Some(name) = name {
+            if Package::has_name(tbl) {
+                match Package::extract_name(tbl) {
+                    // package_name = { [1] = "<name>" | name = "<name>" }
+                    Ok(package_name) => {
+                        warn!(
+                            "key Named '{}' overrides package name '{}'",
+                            name, package_name
+                        );
+                    }
+                    Err(err) => {
+                        warn!("{}", err);
+                    }
+                }
+            }
+            package = Some(Package::new(name));
+        } else {
+            match Package::extract_name(tbl) {
+                Ok(name) => {
+                    package = Some(Package::new(name));
+                }
+                Err(err) => {
+                    fatal!("{}", err);
+                }
+            }
+        }
+        if let Some(mut pkg) = package {
+            for (k, value) in ordered_pairs(tbl, &pkg.name) {
+                if let Value::String(lua_key) = k {
+                    let key: &str = &lua_str_to_str(&lua_key);
+                    let path = &format!("{}.{}", pkg.name, key);
+                    match key {
+                        "links" => {
+                            if let Some(tbl) = value.as_table() {
+                                pkg.links = Package::extract_links(path, tbl);
+                            } else {
+                                fatal!("expected 'Table', found '{:?}'", value);
+                            }
+                        }
+                        "name" => (),
+                        "enabled" => {
+                            pkg.enabled = match value {
+                                Value::Boolean(enabled) => Enabled::Enable(enabled),
+                                Value::Function(hook) => Enabled::Hook(hook),
+                                v => fatal!(
+                                    "'enabled' expected type 'Boolean' or 'Function', got {:?}",
+                                    v
+                                ),
+                            };
+                        }
+                        "depends" => {
+                            pkg.depends = Package::extract_packages(path, &value);
+                        }
+                        "wants" => {
+                            pkg.wants = Package::extract_strings(path, &value);
+                        }
+                        "requires_bin" => {
+                            pkg.requires_bin = Package::extract_bins(path, &value);
+                        }
+                        "tags" => {
+                            pkg.tags = Package::extract_strings(path, &value);
+                        }
+                        "renamed_from" => {
+                            pkg.renamed_from = Package::extract_strings(path, &value);
+                        }
+                        "package_name" => {
+                            pkg.package_name = Some(OSPackageName::from_value(path, &value));
+                        }
+                        "excludes" => {
+                            pkg.excludes = Package::extract_targets(path, &value);
+                        }
+                        "templates" => {
+                            pkg.templates = render::files_from_value(&value);
+                        }
+                        "description" => {
+                            pkg.description = Some(lua_value_to_str(&value));
+                        }
+                        "docs" => {
+                            pkg.docs = Some(PathBuf::from(lua_value_to_str(&value)));
+                        }
+                        "template_delims" => {
+                            pkg.template_delims = Some(template::delims_from_value(path, &value));
+                        }
+                        "template_engine" => {
+                            pkg.template_engine =
+                                Some(template::Engine::parse(path, &lua_value_to_str(&value)));
+                        }
+                        "default_target" => {
+                            pkg.default_target = Some(PathBuf::from(lua_value_to_str(&value)));
+                        }
+                        "priority" => {
+                            pkg.priority = match value {
+                                Value::Integer(priority) => priority,
+                                v => fatal!("'priority' expected type 'Integer', got {:?}", v),
+                            };
+                        }
+                        "retries" => {
+                            pkg.retries = match value {
+                                Value::Integer(n) if n >= 0 => Some(n as u32),
+                                v => fatal!(
+                                    "'retries' expected a non-negative 'Integer', got {:?}",
+                                    v
+                                ),
+                            };
+                        }
+                        "root" => {
+                            pkg.root = match value {
+                                Value::Boolean(root) => root,
+                                v => fatal!("'root' expected type 'Boolean', got {:?}", v),
+                            };
+                        }
+                        "ssh_keygen" => {
+                            pkg.ssh_keygen = keys::SshKey::from_value(&value);
+                        }
+                        "gpg_import" => {
+                            pkg.gpg_import = Package::extract_targets(path, &value);
+                        }
+                        "defaults" => {
+                            pkg.defaults = macos::defaults_from_value("defaults", &value);
+                        }
+                        "assets" => {
+                            pkg.assets = Some(assets::Assets::from_value(&value));
+                        }
+                        "fetch" => {
+                            pkg.fetch = fetch::from_value(&value);
+                        }
+                        "path" => {
+                            pkg.path = exports::path_from_value(&value);
+                        }
+                        "env" => {
+                            pkg.env = exports::from_value("env", &value);
+                        }
+                        "aliases" => {
+                            pkg.aliases = exports::from_value("aliases", &value);
+                        }
+                        "ssh_hosts" => {
+                            pkg.ssh_hosts = ssh::hosts_from_value(&value);
+                        }
+                        "fonts" => {
+                            pkg.fonts = Package::extract_targets(path, &value);
+                        }
+                        "settings" => {
+                            pkg.settings = macos::defaults_from_value("settings", &value);
+                        }
+                        "on_install" => {
+                            pkg.on_install = HookAction::from_value(path, &value);
+                        }
+                        "on_deploy" => {
+                            pkg.on_deploy = HookAction::from_value(path, &value);
+                        }
+                        "reload" => {
+                            pkg.reload.extend(reload::from_value(path, &value));
+                        }
+                        "nvim_health" => {
+                            pkg.nvim_health = health::from_value(path, &value);
+                        }
+                        "tmux_reload" => {
+                            pkg.reload.extend(reload::tmux_from_value(path, &value));
+                        }
+                        "actions" => {
+                            pkg.actions = HookAction::actions_from_value(path, &value);
+                        }
+                        "vars" => {
+                            if let Some(tbl) = value.as_table() {
+                                template::flatten("vars", tbl, &mut pkg.vars);
+                            } else {
+                                fatal!("expected 'Table', found '{:?}'", value);
+                            }
+                        }
+                        _ => match schema::hint(key) {
+                            Some(hint) => warn!("key '{}' is ignored: {}", key, hint),
+                            None => warn!("key '{}' is ignored", key),
+                        },
+                    }
+                }
+            }
+            return pkg;
+        }
+        unreachable!();
+    }
+
+    fn from_pair(pair: (&Value, &Value)) -> Option<Package> {
+        match pair {
+            (Value::Integer(_), Value::String(name)) => Some(Package::new(lua_str_to_str(name))),
+            (Value::Integer(_), Value::Table(tbl)) => Some(Package::from_table(None, tbl)),
+            (Value::String(name), Value::Table(tbl)) => {
+                Some(Package::from_table(Some(lua_str_to_str(name)), tbl))
+            }
+            (key, value) => {
+                fatal!("Unsupported package format: {:?} = {:?}", key, value);
+            }
+        }
+    }
+}
+
+pub struct Context {
+    lua: Lua,
+    /// Root of the dotfiles repo; package sources live in `<config_path>/<name>`.
+    config_path: PathBuf,
+    /// Lua file the config is evaluated from.
+    entry: PathBuf,
+    /// Where mdot remembers what previous runs did: the state manifest
+    /// and the journal. `$MDOT_STATE_DIR`, else `$XDG_STATE_HOME/mdot`.
+    state_dir: PathBuf,
+    /// Data worth keeping that mdot produces, such as backups of replaced
+    /// targets. `$MDOT_DATA_DIR`, else `$XDG_DATA_HOME/mdot`.
+    data_dir: PathBuf,
+    /// Lua files registering actions and commands, evaluated before the
+    /// config. `plugins` in the default config dir.
+    plugins_dir: PathBuf,
+    /// Anything that can be rebuilt. `$MDOT_CACHE_DIR`, else
+    /// `$XDG_CACHE_HOME/mdot`.
+    cache_dir: PathBuf,
+    platform: platform::Platform,
+    /// Profile given with `--profile`, whose `vars` apply to this run.
+    profile: Option<String>,
+    /// Variables given with `--set`.
+    overrides: template::Vars,
+    /// Copy sources instead of linking them, `--copy`.
+    copy: bool,
+    /// Download `fetch` entries that cannot be verified, `--insecure-fetch`.
+    insecure_fetch: bool,
+    /// Ask before running each hook, `--confirm-hooks`.
+    confirm_hooks: bool,
+    /// Link sensitive targets without asking, `--allow-sensitive`.
+    allow_sensitive: bool,
+    /// Action types compiled in, which win over Lua plugins of the same
+    /// name.
+    providers: Vec<Rc<dyn ActionProvider>>,
+    /// Time and memory Lua code may use.
+    limits: limits::Limits,
+}
+
+/// Builds a [`Context`]. Whatever is not set comes from the environment
+/// when built: `$MDOT_APPNAME`, the `$MDOT_*_DIR` variables, the XDG dirs
+/// and the detected platform. mdot logs through the `log` crate, to the
+/// logger of the embedding program when it sets one, and works on the
+/// real file system below the directories given here.
+#[derive(Default)]
+pub struct ContextBuilder {
+    entry: Option<PathBuf>,
+    config_dir: Option<PathBuf>,
+    state_dir: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    plugins_dir: Option<PathBuf>,
+    lua: Option<Lua>,
+    limits: limits::Limits,
+    platform: Option<platform::Platform>,
+    providers: Vec<Rc<dyn ActionProvider>>,
+}
+
+/// Moves the state an older mdot kept in `legacy` to `state_dir`, unless
+/// there is state there already, returning the dir to use: `legacy`
+/// itself when it cannot be moved.
+fn migrate_state(legacy: &Path, state_dir: &Path) -> PathBuf {
+    if legacy == state_dir || !legacy.is_dir() || state_dir.exists() {
+        return state_dir.to_path_buf();
+    }
+    let moved = state_dir
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::rename(legacy, state_dir));
+    match moved {
+        Ok(()) => {
+            info!(
+                "moved the state from {} to {}",
+                legacy.display(),
+                state_dir.display()
+            );
+            state_dir.to_path_buf()
+        }
+        Err(err) => {
+            warn!(
+                "failed to move the state from {} to {}, still using it there: {}",
+                legacy.display(),
+                state_dir.display(),
+                err
+            );
+            legacy.to_path_buf()
+        }
+    }
+}
+
+impl ContextBuilder {
+    /// The entry file, absolute or relative to the working directory or
+    /// the config dir. Defaults to `<config dir>/main.lua`.
+    pub fn entry(mut self, entry: impl Into<PathBuf>) -> Self {
+        self.entry = Some(entry.into());
+        self
+    }
+
+    /// Where relative entry files and plugins are looked up, instead of
+    /// `$XDG_CONFIG_HOME/mdot`.
+    pub fn config_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config_dir = Some(dir.into());
+        self
+    }
+
+    pub fn state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(dir.into());
+        self
+    }
+
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    pub fn plugins_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.plugins_dir = Some(dir.into());
+        self
+    }
+
+    /// The Lua state configs are evaluated in, e.g. one made with
+    /// `Lua::new_with` to choose the standard libraries.
+    pub fn lua(mut self, lua: Lua) -> Self {
+        self.lua = Some(lua);
+        self
+    }
+
+    /// How long and how much memory Lua code may take before it fails.
+    pub fn limits(mut self, limits: limits::Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The platform packages are resolved for, instead of this machine.
+    pub fn platform(mut self, platform: platform::Platform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
+    /// An action type compiled in, as with [`Context::register_action`].
+    pub fn action(mut self, provider: Rc<dyn ActionProvider>) -> Self {
+        self.providers.retain(|p| p.name() != provider.name());
+        self.providers.push(provider);
+        self
+    }
+
+    /// The directory containing the entry file is treated as the repo root.
+    pub fn build(self) -> Context {
+        let app_name = env::var("MDOT_APPNAME").unwrap_or(APP_NAME.to_string());
+        let dir = |dir: Option<PathBuf>, var: &str, base: fn() -> PathBuf| {
+            dir.unwrap_or_else(|| {
+                env::var_os(var)
+                    .map(PathBuf::from)
+                    .filter(|path| path.is_absolute())
+                    .unwrap_or_else(|| base().join(&app_name))
+            })
+        };
+        let defaulted = self.state_dir.is_none() && env::var_os("MDOT_STATE_DIR").is_none();
+        let mut state_dir = dir(self.state_dir, "MDOT_STATE_DIR", xdg::state_home);
+        // Platforms without a state dir, such as macOS, used to keep it in
+        // the local data dir.
+        if defaulted
+            && dirs::state_dir().is_none()
+            && let Some(legacy) = dirs::data_local_dir()
+        {
+            state_dir = migrate_state(&legacy.join(&app_name), &state_dir);
+        }
+        let data_dir = dir(self.data_dir, "MDOT_DATA_DIR", xdg::data_home);
+        let cache_dir = dir(self.cache_dir, "MDOT_CACHE_DIR", xdg::cache_home);
+        let mut config_path = self.config_dir.unwrap_or_else(|| {
+            dirs::config_dir()
+                .unwrap_or_else(xdg::config_home)
+                .join(&app_name)
+        });
+        let plugins_dir = self
+            .plugins_dir
+            .unwrap_or_else(|| config_path.join(plugins::PLUGINS_DIR));
+        let entry = match self.entry {
+            Some(entry) if entry.is_absolute() || entry.exists() => entry,
+            Some(entry) => config_path.join(entry),
+            None => config_path.join("main.lua"),
+        };
+        let entry = entry.canonicalize().unwrap_or(entry);
+        if let Some(parent) = entry.parent() {
+            config_path = parent.to_path_buf();
+        }
+        Context {
+            lua: self.lua.unwrap_or_default(),
+            config_path,
+            entry,
+            state_dir,
+            data_dir,
+            plugins_dir,
+            cache_dir,
+            platform: self.platform.unwrap_or_else(platform::Platform::detect),
+            profile: None,
+            overrides: template::Vars::new(),
+            copy: false,
+            insecure_fetch: false,
+            confirm_hooks: false,
+            allow_sensitive: false,
+            providers: self.providers,
+            limits: self.limits,
+        }
+    }
+}
+
+impl Context {
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::default()
+    }
+
+    /// Creates a context for the given entry file, or `<config dir>/main.lua`,
+    /// with everything else from the environment.
+    pub fn new(entry: Option<PathBuf>) -> Self {
+        ContextBuilder {
+            entry,
+            ..Context::builder()
+        }
+        .build()
+    }
+
+    /// Makes the action `provider` handles available to packages, as
+    /// `actions = { [provider.name()] = args }`.
+    pub fn register_action(&mut self, provider: Rc<dyn ActionProvider>) {
+        self.providers.retain(|p| p.name() != provider.name());
+        self.providers.push(provider);
+    }
+
+    /// The directory holding the sources of `pkg`, in this repo or in the
+    /// base repo it comes from.
+    pub fn package_dir(&self, pkg: &Package) -> PathBuf {
+        match &pkg.base {
+            Some(base) => base.join(&pkg.name),
+            None => self.config_path.join(&pkg.name),
+        }
+    }
+
+    /// Where replaced targets are moved to when a link sets `backup`.
+    fn backup_dir(&self) -> PathBuf {
+        self.data_dir.join("backups")
+    }
+
+    /// A fresh context for the same entry file, dirs, profile and
+    /// overrides, to evaluate the config again after it changed.
+    fn reload(&self) -> Self {
+        Self {
+            state_dir: self.state_dir.clone(),
+            data_dir: self.data_dir.clone(),
+            plugins_dir: self.plugins_dir.clone(),
+            cache_dir: self.cache_dir.clone(),
+            profile: self.profile.clone(),
+            overrides: self.overrides.clone(),
+            copy: self.copy,
+            insecure_fetch: self.insecure_fetch,
+            confirm_hooks: self.confirm_hooks,
+            allow_sensitive: self.allow_sensitive,
+            providers: self.providers.clone(),
+            limits: self.limits,
+            platform: self.platform.clone(),
+            ..Self::new(Some(self.entry.clone()))
+        }
+    }
+}
+
+fn setup_logger() -> Result<(), fern::InitError> {
+    fern::Dispatch::new()
+        .format(|out, message, record| {
+            // 2. Define the color based on the level
+            let level_color = match record.level() {
+                log::Level::Error => record.level().to_string().red(),
+                log::Level::Warn => record.level().to_string().yellow(),
+                log::Level::Info => record.level().to_string().green(),
+                log::Level::Debug => record.level().to_string().blue(),
+                log::Level::Trace => record.level().to_string().magenta(),
+            };
+
+            out.finish(format_args!(
+                "[{}] {}",
+                level_color, // 3. Use the colored level
+                message
+            ))
+        })
+        .level(log::LevelFilter::Debug)
+        .level_for("globset", log::LevelFilter::Info)
+        .level_for("ignore", log::LevelFilter::Info)
+        // Logs go to stderr, leaving stdout to what commands print, such
+        // as `query --json` and `--porcelain` events.
+        .chain(std::io::stderr())
+        .apply()?;
+    Ok(())
+}
+
+/// Runs mdot on the arguments of the process.
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    run_args(env::args_os().collect(), None)
+}
+
+/// Runs mdot on `args`, the program name first, in `ctx` instead of a
+/// context made from `--config` and the environment. A logger set before
+/// is kept, so that an embedding program decides where mdot logs to.
+pub fn main_with<I, T>(ctx: Context, args: I) -> Result<(), Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    run_args(args.into_iter().map(Into::into).collect(), Some(ctx))
+}
+
+fn run_args(args: Vec<OsString>, ctx: Option<Context>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = cli::Cli::parse_from(&args);
+    if cli.porcelain {
+        events::enable();
+    }
+    if cli.offline {
+        net::set_offline();
+    }
+    if cli.paranoid {
+        assets::set_paranoid();
+    }
+    interactive::init(cli.assume_yes, cli.assume_no);
+    match ctx {
+        Some(_) => {
+            let _ = setup_logger();
+        }
+        None => setup_logger()?,
+    }
+    if let cli::Command::ShellInit { shell } = &cli.command {
+        println!(
+            "{}",
+            shell::init(shell).unwrap_or_else(|err| fatal!("{}", err))
+        );
+        return Ok(());
+    }
+    if let cli::Command::Man { dir } = &cli.command {
+        match dir {
+            Some(dir) => {
+                for path in man::generate(dir).unwrap_or_else(|err| fatal!("{}", err)) {
+                    info!("wrote {}", path.display());
+                }
+            }
+            None => print!("{}", man::render()),
+        }
+        return Ok(());
+    }
+    let mut ctx = ctx.unwrap_or_else(|| Context::new(cli.config.take()));
+    if let Some(secs) = cli.lua_timeout {
+        ctx.limits.timeout = Duration::from_secs(secs);
+    }
+    if let Some(mb) = cli.lua_memory {
+        ctx.limits.memory = mb * 1024 * 1024;
+    }
+    if let Some(hostname) = &cli.as_host {
+        if !cli.command.is_read_only() {
+            fatal!("--as-host only works with commands that change nothing, such as plan");
+        }
+        ctx.platform.hostname = hostname.clone();
+    }
+    if let cli::Command::Plan {
+        simulate: Some(spec),
+        ..
+    } = &cli.command
+    {
+        ctx.platform = ctx
+            .platform
+            .simulate(spec)
+            .unwrap_or_else(|err| fatal!("invalid --simulate: {}", err));
+    }
+    ctx.profile = cli.profile.clone();
+    ctx.overrides = cli.set.iter().cloned().collect();
+    let cache_dir = ctx.cache_dir.clone();
+    assets::load_hashes(&cache_dir);
+    let config = config::load(&ctx);
+    if cli.profile.is_none() {
+        cli.profile = config.host.profile.clone();
+        ctx.profile = cli.profile.clone();
+    }
+    if cli.command.is_mutating()
+        || matches!(
+            cli.command,
+            cli::Command::Daemon | cli::Command::Plan { simulate: None, .. }
+        )
+    {
+        fscaps::allow_probing();
+    }
+    if cli.command.is_mutating() {
+        let command: Vec<String> = args
+            .iter()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        if let Err(err) = journal::begin(&ctx.state_dir, &command.join(" ")) {
+            warn!("failed to start the journal: {}", err);
+        }
+    }
+    match cli.command {
+        cli::Command::Deploy {
+            packages,
+            filter,
+            no_install,
+            prune,
+            copy,
+            mut minimal,
+            insecure_fetch,
+            confirm_hooks,
+            allow_sensitive,
+            locked,
+            resume,
+        } => {
+            let mut filter = filter;
+            minimal |= cli.profile.as_deref() == Some(select::CONTAINER_PROFILE);
+            if minimal {
+                log::set_max_level(log::LevelFilter::Warn);
+                filter.exclude_tags.push(select::GUI_TAG.to_string());
+            }
+            ctx.copy = copy || minimal;
+            ctx.insecure_fetch = insecure_fetch;
+            ctx.confirm_hooks = confirm_hooks;
+            ctx.allow_sensitive = allow_sensitive;
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            if locked {
+                let lock = lock::Lock::load(&lock::Lock::path(&ctx.config_path))
+                    .unwrap_or_else(|err| fatal!("{}", err));
+                let planned = lock::Lock::from_packages(&ctx, &config, &selection.packages);
+                let violations = lock.violations(&planned);
+                for entry in &violations {
+                    error!("{} is not in {}", entry, lock::LOCK_FILE);
+                }
+                if !violations.is_empty() {
+                    fatal!("refusing to deploy, run `mdot lock` after reviewing the changes");
+                }
+            }
+            let progress = progress::begin(&ctx.state_dir, resume).unwrap_or_else(|err| {
+                warn!("failed to record the progress of the deploy: {}", err);
+                progress::Progress::default()
+            });
+            if !no_install && !minimal && !progress.installed {
+                install::install(&selection.packages, &ctx.platform, &config.options)
+                    .unwrap_or_else(|err| fatal!("install failed: {}", err));
+                progress::installed();
+            }
+            deploy::apply(&ctx, &config, &selection, prune, &progress);
+            progress::finish();
+            if interactive::failed() {
+                fatal!("conflicts were left unresolved");
+            }
+            if !check::report_bins(&config, &selection.packages, &ctx.platform) {
+                journal::finish(false);
+                std::process::exit(1);
+            }
+        }
+        cli::Command::Install { packages, filter } => {
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            install::install(&selection.packages, &ctx.platform, &config.options)
+                .unwrap_or_else(|err| fatal!("install failed: {}", err));
+        }
+        cli::Command::Export {
+            what: cli::ExportKind::Nix { packages, filter },
+        } => {
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            let links = deploy::plan(&ctx, &config, &selection.packages);
+            let home = dirs::home_dir().unwrap_or_default();
+            let (module, skipped) = nix::home_manager(&links, &home, &xdg::config_home());
+            for target in skipped {
+                warn!(
+                    "{} is outside the home directory, skipping",
+                    target.display()
+                );
+            }
+            print!("{}", module);
+        }
+        cli::Command::Export {
+            what:
+                cli::ExportKind::Devcontainer {
+                    dir,
+                    packages,
+                    mut filter,
+                    format,
+                },
+        } => {
+            filter.exclude_tags.push(select::GUI_TAG.to_string());
+            ctx.copy = true;
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            let links = deploy::plan(&ctx, &config, &selection.packages);
+            let manager = format.unwrap_or(cli::PackageManager::Apt).name();
+            let platform = install::platform_for(&ctx.platform, manager)
+                .unwrap_or_else(|err| fatal!("{}", err));
+            let names = install::names(&selection.packages, &platform);
+            let templates = render::templates(&ctx, &config, &selection.packages)
+                .unwrap_or_else(|err| fatal!("{}", err));
+            let home = dirs::home_dir().unwrap_or_default();
+            let skipped = devcontainer::write(&dir, &links, &templates, &home, &names, manager)
+                .unwrap_or_else(|err| fatal!("{}", err));
+            for target in skipped {
+                warn!(
+                    "{} is outside the home directory, skipping",
+                    target.display()
+                );
+            }
+            info!("wrote a devcontainer feature to {}", dir.display());
+        }
+        cli::Command::Export { what } => {
+            let (packages, filter, manager, brewfile) = match what {
+                cli::ExportKind::Brewfile { packages, filter } => {
+                    (packages, filter, Some(cli::PackageManager::Brew), true)
+                }
+                cli::ExportKind::Pkglist {
+                    packages,
+                    filter,
+                    format,
+                } => (packages, filter, format, false),
+                cli::ExportKind::Nix { .. } | cli::ExportKind::Devcontainer { .. } => {
+                    unreachable!()
+                }
+            };
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            let platform = match manager {
+                Some(manager) => install::platform_for(&ctx.platform, manager.name())
+                    .unwrap_or_else(|err| fatal!("{}", err)),
+                None => ctx.platform.clone(),
+            };
+            let names: Vec<String> = install::names(&selection.packages, &platform)
+                .into_iter()
+                .map(|name| match manager {
+                    Some(manager) => install::qualify(manager.name(), name),
+                    None => name,
+                })
+                .collect();
+            if brewfile {
+                print!("{}", install::brewfile(&names));
+            } else {
+                for name in names {
+                    println!("{}", name);
+                }
+            }
+        }
+        cli::Command::Check {
+            packages,
+            filter,
+            fix,
+            determinism,
+        } => {
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            let bins_ok = check::report_bins(&config, &selection.packages, &ctx.platform);
+            for (package, dir) in exports::missing_path_dirs(&selection.packages) {
+                warn!("[{}] PATH entry '{}' does not exist", package, dir);
+            }
+            let lints = lint::check(&ctx, &config);
+            for lint in &lints {
+                warn!("{}", lint);
+            }
+            if fix {
+                let fixed =
+                    lint::fix(&ctx, &config, &lints).unwrap_or_else(|err| fatal!("{}", err));
+                info!("fixed {} of {} issue(s)", fixed, lints.len());
+            } else if lints.is_empty() {
+                info!("no issues found in the config");
+            }
+            if determinism {
+                let diff = check::determinism(&ctx, &config, &filter);
+                if diff.is_empty() {
+                    info!("the config evaluated the same twice");
+                } else {
+                    warn!("the config evaluates differently between runs:");
+                }
+                for line in diff {
+                    warn!("  {}", line);
+                }
+            }
+            if !bins_ok {
+                std::process::exit(1);
+            }
+        }
+        cli::Command::Ci {
+            simulate,
+            format,
+            output,
+        } => {
+            let cases = ci::run(&ctx, &config, &simulate).unwrap_or_else(|err| fatal!("{}", err));
+            let report = ci::report(&cases, format);
+            match output {
+                Some(path) => std::fs::write(&path, report)
+                    .unwrap_or_else(|err| fatal!("failed to write {}: {}", path.display(), err)),
+                None => print!("{}", report),
+            }
+            let failed = cases.iter().filter(|c| !c.failures.is_empty()).count();
+            if failed > 0 {
+                error!("{} of {} checks failed", failed, cases.len());
+                std::process::exit(1);
+            }
+            info!("all {} checks passed", cases.len());
+        }
+        cli::Command::Render {
+            package,
+            check,
+            stdout,
+            out,
+        } => {
+            let packages: Vec<String> = package.iter().cloned().collect();
+            let filter = cli::Filter::default();
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            let selected: Vec<Package> = selection
+                .packages
+                .into_iter()
+                .filter(|pkg| package.as_ref().is_none_or(|name| &pkg.name == name))
+                .collect();
+            let templates =
+                render::templates(&ctx, &config, &selected).unwrap_or_else(|err| fatal!("{}", err));
+            if let Some(file) = stdout {
+                let source = edit::source_arg(&ctx.config_path, &file);
+                let Some(template) = templates
+                    .iter()
+                    .find(|t| t.source.canonicalize().is_ok_and(|s| s == source))
+                else {
+                    fatal!("'{}' is not a template of the selected packages", file);
+                };
+                match render::render(template) {
+                    Ok(content) => print!("{}", content),
+                    Err(problems) => {
+                        for problem in &problems {
+                            error!("{}", problem);
+                        }
+                        std::process::exit(1);
+                    }
+                }
+                return Ok(());
+            }
+            if let Some(out) = out {
+                let links = deploy::plan(&ctx, &config, &selected);
+                let composed =
+                    compose::plan(&ctx, &config, &selected).unwrap_or_else(|err| fatal!("{}", err));
+                let problems = render::materialize(&links, &composed, &templates, &out)
+                    .unwrap_or_else(|err| fatal!("{}", err));
+                for problem in &problems {
+                    error!("{}", problem);
+                }
+                if !problems.is_empty() {
+                    std::process::exit(1);
+                }
+                info!(
+                    "wrote {} target(s) into {}",
+                    links.len() + composed.len(),
+                    out.display()
+                );
+                return Ok(());
+            }
+            let dir = render::render_dir(&ctx).unwrap_or_else(|err| fatal!("{}", err));
+            let problems =
+                render::render_all(&templates, &dir).unwrap_or_else(|err| fatal!("{}", err));
+            if check {
+                let _ = std::fs::remove_dir_all(&dir);
+            } else if dir.exists() {
+                info!(
+                    "rendered {} template(s) into {}",
+                    templates.len(),
+                    dir.display()
+                );
+            }
+            for problem in &problems {
+                error!("{}", problem);
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+            if check {
+                info!("{} template(s) render", templates.len());
+            }
+        }
+        cli::Command::Plan {
+            packages, filter, ..
+        } => {
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            println!(
+                "{}",
+                plan::render(&ctx, &config, &selection).unwrap_or_else(|err| fatal!("{}", err))
+            );
+        }
+        cli::Command::Status {
+            packages,
+            filter,
+            since_last,
+        } => {
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            let links = deploy::plan(&ctx, &config, &selection.packages);
+            if !since_last {
+                let home = dirs::home_dir().unwrap_or_default();
+                for (link, status) in links.iter().zip(deploy::link_statuses(&links)) {
+                    let notice = match sensitive::reason(link, &home) {
+                        Some(reason) => format!("  ! {}", reason).yellow().bold().to_string(),
+                        None => String::new(),
+                    };
+                    println!(
+                        "{:<8} [{}] {}{}",
+                        status,
+                        link.package,
+                        link.target.display(),
+                        notice
+                    );
+                }
+                for key in selection
+                    .packages
+                    .iter()
+                    .flat_map(|p| keys::status(&ctx, p))
+                {
+                    let status = if key.present { "present" } else { "missing" };
+                    match &key.fingerprint {
+                        Some(fingerprint) => println!(
+                            "{:<8} [{}] {} {}",
+                            status, key.package, key.description, fingerprint
+                        ),
+                        None => println!("{:<8} [{}] {}", status, key.package, key.description),
+                    }
+                }
+                let composed = compose::plan(&ctx, &config, &selection.packages)
+                    .unwrap_or_else(|err| fatal!("{}", err));
+                for composed in composed {
+                    let status = compose::status(&composed);
+                    let packages: Vec<&str> = composed
+                        .fragments
+                        .iter()
+                        .map(|f| f.package.as_str())
+                        .collect();
+                    println!(
+                        "{:<8} [{}] composed {}",
+                        status,
+                        packages.join(","),
+                        composed.target.display()
+                    );
+                }
+                for pkg in &selection.packages {
+                    let vars = template::package_vars(&config, pkg, &ctx.platform);
+                    let assets = assets::plan(&ctx, pkg, &vars)
+                        .unwrap_or_else(|err| fatal!("[{}] {}", pkg.name, err));
+                    for (source, target) in assets {
+                        let status = assets::status(&source, &target);
+                        println!("{:<8} [{}] asset {}", status, pkg.name, target.display());
+                    }
+                    let fonts = fonts::status(&ctx, &config.options, pkg)
+                        .unwrap_or_else(|err| fatal!("[{}] {}", pkg.name, err));
+                    for font in fonts {
+                        let status = if font.installed { "present" } else { "missing" };
+                        if font.families.is_empty() {
+                            println!(
+                                "{:<8} [{}] font {}",
+                                status,
+                                font.package,
+                                font.file.display()
+                            );
+                        } else {
+                            println!(
+                                "{:<8} [{}] font {} ({})",
+                                status,
+                                font.package,
+                                font.file.display(),
+                                font.families.join(", ")
+                            );
+                        }
+                    }
+                }
+                for setting in selection
+                    .packages
+                    .iter()
+                    .flat_map(|p| settings::status(&ctx, p))
+                {
+                    let status = if setting.drifted() { "drift" } else { "ok" };
+                    let current = setting.current.as_deref().unwrap_or("unset");
+                    println!(
+                        "{:<8} [{}] {} = {} (want {})",
+                        status, setting.package, setting.description, current, setting.expected
+                    );
+                }
+                return Ok(());
+            }
+            let old = state::State::load(&state::State::path(&ctx))
+                .unwrap_or_else(|err| fatal!("{}", err));
+            if old.packages.is_empty() {
+                println!("no previous deploy recorded");
+            }
+            let new = state::State::from_plan(&selection.packages, &links);
+            let changes = state::diff(&old, &new, &selection);
+            if changes.is_empty() {
+                println!("nothing changed since the last deploy");
+            }
+            for change in changes {
+                println!("{}", change);
+            }
+        }
+        cli::Command::Mv { old, new } => {
+            let path = state::State::path(&ctx);
+            let mut state = state::State::load(&path).unwrap_or_else(|err| fatal!("{}", err));
+            state
+                .rename(&old, &new)
+                .unwrap_or_else(|err| fatal!("{}", err));
+            state.save(&path).unwrap_or_else(|err| {
+                fatal!("failed to save state to {}: {}", path.display(), err)
+            });
+            info!("links of '{}' now belong to '{}'", old, new);
+        }
+        cli::Command::Daemon => daemon::run(ctx, config, cli.profile)?,
+        cli::Command::Git {
+            command: cli::GitCommand::Sync,
+        } => {
+            git::pull(&ctx.config_path, config.options.retries)
+                .unwrap_or_else(|err| fatal!("{}", err));
+            let ctx = ctx.reload();
+            let config = config::load(&ctx);
+            let selection = select::select(
+                &config,
+                &[],
+                cli.profile.as_deref(),
+                &cli::Filter::default(),
+            );
+            deploy::apply(
+                &ctx,
+                &config,
+                &selection,
+                false,
+                &progress::Progress::default(),
+            );
+        }
+        cli::Command::UpdateBase => {
+            let Some(url) = &config.base else {
+                fatal!("the config does not set 'extends'");
+            };
+            base::update(&ctx, url, config.options.retries).unwrap_or_else(|err| fatal!("{}", err));
+        }
+        cli::Command::AddRemote { spec, retries } => {
+            remote::add(&ctx, &spec, retries, config.options.retries)
+                .unwrap_or_else(|err| fatal!("{}", err));
+        }
+        cli::Command::UpdateRemote { name } => {
+            remote::update(&ctx, name.as_deref(), config.options.retries)
+                .unwrap_or_else(|err| fatal!("{}", err));
+        }
+        cli::Command::Bundle { output, binary } => {
+            bundle::create(&ctx, &output, binary).unwrap_or_else(|err| fatal!("{}", err));
+        }
+        cli::Command::Plugin(args) => {
+            plugins::run_command(&ctx, &args).unwrap_or_else(|err| fatal!("{}", err));
+        }
+        cli::Command::RemoveRemote { name } => {
+            remote::remove(&ctx, &name).unwrap_or_else(|err| fatal!("{}", err));
+        }
+        cli::Command::Schedule { action } => {
+            let result = match action {
+                cli::ScheduleAction::Install { interval } => {
+                    schedule::install(&ctx, cli.profile.as_deref(), &interval)
+                }
+                cli::ScheduleAction::Remove => schedule::remove(&ctx),
+            };
+            result.unwrap_or_else(|err| fatal!("{}", err));
+        }
+        cli::Command::Edit { target } => {
+            let selection = select::select(
+                &config,
+                &[],
+                cli.profile.as_deref(),
+                &cli::Filter::default(),
+            );
+            let links = deploy::plan(&ctx, &config, &selection.packages);
+            let names: Vec<String> = selection.decisions.keys().cloned().collect();
+            let (package, path) = edit::resolve(&ctx.config_path, &links, &names, &target)
+                .unwrap_or_else(|err| fatal!("{}", err));
+            edit::open_editor(&path).unwrap_or_else(|err| fatal!("{}", err));
+            let ctx = ctx.reload();
+            let config = config::load(&ctx);
+            match select::all_packages(&config)
+                .into_iter()
+                .find(|pkg| pkg.name == package)
+            {
+                Some(pkg) => {
+                    deploy::redeploy(&ctx, &config, pkg, &path);
+                }
+                None => warn!("'{}' is no longer in the config", package),
+            }
+        }
+        cli::Command::SourcePath { target } => {
+            let selection = select::select(
+                &config,
+                &[],
+                cli.profile.as_deref(),
+                &cli::Filter::default(),
+            );
+            let links = deploy::plan(&ctx, &config, &selection.packages);
+            match edit::source_for(&links, &edit::target_arg(&target)) {
+                Some((_, source)) => println!("{}", source.display()),
+                None => fatal!("'{}' is not managed by mdot", target),
+            }
+        }
+        cli::Command::TargetPath { source } => {
+            let selection = select::select(
+                &config,
+                &[],
+                cli.profile.as_deref(),
+                &cli::Filter::default(),
+            );
+            let links = deploy::plan(&ctx, &config, &selection.packages);
+            let targets = edit::targets_for(&links, &edit::source_arg(&ctx.config_path, &source));
+            if targets.is_empty() {
+                fatal!("'{}' is not deployed anywhere", source);
+            }
+            for target in targets {
+                println!("{}", target.display());
+            }
+        }
+        cli::Command::Query { what, json } => {
+            let selection = select::select(
+                &config,
+                &[],
+                cli.profile.as_deref(),
+                &cli::Filter::default(),
+            );
+            let result = match what {
+                cli::QueryKind::Links => {
+                    query::links(&deploy::plan(&ctx, &config, &selection.packages))
+                }
+                cli::QueryKind::Packages => query::packages(&config, &selection, &ctx.platform),
+                cli::QueryKind::Vars => query::vars(&config, &selection, &ctx.platform),
+            };
+            if json {
+                println!("{}", result);
+            } else {
+                let mut lines = Vec::new();
+                query::lines(&result, "", &mut lines);
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+        }
+        cli::Command::Lock { packages, filter } => {
+            let selection = select::select(&config, &packages, cli.profile.as_deref(), &filter);
+            let path = lock::Lock::path(&ctx.config_path);
+            let lock = lock::Lock::from_packages(&ctx, &config, &selection.packages);
+            lock.save(&path)
+                .unwrap_or_else(|err| fatal!("failed to write {}: {}", path.display(), err));
+            info!(
+                "locked {} entries in {}",
+                lock.entries.len(),
+                path.display()
+            );
+        }
+        cli::Command::Undo => {
+            let path = state::State::path(&ctx);
+            let mut state = state::State::load(&path).unwrap_or_else(|err| fatal!("{}", err));
+            match journal::undo(&ctx.state_dir, &mut state) {
+                Ok(Some(id)) => info!("reverted run {}", id),
+                Ok(None) => info!("nothing to undo"),
+                Err(err) => fatal!("{}", err),
+            }
+            if let Err(err) = state.save(&path) {
+                warn!("failed to save state to {}: {}", path.display(), err);
+            }
+        }
+        cli::Command::History { action: None } => {
+            let runs = journal::runs(&ctx.state_dir).unwrap_or_else(|err| fatal!("{}", err));
+            for run in runs {
+                let packages: Vec<&str> = run.packages().into_iter().collect();
+                let line = format!(
+                    "{:>4}  {}  {:<10} {:<30} {}{}",
+                    run.id,
+                    journal::format_time(run.time),
+                    run.result(),
+                    run.command,
+                    packages.join(","),
+                    if run.undone { " (undone)" } else { "" }
+                );
+                println!("{}", line.trim_end());
+            }
+        }
+        cli::Command::History {
+            action: Some(cli::HistoryAction::Show { id }),
+        } => {
+            let run = journal::run(&ctx.state_dir, id)
+                .unwrap_or_else(|err| fatal!("no run {} in the journal: {}", id, err));
+            println!("run      {}", run.id);
+            println!("time     {}", journal::format_time(run.time));
+            println!("user     {}", run.user);
+            println!("command  {}", run.command);
+            println!(
+                "result   {}{}",
+                run.result(),
+                if run.undone { ", undone" } else { "" }
+            );
+            for action in &run.actions {
+                println!("{}", action);
+            }
+        }
+        cli::Command::Root => println!("{}", ctx.config_path.display()),
+        cli::Command::Hosts { action } => match action {
+            cli::HostsAction::List => {
+                let hosts = hosts::load(&ctx.lua, &ctx.config_path);
+                if hosts.is_empty() {
+                    info!("no {} in {}", hosts::HOSTS_FILE, ctx.config_path.display());
+                }
+                println!("{}", hosts::render(&hosts, &ctx.platform.hostname));
+            }
+        },
+        cli::Command::Paths => {
+            println!("config  {}", ctx.config_path.display());
+            println!("state   {}", ctx.state_dir.display());
+            println!("data    {}", ctx.data_dir.display());
+            println!("backups {}", ctx.backup_dir().display());
+            println!("cache   {}", ctx.cache_dir.display());
+        }
+        cli::Command::ShellInit { .. } | cli::Command::Man { .. } => unreachable!(),
+        cli::Command::Complete { what } => {
+            for candidate in shell::candidates(&ctx, &config, what) {
+                println!("{}", candidate);
+            }
+        }
+        cli::Command::List { long } => {
+            for pkg in &config.packages {
+                match pkg.os_package(&ctx.platform) {
+                    Some(os_package) if os_package != pkg.name => {
+                        println!("{} ({})", pkg.name, os_package)
+                    }
+                    _ => println!("{}", pkg.name),
+                }
+                if long {
+                    if let Some(description) = &pkg.description {
+                        println!("    {}", description);
+                    }
+                    println!("    {}", info::counts(pkg));
+                }
+            }
+        }
+        cli::Command::Info { package } => {
+            let selection = select::select(
+                &config,
+                &[],
+                cli.profile.as_deref(),
+                &cli::Filter::default(),
+            );
+            println!(
+                "{}",
+                info::info(&ctx, &config, &selection, &package)
+                    .unwrap_or_else(|err| fatal!("{}", err))
+            );
+        }
+        cli::Command::Why { package, filter } => {
+            let selection = select::select(&config, &[], cli.profile.as_deref(), &filter);
+            match select::why(&selection, &package) {
+                Some(explanation) => println!("{}", explanation),
+                None => fatal!("package '{}' is not defined in the config", package),
+            }
+            let (_, shadowed) = deploy::plan_with_shadowed(&ctx, &config, &selection.packages);
+            for s in shadowed {
+                if s.package == package {
+                    println!("- {} is shadowed by {}", s.target.display(), s.by);
+                } else if s.by == package {
+                    println!("- {} shadows {}", s.target.display(), s.package);
+                }
+            }
+        }
+        cli::Command::Vars { package } => {
+            let pkg = package.map(|name| {
+                select::find_package(&config.packages, &name)
+                    .unwrap_or_else(|| fatal!("package '{}' is not defined in the config", name))
+            });
+            for (key, (value, layer)) in template::layered_vars(&config, pkg, &ctx.platform) {
+                println!("{} = {}  ({})", key, value, layer);
+            }
+        }
+        cli::Command::Facts => {
+            for (key, value) in &config.facts {
+                println!("{} = {}", key.trim_start_matches("facts."), value);
+            }
+        }
+    }
+    if let Err(err) = assets::save_hashes(&cache_dir) {
+        warn!("failed to record file hashes: {}", err);
+    }
+    journal::finish(true);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    use mlua::IntoLua;
+    use std::fs;
+
+    #[test]
+    fn test_context_builder() {
+        let root = env::temp_dir().join(format!("mdot-builder-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("home.lua"), "return {}").unwrap();
+        let platform = platform::Platform {
+            hostname: "box".to_string(),
+            ..platform::Platform::detect()
+        };
+        let ctx = Context::builder()
+            .config_dir(&root)
+            .entry("home.lua")
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .platform(platform)
+            .build();
+        let root = root.canonicalize().unwrap();
+        assert_eq!(ctx.entry, root.join("home.lua"));
+        assert_eq!(ctx.config_path, root);
+        assert_eq!(ctx.plugins_dir, root.join("plugins"));
+        assert_eq!(ctx.platform.hostname, "box");
+        assert!(ctx.state_dir.ends_with("state") && ctx.cache_dir.ends_with("cache"));
+
+        // The state an older mdot kept in the local data dir moves over.
+        let legacy = root.join("Library/Application Support/mdot");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("state"), "").unwrap();
+        let state_dir = root.join(".local/state/mdot");
+        assert_eq!(migrate_state(&legacy, &state_dir), state_dir);
+        assert!(state_dir.join("state").exists() && !legacy.exists());
+        fs::create_dir_all(&legacy).unwrap();
+        assert_eq!(migrate_state(&legacy, &state_dir), state_dir);
+        assert!(legacy.exists());
+
+        // The config of another machine is only ever looked at.
+        let args = ["mdot", "--as-host", "other", "deploy"];
+        let denied = catch_fatal(|| main_with(ctx, args).is_ok()).unwrap_err();
+        assert!(denied.contains("--as-host only works with"), "{}", denied);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_metatables() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-metatables-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"local defaults = { tags = "cli", links = { "config" } }
+            local function pkg(name)
+                return function(spec)
+                    spec[1] = name
+                    return setmetatable(spec, { __index = defaults })
+                end
+            end
+            local backing = { "git", tags = { "vcs" } }
+            local proxy = setmetatable({}, {
+                __index = backing,
+                __pairs = function() return next, backing, nil end,
+            })
+            return { pkg "kitty" { description = "terminal" }, pkg "fish" { tags = "shell" }, proxy }"#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let packages: Vec<(&str, Vec<&str>, usize)> = config
+            .packages
+            .iter()
+            .map(|pkg| {
+                let tags = pkg.tags.iter().map(String::as_str).collect();
+                (pkg.name.as_str(), tags, pkg.links.len())
+            })
+            .collect();
+        assert_eq!(
+            packages,
+            [
+                ("kitty", vec!["cli"], 1),
+                ("fish", vec!["shell"], 1),
+                ("git", vec!["vcs"], 0)
+            ]
+        );
+        assert_eq!(config.packages[0].description.as_deref(), Some("terminal"));
+
+        let cyclic: Table = ctx
+            .lua
+            .load("local t = { a = 1 }; return setmetatable(t, { __index = t })")
+            .eval()
+            .unwrap();
+        assert_eq!(entries(&cyclic).unwrap().len(), 1);
+
+        let class: Table = ctx
+            .lua
+            .load(
+                r#"local Base = { tags = "cli" }
+                Base.__index = Base
+                function Base.new(spec) return setmetatable(spec, Base) end
+                return Base.new({ "zsh" })"#,
+            )
+            .eval()
+            .unwrap();
+        let keys: Vec<String> = entries(&class)
+            .unwrap()
+            .iter()
+            .map(|(key, _)| key.to_string().unwrap())
+            .collect();
+        assert_eq!(keys, ["1", "tags"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_package_string() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-package-string-{}", std::process::id()));
+        let ctx = Context::builder()
+            .config_dir(root.join("config"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let s = ctx.lua.create_string("foo").unwrap();
+        let e = Package::new("foo".to_string());
+        assert_eq!(
+            Package::from_pair((&Value::Integer(1), &Value::String(s))),
+            Some(e)
+        );
+    }
+
+    #[test]
+    fn test_package_table() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-package-table-{}", std::process::id()));
+        let ctx = Context::builder()
+            .config_dir(root.join("config"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let name_foo = "foo".into_lua(&ctx.lua).unwrap();
+        let name_bar = "bar".into_lua(&ctx.lua).unwrap();
+        let name_name = "name".into_lua(&ctx.lua).unwrap();
+        let expected = Some(Package::new("foo".to_string()));
+
+        let tbl = ctx.lua.create_table().unwrap();
+        tbl.set(1, &name_foo).unwrap();
+
+        assert_eq!(
+            Package::from_pair((&Value::Integer(1), &Value::Table(tbl.clone()))),
+            expected
+        );
+
+        tbl.set(1, &name_bar).unwrap();
+        assert_eq!(
+            Package::from_pair((&name_foo, &Value::Table(tbl.clone()))),
+            expected
+        );
+
+        tbl.set(1, &name_bar).unwrap();
+        tbl.set(name_name.clone(), &name_bar).unwrap();
+        assert_eq!(
+            Package::from_pair((&name_foo, &Value::Table(tbl.clone()))),
+            expected
+        );
+        tbl.set(name_name.clone(), Value::Nil).unwrap();
+        assert_eq!(
+            Package::from_pair((&name_foo, &Value::Table(tbl.clone()))),
+            expected
+        );
+        tbl.set(1, Value::Nil).unwrap();
+        assert_eq!(
+            Package::from_pair((&name_foo, &Value::Table(tbl.clone()))),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_os_package() {
+        let ubuntu = platform::Platform {
+            os: "linux".to_string(),
+            distro: Some("ubuntu".to_string()),
+            family: Some("debian".to_string()),
+            arch: "x86_64".to_string(),
+            wsl: false,
+            hostname: "box".to_string(),
+        };
+        let mut pkg = Package::new("hypr".to_string());
+        assert_eq!(pkg.os_package(&ubuntu), Some("hypr".to_string()));
+
+        pkg.package_name = Some(OSPackageName::Package(OSPackage::from([
+            ("arch".to_string(), "hyprland".to_string()),
+            ("debian".to_string(), "hyprland-deb".to_string()),
+        ])));
+        assert_eq!(pkg.os_package(&ubuntu), Some("hyprland-deb".to_string()));
+
+        pkg.package_name = Some(OSPackageName::AsPackage(false));
+        assert_eq!(pkg.os_package(&ubuntu), None);
+    }
+}
@@ -0,0 +1,104 @@
+use mlua::{HookTriggers, Lua, Result as LuaResult, VmState};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// Instructions run between two looks at the clock.
+const CHECK_EVERY: u32 = 100_000;
+
+/// What Lua code may use before mdot gives up on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Wall time of one evaluation of the config, or one call of an
+    /// `enabled` or hook function.
+    pub timeout: Duration,
+    /// Bytes the Lua state may allocate in total. LuaJIT manages its own
+    /// memory and ignores it.
+    pub memory: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            timeout: Duration::from_secs(10),
+            memory: 512 * 1024 * 1024,
+        }
+    }
+}
+
+thread_local! {
+    static TIMEOUT: Cell<Duration> = Cell::new(Limits::default().timeout);
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Caps the memory of `lua` and stops code running past the deadline
+/// [`budgeted`] sets.
+pub fn install(lua: &Lua, limits: &Limits) -> LuaResult<()> {
+    TIMEOUT.set(limits.timeout);
+    match lua.set_memory_limit(limits.memory) {
+        Ok(_) | Err(mlua::Error::MemoryControlNotAvailable) => (),
+        Err(err) => return Err(err),
+    }
+    watch(lua)
+}
+
+/// Stops code of `lua` running past the deadline [`budgeted`] sets,
+/// without capping its memory.
+pub fn watch(lua: &Lua) -> LuaResult<()> {
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(CHECK_EVERY),
+        move |_, _| match DEADLINE.get() {
+            Some(deadline) if Instant::now() > deadline => Err(mlua::Error::runtime(format!(
+                "gave up after {:?}, does it loop forever?",
+                TIMEOUT.get()
+            ))),
+            _ => Ok(VmState::Continue),
+        },
+    )
+}
+
+/// Runs `f`, which calls into Lua, with the timeout as its budget. A
+/// budgeted call made while another runs, such as the `enabled` function
+/// of a package read during the config evaluation, gets a budget of its
+/// own, and the outer deadline applies again once it returns.
+pub fn budgeted<T>(f: impl FnOnce() -> T) -> T {
+    let outer = DEADLINE.replace(Some(Instant::now() + TIMEOUT.get()));
+    let result = f();
+    DEADLINE.set(outer);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limits::*;
+
+    #[test]
+    fn test_limits() {
+        let lua = Lua::new();
+        let limits = Limits {
+            timeout: Duration::from_millis(100),
+            memory: 8 * 1024 * 1024,
+        };
+        install(&lua, &limits).unwrap();
+        let err = budgeted(|| lua.load("while true do end").exec()).unwrap_err();
+        assert!(err.to_string().contains("gave up after 100ms"), "{}", err);
+        // Without a budget the same loop would run on, so only count up.
+        lua.load("for i = 1, 1000000 do end").exec().unwrap();
+        assert_eq!(
+            budgeted(|| lua.load("return 1 + 1").eval::<i64>()).ok(),
+            Some(2)
+        );
+        // A call in a call fails on its own budget, not on the outer one.
+        let outer = budgeted(|| {
+            std::thread::sleep(Duration::from_millis(150));
+            let inner = budgeted(|| lua.load("for i = 1, 1000000 do end").exec());
+            (inner.is_ok(), lua.load("while true do end").exec().is_err())
+        });
+        assert_eq!(outer, (true, true));
+
+        if !cfg!(feature = "luajit") {
+            let err = budgeted(|| lua.load("return string.rep('x', 64 * 1024 * 1024)").exec())
+                .unwrap_err();
+            assert!(matches!(err, mlua::Error::MemoryError(_)), "{}", err);
+        }
+    }
+}
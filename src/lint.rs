@@ -0,0 +1,381 @@
+use crate::config::Config;
+use crate::deploy::{self, resolve_target};
+use crate::select::all_packages;
+use crate::{Context, Package, catch_fatal, config, template, walk, xdg};
+use ignore::gitignore::GitignoreBuilder;
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// A problem found in the config.
+#[derive(Debug, PartialEq)]
+pub struct Lint {
+    pub package: Option<String>,
+    pub message: String,
+    pub fix: Option<Fix>,
+}
+
+/// A change `mdot check --fix` can make to the config files.
+#[derive(Debug, PartialEq)]
+pub enum Fix {
+    /// Delete this pattern from the package's `excludes`.
+    RemoveExclude(String),
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(package) = &self.package {
+            write!(f, "[{}] ", package)?;
+        }
+        f.write_str(&self.message)?;
+        if self.fix.is_some() {
+            f.write_str(" (fixable with --fix)")?;
+        }
+        Ok(())
+    }
+}
+
+fn lint(package: &str, message: String) -> Lint {
+    Lint {
+        package: Some(package.to_string()),
+        message,
+        fix: None,
+    }
+}
+
+/// `excludes` patterns that match no file of the package.
+fn unused_excludes(ctx: &Context, config: &Config, pkg: &Package, out: &mut Vec<Lint>) {
+    let dir = ctx.package_dir(pkg);
+    let Ok(files) = walk::all_files(&dir, &config.options) else {
+        return;
+    };
+    for pattern in &pkg.excludes {
+        let mut builder = GitignoreBuilder::new(&dir);
+        if builder.add_line(None, &pattern.to_string_lossy()).is_err() {
+            continue;
+        }
+        let Ok(matcher) = builder.build() else {
+            continue;
+        };
+        let used = files.iter().any(|rel| {
+            let matched = matcher.matched_path_or_any_parents(dir.join(rel), false);
+            matched.is_ignore() || matched.is_whitelist()
+        });
+        if !used {
+            let pattern = pattern.to_string_lossy().into_owned();
+            out.push(Lint {
+                fix: Some(Fix::RemoveExclude(pattern.clone())),
+                ..lint(&pkg.name, format!("exclude '{}' matches nothing", pattern))
+            });
+        }
+    }
+}
+
+/// Link sources that do not exist, and globs matching nothing.
+fn missing_sources(ctx: &Context, config: &Config, pkg: &Package, out: &mut Vec<Lint>) {
+    let dir = ctx.package_dir(pkg);
+    for link in &pkg.links {
+        let found = if walk::is_glob(&link.source) {
+            walk::all_files(&dir, &config.options)
+                .and_then(|files| walk::glob(&link.source, files).map_err(std::io::Error::other))
+                .is_ok_and(|matches| !matches.is_empty())
+        } else {
+            dir.join(&link.source).exists()
+        };
+        if !found {
+            out.push(lint(
+                &pkg.name,
+                format!(
+                    "link source {} does not exist",
+                    dir.join(&link.source).display()
+                ),
+            ));
+        }
+    }
+}
+
+/// Absolute targets outside `$HOME` in packages not marked `root`.
+fn system_targets(ctx: &Context, config: &Config, pkg: &Package, out: &mut Vec<Lint>) {
+    if pkg.root {
+        return;
+    }
+    let home = dirs::home_dir().unwrap_or_default();
+    let vars = template::package_vars(config, pkg, &ctx.platform);
+    let targets = pkg
+        .links
+        .iter()
+        .flat_map(|link| &link.targets)
+        .chain(&pkg.default_target);
+    for target in targets {
+        let Ok(resolved) = resolve_target(target, &vars) else {
+            continue;
+        };
+        if resolved.is_absolute() && !resolved.starts_with(&home) {
+            out.push(lint(
+                &pkg.name,
+                format!(
+                    "target {} is outside $HOME, set 'root = true' on the package",
+                    resolved.display()
+                ),
+            ));
+        }
+    }
+}
+
+/// Targets planned by more than one package or link, and top-level
+/// dotfiles with an XDG location when `mdot.options.xdg` is set.
+fn planned_targets(ctx: &Context, config: &Config, packages: &[Package], out: &mut Vec<Lint>) {
+    let home = dirs::home_dir().unwrap_or_default();
+    let mut claims: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for link in deploy::plan(ctx, config, packages) {
+        if config.options.xdg
+            && let Some(xdg) = xdg::suggestion(&link.target, &home)
+        {
+            out.push(lint(
+                &link.package,
+                format!(
+                    "target {} could live in {}",
+                    link.target.display(),
+                    xdg.display()
+                ),
+            ));
+        }
+        claims.entry(link.target).or_default().push(link.package);
+    }
+    for (target, owners) in claims.into_iter().filter(|(_, o)| o.len() > 1) {
+        let owners: BTreeSet<String> = owners.into_iter().collect();
+        let owners: Vec<String> = owners.into_iter().collect();
+        out.push(Lint {
+            package: None,
+            message: format!(
+                "target {} is claimed more than once (by {})",
+                target.display(),
+                owners.join(", ")
+            ),
+            fix: None,
+        });
+    }
+}
+
+/// Top-level packages that no profile selects and nothing depends on.
+/// Without profiles every package is deployed, so none is unreachable.
+fn unreachable_packages(config: &Config, all: &[&Package], out: &mut Vec<Lint>) {
+    if config.profiles.is_empty() {
+        return;
+    }
+    let depended: BTreeSet<&str> = all
+        .iter()
+        .flat_map(|pkg| &pkg.depends)
+        .map(|dep| dep.name.as_str())
+        .collect();
+    for pkg in &config.packages {
+        let in_profile = config.profiles.values().any(|profile| {
+            profile.packages.contains(&pkg.name)
+                || pkg.tags.iter().any(|tag| profile.tags.contains(tag))
+        });
+        if !in_profile && !depended.contains(pkg.name.as_str()) {
+            out.push(lint(
+                &pkg.name,
+                "is in no profile and nothing depends on it".to_string(),
+            ));
+        }
+    }
+}
+
+/// Runs every lint rule over the whole config.
+pub fn check(ctx: &Context, config: &Config) -> Vec<Lint> {
+    let all = all_packages(config);
+    let mut lints = Vec::new();
+    for pkg in &all {
+        unused_excludes(ctx, config, pkg, &mut lints);
+        missing_sources(ctx, config, pkg, &mut lints);
+        system_targets(ctx, config, pkg, &mut lints);
+    }
+    let packages: Vec<Package> = all.iter().map(|&pkg| pkg.clone()).collect();
+    planned_targets(ctx, config, &packages, &mut lints);
+    unreachable_packages(config, &all, &mut lints);
+    lints
+}
+
+/// Replaces a byte range of a config file.
+type Edit = (Range<usize>, &'static str);
+
+/// Ways to delete `pattern` where it is written as a string literal in
+/// `src`: with the comma after it, with the comma before it, or as the
+/// whole value, which becomes `{}`.
+fn deletions(src: &str, pattern: &str) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    for quote in ['"', '\''] {
+        let literal = format!("{}{}{}", quote, pattern, quote);
+        for (start, _) in src.match_indices(&literal) {
+            let end = start + literal.len();
+            let after = &src[end..];
+            let comma = after.trim_start_matches([' ', '\t']);
+            if let Some(rest) = comma.strip_prefix(',') {
+                let rest = rest.trim_start_matches([' ', '\t']);
+                edits.push((start..src.len() - rest.len(), ""));
+            }
+            let before = src[..start].trim_end_matches([' ', '\t']);
+            if before.ends_with(',') {
+                edits.push((before.len() - 1..end, ""));
+            }
+            edits.push((start..end, "{}"));
+        }
+    }
+    edits
+}
+
+/// The `excludes` of every package of `config`, by package name.
+fn all_excludes(config: &Config) -> BTreeMap<String, Vec<PathBuf>> {
+    all_packages(config)
+        .into_iter()
+        .map(|pkg| (pkg.name.clone(), pkg.excludes.clone()))
+        .collect()
+}
+
+/// The `excludes` of the config evaluated again, if it still evaluates.
+fn reload_excludes(ctx: &Context) -> Option<BTreeMap<String, Vec<PathBuf>>> {
+    let ctx = ctx.reload();
+    catch_fatal(|| config::load(&ctx))
+        .ok()
+        .map(|config| all_excludes(&config))
+}
+
+/// Applies the fixes of `lints` to the config files. Each place the
+/// pattern is written is deleted in turn, and the first deletion after
+/// which the config evaluates to the same excludes, but for that one, is
+/// kept.
+pub fn fix(ctx: &Context, config: &Config, lints: &[Lint]) -> Result<usize, String> {
+    let mut sources = BTreeMap::new();
+    for file in &config.files {
+        let src = fs::read_to_string(file)
+            .map_err(|err| format!("failed to read {}: {}", file.display(), err))?;
+        sources.insert(file.clone(), src);
+    }
+    let mut excludes = all_excludes(config);
+    let mut fixed = 0;
+    for lint in lints {
+        let (Some(Fix::RemoveExclude(pattern)), Some(package)) = (&lint.fix, &lint.package) else {
+            continue;
+        };
+        let mut expected = excludes.clone();
+        if let Some(excludes) = expected.get_mut(package) {
+            excludes.retain(|exclude| exclude.as_os_str() != pattern.as_str());
+        }
+        let mut done = None;
+        'files: for (file, src) in &sources {
+            let write = |src: &str| {
+                fs::write(file, src)
+                    .map_err(|err| format!("failed to write {}: {}", file.display(), err))
+            };
+            for (range, replacement) in deletions(src, pattern) {
+                let mut edited = src.clone();
+                edited.replace_range(range, replacement);
+                write(&edited)?;
+                if reload_excludes(ctx).as_ref() == Some(&expected) {
+                    done = Some((file.clone(), edited));
+                    break 'files;
+                }
+            }
+            write(src)?;
+        }
+        match done {
+            Some((file, edited)) => {
+                info!("removed exclude '{}' from {}", pattern, file.display());
+                sources.insert(file, edited);
+                excludes = expected;
+                fixed += 1;
+            }
+            None => warn!(
+                "[{}] exclude '{}' cannot be removed on its own, fix it by hand",
+                package, pattern
+            ),
+        }
+    }
+    Ok(fixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_lint_and_fix() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-lint-{}", std::process::id()));
+        fs::create_dir_all(root.join("zsh")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::create_dir_all(root.join("copy")).unwrap();
+        fs::write(root.join("zsh/.zshrc"), "").unwrap();
+        fs::write(root.join("copy/.zshrc"), "").unwrap();
+        fs::write(root.join("zsh/README.md"), "").unwrap();
+        fs::write(root.join("etc/hosts"), "").unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.profiles.base = { "zsh" }
+            return {
+                { "zsh", excludes = { "README.md", "*.orig", "*" .. ".bak" },
+                  description = [[excludes = "*.bak"]],
+                  links = { { source = ".zshrc", targets = { "~/.zshrc", "~/.zshrc2" } },
+                            { source = "missing", targets = "~/.missing" } } },
+                { "etc", excludes = "nothing", links = { { source = "hosts", targets = "/etc/hosts" } } },
+                { "copy", depends = { "zsh" }, links = { { source = ".zshrc", targets = "~/.zshrc" } } },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let lints = lint::check(&ctx, &config);
+        let messages: Vec<String> = lints.iter().map(|l| l.to_string()).collect();
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            messages,
+            vec![
+                "[zsh] exclude '*.orig' matches nothing (fixable with --fix)".to_string(),
+                "[zsh] exclude '*.bak' matches nothing (fixable with --fix)".to_string(),
+                format!(
+                    "[zsh] link source {} does not exist",
+                    root.join("zsh/missing").display()
+                ),
+                "[etc] exclude 'nothing' matches nothing (fixable with --fix)".to_string(),
+                "[etc] target /etc/hosts is outside $HOME, set 'root = true' on the package"
+                    .to_string(),
+                format!(
+                    "target {} is claimed more than once (by copy, zsh)",
+                    home.join(".zshrc").display()
+                ),
+                "[etc] is in no profile and nothing depends on it".to_string(),
+                "[copy] is in no profile and nothing depends on it".to_string(),
+            ]
+        );
+
+        // The only '*.bak' written is not the exclude, so it stays.
+        assert_eq!(lint::fix(&ctx, &config, &lints), Ok(2));
+        let src = fs::read_to_string(root.join("main.lua")).unwrap();
+        assert!(src.contains(r#"excludes = { "README.md", "*" .. ".bak" },"#));
+        assert!(src.contains(r#"[[excludes = "*.bak"]]"#));
+        assert!(src.contains(r#"{ "etc", excludes = {}, links"#));
+        let config = config::load(&ctx);
+        let fixable: Vec<String> = lint::check(&ctx, &config)
+            .iter()
+            .filter(|l| l.fix.is_some())
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(
+            fixable,
+            ["[zsh] exclude '*.bak' matches nothing (fixable with --fix)"]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,423 @@
+use crate::config::Config;
+use crate::fetch::Signature;
+use crate::ssh::Identity;
+use crate::state::{escape, unescape};
+use crate::{Context, HookAction, Package, base, fetch, git, ordered_pairs, remote};
+use mlua::Value;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const LOCK_FILE: &str = "mdot.lock";
+const HEADER: &str = "# mdot lock v3, written by `mdot lock`";
+
+/// One thing a locked deploy may do.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Entry {
+    /// `package <name>`
+    Package(String),
+    /// `hook <package> <kind> <hash>`, the SHA-256 of a command the deploy
+    /// runs for the package: an `on_install`, `on_deploy` or `action` hook,
+    /// `assets.apply`, an ssh `identity` command, `reload` or `nvim_health`.
+    Hook {
+        package: String,
+        hook: String,
+        hash: String,
+    },
+    /// `fetch <package> <url> <digest>`, the checksum and signing key a
+    /// download is checked with, comma separated, or `-` for neither.
+    Fetch {
+        package: String,
+        url: String,
+        digest: String,
+    },
+    /// `plugin <file> <sha256>`, a plugin file defining actions and commands.
+    Plugin { file: String, hash: String },
+    /// `repo <url> <commit>`, the checkout of the `extends` base or a remote.
+    Repo { url: String, commit: String },
+    /// `config <file> <sha256>`, the entry file or one it includes. Function
+    /// hooks are hashed without the values they capture, which these cover.
+    Config { file: String, hash: String },
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Package(name) => write!(f, "package '{}'", name),
+            Entry::Hook {
+                package,
+                hook,
+                hash,
+            } => write!(f, "[{}] {} hook #{}", package, hook, hash),
+            Entry::Fetch {
+                package,
+                url,
+                digest,
+            } => write!(f, "[{}] fetch {} ({})", package, url, digest),
+            Entry::Plugin { file, hash } => write!(f, "plugin {} #{}", file, hash),
+            Entry::Repo { url, commit } => write!(f, "{} at {}", url, commit),
+            Entry::Config { file, hash } => write!(f, "config {} #{}", file, hash),
+        }
+    }
+}
+
+/// Appends a stable encoding of `value` to `out`: tables in the order of
+/// [`ordered_pairs`], functions as their bytecode.
+fn encode(value: &Value, out: &mut Vec<u8>, depth: usize) {
+    fn text(out: &mut Vec<u8>, kind: &str, bytes: &[u8]) {
+        out.extend(format!("{}:{}:", kind, bytes.len()).as_bytes());
+        out.extend(bytes);
+    }
+    match value {
+        Value::String(s) => text(out, "s", &s.as_bytes()),
+        Value::Function(f) => text(out, "f", &f.dump(true)),
+        Value::Table(tbl) if depth < 32 => {
+            out.extend(b"{");
+            for (key, value) in ordered_pairs(tbl, "actions") {
+                encode(&key, out, depth + 1);
+                encode(&value, out, depth + 1);
+            }
+            out.extend(b"}");
+        }
+        v => text(
+            out,
+            v.type_name(),
+            v.to_string().unwrap_or_default().as_bytes(),
+        ),
+    }
+}
+
+/// What a hook runs, in full, for its hash: the command text, the bytecode
+/// of a function, or the name and arguments of an action.
+fn contents(action: &HookAction) -> Vec<u8> {
+    match action {
+        HookAction::Command(cmd) => cmd.as_bytes().to_vec(),
+        HookAction::Sandboxed(cmd) => format!("sandboxed:{}", cmd).into_bytes(),
+        HookAction::Function(hook) => hook.dump(true),
+        HookAction::Action { name, args } => {
+            let mut out = format!("action:{}:", name).into_bytes();
+            encode(args, &mut out, 0);
+            out
+        }
+    }
+}
+
+/// The packages, the SHA-256 of every command they run, their downloads,
+/// the plugins, the config files and the commits of other repos a deploy
+/// is allowed to use,
+/// kept as tab separated records in `mdot.lock` at the repo root.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Lock {
+    pub entries: BTreeSet<Entry>,
+}
+
+impl Lock {
+    pub fn path(root: &Path) -> PathBuf {
+        root.join(LOCK_FILE)
+    }
+
+    /// What deploying `packages` of `config` runs.
+    pub fn from_packages(ctx: &Context, config: &Config, packages: &[Package]) -> Lock {
+        let mut entries = BTreeSet::new();
+        for pkg in packages {
+            entries.insert(Entry::Package(pkg.name.clone()));
+            let mut hook = |hook: &str, contents: &[u8]| {
+                entries.insert(Entry::Hook {
+                    package: pkg.name.clone(),
+                    hook: hook.to_string(),
+                    hash: fetch::sha256(contents),
+                });
+            };
+            for (name, actions) in [
+                ("on_install", &pkg.on_install),
+                ("on_deploy", &pkg.on_deploy),
+                ("action", &pkg.actions),
+            ] {
+                for action in actions {
+                    hook(name, &contents(action));
+                }
+            }
+            if let Some(apply) = pkg.assets.as_ref().and_then(|a| a.apply.as_ref()) {
+                hook("assets.apply", apply.as_bytes());
+            }
+            for host in &pkg.ssh_hosts {
+                if let Some(Identity::Command(command)) = &host.identity {
+                    hook("identity", command.as_bytes());
+                }
+            }
+            for reload in &pkg.reload {
+                hook("reload", reload.describe().as_bytes());
+            }
+            if let Some(command) = &pkg.nvim_health {
+                hook("nvim_health", command.as_bytes());
+            }
+            for fetch in &pkg.fetch {
+                let mut digest = Vec::new();
+                match (&fetch.sha256, &fetch.sha512) {
+                    (Some(sha256), _) => digest.push(format!("sha256:{}", sha256)),
+                    (None, Some(sha512)) => digest.push(format!("sha512:{}", sha512)),
+                    (None, None) => {}
+                }
+                match &fetch.signature {
+                    Some(Signature::Minisign(key)) => digest.push(format!("minisign:{}", key)),
+                    Some(Signature::Gpg(key)) => {
+                        let key = fs::read(ctx.package_dir(pkg).join(key))
+                            .map(fetch::sha256)
+                            .unwrap_or_else(|_| "-".to_string());
+                        digest.push(format!("gpg:{}", key));
+                    }
+                    None => {}
+                }
+                entries.insert(Entry::Fetch {
+                    package: pkg.name.clone(),
+                    url: fetch.url.clone(),
+                    digest: match digest.is_empty() {
+                        true => "-".to_string(),
+                        false => digest.join(","),
+                    },
+                });
+            }
+        }
+        if let Ok(files) = fs::read_dir(&ctx.plugins_dir) {
+            for path in files.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+                if path.extension().is_none_or(|ext| ext != "lua") || !path.is_file() {
+                    continue;
+                }
+                entries.insert(Entry::Plugin {
+                    file: path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into(),
+                    hash: fs::read(&path).map(fetch::sha256).unwrap_or_default(),
+                });
+            }
+        }
+        let mut repos: Vec<(String, PathBuf)> = Vec::new();
+        if let Some(url) = &config.base {
+            repos.push((url.clone(), base::dir(ctx, url)));
+        }
+        for remote in remote::load(&ctx.config_path).unwrap_or_default() {
+            repos.push((remote.url.clone(), remote::dir(ctx, &remote)));
+        }
+        for (url, dir) in repos {
+            entries.insert(Entry::Repo {
+                url,
+                commit: git::head(&dir).unwrap_or_else(|_| "-".to_string()),
+            });
+        }
+        for file in &config.files {
+            entries.insert(Entry::Config {
+                file: file
+                    .strip_prefix(&ctx.config_path)
+                    .unwrap_or(file)
+                    .display()
+                    .to_string(),
+                hash: fs::read(file).map(fetch::sha256).unwrap_or_default(),
+            });
+        }
+        Lock { entries }
+    }
+
+    /// Reads the lockfile at `path`.
+    pub fn load(path: &Path) -> Result<Lock, String> {
+        let contents = fs::read_to_string(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => {
+                format!(
+                    "{} does not exist, create it with `mdot lock`",
+                    path.display()
+                )
+            }
+            _ => format!("failed to read {}: {}", path.display(), err),
+        })?;
+        if contents.starts_with("# mdot lock v1") || contents.starts_with("# mdot lock v2") {
+            return Err(format!(
+                "{} was written by an older mdot, run `mdot lock` again after reviewing the config",
+                path.display()
+            ));
+        }
+        let mut lock = Lock::default();
+        for (n, line) in contents.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<String> = line.split('\t').map(unescape).collect();
+            let entry = match fields.as_slice() {
+                [kind, name] if kind == "package" => Entry::Package(name.clone()),
+                [kind, package, hook, hash] if kind == "hook" => Entry::Hook {
+                    package: package.clone(),
+                    hook: hook.clone(),
+                    hash: hash.clone(),
+                },
+                [kind, package, url, digest] if kind == "fetch" => Entry::Fetch {
+                    package: package.clone(),
+                    url: url.clone(),
+                    digest: digest.clone(),
+                },
+                [kind, file, hash] if kind == "plugin" => Entry::Plugin {
+                    file: file.clone(),
+                    hash: hash.clone(),
+                },
+                [kind, url, commit] if kind == "repo" => Entry::Repo {
+                    url: url.clone(),
+                    commit: commit.clone(),
+                },
+                [kind, file, hash] if kind == "config" => Entry::Config {
+                    file: file.clone(),
+                    hash: hash.clone(),
+                },
+                _ => {
+                    return Err(format!("{}:{}: invalid lock record", path.display(), n + 1));
+                }
+            };
+            lock.entries.insert(entry);
+        }
+        Ok(lock)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = format!("{}\n", HEADER);
+        for entry in &self.entries {
+            let fields = match entry {
+                Entry::Package(name) => vec!["package", name],
+                Entry::Hook {
+                    package,
+                    hook,
+                    hash,
+                } => vec!["hook", package, hook, hash],
+                Entry::Fetch {
+                    package,
+                    url,
+                    digest,
+                } => vec!["fetch", package, url, digest],
+                Entry::Plugin { file, hash } => vec!["plugin", file, hash],
+                Entry::Repo { url, commit } => vec!["repo", url, commit],
+                Entry::Config { file, hash } => vec!["config", file, hash],
+            };
+            let fields: Vec<String> = fields.into_iter().map(escape).collect();
+            out.push_str(&fields.join("\t"));
+            out.push('\n');
+        }
+        fs::write(path, out)
+    }
+
+    /// The entries of `planned` that this lock does not allow.
+    pub fn violations<'a>(&'a self, planned: &'a Lock) -> Vec<&'a Entry> {
+        planned.entries.difference(&self.entries).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::*;
+    use crate::*;
+
+    #[test]
+    fn test_lock() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-lock-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let load = |lua: &str| {
+            fs::write(root.join("main.lua"), lua).unwrap();
+            let ctx = Context::builder()
+                .entry(root.join("main.lua"))
+                .plugins_dir(root.join("plugins"))
+                .build();
+            let config = config::load(&ctx);
+            Lock::from_packages(&ctx, &config, &config.packages)
+        };
+        let hook = |package: &str, hook: &str, contents: &str| {
+            format!(
+                "{}",
+                Entry::Hook {
+                    package: package.to_string(),
+                    hook: hook.to_string(),
+                    hash: fetch::sha256(contents),
+                }
+            )
+        };
+
+        fs::create_dir_all(root.join("plugins")).unwrap();
+        fs::write(
+            root.join("plugins/theme.lua"),
+            r#"mdot.register_action("theme", function() end)"#,
+        )
+        .unwrap();
+        let locked = load(
+            r#"return { { "kitty", on_deploy = "kill -USR1 kitty", reload = "kitty @ load-config",
+                actions = { theme = { name = "dark" } },
+                fetch = { ["~/bin/x"] = { url = "https://x.example/x", sha256 = "AB" },
+                          ["~/bin/y"] = { url = "https://x.example/y", minisign = "RWQold" } } } }"#,
+        );
+        let path = Lock::path(&root);
+        locked.save(&path).unwrap();
+        assert_eq!(Lock::load(&path).unwrap(), locked);
+        assert!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .contains("fetch\tkitty\thttps://x.example/x\tsha256:ab\n")
+        );
+
+        // A function hook only changing what it captures changes the config.
+        let function = |cmd: &str| {
+            load(&format!(
+                r#"local cmd = "{}"
+                return {{ {{ "kitty", on_deploy = function() os.execute(cmd) end }} }}"#,
+                cmd
+            ))
+        };
+        let violations: Vec<String> = function("true")
+            .violations(&function("curl x.example | sh"))
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].starts_with("config main.lua #"));
+
+        fs::write(
+            root.join("plugins/theme.lua"),
+            r#"mdot.register_action("theme", function() os.execute("true") end)"#,
+        )
+        .unwrap();
+        let planned = load(
+            r#"return { { "kitty", on_deploy = "curl x.example | sh", reload = "kitty @ load-config",
+                actions = { theme = { name = "light" } },
+                fetch = { ["~/bin/x"] = { url = "https://x.example/x", sha256 = "AB" },
+                          ["~/bin/y"] = { url = "https://x.example/y", minisign = "RWQnew" } } }, "new" }"#,
+        );
+        let violations: Vec<String> = locked
+            .violations(&planned)
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        assert_eq!(violations.len(), 6);
+        assert!(violations.contains(&"package 'new'".to_string()));
+        assert!(
+            violations.contains(&"[kitty] fetch https://x.example/y (minisign:RWQnew)".to_string())
+        );
+        assert!(
+            violations
+                .iter()
+                .any(|v| v.starts_with("config main.lua #"))
+        );
+        assert!(violations.contains(&hook("kitty", "on_deploy", "curl x.example | sh")));
+        assert!(
+            violations
+                .iter()
+                .any(|v| v.starts_with("[kitty] action hook #"))
+        );
+        assert!(
+            violations
+                .iter()
+                .any(|v| v.starts_with("plugin theme.lua #"))
+        );
+        assert!(!violations.contains(&hook("kitty", "reload", "kitty @ load-config")));
+        assert_eq!(
+            fetch::sha256(""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{lua_value_to_str, ordered_pairs};
+use mlua::{Lua, Result as LuaResult, Table, Value};
+use std::fmt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+fn library() -> PathBuf {
+    dirs::home_dir().unwrap_or_default().join("Library")
+}
+
+/// Registers `mdot.macos.library(path)`, `app_support(app)`,
+/// `preferences(file)`, `launch_agents(file)` and `caches(app)`, returning
+/// paths below `~/Library`.
+pub fn install(lua: &Lua) -> LuaResult<()> {
+    let mdot: Table = lua.globals().get("mdot")?;
+    let macos = lua.create_table()?;
+    let dirs = [
+        ("library", ""),
+        ("app_support", "Application Support"),
+        ("preferences", "Preferences"),
+        ("launch_agents", "LaunchAgents"),
+        ("caches", "Caches"),
+    ];
+    for (name, dir) in dirs {
+        macos.set(
+            name,
+            lua.create_function(move |_, path: Option<String>| {
+                let mut full = library().join(dir);
+                if let Some(path) = path {
+                    full.push(path);
+                }
+                Ok(full.display().to_string())
+            })?,
+        )?;
+    }
+    mdot.set("macos", macos)?;
+    Ok(())
+}
+
+/// A value written with `defaults write`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DefaultsValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl DefaultsValue {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        Ok(match value {
+            Value::Boolean(b) => DefaultsValue::Bool(*b),
+            Value::Integer(i) => DefaultsValue::Int(*i),
+            Value::Number(n) => DefaultsValue::Float(*n),
+            Value::String(s) => DefaultsValue::String(s.to_string_lossy()),
+            v => return Err(format!("unsupported value type {}", v.type_name())),
+        })
+    }
+
+    fn type_flag(&self) -> &'static str {
+        match self {
+            DefaultsValue::Bool(_) => "-bool",
+            DefaultsValue::Int(_) => "-int",
+            DefaultsValue::Float(_) => "-float",
+            DefaultsValue::String(_) => "-string",
+        }
+    }
+}
+
+/// Formats the value the way `defaults read` prints it.
+impl fmt::Display for DefaultsValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DefaultsValue::Bool(b) => write!(f, "{}", *b as u8),
+            DefaultsValue::Int(i) => write!(f, "{}", i),
+            DefaultsValue::Float(n) => write!(f, "{}", n),
+            DefaultsValue::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// One `defaults` or `settings` entry of a package: the domain or schema,
+/// the key and the value.
+pub type Setting = (String, String, DefaultsValue);
+
+/// Parses `defaults = { ["com.apple.dock"] = { autohide = true } }`, and
+/// `settings` of the same shape.
+pub fn defaults_from_value(field: &str, value: &Value) -> Vec<Setting> {
+    let Value::Table(domains) = value else {
+        fatal!(
+            "'{}' expected type 'Table', got {}",
+            field,
+            value.type_name()
+        );
+    };
+    let mut out = Vec::new();
+    for (domain, keys) in ordered_pairs(domains, field) {
+        let domain = lua_value_to_str(&domain);
+        let Value::Table(keys) = keys else {
+            fatal!(
+                "'{}.{}' expected type 'Table', got {}",
+                field,
+                domain,
+                keys.type_name()
+            );
+        };
+        for (key, value) in ordered_pairs(&keys, &format!("{}.{}", field, domain)) {
+            let key = lua_value_to_str(&key);
+            let value = DefaultsValue::from_value(&value)
+                .unwrap_or_else(|err| fatal!("'{}.{}.{}': {}", field, domain, key, err));
+            out.push((domain.clone(), key, value));
+        }
+    }
+    out.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+    out
+}
+
+pub fn write_args(domain: &str, key: &str, value: &DefaultsValue) -> Vec<String> {
+    vec![
+        "write".to_string(),
+        domain.to_string(),
+        key.to_string(),
+        value.type_flag().to_string(),
+        value.to_string(),
+    ]
+}
+
+/// The current value of `key` in `domain`, if it is set.
+pub fn read(domain: &str, key: &str) -> Option<String> {
+    let out = Command::new("defaults")
+        .args(["read", domain, key])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::macos::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_defaults() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-macos-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return { { "dock",
+                links = { ["x.plist"] = mdot.macos.preferences("x.plist") },
+                defaults = { ["com.apple.dock"] = { autohide = true, tilesize = 48, orientation = "left" } },
+            } }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let pkg = &config.packages[0];
+        assert_eq!(
+            pkg.links[0].targets[0],
+            dirs::home_dir()
+                .unwrap()
+                .join("Library/Preferences/x.plist")
+        );
+        assert_eq!(
+            pkg.defaults[0],
+            (
+                "com.apple.dock".to_string(),
+                "autohide".to_string(),
+                DefaultsValue::Bool(true)
+            )
+        );
+        assert_eq!(
+            write_args(&pkg.defaults[2].0, &pkg.defaults[2].1, &pkg.defaults[2].2),
+            ["write", "com.apple.dock", "tilesize", "-int", "48"]
+        );
+        assert_eq!(DefaultsValue::Bool(false).to_string(), "0");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use crate::cli::Cli;
+use clap::CommandFactory;
+use clap_mangen::Man;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The Lua schema, as documented at the top of main.rs.
+const SCHEMA_SOURCE: &str = include_str!("lib.rs");
+
+const DESCRIPTION: &str = "mdot evaluates a Lua config that returns a list of packages and links \
+     their files into place, installs their OS packages and runs their hooks.";
+
+const ENVIRONMENT: &[(&str, &str)] = &[
+    ("MDOT_CONFIG", "Entry config file, like --config."),
+    ("MDOT_PROFILE", "Profile to select, like --profile."),
+    (
+        "MDOT_APPNAME",
+        "Directory name used below the XDG directories, \"mdot\" by default.",
+    ),
+    (
+        "MDOT_STATE_DIR, MDOT_DATA_DIR, MDOT_CACHE_DIR",
+        "Absolute paths overriding where the state, data and cache are kept.",
+    ),
+    (
+        "MDOT_FACT_*",
+        "Override the fact of the same name, e.g. MDOT_FACT_GPU=nvidia.",
+    ),
+    (
+        "XDG_CONFIG_HOME, XDG_DATA_HOME, XDG_STATE_HOME, XDG_CACHE_HOME",
+        "Base directories of the config, data, state and cache.",
+    ),
+    ("VISUAL, EDITOR", "Editor opened by mdot edit."),
+    (
+        "CLICOLOR_FORCE",
+        "Keep colors when stdout is not a terminal.",
+    ),
+];
+
+const EXIT_STATUS: &[(&str, &str)] = &[
+    ("0", "Success."),
+    (
+        "1",
+        "The config failed to load, a command failed, a check found missing \
+         binaries, or a conflict was left unanswered without a terminal.",
+    ),
+    ("2", "Invalid command line arguments."),
+];
+
+/// Escapes `text` for roff.
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    match text.starts_with(['.', '\'']) {
+        true => format!("\\&{}", text),
+        false => text,
+    }
+}
+
+fn bold(text: &str) -> String {
+    format!("\\fB{}\\fR", escape(text))
+}
+
+/// The `alias`, `class` and `field` comments documenting the Lua schema.
+fn schema() -> Vec<&'static str> {
+    SCHEMA_SOURCE
+        .lines()
+        .skip_while(|line| !line.starts_with("//"))
+        .take_while(|line| line.starts_with("//"))
+        .map(|line| line.trim_start_matches("//").trim_start())
+        .collect()
+}
+
+fn definitions(out: &mut String, title: &str, items: &[(&str, &str)]) {
+    out.push_str(&format!(".SH {}\n", title));
+    for (name, description) in items {
+        out.push_str(&format!(".TP\n{}\n{}\n", bold(name), escape(description)));
+    }
+}
+
+/// The clap definitions of mdot as its pages show them.
+fn command() -> clap::Command {
+    let mut cli = Cli::command()
+        .long_about(DESCRIPTION)
+        .disable_help_subcommand(true);
+    cli.build();
+    cli
+}
+
+fn page(cmd: clap::Command) -> Man {
+    let title = cmd
+        .get_display_name()
+        .unwrap_or(cmd.get_name())
+        .to_uppercase();
+    Man::new(cmd)
+        .title(title)
+        .source(format!("mdot {}", env!("CARGO_PKG_VERSION")))
+        .manual("User Commands")
+}
+
+/// The man page of mdot in roff: the clap definitions rendered by
+/// clap_mangen, then the schema, the environment and the exit status.
+pub fn render() -> String {
+    let mut roff = Vec::new();
+    page(command())
+        .render(&mut roff)
+        .expect("writing to a Vec does not fail");
+    let mut out = String::from_utf8_lossy(&roff).into_owned();
+    out.push_str(".SH CONFIGURATION\n");
+    out.push_str("The entry file returns a list of packages with this schema:\n");
+    out.push_str(".PP\n.nf\n.RS\n");
+    for line in schema() {
+        out.push_str(&escape(line));
+        out.push('\n');
+    }
+    out.push_str(".RE\n.fi\n");
+    definitions(&mut out, "ENVIRONMENT", ENVIRONMENT);
+    definitions(&mut out, "EXIT STATUS", EXIT_STATUS);
+    out
+}
+
+/// Writes the man page of mdot and one per subcommand, such as
+/// `mdot-deploy.1`, into `dir` for packaging, returning their paths.
+pub fn generate(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fn subcommands(cmd: &clap::Command, dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+        for sub in cmd.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+            paths.push(page(sub.clone()).generate_to(dir)?);
+            subcommands(sub, dir, paths)?;
+        }
+        Ok(())
+    }
+    fs::create_dir_all(dir)?;
+    let main = dir.join("mdot.1");
+    fs::write(&main, render())?;
+    let mut paths = vec![main];
+    subcommands(&command(), dir, &mut paths)?;
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::man::*;
+    use std::env;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(escape(".hidden --x"), "\\&.hidden \\-\\-x");
+        let page = render();
+        assert!(page.contains("\n.TH MDOT 1 "));
+        assert!(page.contains("\\fB\\-y\\fR, \\fB\\-\\-assume\\-yes\\fR"));
+        assert!(page.contains("mdot\\-deploy(1)"));
+        assert!(page.contains("field links? LinksArraySpec\n"));
+        assert!(page.contains(".SH EXIT STATUS\n"));
+        assert!(!page.contains("mdot\\-complete"));
+
+        let dir = env::temp_dir().join(format!("mdot-man-{}", std::process::id()));
+        let paths = generate(&dir).unwrap();
+        assert_eq!(paths[0], dir.join("mdot.1"));
+        assert!(paths.contains(&dir.join("mdot-git-sync.1")));
+        assert!(!dir.join("mdot-complete.1").exists());
+        let deploy = fs::read_to_string(dir.join("mdot-deploy.1")).unwrap();
+        assert!(deploy.contains("\n.TH MDOT-DEPLOY 1 "));
+        assert!(deploy.contains("\\fB\\-\\-tag\\fR"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
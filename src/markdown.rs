@@ -0,0 +1,97 @@
+use colored::*;
+
+/// Styles `code`, **strong** and *emphasis* spans of one line.
+fn inline(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(['`', '*', '_']) {
+        out.push_str(&rest[..start]);
+        let marker = if rest[start..].starts_with("**") || rest[start..].starts_with("__") {
+            &rest[start..start + 2]
+        } else {
+            &rest[start..start + 1]
+        };
+        let body = &rest[start + marker.len()..];
+        let intraword = out.chars().next_back().is_some_and(char::is_alphanumeric);
+        if marker.starts_with('_') && intraword {
+            out.push_str(marker);
+            rest = body;
+            continue;
+        }
+        let Some(end) = body.find(marker).filter(|&end| end > 0) else {
+            out.push_str(marker);
+            rest = body;
+            continue;
+        };
+        let text = &body[..end];
+        let styled = match marker {
+            "`" => text.cyan(),
+            "**" | "__" => text.bold(),
+            _ => text.italic(),
+        };
+        out.push_str(&styled.to_string());
+        rest = &body[end + marker.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Renders markdown for the terminal: headings, lists, quotes, code blocks
+/// and inline styles. Everything else is printed as written.
+pub fn render(markdown: &str) -> String {
+    let mut out = Vec::new();
+    let mut in_code = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            out.push(format!("    {}", line.dimmed()));
+            continue;
+        }
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        if level > 0 && trimmed[level..].starts_with(' ') {
+            let title = trimmed[level..].trim();
+            out.push(match level {
+                1 => title.bold().underline().to_string(),
+                _ => title.bold().to_string(),
+            });
+            continue;
+        }
+        let indent = &line[..line.len() - trimmed.len()];
+        if let Some(item) = ["- ", "* ", "+ "]
+            .iter()
+            .find_map(|bullet| trimmed.strip_prefix(bullet))
+        {
+            out.push(format!("{}  • {}", indent, inline(item)));
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            out.push(format!(
+                "{}  │ {}",
+                indent,
+                inline(quote.trim_start()).dimmed()
+            ));
+        } else {
+            out.push(inline(line));
+        }
+    }
+    out.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markdown::*;
+
+    #[test]
+    fn test_render() {
+        colored::control::set_override(false);
+        let rendered = render(
+            "# kitty\n\nA *fast* terminal, see `kitty.conf`.\n\n- one\n  * two\n> note\n```\nlet x = 1;\n```\n2 * 3 and snake_case_name",
+        );
+        assert_eq!(
+            rendered,
+            "kitty\n\nA fast terminal, see kitty.conf.\n\n  • one\n    • two\n  │ note\n    let x = 1;\n2 * 3 and snake_case_name"
+        );
+    }
+}
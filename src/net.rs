@@ -0,0 +1,248 @@
+use crate::state::{escape, unescape};
+use log::warn;
+use mlua::{Lua, Result as LuaResult, Table};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Results of the probes, in the cache dir.
+const CACHE_FILE: &str = "net";
+/// How long a probe result is trusted.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Port probed when a host is given without one.
+const DEFAULT_PORT: u16 = 443;
+/// Probed by `online()`; addresses rather than names so that a broken
+/// resolver does not make a working connection look offline.
+const ONLINE_HOSTS: &[&str] = &["1.1.1.1:443", "8.8.8.8:443"];
+/// The longest wait between two attempts of a retried action.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// `--offline`: network checks fail without probing and network
+/// dependent actions are skipped.
+pub fn set_offline() {
+    OFFLINE.store(true, Ordering::Relaxed);
+}
+
+pub fn offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// Whether `action` has to be skipped because of `--offline`, warning
+/// when it does.
+pub fn skip(action: &str) -> bool {
+    if offline() {
+        warn!("offline, skipping {}", action);
+    }
+    offline()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The addresses of `host`, which may carry a port.
+fn addresses(host: &str) -> Vec<SocketAddr> {
+    host.to_socket_addrs()
+        .or_else(|_| (host, DEFAULT_PORT).to_socket_addrs())
+        .map(|addrs| addrs.collect())
+        .unwrap_or_default()
+}
+
+fn probe(host: &str) -> bool {
+    addresses(host)
+        .iter()
+        .any(|addr| TcpStream::connect_timeout(addr, TIMEOUT).is_ok())
+}
+
+/// Probe results keyed by host, with the time they were taken.
+fn load(path: &Path) -> BTreeMap<String, (u64, bool)> {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<String> = line.split('\t').map(unescape).collect();
+            match fields.as_slice() {
+                [host, time, up] => Some((host.clone(), (time.parse().ok()?, up == "1"))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn save(path: &Path, results: &BTreeMap<String, (u64, bool)>) {
+    let out: String = results
+        .iter()
+        .map(|(host, (time, up))| format!("{}\t{}\t{}\n", escape(host), time, *up as u8))
+        .collect();
+    let written = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(path, out));
+    if let Err(err) = written {
+        warn!("failed to write {}: {}", path.display(), err);
+    }
+}
+
+/// Whether `host` accepts connections, probing it only when the cached
+/// result in `cache_dir` is older than a minute.
+fn reachable_with(cache_dir: &Path, host: &str, probe: impl Fn(&str) -> bool) -> bool {
+    let path = cache_dir.join(CACHE_FILE);
+    let mut results = load(&path);
+    let now = now();
+    if let Some((time, up)) = results.get(host)
+        && now.saturating_sub(*time) < CACHE_TTL.as_secs()
+    {
+        return *up;
+    }
+    let up = probe(host);
+    results.retain(|_, (time, _)| now.saturating_sub(*time) < CACHE_TTL.as_secs());
+    results.insert(host.to_string(), (now, up));
+    save(&path, &results);
+    up
+}
+
+/// Whether `host`, e.g. `github.com` or `git.example.com:22`, accepts
+/// connections. Always false with `--offline`.
+pub fn reachable(cache_dir: &Path, host: &str) -> bool {
+    !offline() && reachable_with(cache_dir, host, probe)
+}
+
+/// Whether the machine can reach the internet.
+pub fn online(cache_dir: &Path) -> bool {
+    ONLINE_HOSTS.iter().any(|host| reachable(cache_dir, host))
+}
+
+fn retry_with<T>(
+    action: &str,
+    retries: u32,
+    delay: Duration,
+    mut attempt: impl FnMut() -> Result<T, String>,
+) -> Result<T, String> {
+    let mut delay = delay;
+    for n in 0..=retries {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(err) if n == retries => {
+                return Err(format!(
+                    "{} failed after {} attempt{}: {}; check the connection, raise \
+                     `retries`, or pass --offline to skip it",
+                    action,
+                    n + 1,
+                    if n == 0 { "" } else { "s" },
+                    err
+                ));
+            }
+            Err(err) => {
+                warn!(
+                    "{} failed: {}, retrying in {}s ({}/{})",
+                    action,
+                    err,
+                    delay.as_secs(),
+                    n + 1,
+                    retries
+                );
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_DELAY);
+            }
+        }
+    }
+    unreachable!()
+}
+
+/// Runs the network dependent `attempt` up to `retries` more times when
+/// it fails, waiting a second before the first retry and twice as long
+/// before each next one, up to a minute.
+pub fn retry<T>(
+    action: &str,
+    retries: u32,
+    attempt: impl FnMut() -> Result<T, String>,
+) -> Result<T, String> {
+    retry_with(action, retries, Duration::from_secs(1), attempt)
+}
+
+/// Registers `mdot.net.online()` and `mdot.net.reachable(host)`.
+pub fn install(lua: &Lua, cache_dir: &Path) -> LuaResult<()> {
+    let mdot: Table = lua.globals().get("mdot")?;
+    let net = lua.create_table()?;
+    let dir = cache_dir.to_path_buf();
+    net.set(
+        "online",
+        lua.create_function(move |_, ()| Ok(online(&dir)))?,
+    )?;
+    let dir = cache_dir.to_path_buf();
+    net.set(
+        "reachable",
+        lua.create_function(move |_, host: String| Ok(reachable(&dir, &host)))?,
+    )?;
+    mdot.set("net", net)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::*;
+    use std::cell::Cell;
+    use std::env;
+
+    #[test]
+    fn test_reachable_cache() {
+        let root = env::temp_dir().join(format!("mdot-net-{}", std::process::id()));
+        assert_eq!(
+            addresses("127.0.0.1:22"),
+            ["127.0.0.1:22".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            addresses("127.0.0.1"),
+            ["127.0.0.1:443".parse::<SocketAddr>().unwrap()]
+        );
+
+        let probes = Cell::new(0);
+        let probe = |host: &str| {
+            probes.set(probes.get() + 1);
+            host == "up.example"
+        };
+        assert!(reachable_with(&root, "up.example", probe));
+        assert!(!reachable_with(&root, "down.example", probe));
+        assert!(reachable_with(&root, "up.example", probe));
+        assert!(!reachable_with(&root, "down.example", probe));
+        assert_eq!(probes.get(), 2);
+
+        let stale = format!("up.example\t{}\t1\n", now() - CACHE_TTL.as_secs());
+        fs::write(root.join(CACHE_FILE), stale).unwrap();
+        assert!(reachable_with(&root, "up.example", probe));
+        assert_eq!(probes.get(), 3);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_retry() {
+        let attempts = Cell::new(0);
+        let flaky = || {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                3 => Ok(attempts.get()),
+                _ => Err("mirror timed out".to_string()),
+            }
+        };
+        assert_eq!(retry_with("pull", 3, Duration::ZERO, flaky), Ok(3));
+        attempts.set(0);
+        assert_eq!(
+            retry_with("pull", 1, Duration::ZERO, flaky),
+            Err(
+                "pull failed after 2 attempts: mirror timed out; check the connection, \
+                 raise `retries`, or pass --offline to skip it"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use crate::deploy::PlannedLink;
+use std::path::{Path, PathBuf};
+
+/// `value` as a Nix string literal.
+fn string(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
+fn attrset(out: &mut String, name: &str, entries: &[(String, &Path)]) {
+    if entries.is_empty() {
+        return;
+    }
+    out.push_str(&format!("  {} = {{\n", name));
+    for (target, source) in entries {
+        out.push_str(&format!(
+            "    {}.source = config.lib.file.mkOutOfStoreSymlink {};\n",
+            string(target),
+            string(&source.display().to_string())
+        ));
+    }
+    out.push_str("  };\n");
+}
+
+/// A Home Manager module linking each of `links` to its source in the
+/// repo, out of the store so that edits show up without a rebuild.
+/// Targets below `config_home` go to `xdg.configFile`, other targets below
+/// `home` to `home.file`. Returns the module and the targets it cannot
+/// express.
+pub fn home_manager(
+    links: &[PlannedLink],
+    home: &Path,
+    config_home: &Path,
+) -> (String, Vec<PathBuf>) {
+    let mut config_files = Vec::new();
+    let mut home_files = Vec::new();
+    let mut skipped = Vec::new();
+    for link in links {
+        if let Ok(rel) = link.target.strip_prefix(config_home) {
+            config_files.push((rel.display().to_string(), link.source.as_path()));
+        } else if let Ok(rel) = link.target.strip_prefix(home) {
+            home_files.push((rel.display().to_string(), link.source.as_path()));
+        } else {
+            skipped.push(link.target.clone());
+        }
+    }
+    let mut out = String::from("# Generated by `mdot export nix`\n{ config, ... }:\n{\n");
+    attrset(&mut out, "home.file", &home_files);
+    attrset(&mut out, "xdg.configFile", &config_files);
+    out.push_str("}\n");
+    (out, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::PlannedLink;
+    use crate::nix::*;
+
+    fn link(source: &str, target: &str) -> PlannedLink {
+        PlannedLink {
+            package: "pkg".to_string(),
+            source: PathBuf::from(source),
+            target: PathBuf::from(target),
+            overwrite: false,
+            backup: false,
+            copy: false,
+            rendered_from: None,
+        }
+    }
+
+    #[test]
+    fn test_home_manager() {
+        assert_eq!(string(r#"a "${b}" \c"#), r#""a \"\${b}\" \\c""#);
+        let links = [
+            link("/repo/bash/bashrc", "/home/me/.bashrc"),
+            link("/repo/nvim/init.lua", "/home/me/.config/nvim/init.lua"),
+            link("/repo/etc/hosts", "/etc/hosts"),
+        ];
+        let (module, skipped) =
+            home_manager(&links, Path::new("/home/me"), Path::new("/home/me/.config"));
+        assert_eq!(
+            module,
+            "# Generated by `mdot export nix`\n{ config, ... }:\n{\n  \
+             home.file = {\n    \".bashrc\".source = config.lib.file.mkOutOfStoreSymlink \
+             \"/repo/bash/bashrc\";\n  };\n  \
+             xdg.configFile = {\n    \"nvim/init.lua\".source = config.lib.file.mkOutOfStoreSymlink \
+             \"/repo/nvim/init.lua\";\n  };\n}\n"
+        );
+        assert_eq!(skipped, [PathBuf::from("/etc/hosts")]);
+    }
+}
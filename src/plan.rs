@@ -0,0 +1,121 @@
+use crate::config::Config;
+use crate::select::Selection;
+use crate::{Context, compose, deploy, fscaps};
+
+/// What deploying `selection` would do on `ctx.platform`, without looking
+/// at or changing the targets: the OS packages installed, what the
+/// filesystems of the targets support, then the links and composed files
+/// written. Targets on a filesystem without symlinks are copied.
+pub fn render(ctx: &Context, config: &Config, selection: &Selection) -> Result<String, String> {
+    let platform = &ctx.platform;
+    let mut lines = vec![format!(
+        "platform os={} distro={} family={} arch={} wsl={} hostname={}",
+        platform.os,
+        platform.distro.as_deref().unwrap_or("-"),
+        platform.family.as_deref().unwrap_or("-"),
+        platform.arch,
+        platform.wsl,
+        platform.hostname
+    )];
+    for pkg in &selection.packages {
+        if let Some(name) = pkg.os_package(platform) {
+            lines.push(format!("[{}] install {}", pkg.name, name));
+        }
+    }
+    let links = deploy::plan(ctx, config, &selection.packages);
+    for (dir, caps) in fscaps::probed(links.iter().map(|link| link.target.as_path())) {
+        lines.push(match caps {
+            Some(caps) if !caps.symlinks => {
+                format!("filesystem {} {}, copying", dir.display(), caps.describe())
+            }
+            Some(caps) => format!("filesystem {} {}", dir.display(), caps.describe()),
+            None => format!("filesystem {} not probed", dir.display()),
+        });
+    }
+    for link in links {
+        let verb = if link.copy { "copy" } else { "link" };
+        lines.push(format!(
+            "[{}] {} {} -> {}",
+            link.package,
+            verb,
+            link.target.display(),
+            link.source.display()
+        ));
+    }
+    for composed in compose::plan(ctx, config, &selection.packages)? {
+        let packages: Vec<&str> = composed
+            .fragments
+            .iter()
+            .map(|f| f.package.as_str())
+            .collect();
+        lines.push(format!(
+            "[{}] compose {}",
+            packages.join(","),
+            composed.target.display()
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plan::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_plan_simulate() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-plan-{}", std::process::id()));
+        fs::create_dir_all(root.join("kitty")).unwrap();
+        fs::create_dir_all(root.join("aerospace")).unwrap();
+        fs::write(root.join("kitty/kitty.conf"), "").unwrap();
+        fs::write(root.join("aerospace/aerospace.toml"), "").unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"return {
+                { "kitty", package_name = { macos = "kitty", debian = "kitty-terminal" },
+                  default_target = "~/.config/kitty" },
+                mdot.on_os("macos", { "aerospace", default_target = "~/.config/aerospace" }),
+            }"#,
+        )
+        .unwrap();
+        let detected = platform::Platform {
+            os: "linux".to_string(),
+            distro: Some("ubuntu".to_string()),
+            family: Some("debian".to_string()),
+            arch: "x86_64".to_string(),
+            wsl: false,
+            hostname: "box".to_string(),
+        };
+        let plan = |spec: &str| {
+            let ctx = Context::builder()
+                .entry(root.join("main.lua"))
+                .platform(detected.simulate(spec).unwrap())
+                .build();
+            let config = config::load(&ctx);
+            let selection = select::select(&config, &[], None, &cli::Filter::default());
+            render(&ctx, &config, &selection).unwrap()
+        };
+        let home = dirs::home_dir().unwrap();
+        let linux = plan("");
+        assert!(linux.starts_with("platform os=linux distro=ubuntu family=debian"));
+        assert!(linux.contains("[kitty] install kitty-terminal"));
+        // Only a plan of this machine writes the probes next to the targets.
+        assert!(linux.contains(" not probed\n"));
+        assert!(!linux.contains("aerospace"));
+        let mac = plan("os=macos,hostname=mbp");
+        assert!(
+            mac.starts_with(
+                "platform os=macos distro=- family=- arch=x86_64 wsl=false hostname=mbp"
+            )
+        );
+        assert!(mac.contains("[kitty] install kitty\n"));
+        assert!(mac.contains(&format!(
+            "[aerospace] link {}/.config/aerospace/aerospace.toml -> {}",
+            home.display(),
+            root.join("aerospace/aerospace.toml").display()
+        )));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
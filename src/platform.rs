@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{env, fs};
+
+/// Distro families `OSPackageName` tables can target, and the distro IDs
+/// (from `ID`/`ID_LIKE` in os-release) that belong to each.
+const FAMILIES: &[(&str, &[&str])] = &[
+    (
+        "arch",
+        &["arch", "manjaro", "endeavouros", "artix", "cachyos"],
+    ),
+    (
+        "debian",
+        &["debian", "ubuntu", "linuxmint", "pop", "raspbian", "kali"],
+    ),
+    (
+        "rhel",
+        &["rhel", "fedora", "centos", "rocky", "almalinux", "ol"],
+    ),
+    ("alpine", &["alpine", "postmarketos"]),
+];
+
+/// Facts about the machine mdot runs on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Platform {
+    /// `linux`, `macos`, `windows`, ... as in `std::env::consts::OS`.
+    pub os: String,
+    /// Distro `ID` from os-release, e.g. `ubuntu`.
+    pub distro: Option<String>,
+    /// One of the `FAMILIES`, or the distro ID itself when unknown.
+    pub family: Option<String>,
+    pub arch: String,
+    pub wsl: bool,
+    pub hostname: String,
+}
+
+/// Best effort lookup of the machine hostname without shelling out.
+pub fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .find(|name| !name.is_empty())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .unwrap_or_default()
+}
+
+fn is_wsl() -> bool {
+    env::var_os("WSL_DISTRO_NAME").is_some()
+        || fs::read_to_string("/proc/sys/kernel/osrelease")
+            .is_ok_and(|r| r.to_lowercase().contains("microsoft"))
+}
+
+/// The Windows user profile as seen from WSL, e.g. `/mnt/c/Users/me`:
+/// `%USERPROFILE%` from `cmd.exe`, translated by `wslpath`.
+pub fn windows_home() -> Option<PathBuf> {
+    let output = |program: &str, args: &[&str]| {
+        let out = Command::new(program)
+            .args(args)
+            .current_dir("/")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        let text = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        (out.status.success() && !text.is_empty()).then_some(text)
+    };
+    let profile = output("cmd.exe", &["/C", "echo %USERPROFILE%"])?;
+    output("wslpath", &["-u", &profile]).map(PathBuf::from)
+}
+
+/// Whether `path` is on a Windows drive mounted into WSL, `/mnt/<letter>/`.
+/// Symlinks there are unreliable, so such targets are copied.
+pub fn on_windows_drive(path: &Path) -> bool {
+    let mut components = path.components();
+    components.next() == Some(Component::RootDir)
+        && components.next() == Some(Component::Normal("mnt".as_ref()))
+        && components.next().is_some_and(|drive| {
+            let drive = drive.as_os_str().as_encoded_bytes();
+            drive.len() == 1 && drive[0].is_ascii_alphabetic()
+        })
+}
+
+/// Parses the `KEY=value` lines of an os-release file.
+fn parse_os_release(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().trim_matches('"').to_string()))
+        .collect()
+}
+
+/// Returns the distro ID and family described by an os-release file.
+fn distro_from_os_release(contents: &str) -> (Option<String>, Option<String>) {
+    let fields = parse_os_release(contents);
+    let Some(id) = fields.get("ID").map(|id| id.to_lowercase()) else {
+        return (None, None);
+    };
+    let like = fields
+        .get("ID_LIKE")
+        .map(|l| l.to_lowercase())
+        .unwrap_or_default();
+    let family = std::iter::once(id.as_str())
+        .chain(like.split_whitespace())
+        .find_map(family_of)
+        .unwrap_or_else(|| id.clone());
+    (Some(id), Some(family))
+}
+
+/// The family in `FAMILIES` the distro `id` belongs to.
+fn family_of(id: &str) -> Option<String> {
+    FAMILIES
+        .iter()
+        .find(|(_, members)| members.contains(&id))
+        .map(|(family, _)| family.to_string())
+}
+
+impl Platform {
+    pub fn detect() -> Self {
+        let (distro, family) = ["/etc/os-release", "/usr/lib/os-release"]
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .map(|contents| distro_from_os_release(&contents))
+            .unwrap_or_default();
+        Self {
+            os: env::consts::OS.to_string(),
+            distro,
+            family,
+            arch: env::consts::ARCH.to_string(),
+            wsl: is_wsl(),
+            hostname: hostname(),
+        }
+    }
+
+    /// This platform with the `key=value` pairs of `spec`, e.g.
+    /// `os=macos,hostname=mbp`, in place of the detected facts. Setting
+    /// another `os` drops the distro, family and WSL flag, and a `distro`
+    /// brings its family, unless `spec` sets those too.
+    pub fn simulate(&self, spec: &str) -> Result<Platform, String> {
+        let mut facts = HashMap::new();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", pair))?;
+            facts.insert(key.trim(), value.trim().to_string());
+        }
+        let mut platform = self.clone();
+        if let Some(os) = facts.remove("os")
+            && os != platform.os
+        {
+            platform.os = os;
+            platform.distro = None;
+            platform.family = None;
+            platform.wsl = false;
+        }
+        if let Some(distro) = facts.remove("distro") {
+            platform.family = Some(family_of(&distro).unwrap_or_else(|| distro.clone()));
+            platform.distro = Some(distro);
+        }
+        if let Some(family) = facts.remove("family") {
+            platform.family = Some(family);
+        }
+        if let Some(arch) = facts.remove("arch") {
+            platform.arch = arch;
+        }
+        if let Some(wsl) = facts.remove("wsl") {
+            platform.wsl = wsl
+                .parse()
+                .map_err(|_| format!("wsl expects true or false, got '{}'", wsl))?;
+        }
+        if let Some(hostname) = facts.remove("hostname") {
+            platform.hostname = hostname;
+        }
+        if let Some(key) = facts.keys().next() {
+            return Err(format!(
+                "unknown platform fact '{}', expected os, distro, family, arch, wsl or hostname",
+                key
+            ));
+        }
+        Ok(platform)
+    }
+
+    /// Keys an `OSPackageName` table is searched with, most specific first.
+    pub fn package_keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        for key in [
+            self.distro.as_deref(),
+            self.family.as_deref(),
+            Some(&self.os),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(["default"])
+        {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_drive() {
+        assert!(on_windows_drive(Path::new("/mnt/c/Users/me/.gitconfig")));
+        assert!(!on_windows_drive(Path::new("/mnt/data/file")));
+        assert!(!on_windows_drive(Path::new("/home/me/mnt/c/file")));
+    }
+
+    #[test]
+    fn test_distro_family() {
+        let ubuntu = "NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\n";
+        assert_eq!(
+            distro_from_os_release(ubuntu),
+            (Some("ubuntu".to_string()), Some("debian".to_string()))
+        );
+        let rocky = "ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n";
+        assert_eq!(distro_from_os_release(rocky).1, Some("rhel".to_string()));
+        let nixos = "ID=nixos\n";
+        assert_eq!(distro_from_os_release(nixos).1, Some("nixos".to_string()));
+        assert_eq!(distro_from_os_release(""), (None, None));
+    }
+
+    #[test]
+    fn test_package_keys() {
+        let platform = Platform {
+            os: "linux".to_string(),
+            distro: Some("ubuntu".to_string()),
+            family: Some("debian".to_string()),
+            arch: "x86_64".to_string(),
+            wsl: false,
+            hostname: "box".to_string(),
+        };
+        assert_eq!(
+            platform.package_keys(),
+            vec!["ubuntu", "debian", "linux", "default"]
+        );
+    }
+
+    #[test]
+    fn test_simulate() {
+        let ubuntu = Platform {
+            os: "linux".to_string(),
+            distro: Some("ubuntu".to_string()),
+            family: Some("debian".to_string()),
+            arch: "x86_64".to_string(),
+            wsl: true,
+            hostname: "box".to_string(),
+        };
+        let mac = ubuntu
+            .simulate("os=macos, hostname=mbp,arch=aarch64")
+            .unwrap();
+        assert_eq!(
+            mac,
+            Platform {
+                os: "macos".to_string(),
+                distro: None,
+                family: None,
+                arch: "aarch64".to_string(),
+                wsl: false,
+                hostname: "mbp".to_string(),
+            }
+        );
+        let fedora = ubuntu.simulate("distro=fedora").unwrap();
+        assert_eq!(fedora.family.as_deref(), Some("rhel"));
+        assert!(fedora.wsl);
+        let nixos = mac.simulate("os=linux,family=nix,distro=nixos").unwrap();
+        assert_eq!(
+            (nixos.distro.as_deref(), nixos.family.as_deref()),
+            (Some("nixos"), Some("nix"))
+        );
+        assert_eq!(ubuntu.simulate("").unwrap(), ubuntu);
+        assert!(ubuntu.simulate("os").is_err());
+        assert!(ubuntu.simulate("wsl=maybe").is_err());
+        assert!(
+            ubuntu
+                .simulate("kernel=6")
+                .unwrap_err()
+                .contains("'kernel'")
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use crate::cli::Cli;
+use crate::{Context, Package, limits};
+use clap::CommandFactory;
+use mlua::{Function, Lua, Result as LuaResult, Table, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Plugins, in the default config dir.
+pub const PLUGINS_DIR: &str = "plugins";
+/// Registry tables of the registered actions and commands.
+const ACTIONS: &str = "mdot.plugins.actions";
+const COMMANDS: &str = "mdot.plugins.commands";
+
+/// An action type implemented in Rust, for integrations too heavy for a
+/// Lua plugin, registered with `Context::register_action`.
+pub trait ActionProvider {
+    /// The key packages set in `actions`.
+    fn name(&self) -> &str;
+    /// Applies the `args` a package gives the action.
+    fn run(&self, ctx: &Context, pkg: &Package, args: &Value) -> Result<(), String>;
+}
+
+/// Registers `mdot.register_action(name, handler)`, whose handler is
+/// called as `handler(args, { name, dir })` for every package setting
+/// `actions = { [name] = args }`, and `mdot.register_command(name,
+/// handler, description?)`, whose handler is called with the arguments
+/// of `mdot <name>`.
+pub fn install(lua: &Lua) -> LuaResult<()> {
+    lua.set_named_registry_value(ACTIONS, lua.create_table()?)?;
+    lua.set_named_registry_value(COMMANDS, lua.create_table()?)?;
+    let mdot: Table = lua.globals().get("mdot")?;
+    mdot.set(
+        "register_action",
+        lua.create_function(|lua, (name, handler): (String, Function)| {
+            let actions: Table = lua.named_registry_value(ACTIONS)?;
+            if actions.contains_key(name.as_str())? {
+                return Err(mlua::Error::runtime(format!(
+                    "action '{}' is already registered",
+                    name
+                )));
+            }
+            actions.set(name, handler)
+        })?,
+    )?;
+    mdot.set(
+        "register_command",
+        lua.create_function(
+            |lua, (name, handler, description): (String, Function, Option<String>)| {
+                let commands: Table = lua.named_registry_value(COMMANDS)?;
+                if Cli::command().find_subcommand(&name).is_some()
+                    || commands.contains_key(name.as_str())?
+                {
+                    return Err(mlua::Error::runtime(format!(
+                        "command '{}' already exists",
+                        name
+                    )));
+                }
+                let command = lua.create_table()?;
+                command.set("run", handler)?;
+                command.set("description", description)?;
+                commands.set(name, command)
+            },
+        )?,
+    )?;
+    Ok(())
+}
+
+/// Evaluates the `*.lua` files in `dir` in name order, if it exists.
+pub fn load(lua: &Lua, dir: &Path) -> Result<(), String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lua") && path.is_file())
+        .collect();
+    files.sort();
+    for file in files {
+        limits::budgeted(|| lua.load(file.as_path()).exec())
+            .map_err(|err| format!("plugin {} failed: {}", file.display(), err))?;
+    }
+    Ok(())
+}
+
+/// Whether a provider or a plugin implements the action `name`.
+pub fn has_action(ctx: &Context, name: &str) -> bool {
+    ctx.providers.iter().any(|p| p.name() == name)
+        || ctx
+            .lua
+            .named_registry_value::<Table>(ACTIONS)
+            .and_then(|actions| actions.contains_key(name))
+            .unwrap_or(false)
+}
+
+/// Calls the provider or plugin handler of the action `name` for `pkg`.
+pub fn run_action(ctx: &Context, pkg: &Package, name: &str, args: &Value) -> Result<(), String> {
+    if let Some(provider) = ctx.providers.iter().find(|p| p.name() == name) {
+        return provider
+            .run(ctx, pkg, args)
+            .map_err(|err| format!("'{}' action failed: {}", name, err));
+    }
+    let lua = &ctx.lua;
+    let handler = lua
+        .named_registry_value::<Table>(ACTIONS)
+        .and_then(|actions| actions.get::<Option<Function>>(name))
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| format!("no plugin registers the '{}' action", name))?;
+    let info = lua
+        .create_table_from([
+            ("name", pkg.name.clone()),
+            ("dir", ctx.package_dir(pkg).display().to_string()),
+        ])
+        .map_err(|err| err.to_string())?;
+    limits::budgeted(|| handler.call::<()>((args.clone(), info)))
+        .map_err(|err| format!("'{}' action failed: {}", name, err))
+}
+
+/// The commands plugins registered, with their descriptions.
+pub fn commands(lua: &Lua) -> Vec<(String, Option<String>)> {
+    let Ok(commands) = lua.named_registry_value::<Table>(COMMANDS) else {
+        return Vec::new();
+    };
+    let mut commands: Vec<(String, Option<String>)> = commands
+        .pairs::<String, Table>()
+        .filter_map(|pair| pair.ok())
+        .map(|(name, command)| (name, command.get("description").ok().flatten()))
+        .collect();
+    commands.sort();
+    commands
+}
+
+/// Runs `mdot <name> <args...>` through the command a plugin registered.
+pub fn run_command(ctx: &Context, args: &[String]) -> Result<(), String> {
+    let lua = &ctx.lua;
+    let Some((name, args)) = args.split_first() else {
+        return Err("no command given".to_string());
+    };
+    let command = lua
+        .named_registry_value::<Table>(COMMANDS)
+        .and_then(|commands| commands.get::<Option<Table>>(name.as_str()))
+        .map_err(|err| err.to_string())?;
+    let Some(command) = command else {
+        let known: Vec<String> = commands(lua).into_iter().map(|(name, _)| name).collect();
+        return Err(match known.is_empty() {
+            true => format!("unknown command '{}', see `mdot --help`", name),
+            false => format!(
+                "unknown command '{}', plugins add: {}",
+                name,
+                known.join(", ")
+            ),
+        });
+    };
+    let handler: Function = command.get("run").map_err(|err| err.to_string())?;
+    let args = lua
+        .create_sequence_from(args.iter().cloned())
+        .map_err(|err| err.to_string())?;
+    limits::budgeted(|| handler.call::<()>(args))
+        .map_err(|err| format!("plugin command '{}' failed: {}", name, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugins::*;
+    use crate::*;
+    use std::cell::RefCell;
+
+    /// Records the packages it ran for.
+    #[derive(Default)]
+    struct Recorder {
+        runs: RefCell<Vec<String>>,
+    }
+
+    impl ActionProvider for Recorder {
+        fn name(&self) -> &str {
+            "gsettings"
+        }
+
+        fn run(&self, _ctx: &Context, pkg: &Package, args: &Value) -> Result<(), String> {
+            let theme: String = args
+                .as_table()
+                .and_then(|args| args.get("theme").ok())
+                .ok_or("expected a theme")?;
+            self.runs
+                .borrow_mut()
+                .push(format!("{} {}", pkg.name, theme));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_plugins() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-plugins-{}", std::process::id()));
+        fs::create_dir_all(root.join("plugins")).unwrap();
+        fs::create_dir_all(root.join("gnome")).unwrap();
+        fs::write(
+            root.join("plugins/gsettings.lua"),
+            r#"mdot.register_action("gsettings", function(args, pkg)
+                local f = io.open(pkg.dir .. "/applied", "w")
+                f:write(pkg.name .. " " .. args.theme)
+                f:close()
+            end)
+            mdot.register_command("greet", function(args)
+                mdot.store.set("greeted", table.concat(args, " "))
+            end, "Say hello")
+            mdot.register_command("spin", function() while true do end end)"#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"return { { "gnome", actions = { gsettings = { theme = "dark" } } } }"#,
+        )
+        .unwrap();
+        let mut ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .plugins_dir(root.join("plugins"))
+            .limits(Limits {
+                timeout: std::time::Duration::from_millis(100),
+                ..Limits::default()
+            })
+            .build();
+        let config = config::load(&ctx);
+        let pkg = &config.packages[0];
+        assert_eq!(pkg.actions[0].describe(), "gsettings (plugin action)");
+
+        hooks::run(&ctx, &config.options, pkg, None, &[]).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("gnome/applied")).unwrap(),
+            "gnome dark"
+        );
+        assert_eq!(
+            run_action(&ctx, pkg, "unknown", &Value::Nil),
+            Err("no plugin registers the 'unknown' action".to_string())
+        );
+
+        assert_eq!(
+            commands(&ctx.lua),
+            [
+                ("greet".to_string(), Some("Say hello".to_string())),
+                ("spin".to_string(), None)
+            ]
+        );
+        let err = run_command(&ctx, &["spin".to_string()]).unwrap_err();
+        assert!(err.contains("plugin command 'spin' failed"), "{}", err);
+        assert!(err.contains("gave up after 100ms"), "{}", err);
+        run_command(
+            &ctx,
+            &["greet".to_string(), "a".to_string(), "b".to_string()],
+        )
+        .unwrap();
+        let greeted: String = ctx.lua.load("mdot.store.get('greeted')").eval().unwrap();
+        assert_eq!(greeted, "a b");
+        assert!(run_command(&ctx, &["nope".to_string()]).is_err());
+        let deploy = ctx
+            .lua
+            .load(r#"mdot.register_command("deploy", function() end)"#)
+            .exec();
+        assert!(deploy.is_err());
+
+        let recorder = Rc::new(Recorder::default());
+        ctx.register_action(recorder.clone());
+        fs::remove_file(root.join("gnome/applied")).unwrap();
+        hooks::run(&ctx, &config.options, pkg, None, &[]).unwrap();
+        assert_eq!(*recorder.runs.borrow(), ["gnome dark"]);
+        assert!(!root.join("gnome/applied").exists());
+        assert_eq!(
+            run_action(&ctx, pkg, "gsettings", &Value::Nil),
+            Err("'gsettings' action failed: expected a theme".to_string())
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
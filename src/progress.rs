@@ -0,0 +1,225 @@
+use crate::deploy::{self, PlannedLink};
+use crate::state::{escape, unescape};
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// What the deploy in progress completed, kept until it finishes.
+pub const PROGRESS_FILE: &str = "deploy.progress";
+const HEADER: &str = "# mdot deploy progress v1";
+
+thread_local! {
+    /// Progress file of the deploy in progress, if it records one.
+    static ACTIVE: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// What an interrupted deploy completed before it stopped.
+#[derive(Debug, Default, PartialEq)]
+pub struct Progress {
+    /// The OS packages were installed.
+    pub installed: bool,
+    /// The links made, target to source.
+    pub links: BTreeMap<PathBuf, PathBuf>,
+    /// The other steps done, package to step name. Steps that are not
+    /// about one package have an empty package.
+    pub steps: BTreeSet<(String, String)>,
+}
+
+impl Progress {
+    /// Whether `link` was made by the interrupted deploy and is still in
+    /// place.
+    pub fn done(&self, link: &PlannedLink) -> bool {
+        self.links.get(&link.target) == Some(&link.source)
+            && deploy::is_deployed(&link.target, &link.source)
+    }
+
+    /// Whether the interrupted deploy did the `step` of `package`.
+    pub fn finished(&self, package: &str, step: &str) -> bool {
+        self.steps
+            .contains(&(package.to_string(), step.to_string()))
+    }
+}
+
+pub fn path(state_dir: &Path) -> PathBuf {
+    state_dir.join(PROGRESS_FILE)
+}
+
+/// The progress recorded in `path`; none when there is no such file.
+pub fn load(path: &Path) -> io::Result<Progress> {
+    let mut progress = Progress::default();
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(progress),
+        Err(err) => return Err(err),
+    };
+    for line in content.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        match (fields[0].as_str(), fields.len()) {
+            ("installed", 1) => progress.installed = true,
+            ("linked", 3) => {
+                progress
+                    .links
+                    .insert(PathBuf::from(&fields[1]), PathBuf::from(&fields[2]));
+            }
+            ("done", 3) => {
+                progress
+                    .steps
+                    .insert((fields[1].clone(), fields[2].clone()));
+            }
+            _ => warn!("{}: ignoring invalid record '{}'", path.display(), line),
+        }
+    }
+    Ok(progress)
+}
+
+/// Starts recording the progress of a deploy. With `resume`, carries on
+/// the progress of the interrupted deploy and returns it; otherwise that
+/// progress is discarded.
+pub fn begin(state_dir: &Path, resume: bool) -> io::Result<Progress> {
+    let path = path(state_dir);
+    let progress = match resume {
+        true => load(&path)?,
+        false => Progress::default(),
+    };
+    if resume && progress == Progress::default() {
+        info!("no interrupted deploy to resume, deploying everything");
+    } else if resume {
+        info!(
+            "resuming an interrupted deploy that made {} link(s) and {} other step(s)",
+            progress.links.len(),
+            progress.steps.len()
+        );
+    } else if path.exists() {
+        info!("discarding the progress of an interrupted deploy (--resume continues it)");
+    }
+    if !resume || !path.exists() {
+        fs::create_dir_all(state_dir)?;
+        fs::write(&path, format!("{}\n", HEADER))?;
+    }
+    ACTIVE.set(Some(path));
+    Ok(progress)
+}
+
+fn record(fields: &[String]) {
+    ACTIVE.with_borrow(|path| {
+        let Some(path) = path else {
+            return;
+        };
+        let line: Vec<String> = fields.iter().map(|f| escape(f)).collect();
+        let written = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", line.join("\t")));
+        if let Err(err) = written {
+            warn!("failed to write {}: {}", path.display(), err);
+        }
+    });
+}
+
+/// Records that the OS packages were installed.
+pub fn installed() {
+    record(&["installed".into()]);
+}
+
+/// Records that `link` was made.
+pub fn linked(link: &PlannedLink) {
+    record(&[
+        "linked".into(),
+        link.target.display().to_string(),
+        link.source.display().to_string(),
+    ]);
+}
+
+/// Records that the `step` of `package` was done.
+pub fn done(package: &str, step: &str) {
+    record(&["done".into(), package.into(), step.into()]);
+}
+
+/// Stops recording: the deploy completed, so there is nothing to resume.
+pub fn finish() {
+    let Some(path) = ACTIVE.take() else {
+        return;
+    };
+    if let Err(err) = fs::remove_file(&path) {
+        warn!("failed to remove {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::progress::*;
+    use crate::*;
+
+    #[test]
+    fn test_resume() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-progress-{}", std::process::id()));
+        fs::create_dir_all(root.join("repo/zsh")).unwrap();
+        fs::write(root.join("repo/zsh/.zshrc"), "").unwrap();
+        fs::write(root.join("repo/zsh/.zshenv"), "").unwrap();
+        fs::write(
+            root.join("repo/main.lua"),
+            format!(
+                r#"return {{ {{ "zsh", default_target = "{}", on_deploy = "echo deploy >> log" }} }}"#,
+                root.join("home").display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("repo/main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        let links = deploy::plan(&ctx, &config, &config.packages);
+        assert_eq!(links.len(), 2);
+
+        // A deploy interrupted after its first link.
+        begin(&ctx.state_dir, false).unwrap();
+        installed();
+        fs::create_dir_all(root.join("home")).unwrap();
+        std::os::unix::fs::symlink(&links[0].source, &links[0].target).unwrap();
+        linked(&links[0]);
+        done("zsh", "hooks");
+        ACTIVE.take();
+
+        let progress = begin(&ctx.state_dir, true).unwrap();
+        assert!(progress.installed);
+        assert!(progress.done(&links[0]));
+        assert!(!progress.done(&links[1]));
+        assert!(progress.finished("zsh", "hooks"));
+        assert!(!progress.finished("zsh", "fonts"));
+        let (applied, changed, _) = deploy::deploy(
+            &ctx,
+            &config,
+            &config.packages,
+            &state::State::default(),
+            &progress,
+        );
+        assert_eq!(applied.len(), 2);
+        assert!(changed.contains("zsh"));
+        assert!(deploy::is_deployed(&links[1].target, &links[1].source));
+        assert_eq!(load(&path(&ctx.state_dir)).unwrap().links.len(), 2);
+
+        // The hooks ran before the interruption, the other steps did not.
+        let selection = select::select(&config, &[], None, &cli::Filter::default());
+        deploy::apply(&ctx, &config, &selection, false, &progress);
+        assert!(!root.join("repo/zsh/log").exists());
+        let recorded = load(&path(&ctx.state_dir)).unwrap();
+        assert!(recorded.finished("zsh", "fonts"));
+        assert!(recorded.finished("", "compose"));
+
+        finish();
+        assert!(!path(&ctx.state_dir).exists());
+        fs::remove_file(&links[0].target).unwrap();
+        let progress = begin(&ctx.state_dir, true).unwrap();
+        assert_eq!(progress, Progress::default());
+        finish();
+        fs::remove_dir_all(root).unwrap();
+    }
+}
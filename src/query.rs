@@ -0,0 +1,184 @@
+use crate::config::Config;
+use crate::deploy::{self, PlannedLink};
+use crate::json::Json;
+use crate::platform::Platform;
+use crate::select::{self, Selection};
+use crate::template::{self, Vars};
+use std::path::Path;
+
+fn path(path: &Path) -> Json {
+    path.display().to_string().into()
+}
+
+fn vars_json(vars: &Vars) -> Json {
+    Json::object(
+        vars.iter()
+            .map(|(k, v)| (k.as_str(), Json::from(v.as_str()))),
+    )
+}
+
+/// Every planned link with its current status.
+pub fn links(links: &[PlannedLink]) -> Json {
+    links
+        .iter()
+        .map(|link| {
+            Json::object([
+                ("package", Json::from(link.package.as_str())),
+                ("source", path(&link.source)),
+                ("target", path(&link.target)),
+                ("status", deploy::link_status(link).into()),
+                ("overwrite", link.overwrite.into()),
+                ("backup", link.backup.into()),
+            ])
+        })
+        .collect::<Vec<Json>>()
+        .into()
+}
+
+/// Every known package with the selection decision about it.
+pub fn packages(config: &Config, selection: &Selection, platform: &Platform) -> Json {
+    selection
+        .decisions
+        .iter()
+        .map(|(name, decision)| {
+            let pkg = select::find_package(&config.packages, name);
+            let strings = |f: fn(&crate::Package) -> Vec<String>| -> Json {
+                pkg.map(f).unwrap_or_default().into()
+            };
+            Json::object([
+                ("name", Json::from(name.as_str())),
+                ("deployed", decision.deployed().into()),
+                (
+                    "reasons",
+                    decision
+                        .reasons
+                        .iter()
+                        .map(|r| r.to_string())
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
+                (
+                    "skip",
+                    decision
+                        .skip
+                        .as_ref()
+                        .map_or(Json::Null, |s| s.to_string().into()),
+                ),
+                (
+                    "os_package",
+                    pkg.and_then(|p| p.os_package(platform))
+                        .map_or(Json::Null, Json::from),
+                ),
+                (
+                    "depends",
+                    strings(|p| p.depends.iter().map(|d| d.name.clone()).collect()),
+                ),
+                ("wants", strings(|p| p.wants.clone())),
+                ("tags", strings(|p| p.tags.clone())),
+            ])
+        })
+        .collect::<Vec<Json>>()
+        .into()
+}
+
+/// Global variables and facts, plus what each deployed package sees.
+pub fn vars(config: &Config, selection: &Selection, platform: &Platform) -> Json {
+    let global: Vars = template::layered_vars(config, None, platform)
+        .into_iter()
+        .map(|(key, (value, _))| (key, value))
+        .collect();
+    let packages = selection.packages.iter().map(|pkg| {
+        (
+            pkg.name.as_str(),
+            vars_json(&template::package_vars(config, pkg, platform)),
+        )
+    });
+    Json::object([
+        ("global", vars_json(&global)),
+        ("packages", Json::object(packages)),
+    ])
+}
+
+/// Plain text form of a query result: one `path = value` line per leaf.
+pub fn lines(value: &Json, prefix: &str, out: &mut Vec<String>) {
+    let key = |k: &dyn std::fmt::Display| match prefix {
+        "" => k.to_string(),
+        _ => format!("{}.{}", prefix, k),
+    };
+    match value {
+        Json::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                lines(item, &key(&i), out);
+            }
+        }
+        Json::Object(map) => {
+            for (k, item) in map {
+                lines(item, &key(k), out);
+            }
+        }
+        Json::String(s) => out.push(format!("{} = {}", prefix, s)),
+        leaf => out.push(format!("{} = {}", prefix, leaf)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_query() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-query-{}", std::process::id()));
+        fs::create_dir_all(root.join("kitty")).unwrap();
+        fs::write(root.join("kitty/kitty.conf"), "").unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.vars.theme = "gruvbox"
+            return {
+                { "kitty", links = { ["kitty.conf"] = "/h/kitty.conf" }, vars = { size = 11 } },
+                { "fish", enabled = false },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let selection = select::select(&config, &[], None, &cli::Filter::default());
+        let links = deploy::plan(&ctx, &config, &selection.packages);
+
+        let links = query::links(&links);
+        assert_eq!(links.as_array().unwrap().len(), 1);
+        assert_eq!(
+            links.as_array().unwrap()[0]
+                .get("status")
+                .and_then(json::Json::as_str),
+            Some("missing")
+        );
+
+        let packages = query::packages(&config, &selection, &ctx.platform);
+        let mut text = Vec::new();
+        query::lines(&packages, "", &mut text);
+        assert!(text.contains(&"0.name = fish".to_string()));
+        assert!(text.contains(&"0.deployed = false".to_string()));
+        assert!(text.contains(&"1.reasons.0 = defined in the config".to_string()));
+
+        let vars = query::vars(&config, &selection, &ctx.platform);
+        let kitty = vars.get("packages").and_then(|p| p.get("kitty")).unwrap();
+        assert_eq!(
+            kitty.get("vars.theme").and_then(json::Json::as_str),
+            Some("gruvbox")
+        );
+        assert_eq!(
+            kitty.get("vars.size").and_then(json::Json::as_str),
+            Some("11")
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
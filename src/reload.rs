@@ -0,0 +1,258 @@
+use crate::config::Options;
+use crate::ipc::{self, Compositor};
+use crate::{Context, HookAction, Package, field, hooks, sequence, tmux};
+use log::{info, warn};
+use mlua::Value;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Signals `reload` may send, without their `SIG` prefix.
+const SIGNALS: &[&str] = &[
+    "HUP", "INT", "QUIT", "TERM", "USR1", "USR2", "CONT", "WINCH",
+];
+
+/// How a program picks up its changed config: `reload = "hyprctl reload"`
+/// runs a command, `reload = { signal = "SIGUSR1", process = "kitty" }`
+/// signals every process with that name and `reload = { ipc = "sway" }`
+/// asks the compositor over its socket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reload {
+    Command(String),
+    Signal {
+        signal: String,
+        process: String,
+    },
+    Ipc(Compositor),
+    /// `tmux_reload`: sources the tmux config, the default one unless
+    /// given, in every session.
+    Tmux(Option<PathBuf>),
+}
+
+impl Reload {
+    fn from_table(path: &str, tbl: &mlua::Table) -> Self {
+        let string = |key| match field(tbl, path, key) {
+            Value::String(s) => s.to_string_lossy(),
+            v => fatal!("'{}.{}' expected type 'String', got {:?}", path, key, v),
+        };
+        if !matches!(field(tbl, path, "ipc"), Value::Nil) {
+            return Reload::Ipc(Compositor::parse(&format!("{}.ipc", path), &string("ipc")));
+        }
+        let signal = string("signal").to_uppercase();
+        let signal = signal.strip_prefix("SIG").unwrap_or(&signal).to_string();
+        if !SIGNALS.contains(&signal.as_str()) {
+            fatal!(
+                "'{}.signal' must be one of {}, got '{}'",
+                path,
+                SIGNALS.join(", "),
+                signal
+            );
+        }
+        Reload::Signal {
+            signal,
+            process: string("process"),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Reload::Command(cmd) => cmd.clone(),
+            Reload::Signal { signal, process } => format!("SIG{} to {}", signal, process),
+            Reload::Ipc(compositor) => format!("{} IPC reload", compositor.name()),
+            Reload::Tmux(Some(conf)) => format!("tmux source-file {}", conf.display()),
+            Reload::Tmux(None) => "tmux source-file".to_string(),
+        }
+    }
+}
+
+/// Parses `reload`: a command, a signal table, or a list of those.
+pub fn from_value(path: &str, value: &Value) -> Vec<Reload> {
+    match value {
+        Value::String(cmd) => vec![Reload::Command(cmd.to_string_lossy())],
+        Value::Table(tbl)
+            if !matches!(field(tbl, path, "signal"), Value::Nil)
+                || !matches!(field(tbl, path, "ipc"), Value::Nil) =>
+        {
+            vec![Reload::from_table(path, tbl)]
+        }
+        Value::Table(tbl) => sequence(tbl, path)
+            .into_iter()
+            .map(|v| match v {
+                Value::String(cmd) => Reload::Command(cmd.to_string_lossy()),
+                Value::Table(tbl) => Reload::from_table(path, &tbl),
+                v => fatal!(
+                    "'{}' entries expected a command or a signal table, got {}",
+                    path,
+                    v.type_name()
+                ),
+            })
+            .collect(),
+        v => fatal!(
+            "'{}' expected a command or a signal table, got {}",
+            path,
+            v.type_name()
+        ),
+    }
+}
+
+/// Parses `tmux_reload = true`, or the path of the config to source.
+pub fn tmux_from_value(path: &str, value: &Value) -> Vec<Reload> {
+    match value {
+        Value::Boolean(true) => vec![Reload::Tmux(None)],
+        Value::Boolean(false) => Vec::new(),
+        Value::String(conf) => vec![Reload::Tmux(Some(PathBuf::from(conf.to_string_lossy())))],
+        v => fatal!(
+            "'{}' expected type 'Boolean' or 'String', got {}",
+            path,
+            v.type_name()
+        ),
+    }
+}
+
+/// The reloads of the `changed` packages among `packages`, each once with
+/// the packages asking for it.
+pub fn pending<'a>(
+    packages: &'a [Package],
+    changed: &BTreeSet<String>,
+) -> Vec<(&'a Reload, Vec<&'a Package>)> {
+    let mut pending: Vec<(&Reload, Vec<&Package>)> = Vec::new();
+    for pkg in packages.iter().filter(|pkg| changed.contains(&pkg.name)) {
+        for reload in &pkg.reload {
+            match pending.iter_mut().find(|(r, _)| *r == reload) {
+                Some((_, by)) => by.push(pkg),
+                None => pending.push((reload, vec![pkg])),
+            }
+        }
+    }
+    pending
+}
+
+fn send(signal: &str, process: &str) -> Result<(), String> {
+    let status = Command::new("pkill")
+        .arg(format!("-{}", signal))
+        .arg("-x")
+        .arg(process)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|err| format!("failed to run pkill: {}", err))?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(1) => {
+            info!("{} is not running", process);
+            Ok(())
+        }
+        _ => Err(format!("pkill exited with {}", status)),
+    }
+}
+
+/// Reloads the programs of the `changed` packages among `packages`,
+/// running a reload several packages share once. Reload commands are
+/// hooks, so the allowlist of hooks applies to them.
+pub fn run(ctx: &Context, options: &Options, packages: &[Package], changed: &BTreeSet<String>) {
+    for (reload, by) in pending(packages, changed) {
+        let names: Vec<&str> = by.iter().map(|pkg| pkg.name.as_str()).collect();
+        let names = names.join(",");
+        let result = match reload {
+            Reload::Command(cmd) => {
+                let action = HookAction::Command(cmd.clone());
+                match hooks::permitted(ctx, options, by[0], "reload", &action) {
+                    Ok(true) => {
+                        info!("[{}] reload: {}", names, cmd);
+                        Command::new("sh")
+                            .arg("-c")
+                            .arg(cmd)
+                            .current_dir(&ctx.config_path)
+                            .stdin(Stdio::null())
+                            .status()
+                            .map_err(|err| format!("failed to run '{}': {}", cmd, err))
+                            .and_then(|status| match status.success() {
+                                true => Ok(()),
+                                false => Err(format!("'{}' exited with {}", cmd, status)),
+                            })
+                    }
+                    Ok(false) => Ok(()),
+                    Err(err) => Err(err),
+                }
+            }
+            Reload::Signal { signal, process } => {
+                info!("[{}] reload: {}", names, reload.describe());
+                send(signal, process)
+            }
+            Reload::Ipc(compositor) => {
+                info!("[{}] reload: {}", names, reload.describe());
+                ipc::reload(*compositor)
+            }
+            Reload::Tmux(conf) => {
+                info!("[{}] reload: {}", names, reload.describe());
+                tmux::reload(conf.as_deref())
+            }
+        };
+        if let Err(err) = result {
+            warn!("[{}] reload failed: {}", names, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reload::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_reload() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-reload-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"return {
+                { "hypr", reload = { "echo hypr >> reloads", { ipc = "hyprland" } } },
+                { "waybar", reload = { "echo hypr >> reloads", { signal = "sigusr2", process = "mdot-no-such-bar" } } },
+                { "kitty", reload = { signal = "SIGUSR1", process = "kitty" } },
+                { "tmux", tmux_reload = true, reload = "true" },
+                "git",
+            }"#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        assert_eq!(
+            config.packages[2].reload,
+            [Reload::Signal {
+                signal: "USR1".to_string(),
+                process: "kitty".to_string()
+            }]
+        );
+        assert_eq!(
+            config.packages[3].reload,
+            [Reload::Command("true".to_string()), Reload::Tmux(None)]
+        );
+
+        let changed = BTreeSet::from(["hypr".to_string(), "waybar".to_string(), "git".to_string()]);
+        let pending: Vec<(String, Vec<&str>)> = pending(&config.packages, &changed)
+            .into_iter()
+            .map(|(reload, by)| {
+                let by = by.iter().map(|pkg| pkg.name.as_str()).collect();
+                (reload.describe(), by)
+            })
+            .collect();
+        assert_eq!(
+            pending,
+            [
+                ("echo hypr >> reloads".to_string(), vec!["hypr", "waybar"]),
+                ("hyprland IPC reload".to_string(), vec!["hypr"]),
+                ("SIGUSR2 to mdot-no-such-bar".to_string(), vec!["waybar"]),
+            ]
+        );
+
+        // Without hypr, whose IPC reload would reach a running compositor.
+        let changed = BTreeSet::from(["waybar".to_string()]);
+        run(&ctx, &config.options, &config.packages, &changed);
+        assert_eq!(fs::read_to_string(root.join("reloads")).unwrap(), "hypr\n");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+use crate::state::{escape, unescape};
+use crate::{Context, git};
+use log::info;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Remote packages of the repo, in the repo root so that every machine
+/// deploying it gets them.
+pub const REMOTES_FILE: &str = "remotes";
+const HEADER: &str = "# mdot remotes v1, written by `mdot add-remote`";
+/// Checkouts of remote packages, in the data dir.
+const REMOTES_DIR: &str = "remotes";
+
+/// A community package repo: a `main.lua` returning its packages, next to
+/// their directories.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    /// Overrides `mdot.options.retries` when cloning and pulling it.
+    pub retries: Option<u32>,
+}
+
+impl Remote {
+    /// Reads `github:user/repo`, `gitlab:user/repo`, a `git+` url or a
+    /// plain git url. The name is the repo name without an `mdot-` prefix.
+    pub fn parse(spec: &str) -> Result<Remote, String> {
+        let url = if let Some(path) = spec.strip_prefix("github:") {
+            format!("https://github.com/{}.git", path)
+        } else if let Some(path) = spec.strip_prefix("gitlab:") {
+            format!("https://gitlab.com/{}.git", path)
+        } else {
+            spec.strip_prefix("git+").unwrap_or(spec).to_string()
+        };
+        let repo = url
+            .trim_end_matches('/')
+            .rsplit(['/', ':'])
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches(".git");
+        let name = repo.strip_prefix("mdot-").unwrap_or(repo);
+        if name.is_empty() || name.starts_with('.') {
+            return Err(format!("cannot tell the package name of '{}'", spec));
+        }
+        Ok(Remote {
+            name: name.to_string(),
+            url,
+            retries: None,
+        })
+    }
+}
+
+/// The remotes recorded in the repo at `root`.
+pub fn load(root: &Path) -> Result<Vec<Remote>, String> {
+    let path = root.join(REMOTES_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+    };
+    let mut remotes = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        match fields.as_slice() {
+            [name, url] => remotes.push(Remote {
+                name: name.clone(),
+                url: url.clone(),
+                retries: None,
+            }),
+            [name, url, retries] if retries.parse::<u32>().is_ok() => remotes.push(Remote {
+                name: name.clone(),
+                url: url.clone(),
+                retries: retries.parse().ok(),
+            }),
+            _ => {
+                return Err(format!(
+                    "{}:{}: invalid remote record",
+                    path.display(),
+                    n + 1
+                ));
+            }
+        }
+    }
+    Ok(remotes)
+}
+
+fn save(root: &Path, remotes: &[Remote]) -> Result<(), String> {
+    let path = root.join(REMOTES_FILE);
+    if remotes.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.to_string()),
+            _ => Ok(()),
+        };
+    }
+    let mut out = format!("{}\n", HEADER);
+    for remote in remotes {
+        out.push_str(&format!(
+            "{}\t{}",
+            escape(&remote.name),
+            escape(&remote.url)
+        ));
+        if let Some(retries) = remote.retries {
+            out.push_str(&format!("\t{}", retries));
+        }
+        out.push('\n');
+    }
+    fs::write(&path, out).map_err(|err| format!("failed to write {}: {}", path.display(), err))
+}
+
+pub fn dir(ctx: &Context, remote: &Remote) -> PathBuf {
+    ctx.data_dir.join(REMOTES_DIR).join(&remote.name)
+}
+
+/// The checkout of `remote`, cloned on first use. `retries` applies when
+/// the remote sets none of its own.
+pub fn checkout(ctx: &Context, remote: &Remote, retries: u32) -> Result<PathBuf, String> {
+    let dir = dir(ctx, remote);
+    if !dir.join(".git").exists() {
+        git::clone(&remote.url, &dir, remote.retries.unwrap_or(retries))?;
+    }
+    Ok(dir)
+}
+
+/// Clones the remote package repo `spec` and records it in the repo, with
+/// its own `retries` when given.
+pub fn add(
+    ctx: &Context,
+    spec: &str,
+    own_retries: Option<u32>,
+    retries: u32,
+) -> Result<Remote, String> {
+    let remote = Remote {
+        retries: own_retries,
+        ..Remote::parse(spec)?
+    };
+    let mut remotes = load(&ctx.config_path)?;
+    if remotes.iter().any(|r| r.name == remote.name) {
+        return Err(format!("a remote named '{}' already exists", remote.name));
+    }
+    let dir = checkout(ctx, &remote, retries)?;
+    if !dir.join("main.lua").is_file() {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(format!(
+            "{} has no main.lua returning its packages",
+            remote.url
+        ));
+    }
+    remotes.push(remote.clone());
+    save(&ctx.config_path, &remotes)?;
+    info!("added remote '{}' from {}", remote.name, remote.url);
+    Ok(remote)
+}
+
+/// Fast-forwards the checkouts of the remotes, or only of `name`.
+pub fn update(ctx: &Context, name: Option<&str>, retries: u32) -> Result<(), String> {
+    let remotes = load(&ctx.config_path)?;
+    if let Some(name) = name
+        && !remotes.iter().any(|r| r.name == name)
+    {
+        return Err(format!("no remote named '{}'", name));
+    }
+    for remote in remotes
+        .iter()
+        .filter(|r| name.is_none_or(|name| r.name == name))
+    {
+        let dir = checkout(ctx, remote, retries)?;
+        git::pull(&dir, remote.retries.unwrap_or(retries))?;
+    }
+    Ok(())
+}
+
+/// Forgets the remote `name` and deletes its checkout.
+pub fn remove(ctx: &Context, name: &str) -> Result<(), String> {
+    let mut remotes = load(&ctx.config_path)?;
+    let Some(i) = remotes.iter().position(|r| r.name == name) else {
+        return Err(format!("no remote named '{}'", name));
+    };
+    let remote = remotes.remove(i);
+    save(&ctx.config_path, &remotes)?;
+    let dir = dir(ctx, &remote);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)
+            .map_err(|err| format!("failed to remove {}: {}", dir.display(), err))?;
+    }
+    info!("removed remote '{}'", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::remote::*;
+    use crate::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_remotes() {
+        let _ = setup_logger();
+        assert_eq!(
+            Remote::parse("github:user/mdot-kitty").unwrap(),
+            Remote {
+                name: "kitty".to_string(),
+                url: "https://github.com/user/mdot-kitty.git".to_string(),
+                retries: None
+            }
+        );
+        assert_eq!(
+            Remote::parse("git+ssh://git@example.com:user/nvim.git")
+                .unwrap()
+                .name,
+            "nvim"
+        );
+        assert!(Remote::parse("github:user/").is_err());
+
+        let root = env::temp_dir().join(format!("mdot-remote-{}", std::process::id()));
+        let (upstream, repo) = (root.join("mdot-kitty"), root.join("repo"));
+        fs::create_dir_all(upstream.join("kitty")).unwrap();
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(upstream.join("kitty/kitty.conf"), "font_size 11\n").unwrap();
+        fs::write(upstream.join("main.lua"), r#"return { "kitty" }"#).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&upstream)
+                .args(["-c", "user.name=mdot", "-c", "user.email=mdot@example.com"])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success());
+        };
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "kitty"]);
+        fs::write(repo.join("main.lua"), r#"return { "git" }"#).unwrap();
+
+        let ctx = Context::builder()
+            .entry(repo.join("main.lua"))
+            .data_dir(root.join("data"))
+            .build();
+        let spec = format!("file://{}", upstream.display());
+        let remote = add(&ctx, &spec, Some(2), 0).unwrap();
+        assert_eq!(remote.retries, Some(2));
+        assert!(add(&ctx, &spec, None, 0).is_err());
+        assert_eq!(load(&repo).unwrap(), std::slice::from_ref(&remote));
+        update(&ctx, Some("kitty"), 0).unwrap();
+
+        let config = config::load(&ctx);
+        let names: Vec<&str> = config.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["kitty", "git"]);
+        assert_eq!(
+            ctx.package_dir(&config.packages[0]),
+            root.join("data/remotes/kitty/kitty")
+        );
+
+        remove(&ctx, "kitty").unwrap();
+        assert!(!repo.join(REMOTES_FILE).exists());
+        assert!(!dir(&ctx, &remote).exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,538 @@
+use crate::compose::{self, Compose, Composed};
+use crate::config::Config;
+use crate::deploy::PlannedLink;
+use crate::template::{self, Settings, Vars};
+use crate::{Context, Package, field, sequence, walk};
+use mlua::Value;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// Below the data dir, where a deploy renders templates before copying
+/// them into their targets.
+pub const RENDERED_DIR: &str = "rendered";
+
+/// Below the cache dir, where `mdot render` writes templates, readable by
+/// the user alone.
+const RENDER_DIR: &str = "render";
+
+/// A file rendered with the variables of its package.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pub package: String,
+    /// The file in the repo.
+    pub source: PathBuf,
+    /// Where it is rendered to below an output directory.
+    pub dest: PathBuf,
+    pub vars: Vars,
+    pub settings: Settings,
+}
+
+/// A `templates` entry: `"kitty.conf"` renders strictly,
+/// `{ "themes/*.conf", lenient = true }` with undefined variables empty and
+/// `{ "prompt.toml", delims = { "<%", "%>" } }` with other placeholders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateFile {
+    /// A file in the package, or a glob matching several.
+    pub path: PathBuf,
+    pub lenient: bool,
+    /// Overrides the `template_delims` of the package.
+    pub delims: Option<(String, String)>,
+}
+
+impl TemplateFile {
+    /// How the files of the entry are rendered in `pkg`.
+    pub fn settings(&self, config: &Config, pkg: &Package) -> Settings {
+        let mut settings = template::package_settings(config, pkg);
+        settings.lenient = self.lenient;
+        if self.delims.is_some() {
+            settings.delims = self.delims.clone();
+        }
+        settings
+    }
+}
+
+/// Parses `templates = { "kitty.conf", { "themes/*.conf", lenient = true } }`.
+pub fn files_from_value(value: &Value) -> Vec<TemplateFile> {
+    let entry = |value: Value| match value {
+        Value::String(path) => TemplateFile {
+            path: PathBuf::from(path.to_string_lossy()),
+            lenient: false,
+            delims: None,
+        },
+        Value::Table(tbl) => {
+            let path: String = tbl
+                .get(1)
+                .unwrap_or_else(|err| fatal!("'templates' entry expects a file: {}", err));
+            let lenient = match field(&tbl, "templates", "lenient") {
+                Value::Boolean(lenient) => lenient,
+                Value::Nil => false,
+                v => fatal!("'templates' 'lenient' expected type 'Boolean', got {:?}", v),
+            };
+            let delims = match field(&tbl, "templates", "delims") {
+                Value::Nil => None,
+                v => Some(template::delims_from_value("templates.delims", &v)),
+            };
+            TemplateFile {
+                path: PathBuf::from(path),
+                lenient,
+                delims,
+            }
+        }
+        v => fatal!(
+            "'templates' entries expected type 'String' or 'Table', got {}",
+            v.type_name()
+        ),
+    };
+    match value {
+        Value::String(_) => vec![entry(value.clone())],
+        Value::Table(tbl) => sequence(tbl, "templates").into_iter().map(entry).collect(),
+        v => fatal!(
+            "'templates' expected type 'String' or 'Table', got {}",
+            v.type_name()
+        ),
+    }
+}
+
+/// Something keeping a template from rendering.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub source: PathBuf,
+    /// 1-based, or 0 when the problem is not on a line.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}: {}", self.source.display(), self.message),
+            line => write!(f, "{}:{}: {}", self.source.display(), line, self.message),
+        }
+    }
+}
+
+/// The files `pkg.templates` names, globs expanded, relative to its
+/// directory, with the settings of the entry naming them.
+fn template_files(
+    ctx: &Context,
+    config: &Config,
+    pkg: &Package,
+) -> Result<Vec<(PathBuf, Settings)>, String> {
+    let dir = ctx.package_dir(pkg);
+    let mut files = Vec::new();
+    for file in &pkg.templates {
+        let pattern = &file.path;
+        if walk::is_glob(pattern) {
+            let all = walk::all_files(&dir, &config.options).map_err(|err| err.to_string())?;
+            files.extend(
+                walk::glob(pattern, all)?
+                    .into_iter()
+                    .map(|path| (path, file.settings(config, pkg))),
+            );
+        } else if dir.join(pattern).is_file() {
+            files.push((pattern.clone(), file.settings(config, pkg)));
+        } else {
+            return Err(format!(
+                "[{}] template '{}' does not exist",
+                pkg.name,
+                pattern.display()
+            ));
+        }
+    }
+    Ok(files)
+}
+
+/// The files the `templates` of `pkg` name, each rendering to
+/// `<package>/<path in the package>`.
+pub fn package_templates(
+    ctx: &Context,
+    config: &Config,
+    pkg: &Package,
+) -> Result<Vec<Template>, String> {
+    let dir = ctx.package_dir(pkg);
+    let vars = template::package_vars(config, pkg, &ctx.platform);
+    Ok(template_files(ctx, config, pkg)?
+        .into_iter()
+        .map(|(rel, settings)| Template {
+            package: pkg.name.clone(),
+            source: dir.join(&rel),
+            dest: Path::new(&pkg.name).join(rel),
+            vars: vars.clone(),
+            settings,
+        })
+        .collect())
+}
+
+/// Where a deploy renders `template` to.
+pub fn rendered_path(ctx: &Context, template: &Template) -> PathBuf {
+    ctx.data_dir.join(RENDERED_DIR).join(&template.dest)
+}
+
+/// The dir `mdot render` writes into, created for the user alone and
+/// emptied of what an earlier run left there.
+pub fn render_dir(ctx: &Context) -> Result<PathBuf, String> {
+    let dir = ctx.cache_dir.join(RENDER_DIR);
+    match fs::remove_dir_all(&dir) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(format!("failed to clear {}: {}", dir.display(), err));
+        }
+        _ => (),
+    }
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .and_then(|_| fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)))
+        .map_err(|err| format!("failed to create {}: {}", dir.display(), err))?;
+    Ok(dir)
+}
+
+/// The templates of `packages`: the files their `templates` name, and the
+/// fragments of their `compose = "fragments"` links. Each renders to
+/// `<package>/<path in the package>`.
+pub fn templates(
+    ctx: &Context,
+    config: &Config,
+    packages: &[Package],
+) -> Result<Vec<Template>, String> {
+    let mut templates = Vec::new();
+    for pkg in packages {
+        templates.extend(package_templates(ctx, config, pkg)?);
+    }
+    for composed in compose::plan(ctx, config, packages)? {
+        if composed.mode != Compose::Fragments {
+            continue;
+        }
+        for fragment in composed.fragments {
+            let dir = packages
+                .iter()
+                .find(|p| p.name == fragment.package)
+                .map(|p| ctx.package_dir(p))
+                .unwrap_or_default();
+            let rel = fragment
+                .source
+                .strip_prefix(&dir)
+                .unwrap_or(&fragment.source);
+            let dest = Path::new(&fragment.package).join(rel);
+            if templates.iter().any(|t| t.dest == dest) {
+                continue;
+            }
+            templates.push(Template {
+                package: fragment.package,
+                dest,
+                source: fragment.source,
+                vars: fragment.vars,
+                settings: fragment.settings,
+            });
+        }
+    }
+    Ok(templates)
+}
+
+/// Checks `template`, returning its rendered content when it has no
+/// problems.
+pub fn render(template: &Template) -> Result<String, Vec<Problem>> {
+    let problem = |line, message| Problem {
+        source: template.source.clone(),
+        line,
+        message,
+    };
+    let input = fs::read_to_string(&template.source)
+        .map_err(|err| vec![problem(0, format!("failed to read: {}", err))])?;
+    let problems: Vec<Problem> = template::check(&input, &template.vars, &template.settings)
+        .into_iter()
+        .map(|(line, message)| problem(line, message))
+        .collect();
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    template::render_with(&input, &template.vars, &template.settings)
+        .map_err(|err| vec![problem(0, err)])
+}
+
+/// Renders `templates` below `dir` with the permissions of their sources,
+/// returning the problems of those that do not render.
+pub fn render_all(templates: &[Template], dir: &Path) -> Result<Vec<Problem>, String> {
+    let mut problems = Vec::new();
+    for template in templates {
+        match render(template) {
+            Ok(content) => {
+                let out = dir.join(&template.dest);
+                if let Some(parent) = out.parent() {
+                    fs::create_dir_all(parent)
+                        .map_err(|err| format!("failed to create {}: {}", parent.display(), err))?;
+                }
+                if fs::read_to_string(&out).is_ok_and(|old| old == content) {
+                    continue;
+                }
+                fs::write(&out, content)
+                    .and_then(|_| fs::metadata(&template.source))
+                    .and_then(|meta| fs::set_permissions(&out, meta.permissions()))
+                    .map_err(|err| format!("failed to write {}: {}", out.display(), err))?;
+            }
+            Err(found) => problems.extend(found),
+        }
+    }
+    Ok(problems)
+}
+
+/// Where the absolute `path` goes below `out`.
+pub fn under(out: &Path, path: &Path) -> PathBuf {
+    out.join(
+        path.components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect::<PathBuf>(),
+    )
+}
+
+/// Copies `source` to `dest`, directories recursively, rendering the
+/// files that are `templates`.
+pub fn materialize_file(
+    source: &Path,
+    dest: &Path,
+    templates: &[Template],
+    problems: &mut Vec<Problem>,
+) -> Result<(), String> {
+    let failed = |err: std::io::Error| format!("failed to copy {}: {}", source.display(), err);
+    if source.is_dir() {
+        fs::create_dir_all(dest).map_err(failed)?;
+        let mut entries: Vec<PathBuf> = fs::read_dir(source)
+            .map_err(failed)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect();
+        entries.sort();
+        for entry in entries {
+            let name = entry.file_name().unwrap_or_default();
+            materialize_file(&entry, &dest.join(name), templates, problems)?;
+        }
+        return Ok(());
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(failed)?;
+    }
+    match templates.iter().find(|t| t.source == source) {
+        Some(template) => match render(template) {
+            Ok(content) => fs::write(dest, content)
+                .and_then(|_| fs::metadata(source))
+                .and_then(|meta| fs::set_permissions(dest, meta.permissions()))
+                .map_err(failed)?,
+            Err(found) => problems.extend(found),
+        },
+        None => {
+            fs::copy(source, dest).map_err(failed)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes what deploying would create below `out`, each target at its
+/// absolute path: link sources copied, templates rendered and composed
+/// files built. Returns the problems of the templates that do not render.
+pub fn materialize(
+    links: &[PlannedLink],
+    composed: &[Composed],
+    templates: &[Template],
+    out: &Path,
+) -> Result<Vec<Problem>, String> {
+    let mut problems = Vec::new();
+    for link in links {
+        materialize_file(
+            link.rendered_from.as_ref().unwrap_or(&link.source),
+            &under(out, &link.target),
+            templates,
+            &mut problems,
+        )?;
+    }
+    for composed in composed {
+        let dest = under(out, &composed.target);
+        match compose::build(composed) {
+            Ok(content) => {
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+                }
+                fs::write(&dest, content)
+                    .map_err(|err| format!("failed to write {}: {}", dest.display(), err))?;
+            }
+            Err(message) => problems.push(Problem {
+                source: composed.target.clone(),
+                line: 0,
+                message,
+            }),
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::render::*;
+    use crate::*;
+
+    #[test]
+    fn test_render_templates() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-render-{}", std::process::id()));
+        fs::create_dir_all(root.join("kitty/themes")).unwrap();
+        fs::create_dir_all(root.join("shell")).unwrap();
+        fs::write(
+            root.join("kitty/kitty.conf"),
+            "font {{ vars.font }} {{ vars.size | default(\"11\") }}\n",
+        )
+        .unwrap();
+        fs::write(root.join("kitty/tab.conf"), "tab {{ vars.tab }}\n").unwrap();
+        fs::write(
+            root.join("kitty/themes/dark.conf"),
+            "bg {{ vars.bg }}\nfg {{ vars.fg }}\n{{ name",
+        )
+        .unwrap();
+        fs::write(root.join("shell/env.sh"), "export NAME=<%= name %>\n").unwrap();
+        fs::write(root.join("shell/aliases.sh"), "alias <%= name %>=true\n").unwrap();
+        fs::create_dir_all(root.join("waybar")).unwrap();
+        fs::write(
+            root.join("waybar/config.json"),
+            "{ \"format\": \"{{icon}} [[ vars.font ]]\" }\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("waybar/style.css"),
+            "* { font: {{ vars.font }}; }\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r##"
+            mdot.vars.font = "Iosevka"
+            mdot.vars.fg = "#fff"
+            return {
+                { "kitty", templates = { "kitty.conf", "themes/*.conf", { "tab.conf", lenient = true } } },
+                { "shell", links = { { source = "env.sh", targets = "~/.env", compose = "fragments" } },
+                  template_engine = "lua", templates = { "aliases.sh" } },
+                { "waybar", template_delims = { "[[", "]]" },
+                  templates = { "config.json", { "style.css", delims = { "{{", "}}" } } } },
+            }
+            "##,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let templates = templates(&ctx, &config, &config.packages).unwrap();
+        let dests: Vec<&Path> = templates.iter().map(|t| t.dest.as_path()).collect();
+        assert_eq!(
+            dests,
+            [
+                Path::new("kitty/kitty.conf"),
+                Path::new("kitty/themes/dark.conf"),
+                Path::new("kitty/tab.conf"),
+                Path::new("shell/aliases.sh"),
+                Path::new("waybar/config.json"),
+                Path::new("waybar/style.css"),
+                Path::new("shell/env.sh"),
+            ]
+        );
+
+        // What an earlier run left is cleared, and the output is private.
+        fs::create_dir_all(root.join("cache/render/gone")).unwrap();
+        let out = render_dir(&ctx).unwrap();
+        assert_eq!(out, root.join("cache/render"));
+        assert!(!out.join("gone").exists());
+        let mode = fs::metadata(&out).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        let problems = render_all(&templates, &out).unwrap();
+        let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+        let dark = root.join("kitty/themes/dark.conf");
+        assert_eq!(
+            problems,
+            [
+                format!("{}:1: undefined variable 'vars.bg'", dark.display()),
+                format!("{}:3: unterminated '{{{{'", dark.display()),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("kitty/kitty.conf")).unwrap(),
+            "font Iosevka 11\n"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("kitty/tab.conf")).unwrap(),
+            "tab \n"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("waybar/config.json")).unwrap(),
+            "{ \"format\": \"{{icon}} Iosevka\" }\n"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("waybar/style.css")).unwrap(),
+            "* { font: Iosevka; }\n"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("shell/aliases.sh")).unwrap(),
+            "alias shell=true\n"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("shell/env.sh")).unwrap(),
+            "export NAME=shell\n"
+        );
+        assert!(!out.join("kitty/themes/dark.conf").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_materialize() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-materialize-{}", std::process::id()));
+        fs::create_dir_all(root.join("nvim/lua")).unwrap();
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("nvim/init.lua"), "require('{{ vars.plugin }}')\n").unwrap();
+        fs::write(root.join("nvim/lua/opts.lua"), "vim.o.number = true\n").unwrap();
+        fs::write(root.join("bin/hello"), "#!/bin/sh\necho {{ name }}\n").unwrap();
+        fs::set_permissions(
+            root.join("bin/hello"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.vars.plugin = "lazy"
+            return {
+                { "nvim", default_target = "/etc/xdg/nvim", templates = { "init.lua" } },
+                { "bin", links = { { source = "hello", targets = "/usr/local/bin/hello" } },
+                  templates = { "hello" } },
+            }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let links = deploy::plan(&ctx, &config, &config.packages);
+        let templates = templates(&ctx, &config, &config.packages).unwrap();
+        let out = root.join("out");
+        assert_eq!(materialize(&links, &[], &templates, &out).unwrap(), []);
+        assert_eq!(
+            fs::read_to_string(out.join("etc/xdg/nvim/init.lua")).unwrap(),
+            "require('lazy')\n"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("etc/xdg/nvim/lua/opts.lua")).unwrap(),
+            "vim.o.number = true\n"
+        );
+        let hello = out.join("usr/local/bin/hello");
+        assert_eq!(fs::read_to_string(&hello).unwrap(), "#!/bin/sh\necho bin\n");
+        assert!(!fs::symlink_metadata(&hello).unwrap().is_symlink());
+        let mode =
+            std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&hello).unwrap().permissions());
+        assert_eq!(mode & 0o777, 0o755);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use crate::Context;
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const UNIT: &str = "mdot-sync";
+const LAUNCHD_LABEL: &str = "io.github.mdot.sync";
+
+/// Parses `90s`, `30m`, `1h` or `1d` (a bare number is seconds).
+pub fn parse_interval(interval: &str) -> Result<u64, String> {
+    let interval = interval.trim();
+    let (digits, unit) = match interval.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => interval.split_at(i),
+        None => (interval, "s"),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid interval '{}'", interval))?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid interval unit in '{}'", interval)),
+    };
+    match n.checked_mul(scale) {
+        Some(0) => Err("the interval must not be zero".to_string()),
+        Some(secs) => Ok(secs),
+        None => Err(format!("interval '{}' is too long", interval)),
+    }
+}
+
+/// The command line the scheduled job runs.
+fn sync_args(ctx: &Context, profile: Option<&str>) -> Vec<String> {
+    let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("mdot"));
+    let mut args = vec![
+        exe.display().to_string(),
+        "--config".to_string(),
+        ctx.entry.display().to_string(),
+    ];
+    if let Some(profile) = profile {
+        args.extend(["--profile".to_string(), profile.to_string()]);
+    }
+    args.extend(["git".to_string(), "sync".to_string()]);
+    args
+}
+
+/// Quotes an argument for a systemd `ExecStart=` line, doubling `%` and
+/// `$` so that systemd does not expand specifiers and variables in it.
+fn systemd_quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if arg
+        .chars()
+        .any(|c| c.is_whitespace() || "\"'\\".contains(c))
+    {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+/// The service and timer units running the sync every `secs` seconds.
+pub fn systemd_units(args: &[String], secs: u64) -> (String, String) {
+    let exec: Vec<String> = args.iter().map(|a| systemd_quote(a)).collect();
+    let service = format!(
+        "[Unit]\nDescription=Sync dotfiles with mdot\n\n\
+         [Service]\nType=oneshot\nExecStart={}\n",
+        exec.join(" ")
+    );
+    let timer = format!(
+        "[Unit]\nDescription=Sync dotfiles with mdot every {secs}s\n\n\
+         [Timer]\nOnBootSec=5min\nOnUnitActiveSec={secs}s\nPersistent=true\n\n\
+         [Install]\nWantedBy=timers.target\n"
+    );
+    (service, timer)
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// A launchd agent running the sync every `secs` seconds.
+pub fn launchd_plist(args: &[String], secs: u64) -> String {
+    let args: String = args
+        .iter()
+        .map(|a| format!("        <string>{}</string>\n", xml_escape(a)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n<dict>\n\
+         \x20   <key>Label</key>\n    <string>{LAUNCHD_LABEL}</string>\n\
+         \x20   <key>ProgramArguments</key>\n    <array>\n{args}    </array>\n\
+         \x20   <key>StartInterval</key>\n    <integer>{secs}</integer>\n\
+         \x20   <key>RunAtLoad</key>\n    <true/>\n\
+         </dict>\n</plist>\n"
+    )
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|err| format!("failed to run {}: {}", program, err))?;
+    if !status.success() {
+        return Err(format!(
+            "{} {} exited with {}",
+            program,
+            args.join(" "),
+            status
+        ));
+    }
+    Ok(())
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+    fs::write(path, contents)
+        .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+    info!("wrote {}", path.display());
+    Ok(())
+}
+
+fn systemd_dir() -> PathBuf {
+    dirs::config_dir().unwrap_or_default().join("systemd/user")
+}
+
+fn launchd_path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_default()
+        .join("Library/LaunchAgents")
+        .join(format!("{}.plist", LAUNCHD_LABEL))
+}
+
+/// Writes and enables a user timer (launchd agent on macOS) running
+/// `mdot git sync` every `interval`.
+pub fn install(ctx: &Context, profile: Option<&str>, interval: &str) -> Result<(), String> {
+    let secs = parse_interval(interval)?;
+    let args = sync_args(ctx, profile);
+    if ctx.platform.os == "macos" {
+        let path = launchd_path();
+        let _ = run("launchctl", &["unload", &path.to_string_lossy()]);
+        write(&path, &launchd_plist(&args, secs))?;
+        return run("launchctl", &["load", "-w", &path.to_string_lossy()]);
+    }
+    let (service, timer) = systemd_units(&args, secs);
+    write(&systemd_dir().join(format!("{}.service", UNIT)), &service)?;
+    write(&systemd_dir().join(format!("{}.timer", UNIT)), &timer)?;
+    run("systemctl", &["--user", "daemon-reload"])?;
+    run(
+        "systemctl",
+        &["--user", "enable", "--now", &format!("{}.timer", UNIT)],
+    )
+}
+
+/// Disables and deletes what `install` set up.
+pub fn remove(ctx: &Context) -> Result<(), String> {
+    if ctx.platform.os == "macos" {
+        let path = launchd_path();
+        run("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+        return fs::remove_file(&path).map_err(|err| err.to_string());
+    }
+    let _ = run(
+        "systemctl",
+        &["--user", "disable", "--now", &format!("{}.timer", UNIT)],
+    );
+    for ext in ["service", "timer"] {
+        let path = systemd_dir().join(format!("{}.{}", UNIT, ext));
+        match fs::remove_file(&path) {
+            Ok(()) => info!("removed {}", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(format!("failed to remove {}: {}", path.display(), err)),
+        }
+    }
+    run("systemctl", &["--user", "daemon-reload"])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schedule::*;
+
+    #[test]
+    fn test_units() {
+        assert_eq!(parse_interval("1h"), Ok(3600));
+        assert_eq!(parse_interval("90"), Ok(90));
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("1w").is_err());
+        assert!(parse_interval("999999999999999999d").is_err());
+        assert_eq!(systemd_quote("/home/me/50%$HOME"), "/home/me/50%%$$HOME");
+
+        let args = ["/bin/mdot", "--config", "/my dots/main.lua", "git", "sync"].map(String::from);
+        let (service, timer) = systemd_units(&args, 600);
+        assert!(service.contains("ExecStart=/bin/mdot --config \"/my dots/main.lua\" git sync\n"));
+        assert!(timer.contains("OnUnitActiveSec=600s\n"));
+        let plist = launchd_plist(&args, 600);
+        assert!(plist.contains("<string>/my dots/main.lua</string>"));
+        assert!(plist.contains("<integer>600</integer>"));
+    }
+}
@@ -0,0 +1,137 @@
+use log::warn;
+use mlua::{Table, Value};
+use std::collections::BTreeMap;
+
+/// The config schema this mdot understands, set as `schema = 1` next to
+/// the packages returned by the entry file. Configs without it are read
+/// as schema 1.
+pub const CURRENT: i64 = 1;
+
+/// A package key renamed in schema `since`.
+struct Rename {
+    since: i64,
+    old: &'static str,
+    new: &'static str,
+}
+
+/// Package keys renamed since schema 1, none so far. A rename bumps
+/// [`CURRENT`] and is listed here, so that older configs keep working.
+const RENAMES: &[Rename] = &[];
+
+/// Reads and removes the `schema` key of a returned package list.
+pub fn take_version(tbl: &Table) -> Result<Option<i64>, String> {
+    let version = match tbl
+        .raw_get::<Value>("schema")
+        .map_err(|err| err.to_string())?
+    {
+        Value::Nil => return Ok(None),
+        Value::Integer(version) => version,
+        v => {
+            return Err(format!(
+                "'schema' expected an integer, got {}",
+                v.type_name()
+            ));
+        }
+    };
+    tbl.raw_set("schema", Value::Nil)
+        .map_err(|err| err.to_string())?;
+    if !(1..=CURRENT).contains(&version) {
+        return Err(format!(
+            "the config uses schema {}, but this mdot only reads schemas 1 to {}; update mdot",
+            version, CURRENT
+        ));
+    }
+    Ok(Some(version))
+}
+
+/// Why `key` is unknown when it was renamed at some point.
+pub fn hint(key: &str) -> Option<String> {
+    RENAMES
+        .iter()
+        .find(|r| r.old == key)
+        .map(|r| format!("it was renamed to '{}' in schema {}", r.new, r.since))
+}
+
+fn upgrade_package(tbl: &Table, version: i64, counts: &mut BTreeMap<usize, usize>) {
+    for (i, rename) in RENAMES.iter().enumerate() {
+        if rename.since <= version {
+            continue;
+        }
+        let Ok(value) = tbl.raw_get::<Value>(rename.old) else {
+            continue;
+        };
+        if value.is_nil() || !tbl.raw_get::<Value>(rename.new).is_ok_and(|v| v.is_nil()) {
+            continue;
+        }
+        let _ = tbl.raw_set(rename.new, value);
+        let _ = tbl.raw_set(rename.old, Value::Nil);
+        *counts.entry(i).or_default() += 1;
+    }
+    if let Ok(Value::Table(depends)) = tbl.raw_get::<Value>("depends") {
+        upgrade_list(&depends, version, counts);
+    }
+}
+
+fn upgrade_list(list: &Table, version: i64, counts: &mut BTreeMap<usize, usize>) {
+    for (_, value) in list.pairs::<Value, Value>().flatten() {
+        if let Value::Table(pkg) = value {
+            upgrade_package(&pkg, version, counts);
+        }
+    }
+}
+
+/// Renames the keys of a schema `version` package list in place, so it
+/// reads like the current schema, and prints one hint per renamed key.
+pub fn upgrade(list: &Table, version: i64) {
+    let mut counts = BTreeMap::new();
+    upgrade_list(list, version, &mut counts);
+    for (i, count) in counts {
+        let rename = &RENAMES[i];
+        warn!(
+            "schema {}: read '{}' as '{}' in {} package(s); rename it and set schema = {}",
+            version, rename.old, rename.new, count, CURRENT
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_version() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-schema-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"return { schema = 1, hypr = { package_name = { arch = "hyprland" } } }"#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        assert_eq!(config.packages.len(), 1);
+        assert!(config.packages[0].package_name.is_some());
+
+        let tbl = ctx.lua.create_table().unwrap();
+        tbl.raw_set("schema", 1).unwrap();
+        assert_eq!(schema::take_version(&tbl), Ok(Some(1)));
+        assert_eq!(schema::take_version(&tbl), Ok(None));
+        tbl.raw_set("schema", 2).unwrap();
+        assert!(
+            schema::take_version(&tbl)
+                .unwrap_err()
+                .contains("update mdot")
+        );
+        tbl.raw_set("schema", "1").unwrap();
+        assert!(schema::take_version(&tbl).is_err());
+        assert_eq!(schema::hint("pkg"), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
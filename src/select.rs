@@ -0,0 +1,534 @@
+use crate::config::Config;
+use crate::{Enabled, Package, cli};
+use log::warn;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// Why a package was picked for deployment.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    /// Defined in the config and no filter narrowed the selection.
+    Config,
+    /// Named on the command line.
+    CommandLine,
+    /// Listed in, or tagged for, the active profile.
+    Profile(String),
+    /// Listed in the `depends` of the named package.
+    RequiredBy(String),
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::Config => write!(f, "defined in the config"),
+            Reason::CommandLine => write!(f, "named on the command line"),
+            Reason::Profile(profile) => write!(f, "selected by profile '{}'", profile),
+            Reason::RequiredBy(pkg) => write!(f, "required by {}", pkg),
+        }
+    }
+}
+
+/// Why a package is left out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Skip {
+    /// Other packages were named on the command line.
+    NotSelected,
+    NotInProfile(String),
+    /// Carries none of the `--tag` filters.
+    MissingTag(Vec<String>),
+    /// Only defined inline in `depends`, and nothing selected requires it.
+    NotRequired,
+    /// Named by `--exclude`.
+    Excluded,
+    /// Carries a tag given to `--exclude-tag`.
+    ExcludedTag(String),
+    /// `enabled = false`.
+    Disabled,
+    /// The `enabled` function returned false.
+    DisabledByHook,
+}
+
+impl Skip {
+    /// Selection filters give way when a selected package requires one.
+    fn is_filter(&self) -> bool {
+        matches!(
+            self,
+            Skip::NotSelected | Skip::NotInProfile(_) | Skip::MissingTag(_) | Skip::NotRequired
+        )
+    }
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Skip::NotSelected => write!(f, "not named on the command line"),
+            Skip::NotInProfile(profile) => write!(f, "not part of profile '{}'", profile),
+            Skip::MissingTag(tags) => write!(f, "has none of the tags {}", tags.join(", ")),
+            Skip::NotRequired => write!(f, "only defined as a dependency, and nothing requires it"),
+            Skip::Excluded => write!(f, "excluded by --exclude"),
+            Skip::ExcludedTag(tag) => write!(f, "tagged '{}', excluded by --exclude-tag", tag),
+            Skip::Disabled => write!(f, "disabled by 'enabled = false'"),
+            Skip::DisabledByHook => write!(f, "disabled by its 'enabled' function"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Decision {
+    pub reasons: Vec<Reason>,
+    pub skip: Option<Skip>,
+}
+
+impl Decision {
+    pub fn deployed(&self) -> bool {
+        self.skip.is_none() && !self.reasons.is_empty()
+    }
+}
+
+pub struct Selection {
+    /// Packages to deploy, dependencies first.
+    pub packages: Vec<Package>,
+    /// The decision for every known package, deployed or not.
+    pub decisions: BTreeMap<String, Decision>,
+}
+
+/// Finds a package by name, including ones only defined inline in some
+/// `depends`.
+pub fn find_package<'a>(packages: &'a [Package], name: &str) -> Option<&'a Package> {
+    packages.iter().find_map(|pkg| {
+        if pkg.name == name {
+            Some(pkg)
+        } else {
+            find_package(&pkg.depends, name)
+        }
+    })
+}
+
+/// Collects every package by name: top-level definitions first, then the
+/// ones only defined inline in some `depends`. Also returns how many of
+/// them are top-level.
+fn registry(config: &Config) -> (Vec<&Package>, HashMap<&str, usize>, usize) {
+    fn register<'a>(
+        pkg: &'a Package,
+        all: &mut Vec<&'a Package>,
+        index: &mut HashMap<&'a str, usize>,
+    ) {
+        for dep in &pkg.depends {
+            if !index.contains_key(dep.name.as_str()) {
+                index.insert(&dep.name, all.len());
+                all.push(dep);
+            }
+            register(dep, all, index);
+        }
+    }
+
+    let mut all = Vec::new();
+    let mut index = HashMap::new();
+    for pkg in &config.packages {
+        if index.contains_key(pkg.name.as_str()) {
+            warn!("package '{}' is defined more than once", pkg.name);
+            continue;
+        }
+        index.insert(pkg.name.as_str(), all.len());
+        all.push(pkg);
+    }
+    let top_level = all.len();
+    for pkg in &config.packages {
+        register(pkg, &mut all, &mut index);
+    }
+    (all, index, top_level)
+}
+
+/// Every package, including the ones only defined inline in some `depends`.
+pub fn all_packages(config: &Config) -> Vec<&Package> {
+    registry(config).0
+}
+
+/// The decision for a top-level package before dependencies are resolved.
+fn root_decision(
+    config: &Config,
+    pkg: &Package,
+    names: &[String],
+    profile: Option<&str>,
+    filter: &cli::Filter,
+) -> Decision {
+    let mut decision = Decision::default();
+    if !names.is_empty() {
+        if names.contains(&pkg.name) {
+            decision.reasons.push(Reason::CommandLine);
+        } else {
+            decision.skip = Some(Skip::NotSelected);
+        }
+    } else if let Some(name) = profile {
+        let profile = &config.profiles[name];
+        if profile.packages.contains(&pkg.name)
+            || pkg.tags.iter().any(|tag| profile.tags.contains(tag))
+        {
+            decision.reasons.push(Reason::Profile(name.to_string()));
+        } else {
+            decision.skip = Some(Skip::NotInProfile(name.to_string()));
+        }
+    } else {
+        decision.reasons.push(Reason::Config);
+    }
+    if decision.skip.is_none()
+        && !filter.tags.is_empty()
+        && !pkg.tags.iter().any(|tag| filter.tags.contains(tag))
+    {
+        decision.reasons.clear();
+        decision.skip = Some(Skip::MissingTag(filter.tags.clone()));
+    }
+    decision
+}
+
+/// Profile that `mdot deploy` treats as `--minimal`, for containers.
+pub const CONTAINER_PROFILE: &str = "container";
+/// Tag of packages that `--minimal` leaves out.
+pub const GUI_TAG: &str = "gui";
+
+/// Decides which packages get deployed, pulling in dependencies of
+/// selected packages, and orders them so dependencies come first.
+///
+/// `wants` never pull a package in; they only order it first when it is
+/// deployed anyway.
+pub fn select(
+    config: &Config,
+    names: &[String],
+    profile: Option<&str>,
+    filter: &cli::Filter,
+) -> Selection {
+    let (all, index, top_level) = registry(config);
+    for name in names.iter().chain(&filter.exclude) {
+        if !index.contains_key(name.as_str()) {
+            fatal!("unknown package '{}'", name);
+        }
+    }
+    if let Some(name) = profile {
+        let Some(profile) = config.profiles.get(name) else {
+            fatal!("unknown profile '{}'", name);
+        };
+        for pkg in &profile.packages {
+            if !index.contains_key(pkg.as_str()) {
+                warn!("profile '{}' lists unknown package '{}'", name, pkg);
+            }
+        }
+    }
+
+    let mut decisions: Vec<Decision> = all
+        .iter()
+        .enumerate()
+        .map(|(i, pkg)| match i < top_level {
+            true => root_decision(config, pkg, names, profile, filter),
+            false => Decision {
+                reasons: Vec::new(),
+                skip: Some(Skip::NotRequired),
+            },
+        })
+        .collect();
+
+    let mut queue: VecDeque<usize> = (0..all.len())
+        .filter(|&i| decisions[i].deployed())
+        .collect();
+    let mut checked = HashSet::new();
+    while let Some(i) = queue.pop_front() {
+        if !checked.insert(i) {
+            continue;
+        }
+        let pkg = all[i];
+        if filter.exclude.contains(&pkg.name) {
+            decisions[i].skip = Some(Skip::Excluded);
+            continue;
+        }
+        if let Some(tag) = pkg
+            .tags
+            .iter()
+            .find(|tag| filter.exclude_tags.contains(tag))
+        {
+            decisions[i].skip = Some(Skip::ExcludedTag(tag.clone()));
+            continue;
+        }
+        if !pkg.is_enabled() {
+            decisions[i].skip = Some(match pkg.enabled {
+                Enabled::Hook(_) => Skip::DisabledByHook,
+                Enabled::Enable(_) => Skip::Disabled,
+            });
+            continue;
+        }
+        for dep in &pkg.depends {
+            let j = index[dep.name.as_str()];
+            let decision = &mut decisions[j];
+            decision.reasons.push(Reason::RequiredBy(pkg.name.clone()));
+            if decision.skip.as_ref().is_some_and(Skip::is_filter) {
+                decision.skip = None;
+            }
+            queue.push_back(j);
+        }
+    }
+
+    for (i, decision) in decisions.iter().enumerate() {
+        if let Some(skip) = decision.skip.as_ref().filter(|s| !s.is_filter()) {
+            for reason in &decision.reasons {
+                if let Reason::RequiredBy(parent) = reason {
+                    warn!("{} requires {}, which is {}", parent, all[i].name, skip);
+                }
+            }
+        }
+    }
+
+    let order = order(&all, &index, &decisions);
+
+    Selection {
+        packages: order.into_iter().map(|i| all[i].clone()).collect(),
+        decisions: all
+            .iter()
+            .zip(decisions)
+            .map(|(pkg, decision)| (pkg.name.clone(), decision))
+            .collect(),
+    }
+}
+
+/// Depth-first topological sort over `edges`, failing on cycles.
+fn visit(
+    i: usize,
+    all: &[&Package],
+    edges: &[Vec<usize>],
+    state: &mut [u8],
+    stack: &mut Vec<usize>,
+    order: &mut Vec<usize>,
+) {
+    const VISITING: u8 = 1;
+    const DONE: u8 = 2;
+    match state[i] {
+        DONE => return,
+        VISITING => {
+            let cycle: Vec<&str> = stack
+                .iter()
+                .skip_while(|&&j| j != i)
+                .chain([&i])
+                .map(|&j| all[j].name.as_str())
+                .collect();
+            fatal!("dependency cycle: {}", cycle.join(" -> "));
+        }
+        _ => (),
+    }
+    state[i] = VISITING;
+    stack.push(i);
+    for &j in &edges[i] {
+        visit(j, all, edges, state, stack, order);
+    }
+    stack.pop();
+    state[i] = DONE;
+    order.push(i);
+}
+
+fn sort(all: &[&Package], edges: &[Vec<usize>], roots: &[usize]) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut state = vec![0u8; all.len()];
+    for &i in roots {
+        visit(i, all, edges, &mut state, &mut Vec::new(), &mut order);
+    }
+    order
+}
+
+fn reachable(from: usize, to: usize, edges: &[Vec<usize>]) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![from];
+    while let Some(i) = stack.pop() {
+        if i == to {
+            return true;
+        }
+        if seen.insert(i) {
+            stack.extend(&edges[i]);
+        }
+    }
+    false
+}
+
+/// Orders deployed packages so that `depends` come first, and so do
+/// `wants` that are deployed too. A cycle through `depends` alone is an
+/// error; a `wants` edge that would close a cycle is dropped instead.
+fn order(all: &[&Package], index: &HashMap<&str, usize>, decisions: &[Decision]) -> Vec<usize> {
+    let deployed: Vec<usize> = (0..all.len())
+        .filter(|&i| decisions[i].deployed())
+        .collect();
+    let mut edges = vec![Vec::new(); all.len()];
+    for &i in &deployed {
+        for dep in &all[i].depends {
+            let j = index[dep.name.as_str()];
+            if decisions[j].deployed() {
+                edges[i].push(j);
+            }
+        }
+    }
+    sort(all, &edges, &deployed);
+
+    for &i in &deployed {
+        for want in &all[i].wants {
+            let Some(&j) = index.get(want.as_str()) else {
+                continue;
+            };
+            if !decisions[j].deployed() || edges[i].contains(&j) {
+                continue;
+            }
+            if reachable(j, i, &edges) {
+                warn!(
+                    "ignoring '{}' wants '{}': it would create a dependency cycle",
+                    all[i].name, want
+                );
+                continue;
+            }
+            edges[i].push(j);
+        }
+    }
+    sort(all, &edges, &deployed)
+}
+
+fn explain_reasons(
+    selection: &Selection,
+    name: &str,
+    depth: usize,
+    seen: &mut HashSet<String>,
+    out: &mut Vec<String>,
+) {
+    for reason in &selection.decisions[name].reasons {
+        out.push(format!("{}- {}", "  ".repeat(depth), reason));
+        if let Reason::RequiredBy(parent) = reason
+            && seen.insert(parent.clone())
+        {
+            explain_reasons(selection, parent, depth + 1, seen, out);
+        }
+    }
+}
+
+/// Describes the decision about `name` and the chain of reasons behind it.
+pub fn why(selection: &Selection, name: &str) -> Option<String> {
+    let decision = selection.decisions.get(name)?;
+    let mut out = Vec::new();
+    match &decision.skip {
+        None => out.push(format!("{} will be deployed", name)),
+        Some(skip) => {
+            out.push(format!("{} will not be deployed", name));
+            out.push(format!("- {}", skip));
+            if !decision.reasons.is_empty() {
+                out.push("even though it is".to_string());
+            }
+        }
+    }
+    let mut seen = HashSet::from([name.to_string()]);
+    explain_reasons(selection, name, 0, &mut seen, &mut out);
+    Some(out.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::fs;
+
+    fn load(name: &str, lua: &str) -> (Context, config::Config) {
+        let root = env::temp_dir().join(format!("mdot-select-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("main.lua"), lua).unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        fs::remove_dir_all(root).unwrap();
+        (ctx, config)
+    }
+
+    const CONFIG: &str = r#"
+        mdot.profiles.desktop = { "git", tags = { "gui" } }
+        return {
+            "fish",
+            { "waybar", tags = "gui", enabled = function() return false end },
+            git = { depends = { "hypr", { "delta" } } },
+            hypr = { depends = { "fish" } },
+        }
+    "#;
+
+    fn names(selection: &select::Selection) -> Vec<&str> {
+        selection.packages.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_select_profile_and_depends() {
+        let _ = setup_logger();
+        let (_ctx, config) = load("profile", CONFIG);
+        let selection = select::select(&config, &[], Some("desktop"), &cli::Filter::default());
+        assert_eq!(names(&selection), vec!["fish", "hypr", "delta", "git"]);
+        assert_eq!(
+            selection.decisions["waybar"].skip,
+            Some(select::Skip::DisabledByHook)
+        );
+        assert_eq!(
+            selection.decisions["fish"].reasons,
+            vec![select::Reason::RequiredBy("hypr".to_string())]
+        );
+        let why = select::why(&selection, "fish").unwrap();
+        assert_eq!(
+            why,
+            "fish will be deployed\n\
+             - required by hypr\n  \
+               - required by git\n    \
+                 - selected by profile 'desktop'"
+        );
+    }
+
+    #[test]
+    fn test_select_exclude() {
+        let _ = setup_logger();
+        let (_ctx, config) = load("exclude", CONFIG);
+        let filter = cli::Filter {
+            exclude: vec!["hypr".to_string()],
+            ..Default::default()
+        };
+        let selection = select::select(&config, &["git".to_string()], None, &filter);
+        assert_eq!(names(&selection), vec!["delta", "git"]);
+        assert_eq!(
+            selection.decisions["hypr"].skip,
+            Some(select::Skip::Excluded)
+        );
+        assert_eq!(
+            selection.decisions["fish"].skip,
+            Some(select::Skip::NotSelected)
+        );
+    }
+
+    #[test]
+    fn test_select_exclude_tag() {
+        let _ = setup_logger();
+        let (_ctx, config) = load("exclude-tag", CONFIG);
+        let filter = cli::Filter {
+            exclude_tags: vec!["gui".to_string()],
+            ..Default::default()
+        };
+        let selection = select::select(&config, &[], None, &filter);
+        assert_eq!(names(&selection), vec!["fish", "hypr", "delta", "git"]);
+        assert_eq!(
+            selection.decisions["waybar"].skip,
+            Some(select::Skip::ExcludedTag("gui".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_select_wants() {
+        let _ = setup_logger();
+        let (_ctx, config) = load(
+            "wants",
+            r#"return {
+                { "waybar", wants = { "fonts", "missing" } },
+                { "fonts", wants = "waybar" },
+                "fish",
+            }"#,
+        );
+        let filter = cli::Filter::default();
+        let selection = select::select(&config, &[], None, &filter);
+        assert_eq!(names(&selection), vec!["fonts", "waybar", "fish"]);
+
+        let selection = select::select(&config, &["waybar".to_string()], None, &filter);
+        assert_eq!(names(&selection), vec!["waybar"]);
+    }
+}
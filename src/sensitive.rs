@@ -0,0 +1,286 @@
+use crate::config::Config;
+use crate::deploy::{PlannedLink, is_deployed, resolve_target};
+use crate::{Context, Package, assets, compose, fetch, interactive, ssh, template};
+use colored::Colorize;
+use log::warn;
+use std::collections::BTreeSet;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Directories below `$HOME` holding credentials.
+const SECRET_DIRS: &[&str] = &[".ssh", ".gnupg"];
+
+/// Files below `$HOME` that shells run on startup.
+const SHELL_FILES: &[&str] = &[
+    ".bashrc",
+    ".bash_profile",
+    ".bash_login",
+    ".bash_logout",
+    ".profile",
+    ".zshrc",
+    ".zshenv",
+    ".zprofile",
+    ".zlogin",
+    ".config/fish/config.fish",
+    ".config/fish/conf.d",
+];
+
+/// Why writing `target` deserves a second look, if it does: it is, holds
+/// or lies in a credentials directory or a shell startup file. A folded
+/// directory holding one counts too.
+fn target_reason(target: &Path, home: &Path) -> Option<&'static str> {
+    let rel = target.strip_prefix(home).ok()?;
+    let covers = |path: &str| rel.starts_with(path) || Path::new(path).starts_with(rel);
+    if rel.as_os_str().is_empty() {
+        None
+    } else if SECRET_DIRS.iter().any(|dir| covers(dir)) {
+        Some("credentials directory")
+    } else if SHELL_FILES.iter().any(|file| covers(file)) {
+        Some("shell startup file")
+    } else {
+        None
+    }
+}
+
+/// Whether `source` is an executable file, or a directory holding one.
+fn executable(source: &Path, depth: usize) -> bool {
+    let Ok(meta) = fs::metadata(source) else {
+        return false;
+    };
+    if meta.is_file() {
+        return meta.permissions().mode() & 0o111 != 0;
+    }
+    meta.is_dir()
+        && depth < 16
+        && fs::read_dir(source).is_ok_and(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .any(|entry| executable(&entry.path(), depth + 1))
+        })
+}
+
+/// Why placing `source` at `target` deserves a second look, if it does.
+fn placing(source: &Path, target: &Path, home: &Path) -> Option<&'static str> {
+    target_reason(target, home).or_else(|| executable(source, 0).then_some("executable"))
+}
+
+/// Why deploying `link` deserves a second look, if it does: it places
+/// credentials, code that shells run on startup, or an executable.
+pub fn reason(link: &PlannedLink, home: &Path) -> Option<&'static str> {
+    placing(&link.source, &link.target, home)
+}
+
+/// A target a deploy is about to write that deserves a second look.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub package: String,
+    pub target: PathBuf,
+    pub reason: &'static str,
+}
+
+/// The sensitive targets deploying `packages` writes: the `links` that
+/// are not deployed yet, then the assets, downloads, composed files and
+/// ssh config that are out of date. `deployed` are the packages the
+/// composed files and ssh config are built from.
+pub fn pending(
+    ctx: &Context,
+    config: &Config,
+    packages: &[Package],
+    deployed: &[Package],
+    links: &[PlannedLink],
+) -> Vec<Pending> {
+    let home = dirs::home_dir().unwrap_or_default();
+    let mut pending = Vec::new();
+    let mut add = |package: &str, target: &Path, reason: Option<&'static str>| {
+        if let Some(reason) = reason {
+            pending.push(Pending {
+                package: package.to_string(),
+                target: target.to_path_buf(),
+                reason,
+            });
+        }
+    };
+    for link in links {
+        if !is_deployed(&link.target, &link.source) {
+            add(&link.package, &link.target, reason(link, &home));
+        }
+    }
+    for pkg in packages {
+        let vars = template::package_vars(config, pkg, &ctx.platform);
+        for (source, target) in assets::plan(ctx, pkg, &vars).unwrap_or_default() {
+            if !assets::up_to_date(&source, &target) {
+                add(&pkg.name, &target, placing(&source, &target, &home));
+            }
+        }
+        for entry in &pkg.fetch {
+            let Ok(target) = resolve_target(&entry.target, &vars) else {
+                continue;
+            };
+            if !fetch::is_current(entry, &target) {
+                let reason = target_reason(&target, &home)
+                    .or_else(|| entry.executable.then_some("executable"));
+                add(&pkg.name, &target, reason);
+            }
+        }
+    }
+    for composed in compose::plan(ctx, config, deployed).unwrap_or_default() {
+        if !compose::up_to_date(&composed) {
+            let source = &composed.fragments[0].source;
+            let reason = placing(source, &composed.target, &home);
+            add(&composed.fragments[0].package, &composed.target, reason);
+        }
+    }
+    if let Some(path) = ssh::changes(deployed) {
+        let package = deployed.iter().find(|pkg| !pkg.ssh_hosts.is_empty());
+        let package = package.map(|pkg| pkg.name.as_str()).unwrap_or_default();
+        add(package, &path, target_reason(&path, &home));
+    }
+    pending
+}
+
+/// Points out the `pending` sensitive targets, and returns those to leave
+/// alone. They are written with `--allow-sensitive`, or when the user
+/// agrees on a terminal; without a terminal they are skipped.
+pub fn review(ctx: &Context, pending: &[Pending]) -> BTreeSet<PathBuf> {
+    for entry in pending {
+        let notice = format!(
+            "[{}] {} is sensitive: {}",
+            entry.package,
+            entry.target.display(),
+            entry.reason
+        );
+        warn!("{}", notice.yellow().bold());
+    }
+    if pending.is_empty() || ctx.allow_sensitive {
+        return BTreeSet::new();
+    }
+    if interactive::can_ask() {
+        let question = format!("write {} sensitive target(s)?", pending.len());
+        if interactive::confirm(&question).unwrap_or(false) {
+            return BTreeSet::new();
+        }
+    } else {
+        warn!("not a terminal, run with --allow-sensitive to write sensitive targets");
+    }
+    pending.iter().map(|entry| entry.target.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::PlannedLink;
+    use crate::sensitive::*;
+    use crate::*;
+
+    #[test]
+    fn test_reason() {
+        let root = env::temp_dir().join(format!("mdot-sensitive-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("plain"), "").unwrap();
+        fs::write(root.join("script"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(root.join("script"), fs::Permissions::from_mode(0o755)).unwrap();
+        let link = |source: &str, target: &str| PlannedLink {
+            package: "pkg".to_string(),
+            source: root.join(source),
+            target: PathBuf::from(target),
+            overwrite: false,
+            backup: false,
+            copy: false,
+            rendered_from: None,
+        };
+        let home = Path::new("/home/me");
+        let reason = |source, target| reason(&link(source, target), home);
+
+        assert_eq!(
+            reason("plain", "/home/me/.ssh/config"),
+            Some("credentials directory")
+        );
+        assert_eq!(
+            reason("plain", "/home/me/.gnupg"),
+            Some("credentials directory")
+        );
+        assert_eq!(
+            reason("plain", "/home/me/.zshrc"),
+            Some("shell startup file")
+        );
+        assert_eq!(
+            reason("plain", "/home/me/.config/fish/conf.d/x.fish"),
+            Some("shell startup file")
+        );
+        assert_eq!(
+            reason("script", "/home/me/.local/bin/x"),
+            Some("executable")
+        );
+        assert_eq!(reason("plain", "/home/me/.config/kitty/kitty.conf"), None);
+        assert_eq!(reason("plain", "/home/me/.sshrc"), None);
+        assert_eq!(reason("plain", "/etc/.ssh/config"), None);
+
+        // Folded directories holding a sensitive path or an executable.
+        assert_eq!(
+            reason("plain", "/home/me/.config/fish"),
+            Some("shell startup file")
+        );
+        assert_eq!(reason("plain", "/home/me"), None);
+        fs::create_dir_all(root.join("bin/nested")).unwrap();
+        fs::rename(root.join("script"), root.join("bin/nested/script")).unwrap();
+        assert_eq!(reason("bin", "/home/me/.local/bin"), Some("executable"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_pending() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-pending-{}", std::process::id()));
+        fs::create_dir_all(root.join("tools")).unwrap();
+        fs::write(root.join("tools/run"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(root.join("tools/run"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(root.join("tools/frag"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(root.join("tools/frag"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            format!(
+                r#"return {{ {{ "tools",
+                    assets = {{ run = "{out}/run" }},
+                    fetch = {{ ["{out}/tool"] = {{ url = "https://x.example/tool", executable = true }} }},
+                    links = {{ {{ source = "frag", targets = "{out}/composed", compose = "concat" }} }},
+                }} }}"#,
+                out = root.join("out").display()
+            ),
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        let pending = pending(&ctx, &config, &config.packages, &config.packages, &[]);
+        let targets: Vec<(PathBuf, &str)> = pending
+            .iter()
+            .map(|entry| (entry.target.clone(), entry.reason))
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                (root.join("out/run"), "executable"),
+                (root.join("out/tool"), "executable"),
+                (root.join("out/composed"), "executable"),
+            ]
+        );
+
+        // Held back targets are left alone.
+        let held_back: BTreeSet<PathBuf> = pending.into_iter().map(|p| p.target).collect();
+        let pkg = &config.packages[0];
+        assert!(
+            assets::sync(&ctx, &config, pkg, &held_back)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            compose::sync(&ctx, &config, &config.packages, &held_back)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(!root.join("out").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
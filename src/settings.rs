@@ -0,0 +1,276 @@
+use crate::macos::{self, DefaultsValue};
+use crate::{Context, Package};
+use log::info;
+use std::process::{Command, Stdio};
+
+/// The tool a setting is read and written with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Gsettings,
+    Dconf,
+    Defaults,
+}
+
+impl Backend {
+    /// `defaults` on macOS, `dconf` for schemas that are paths such as
+    /// `/org/gnome/desktop/interface`, else `gsettings`.
+    pub fn for_schema(os: &str, schema: &str) -> Self {
+        if os == "macos" {
+            Backend::Defaults
+        } else if schema.starts_with('/') {
+            Backend::Dconf
+        } else {
+            Backend::Gsettings
+        }
+    }
+
+    /// `value` the way this backend prints it when read.
+    fn format(self, value: &DefaultsValue) -> String {
+        match self {
+            Backend::Defaults => value.to_string(),
+            Backend::Gsettings | Backend::Dconf => gvariant(value),
+        }
+    }
+
+    fn key_path(self, schema: &str, key: &str) -> String {
+        match self {
+            Backend::Dconf => format!("{}/{}", schema.trim_end_matches('/'), key),
+            _ => format!("{} {}", schema, key),
+        }
+    }
+}
+
+/// The GVariant text form of `value`, as taken by `gsettings set` and
+/// `dconf write`.
+fn gvariant(value: &DefaultsValue) -> String {
+    match value {
+        DefaultsValue::Bool(b) => b.to_string(),
+        DefaultsValue::Int(i) => i.to_string(),
+        DefaultsValue::Float(n) if n.fract() == 0.0 => format!("{:.1}", n),
+        DefaultsValue::Float(n) => n.to_string(),
+        DefaultsValue::String(s) => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+    }
+}
+
+/// Drops the type annotation of typed GVariant numbers, `uint32 5` -> `5`.
+fn normalize(current: &str) -> &str {
+    match current.split_once(' ') {
+        Some((kind, number))
+            if kind.chars().all(|c| c.is_ascii_alphanumeric()) && number.parse::<f64>().is_ok() =>
+        {
+            number
+        }
+        _ => current,
+    }
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+fn read(backend: Backend, schema: &str, key: &str) -> Option<String> {
+    let current = match backend {
+        Backend::Gsettings => output("gsettings", &["get", schema, key]),
+        Backend::Dconf => output("dconf", &["read", &backend.key_path(schema, key)]),
+        Backend::Defaults => macos::read(schema, key),
+    }?;
+    Some(normalize(&current).to_string()).filter(|current| !current.is_empty())
+}
+
+fn write(backend: Backend, schema: &str, key: &str, value: &DefaultsValue) -> Result<(), String> {
+    let (program, args) = match backend {
+        Backend::Gsettings => (
+            "gsettings",
+            vec![
+                "set".to_string(),
+                schema.to_string(),
+                key.to_string(),
+                gvariant(value),
+            ],
+        ),
+        Backend::Dconf => (
+            "dconf",
+            vec![
+                "write".to_string(),
+                backend.key_path(schema, key),
+                gvariant(value),
+            ],
+        ),
+        Backend::Defaults => ("defaults", macos::write_args(schema, key, value)),
+    };
+    let status = Command::new(program)
+        .args(&args)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|err| format!("failed to run {}: {}", program, err))?;
+    if !status.success() {
+        return Err(format!("{} {} failed", program, args.join(" ")));
+    }
+    Ok(())
+}
+
+/// A setting of a package compared with its current value.
+#[derive(Debug, PartialEq)]
+pub struct SettingStatus {
+    pub package: String,
+    pub backend: Backend,
+    pub description: String,
+    pub expected: String,
+    pub current: Option<String>,
+}
+
+impl SettingStatus {
+    pub fn drifted(&self) -> bool {
+        self.current.as_ref() != Some(&self.expected)
+    }
+}
+
+/// The `settings` of `pkg`, plus its `defaults` on macOS, with the backend
+/// each is applied with.
+fn entries<'a>(
+    ctx: &Context,
+    pkg: &'a Package,
+) -> Vec<(Backend, &'a str, &'a str, &'a DefaultsValue)> {
+    let mut out: Vec<_> = pkg
+        .settings
+        .iter()
+        .map(|(schema, key, value)| {
+            let backend = Backend::for_schema(&ctx.platform.os, schema);
+            (backend, schema.as_str(), key.as_str(), value)
+        })
+        .collect();
+    if ctx.platform.os == "macos" {
+        out.extend(
+            pkg.defaults.iter().map(|(domain, key, value)| {
+                (Backend::Defaults, domain.as_str(), key.as_str(), value)
+            }),
+        );
+    } else if !pkg.defaults.is_empty() {
+        info!("[{}] skipping 'defaults' on {}", pkg.name, ctx.platform.os);
+    }
+    out
+}
+
+/// Every setting of `pkg` with the value it currently has.
+pub fn status(ctx: &Context, pkg: &Package) -> Vec<SettingStatus> {
+    entries(ctx, pkg)
+        .into_iter()
+        .map(|(backend, schema, key, value)| SettingStatus {
+            package: pkg.name.clone(),
+            backend,
+            description: backend.key_path(schema, key),
+            expected: backend.format(value),
+            current: read(backend, schema, key),
+        })
+        .collect()
+}
+
+/// Writes the settings of `pkg` whose current value differs.
+pub fn apply(ctx: &Context, pkg: &Package) -> Result<(), String> {
+    for (backend, schema, key, value) in entries(ctx, pkg) {
+        let expected = backend.format(value);
+        if read(backend, schema, key).is_some_and(|current| current == expected) {
+            continue;
+        }
+        write(backend, schema, key, value)?;
+        info!(
+            "[{}] set {} to {}",
+            pkg.name,
+            backend.key_path(schema, key),
+            expected
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::macos::DefaultsValue;
+    use crate::settings::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_settings() {
+        let _ = setup_logger();
+        assert_eq!(
+            Backend::for_schema("linux", "org.gnome.desktop.interface"),
+            Backend::Gsettings
+        );
+        assert_eq!(
+            Backend::for_schema("linux", "/org/gnome/shell/"),
+            Backend::Dconf
+        );
+        assert_eq!(
+            Backend::for_schema("macos", "com.apple.dock"),
+            Backend::Defaults
+        );
+        assert_eq!(gvariant(&DefaultsValue::String("it's".into())), r"'it\'s'");
+        assert_eq!(gvariant(&DefaultsValue::Float(1.0)), "1.0");
+        assert_eq!(normalize("uint32 5"), "5");
+        assert_eq!(normalize("'prefer dark'"), "'prefer dark'");
+        assert_eq!(
+            Backend::Dconf.key_path("/org/gnome/shell/", "favorite-apps"),
+            "/org/gnome/shell/favorite-apps"
+        );
+
+        let root = env::temp_dir().join(format!("mdot-settings-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return { { "gnome",
+                settings = {
+                    ["org.gnome.desktop.interface"] = { ["color-scheme"] = "prefer-dark" },
+                    ["/org/gnome/mutter"] = { ["dynamic-workspaces"] = false },
+                },
+            } }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .platform(platform::Platform::detect().simulate("os=linux").unwrap())
+            .build();
+        let config = config::load(&ctx);
+        // `status` and `apply` read the settings of the host, so only check
+        // what would be written.
+        let written: Vec<_> = settings::entries(&ctx, &config.packages[0])
+            .into_iter()
+            .map(|(backend, schema, key, value)| {
+                (
+                    backend,
+                    backend.key_path(schema, key),
+                    backend.format(value),
+                )
+            })
+            .collect();
+        assert_eq!(
+            written,
+            [
+                (
+                    Backend::Dconf,
+                    "/org/gnome/mutter/dynamic-workspaces".to_string(),
+                    "false".to_string()
+                ),
+                (
+                    Backend::Gsettings,
+                    "org.gnome.desktop.interface color-scheme".to_string(),
+                    "'prefer-dark'".to_string()
+                ),
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
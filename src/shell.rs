@@ -0,0 +1,243 @@
+use crate::Context;
+use crate::cli::{Cli, CompleteKind};
+use crate::config::Config;
+use crate::state::State;
+use clap::CommandFactory;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Subcommand names offered as completions.
+fn subcommands() -> Vec<String> {
+    Cli::command()
+        .get_subcommands()
+        .filter(|cmd| !cmd.is_hide_set())
+        .map(|cmd| cmd.get_name().to_string())
+        .chain(["cd".to_string()])
+        .collect()
+}
+
+/// Prints package names.
+const PACKAGES: &str = "command mdot complete packages 2>/dev/null";
+/// Prints profile names.
+const PROFILES: &str = "command mdot complete profiles 2>/dev/null";
+/// Prints tags.
+const TAGS: &str = "command mdot complete tags 2>/dev/null";
+/// Prints deployed targets.
+const TARGETS: &str = "command mdot complete targets 2>/dev/null";
+/// Counts planned links that are missing or in conflict.
+const DRIFT: &str = "command mdot status 2>/dev/null | grep -cE '^(missing|conflict|drifted)'";
+
+fn bash(commands: &str) -> String {
+    format!(
+        r#"# mdot shell integration for bash
+mdot() {{
+    if [ "$1" = cd ]; then
+        cd "$(command mdot root)" || return
+    else
+        command mdot "$@"
+    fi
+}}
+
+_mdot_complete() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$prev" in
+        -p|--profile) COMPREPLY=($(compgen -W "$({PROFILES})" -- "$cur")); return ;;
+        --tag) COMPREPLY=($(compgen -W "$({TAGS})" -- "$cur")); return ;;
+    esac
+    if [ "$COMP_CWORD" -eq 1 ]; then
+        COMPREPLY=($(compgen -W "{commands}" -- "$cur"))
+    else
+        case "${{COMP_WORDS[1]}}" in
+            edit|source-path) COMPREPLY=($(compgen -W "$({TARGETS}; {PACKAGES})" -- "$cur")) ;;
+            *) COMPREPLY=($(compgen -W "$({PACKAGES})" -- "$cur")) ;;
+        esac
+    fi
+}}
+complete -F _mdot_complete mdot
+
+# Prompt segment: add $(mdot_prompt) to PS1 to show the drift count
+mdot_prompt() {{
+    local n
+    n=$({DRIFT})
+    [ "${{n:-0}}" -gt 0 ] && printf 'mdot:%s ' "$n"
+}}
+"#
+    )
+}
+
+fn zsh(commands: &str) -> String {
+    format!(
+        r#"# mdot shell integration for zsh
+mdot() {{
+    if [[ "$1" == cd ]]; then
+        cd "$(command mdot root)" || return
+    else
+        command mdot "$@"
+    fi
+}}
+
+_mdot() {{
+    case "$words[CURRENT-1]" in
+        -p|--profile) compadd -- ${{(f)"$({PROFILES})"}}; return ;;
+        --tag) compadd -- ${{(f)"$({TAGS})"}}; return ;;
+    esac
+    if (( CURRENT == 2 )); then
+        compadd -- {commands}
+    elif [[ "$words[2]" == (edit|source-path) ]]; then
+        compadd -- ${{(f)"$({TARGETS}; {PACKAGES})"}}
+    else
+        compadd -- ${{(f)"$({PACKAGES})"}}
+    fi
+}}
+(( $+functions[compdef] )) && compdef _mdot mdot
+
+# Prompt segment: add $(mdot_prompt) to PROMPT (with PROMPT_SUBST set)
+mdot_prompt() {{
+    local n=$({DRIFT})
+    (( ${{n:-0}} > 0 )) && print -n "mdot:$n "
+}}
+"#
+    )
+}
+
+fn fish(commands: &str) -> String {
+    format!(
+        r#"# mdot shell integration for fish
+function mdot
+    if test "$argv[1]" = cd
+        cd (command mdot root)
+    else
+        command mdot $argv
+    end
+end
+
+complete -c mdot -f
+complete -c mdot -n __fish_use_subcommand -a "{commands}"
+complete -c mdot -n "not __fish_use_subcommand" -a "({PACKAGES})"
+complete -c mdot -n "__fish_seen_subcommand_from edit source-path" -a "({TARGETS})"
+complete -c mdot -s p -l profile -x -a "({PROFILES})"
+complete -c mdot -l tag -x -a "({TAGS})"
+
+# Prompt segment: call mdot_prompt from fish_prompt to show the drift count
+function mdot_prompt
+    set -l n ({DRIFT})
+    test "$n" -gt 0 2>/dev/null; and printf 'mdot:%s ' $n
+end
+"#
+    )
+}
+
+/// The snippet to `eval` from the rc file of `shell`.
+pub fn init(shell: &str) -> Result<String, String> {
+    let commands = subcommands().join(" ");
+    match shell {
+        "bash" => Ok(bash(&commands)),
+        "zsh" => Ok(zsh(&commands)),
+        "fish" => Ok(fish(&commands)),
+        _ => Err(format!(
+            "unsupported shell '{}', expected bash, zsh or fish",
+            shell
+        )),
+    }
+}
+
+/// `path` with the home directory written as `~`.
+fn tilde(path: &std::path::Path) -> String {
+    match dirs::home_dir().and_then(|home| path.strip_prefix(home).ok().map(PathBuf::from)) {
+        Some(rel) => format!("~/{}", rel.display()),
+        None => path.display().to_string(),
+    }
+}
+
+/// The completion candidates for `what`, sorted and without duplicates.
+pub fn candidates(ctx: &Context, config: &Config, what: CompleteKind) -> Vec<String> {
+    let candidates: BTreeSet<String> = match what {
+        CompleteKind::Packages => config.packages.iter().map(|pkg| pkg.name.clone()).collect(),
+        CompleteKind::Profiles => config.profiles.keys().cloned().collect(),
+        CompleteKind::Tags => config
+            .packages
+            .iter()
+            .flat_map(|pkg| &pkg.tags)
+            .chain(config.profiles.values().flat_map(|profile| &profile.tags))
+            .cloned()
+            .collect(),
+        CompleteKind::Targets => State::load(&State::path(ctx))
+            .unwrap_or_default()
+            .packages
+            .values()
+            .flat_map(|pkg| pkg.links.keys())
+            .map(|target| tilde(target))
+            .collect(),
+    };
+    candidates.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::CompleteKind;
+    use crate::shell::*;
+    use crate::state::State;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_init() {
+        for shell in ["bash", "zsh", "fish"] {
+            let snippet = init(shell).unwrap();
+            assert!(snippet.contains("command mdot root"));
+            assert!(snippet.contains("deploy install check status"));
+            assert!(snippet.contains("mdot_prompt"));
+        }
+        assert!(init("tcsh").is_err());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-complete-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.profiles.work = { "git", tags = { "cli" } }
+            mdot.profiles.desktop = { tags = { "gui" } }
+            return { { "git", tags = "cli" }, { "sway", tags = { "gui", "wayland" } } }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .build();
+        let config = config::load(&ctx);
+        assert_eq!(
+            candidates(&ctx, &config, CompleteKind::Packages),
+            ["git", "sway"]
+        );
+        assert_eq!(
+            candidates(&ctx, &config, CompleteKind::Profiles),
+            ["desktop", "work"]
+        );
+        assert_eq!(
+            candidates(&ctx, &config, CompleteKind::Tags),
+            ["cli", "gui", "wayland"]
+        );
+        assert!(candidates(&ctx, &config, CompleteKind::Targets).is_empty());
+
+        let target = dirs::home_dir().unwrap().join(".gitconfig");
+        let mut state = State::default();
+        state
+            .packages
+            .entry("git".to_string())
+            .or_default()
+            .links
+            .insert(target, root.join("git/gitconfig"));
+        fs::create_dir_all(&ctx.state_dir).unwrap();
+        state.save(&State::path(&ctx)).unwrap();
+        assert_eq!(
+            candidates(&ctx, &config, CompleteKind::Targets),
+            ["~/.gitconfig"]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
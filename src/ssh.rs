@@ -0,0 +1,408 @@
+use crate::{Package, check, lua_value_to_str, ordered_pairs};
+use log::{info, warn};
+use mlua::Value;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// File the hosts are written to, relative to `~/.ssh`.
+pub const CONFIG_FILE: &str = "config.d/mdot.conf";
+/// Directory below `~/.ssh` for identities fetched with a command.
+const IDENTITY_DIR: &str = "mdot";
+
+/// Where the private key of a host comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Identity {
+    /// `identity = "~/.ssh/id_work"`
+    Path(String),
+    /// `identity = { command = "pass show ssh/work" }`: the key is the
+    /// output of the command, kept in `~/.ssh/mdot/<host>`.
+    Command(String),
+}
+
+/// `ssh_hosts = { work = { hostname = "work.example.com", user = "me", identity = "~/.ssh/id_work", jump = "bastion" } }`
+///
+/// One `Host` entry of `~/.ssh/config`. `options` holds any other
+/// keyword, e.g. `options = { ForwardAgent = "yes" }`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SshHost {
+    pub host: String,
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<String>,
+    pub identity: Option<Identity>,
+    pub jump: Option<String>,
+    pub options: Vec<(String, String)>,
+}
+
+fn host_from_value(host: Option<String>, value: &Value) -> SshHost {
+    let Value::Table(tbl) = value else {
+        fatal!(
+            "'ssh_hosts' entries expected type 'Table', got {}",
+            value.type_name()
+        );
+    };
+    let mut entry = SshHost {
+        host: host.unwrap_or_default(),
+        ..SshHost::default()
+    };
+    for (key, value) in ordered_pairs(tbl, "ssh_hosts") {
+        let Value::String(key) = key else {
+            fatal!("invalid 'ssh_hosts' key {:?}", key);
+        };
+        match key.to_string_lossy().as_str() {
+            "host" => entry.host = lua_value_to_str(&value),
+            "hostname" => entry.hostname = Some(lua_value_to_str(&value)),
+            "user" => entry.user = Some(lua_value_to_str(&value)),
+            "port" => {
+                entry.port = Some(match &value {
+                    Value::Integer(port) => port.to_string(),
+                    v => lua_value_to_str(v),
+                })
+            }
+            "jump" => entry.jump = Some(lua_value_to_str(&value)),
+            "identity" => {
+                entry.identity = Some(match &value {
+                    Value::Table(tbl) => match tbl.get::<String>("command") {
+                        Ok(command) => Identity::Command(command),
+                        Err(_) => fatal!("'identity' table expects a 'command' string"),
+                    },
+                    v => Identity::Path(lua_value_to_str(v)),
+                })
+            }
+            "options" => {
+                let Value::Table(options) = &value else {
+                    fatal!(
+                        "'ssh_hosts' 'options' expected type 'Table', got {}",
+                        value.type_name()
+                    );
+                };
+                entry.options = ordered_pairs(options, "ssh_hosts.options")
+                    .into_iter()
+                    .map(|(k, v)| (lua_value_to_str(&k), lua_value_to_str(&v)))
+                    .collect();
+            }
+            key => fatal!("unknown 'ssh_hosts' key '{}'", key),
+        }
+    }
+    if let Err(err) = validate(&entry) {
+        fatal!("invalid 'ssh_hosts' entry: {}", err);
+    }
+    entry
+}
+
+/// Parses `ssh_hosts`, either keyed by host or as a list of tables with a
+/// `host` field.
+pub fn hosts_from_value(value: &Value) -> Vec<SshHost> {
+    let Value::Table(tbl) = value else {
+        fatal!(
+            "'ssh_hosts' expected type 'Table', got {}",
+            value.type_name()
+        );
+    };
+    ordered_pairs(tbl, "ssh_hosts")
+        .into_iter()
+        .map(|(key, value)| match key {
+            Value::Integer(_) => host_from_value(None, &value),
+            key => host_from_value(Some(lua_value_to_str(&key)), &value),
+        })
+        .collect()
+}
+
+/// Checks what `ssh_config` would reject or misread.
+fn validate(host: &SshHost) -> Result<(), String> {
+    if host.host.trim().is_empty() {
+        return Err("missing 'host'".to_string());
+    }
+    // The identity of the host is kept in a file named after it.
+    if matches!(host.identity, Some(Identity::Command(_)))
+        && (host.host.starts_with('.')
+            || !host
+                .host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)))
+    {
+        return Err(format!(
+            "{}: a host with an identity command must be a plain host name",
+            host.host
+        ));
+    }
+    if let Some(port) = &host.port
+        && port.parse::<u16>().is_err()
+    {
+        return Err(format!("{}: invalid port '{}'", host.host, port));
+    }
+    for (keyword, _) in &host.options {
+        if keyword.is_empty() || !keyword.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("{}: invalid option '{}'", host.host, keyword));
+        }
+    }
+    let values = [&host.hostname, &host.user, &host.port, &host.jump]
+        .into_iter()
+        .flatten()
+        .chain(host.options.iter().map(|(_, v)| v))
+        .chain(std::iter::once(&host.host));
+    for value in values {
+        if value.contains(['\n', '\r', '"']) {
+            return Err(format!("{}: invalid value {:?}", host.host, value));
+        }
+    }
+    Ok(())
+}
+
+/// `value` quoted when it holds whitespace.
+fn quote(value: &str) -> String {
+    match value.contains(char::is_whitespace) {
+        true => format!("\"{}\"", value),
+        false => value.to_string(),
+    }
+}
+
+/// The `Host` entries of `packages`, each under a comment naming its
+/// package. Identities fetched with a command live in `identities`.
+pub fn render(packages: &[Package], identities: &Path) -> String {
+    let mut out = String::from("# Generated by mdot from 'ssh_hosts', do not edit\n");
+    for pkg in packages {
+        for host in &pkg.ssh_hosts {
+            out.push_str(&format!("\n# mdot: {}\nHost {}\n", pkg.name, host.host));
+            let mut line = |keyword: &str, value: &str| {
+                out.push_str(&format!("    {} {}\n", keyword, quote(value)));
+            };
+            if let Some(hostname) = &host.hostname {
+                line("HostName", hostname);
+            }
+            if let Some(user) = &host.user {
+                line("User", user);
+            }
+            if let Some(port) = &host.port {
+                line("Port", port);
+            }
+            match &host.identity {
+                Some(Identity::Path(path)) => line("IdentityFile", path),
+                Some(Identity::Command(_)) => line(
+                    "IdentityFile",
+                    &identities.join(&host.host).display().to_string(),
+                ),
+                None => (),
+            }
+            if host.identity.is_some() {
+                line("IdentitiesOnly", "yes");
+            }
+            if let Some(jump) = &host.jump {
+                line("ProxyJump", jump);
+            }
+            for (keyword, value) in &host.options {
+                line(keyword, value);
+            }
+        }
+    }
+    out
+}
+
+/// Lets `ssh -G` parse `config` for every host, when ssh is installed.
+fn check_syntax(config: &Path, packages: &[Package]) -> Result<(), String> {
+    if check::find_bin("ssh").is_none() {
+        return Ok(());
+    }
+    for host in packages.iter().flat_map(|pkg| &pkg.ssh_hosts) {
+        let name = host.host.split_whitespace().next().unwrap_or_default();
+        let out = Command::new("ssh")
+            .arg("-G")
+            .arg("-F")
+            .arg(config)
+            .arg(name.replace(['*', '?', '!'], "x"))
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|err| format!("failed to run ssh: {}", err))?;
+        if !out.status.success() {
+            return Err(format!(
+                "ssh rejects the entry of '{}': {}",
+                host.host,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Creates `dir` and its missing parents private to the user, as ssh
+/// expects of `~/.ssh`.
+fn create_private_dir(dir: &Path) -> Result<(), String> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|err| format!("failed to create {}: {}", dir.display(), err))
+}
+
+/// Writes the identities fetched with a command, when they changed.
+fn write_identities(packages: &[Package], identities: &Path) -> Result<(), String> {
+    for host in packages.iter().flat_map(|pkg| &pkg.ssh_hosts) {
+        let Some(Identity::Command(command)) = &host.identity else {
+            continue;
+        };
+        let out = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|err| format!("failed to run '{}': {}", command, err))?;
+        if !out.status.success() || out.stdout.is_empty() {
+            return Err(format!(
+                "'{}' did not print the identity of '{}'",
+                command, host.host
+            ));
+        }
+        let path = identities.join(&host.host);
+        if fs::read(&path).is_ok_and(|key| key == out.stdout) {
+            continue;
+        }
+        create_private_dir(identities)?;
+        fs::set_permissions(identities, fs::Permissions::from_mode(0o700))
+            .map_err(|err| err.to_string())?;
+        fs::write(&path, &out.stdout).map_err(|err| err.to_string())?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .map_err(|err| err.to_string())?;
+        info!("wrote the identity of '{}'", host.host);
+    }
+    Ok(())
+}
+
+fn sync_in(ssh_dir: &Path, packages: &[Package]) -> Result<Option<PathBuf>, String> {
+    let path = ssh_dir.join(CONFIG_FILE);
+    let identities = ssh_dir.join(IDENTITY_DIR);
+    let content = render(packages, &identities);
+    if fs::read_to_string(&path).ok().as_ref() == Some(&content) {
+        return Ok(None);
+    }
+    let dir = path.parent().unwrap();
+    create_private_dir(dir)?;
+    let staged = dir.join(".mdot.conf.new");
+    fs::write(&staged, &content).map_err(|err| err.to_string())?;
+    if let Err(err) = check_syntax(&staged, packages) {
+        let _ = fs::remove_file(&staged);
+        return Err(err);
+    }
+    write_identities(packages, &identities)?;
+    fs::rename(&staged, &path).map_err(|err| err.to_string())?;
+    info!("wrote {}", path.display());
+
+    let include = format!("Include {}", CONFIG_FILE);
+    let main = ssh_dir.join("config");
+    match fs::read_to_string(&main) {
+        Ok(config) if config.lines().any(|line| line.trim() == include) => (),
+        Ok(_) => warn!(
+            "add '{}' at the top of {} to use the hosts of 'ssh_hosts'",
+            include,
+            main.display()
+        ),
+        Err(_) => {
+            fs::write(&main, format!("{}\n", include)).map_err(|err| err.to_string())?;
+            info!("created {}", main.display());
+        }
+    }
+    Ok(Some(path))
+}
+
+fn ssh_dir() -> PathBuf {
+    dirs::home_dir().unwrap_or_default().join(".ssh")
+}
+
+/// The file [`sync`] would rewrite for `packages`, if it would.
+pub fn changes(packages: &[Package]) -> Option<PathBuf> {
+    let ssh_dir = ssh_dir();
+    let path = ssh_dir.join(CONFIG_FILE);
+    if packages.iter().all(|pkg| pkg.ssh_hosts.is_empty()) && !path.exists() {
+        return None;
+    }
+    let content = render(packages, &ssh_dir.join(IDENTITY_DIR));
+    (fs::read_to_string(&path).ok() != Some(content)).then_some(path)
+}
+
+/// Writes the `ssh_hosts` of `packages` to `~/.ssh/config.d/mdot.conf`
+/// after checking it with `ssh -G`. Returns the file when it changed.
+pub fn sync(packages: &[Package]) -> Result<Option<PathBuf>, String> {
+    let ssh_dir = ssh_dir();
+    if packages.iter().all(|pkg| pkg.ssh_hosts.is_empty()) && !ssh_dir.join(CONFIG_FILE).exists() {
+        return Ok(None);
+    }
+    sync_in(&ssh_dir, packages)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ssh::*;
+    use crate::*;
+    use std::fs;
+
+    #[test]
+    fn test_ssh_hosts() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-ssh-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            return { { "ssh", ssh_hosts = {
+                { host = "bastion", hostname = "bastion.example.com", port = 2222 },
+                work = {
+                    hostname = "10.0.0.5",
+                    user = "me",
+                    identity = { command = "printf key" },
+                    jump = "bastion",
+                    options = { ForwardAgent = "yes" },
+                },
+            } } }
+            "#,
+        )
+        .unwrap();
+        let ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        let config = config::load(&ctx);
+        let ssh_dir = root.join(".ssh");
+
+        let path = sync_in(&ssh_dir, &config.packages).unwrap().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!(
+                "# Generated by mdot from 'ssh_hosts', do not edit\n\
+                 \n# mdot: ssh\nHost bastion\n    HostName bastion.example.com\n    Port 2222\n\
+                 \n# mdot: ssh\nHost work\n    HostName 10.0.0.5\n    User me\n    \
+                 IdentityFile {}\n    IdentitiesOnly yes\n    ProxyJump bastion\n    ForwardAgent yes\n",
+                ssh_dir.join("mdot/work").display()
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(ssh_dir.join("mdot/work")).unwrap(),
+            "key"
+        );
+        assert_eq!(
+            fs::read_to_string(ssh_dir.join("config")).unwrap(),
+            "Include config.d/mdot.conf\n"
+        );
+        assert_eq!(sync_in(&ssh_dir, &config.packages).unwrap(), None);
+        for dir in [&ssh_dir, &ssh_dir.join("config.d"), &ssh_dir.join("mdot")] {
+            let mode = fs::metadata(dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        let bad = SshHost {
+            host: "x".to_string(),
+            port: Some("ssh".to_string()),
+            ..SshHost::default()
+        };
+        assert!(validate(&bad).is_err());
+        let traversal = SshHost {
+            host: "../../.bashrc".to_string(),
+            identity: Some(Identity::Command("printf key".to_string())),
+            ..SshHost::default()
+        };
+        assert!(validate(&traversal).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
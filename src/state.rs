@@ -0,0 +1,392 @@
+use crate::deploy::PlannedLink;
+use crate::select::{Selection, Skip};
+use crate::{Context, Package};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const STATE_FILE: &str = "state";
+const HEADER: &str = "# mdot state v1";
+
+/// What the last deploy applied for one package.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PackageState {
+    /// Link sources keyed by target.
+    pub links: BTreeMap<PathBuf, PathBuf>,
+    /// Hook definitions keyed by `on_install`/`on_deploy`.
+    pub hooks: BTreeMap<String, Vec<String>>,
+}
+
+/// The manifest of everything mdot has deployed, kept in the state dir as
+/// tab separated `package`, `link` and `hook` records.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct State {
+    pub packages: BTreeMap<String, PackageState>,
+}
+
+/// Escapes tabs, newlines and backslashes in a tab separated field.
+pub fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// Reverses `escape`.
+pub fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+impl State {
+    pub fn path(ctx: &Context) -> PathBuf {
+        ctx.state_dir.join(STATE_FILE)
+    }
+
+    /// Reads the manifest at `path`; a missing file is an empty state.
+    pub fn load(path: &Path) -> Result<State, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+        };
+        let mut state = State::default();
+        for (n, line) in contents.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<String> = line.split('\t').map(unescape).collect();
+            match fields.as_slice() {
+                [kind, name] if kind == "package" => {
+                    state.packages.entry(name.clone()).or_default();
+                }
+                [kind, name, source, target] if kind == "link" => {
+                    state
+                        .packages
+                        .entry(name.clone())
+                        .or_default()
+                        .links
+                        .insert(PathBuf::from(target), PathBuf::from(source));
+                }
+                [kind, name, hook, definition] if kind == "hook" => {
+                    state
+                        .packages
+                        .entry(name.clone())
+                        .or_default()
+                        .hooks
+                        .entry(hook.clone())
+                        .or_default()
+                        .push(definition.clone());
+                }
+                _ => {
+                    return Err(format!(
+                        "{}:{}: invalid state record",
+                        path.display(),
+                        n + 1
+                    ));
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = format!("{}\n", HEADER);
+        for (name, pkg) in &self.packages {
+            out.push_str(&format!("package\t{}\n", escape(name)));
+            for (target, source) in &pkg.links {
+                out.push_str(&format!(
+                    "link\t{}\t{}\t{}\n",
+                    escape(name),
+                    escape(&source.to_string_lossy()),
+                    escape(&target.to_string_lossy())
+                ));
+            }
+            for (hook, definitions) in &pkg.hooks {
+                for definition in definitions {
+                    out.push_str(&format!(
+                        "hook\t{}\t{}\t{}\n",
+                        escape(name),
+                        hook,
+                        escape(definition)
+                    ));
+                }
+            }
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, out)?;
+        fs::rename(tmp, path)
+    }
+
+    /// The state a deploy of `packages` creating `links` leaves behind.
+    pub fn from_plan(packages: &[Package], links: &[PlannedLink]) -> State {
+        let mut state = State::default();
+        for pkg in packages {
+            let entry = state.packages.entry(pkg.name.clone()).or_default();
+            for (hook, actions) in [
+                ("on_install", &pkg.on_install),
+                ("on_deploy", &pkg.on_deploy),
+            ] {
+                if !actions.is_empty() {
+                    entry.hooks.insert(
+                        hook.to_string(),
+                        actions.iter().map(|a| a.describe()).collect(),
+                    );
+                }
+            }
+        }
+        for link in links {
+            state
+                .packages
+                .entry(link.package.clone())
+                .or_default()
+                .links
+                .insert(link.target.clone(), link.source.clone());
+        }
+        state
+    }
+
+    /// Recorded packages that `is_removed` from the current config.
+    pub fn vanished(&self, selection: &Selection) -> Vec<String> {
+        self.packages
+            .keys()
+            .filter(|name| is_removed(selection, name))
+            .cloned()
+            .collect()
+    }
+
+    /// Transfers ownership of the links recorded for `old` to `new`.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), String> {
+        if self.packages.contains_key(new) {
+            return Err(format!("package '{}' already has recorded links", new));
+        }
+        let pkg = self
+            .packages
+            .remove(old)
+            .ok_or_else(|| format!("no links are recorded for package '{}'", old))?;
+        self.packages.insert(new.to_string(), pkg);
+        Ok(())
+    }
+
+    /// Replaces the recorded packages that `other` deployed again.
+    pub fn merge(&mut self, other: State) {
+        self.packages.extend(other.packages);
+    }
+}
+
+/// Whether a package recorded in the state is gone for good: no longer
+/// defined, or disabled. Packages merely filtered out of this run are not.
+pub fn is_removed(selection: &Selection, name: &str) -> bool {
+    match selection.decisions.get(name) {
+        None => true,
+        Some(decision) => matches!(decision.skip, Some(Skip::Disabled | Skip::DisabledByHook)),
+    }
+}
+
+/// One difference between the recorded and the current plan.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    AddedPackage(String),
+    RemovedPackage(String),
+    AddedLink {
+        package: String,
+        target: PathBuf,
+        source: PathBuf,
+    },
+    RemovedLink {
+        package: String,
+        target: PathBuf,
+    },
+    /// The target now links to a different source.
+    ChangedLink {
+        package: String,
+        target: PathBuf,
+        source: PathBuf,
+    },
+    ChangedHook {
+        package: String,
+        hook: String,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::AddedPackage(pkg) => write!(f, "+ package {}", pkg),
+            Change::RemovedPackage(pkg) => write!(f, "- package {}", pkg),
+            Change::AddedLink {
+                package,
+                target,
+                source,
+            } => write!(
+                f,
+                "+ [{}] {} -> {}",
+                package,
+                target.display(),
+                source.display()
+            ),
+            Change::RemovedLink { package, target } => {
+                write!(f, "- [{}] {}", package, target.display())
+            }
+            Change::ChangedLink {
+                package,
+                target,
+                source,
+            } => write!(
+                f,
+                "~ [{}] {} -> {}",
+                package,
+                target.display(),
+                source.display()
+            ),
+            Change::ChangedHook { package, hook } => {
+                write!(f, "~ [{}] '{}' hook changed", package, hook)
+            }
+        }
+    }
+}
+
+fn diff_package(name: &str, old: &PackageState, new: &PackageState, changes: &mut Vec<Change>) {
+    for (target, source) in &new.links {
+        match old.links.get(target) {
+            None => changes.push(Change::AddedLink {
+                package: name.to_string(),
+                target: target.clone(),
+                source: source.clone(),
+            }),
+            Some(old_source) if old_source != source => changes.push(Change::ChangedLink {
+                package: name.to_string(),
+                target: target.clone(),
+                source: source.clone(),
+            }),
+            Some(_) => (),
+        }
+    }
+    for target in old.links.keys().filter(|t| !new.links.contains_key(*t)) {
+        changes.push(Change::RemovedLink {
+            package: name.to_string(),
+            target: target.clone(),
+        });
+    }
+    let hooks: BTreeSet<&String> = old.hooks.keys().chain(new.hooks.keys()).collect();
+    for hook in hooks {
+        if old.hooks.get(hook) != new.hooks.get(hook) {
+            changes.push(Change::ChangedHook {
+                package: name.to_string(),
+                hook: hook.clone(),
+            });
+        }
+    }
+}
+
+/// Changes between the `old` state and the `new` one planned for the
+/// packages in `selection`.
+pub fn diff(old: &State, new: &State, selection: &Selection) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (name, pkg) in &new.packages {
+        let empty = PackageState::default();
+        let old_pkg = match old.packages.get(name) {
+            Some(old_pkg) => old_pkg,
+            None => {
+                changes.push(Change::AddedPackage(name.clone()));
+                &empty
+            }
+        };
+        diff_package(name, old_pkg, pkg, &mut changes);
+    }
+    for (name, pkg) in &old.packages {
+        if new.packages.contains_key(name) || !is_removed(selection, name) {
+            continue;
+        }
+        changes.push(Change::RemovedPackage(name.clone()));
+        diff_package(name, pkg, &PackageState::default(), &mut changes);
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::*;
+    use crate::*;
+
+    #[test]
+    fn test_state_roundtrip_and_diff() {
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-state-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let write_config = |lua: &str| {
+            fs::write(root.join("main.lua"), lua).unwrap();
+            let ctx = Context::builder()
+                .entry(root.join("main.lua"))
+                .state_dir(root.join("state"))
+                .data_dir(root.join("data"))
+                .cache_dir(root.join("cache"))
+                .build();
+            let config = config::load(&ctx);
+            (ctx, config)
+        };
+        let link = |package: &str, target: &str| PlannedLink {
+            package: package.to_string(),
+            source: root.join(package),
+            target: PathBuf::from(target),
+            overwrite: false,
+            backup: false,
+            copy: false,
+            rendered_from: None,
+        };
+
+        let (_ctx, config) =
+            write_config(r#"return { { "kitty", on_deploy = "kill -USR1 kitty" }, "old" }"#);
+        let old = State::from_plan(
+            &config.packages,
+            &[link("kitty", "/h/.config/kitty"), link("old", "/h/.old\tx")],
+        );
+        let path = root.join("state/state");
+        old.save(&path).unwrap();
+        assert_eq!(State::load(&path).unwrap(), old);
+
+        let (_ctx, config) = write_config(
+            r#"return { { "kitty", on_deploy = function() end }, { "old", enabled = false } }"#,
+        );
+        let selection = select::select(&config, &[], None, &cli::Filter::default());
+        let new = State::from_plan(
+            &selection.packages,
+            &[
+                link("kitty", "/h/.config/kitty"),
+                link("kitty", "/h/.kittyrc"),
+            ],
+        );
+        let changes: Vec<String> = diff(&old, &new, &selection)
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                format!("+ [kitty] /h/.kittyrc -> {}", root.join("kitty").display()),
+                "~ [kitty] 'on_deploy' hook changed".to_string(),
+                "- package old".to_string(),
+                "- [old] /h/.old\tx".to_string(),
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use crate::state::{escape, unescape};
+use mlua::{Lua, Result as LuaResult, Table, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const STORE_FILE: &str = "store";
+const HEADER: &str = "# mdot store v1";
+
+/// A value remembered by `mdot.store.set`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stored {
+    String(String),
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+}
+
+impl Stored {
+    fn from_lua(value: &Value) -> Result<Stored, String> {
+        Ok(match value {
+            Value::String(s) => Stored::String(s.to_string_lossy()),
+            Value::Integer(n) => Stored::Integer(*n),
+            Value::Number(n) => Stored::Number(*n),
+            Value::Boolean(b) => Stored::Boolean(*b),
+            v => {
+                return Err(format!(
+                    "only strings, numbers and booleans can be stored, got {}",
+                    v.type_name()
+                ));
+            }
+        })
+    }
+
+    fn into_lua(self, lua: &Lua) -> LuaResult<Value> {
+        Ok(match self {
+            Stored::String(s) => Value::String(lua.create_string(s)?),
+            Stored::Integer(n) => Value::Integer(n),
+            Stored::Number(n) => Value::Number(n),
+            Stored::Boolean(b) => Value::Boolean(b),
+        })
+    }
+
+    fn parse(kind: &str, value: &str) -> Option<Stored> {
+        match kind {
+            "string" => Some(Stored::String(value.to_string())),
+            "integer" => value.parse().ok().map(Stored::Integer),
+            "number" => value.parse().ok().map(Stored::Number),
+            "boolean" => value.parse().ok().map(Stored::Boolean),
+            _ => None,
+        }
+    }
+
+    fn record(&self) -> (&'static str, String) {
+        match self {
+            Stored::String(s) => ("string", s.clone()),
+            Stored::Integer(n) => ("integer", n.to_string()),
+            Stored::Number(n) => ("number", n.to_string()),
+            Stored::Boolean(b) => ("boolean", b.to_string()),
+        }
+    }
+}
+
+/// The values kept in the state dir as tab separated `key`, `type` and
+/// `value` records.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Store {
+    pub values: BTreeMap<String, Stored>,
+}
+
+impl Store {
+    pub fn path(state_dir: &Path) -> PathBuf {
+        state_dir.join(STORE_FILE)
+    }
+
+    /// Reads the store at `path`; a missing file is an empty store.
+    pub fn load(path: &Path) -> Result<Store, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Store::default()),
+            Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+        };
+        let mut store = Store::default();
+        for (n, line) in contents.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<String> = line.split('\t').map(unescape).collect();
+            let value = match fields.as_slice() {
+                [key, kind, value] => Stored::parse(kind, value).map(|v| (key.clone(), v)),
+                _ => None,
+            };
+            let Some((key, value)) = value else {
+                return Err(format!(
+                    "{}:{}: invalid store record",
+                    path.display(),
+                    n + 1
+                ));
+            };
+            store.values.insert(key, value);
+        }
+        Ok(store)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = format!("{}\n", HEADER);
+        for (key, value) in &self.values {
+            let (kind, value) = value.record();
+            out.push_str(&format!("{}\t{}\t{}\n", escape(key), kind, escape(&value)));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, out)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// Registers `mdot.store.get(key)` and `mdot.store.set(key, value)`,
+/// remembering strings, numbers and booleans across runs in the store
+/// file of `state_dir`. Setting `nil` forgets the key. Each call reads the
+/// file again and `set` writes it right away, so hooks see what the
+/// config stored during the same run.
+pub fn install(lua: &Lua, state_dir: &Path) -> LuaResult<()> {
+    let mdot: Table = lua.globals().get("mdot")?;
+    let store = lua.create_table()?;
+    let path = Store::path(state_dir);
+    let get_path = path.clone();
+    store.set(
+        "get",
+        lua.create_function(move |lua, key: String| {
+            let store = Store::load(&get_path).map_err(mlua::Error::runtime)?;
+            match store.values.get(&key) {
+                Some(value) => value.clone().into_lua(lua),
+                None => Ok(Value::Nil),
+            }
+        })?,
+    )?;
+    store.set(
+        "set",
+        lua.create_function(move |_, (key, value): (String, Value)| {
+            let mut store = Store::load(&path).map_err(mlua::Error::runtime)?;
+            match value {
+                Value::Nil => {
+                    store.values.remove(&key);
+                }
+                value => {
+                    let value = Stored::from_lua(&value)
+                        .map_err(|err| mlua::Error::runtime(format!("'{}': {}", key, err)))?;
+                    store.values.insert(key, value);
+                }
+            }
+            store.save(&path).map_err(|err| {
+                mlua::Error::runtime(format!("failed to write {}: {}", path.display(), err))
+            })
+        })?,
+    )?;
+    mdot.set("store", store)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::*;
+    use crate::*;
+    use std::env;
+
+    #[test]
+    fn test_store() {
+        let root = env::temp_dir().join(format!("mdot-store-{}", std::process::id()));
+        let ctx = Context::builder()
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        api::install(&ctx.lua, &ctx.platform).unwrap();
+        install(&ctx.lua, &root).unwrap();
+        ctx.lua
+            .load(
+                r#"
+                assert(mdot.store.get("theme") == nil)
+                mdot.store.set("theme", "gruvbox\tdark")
+                mdot.store.set("runs", 3)
+                mdot.store.set("scale", 1.5)
+                mdot.store.set("prompted", true)
+                mdot.store.set("gone", "x")
+                mdot.store.set("gone", nil)
+                "#,
+            )
+            .exec()
+            .unwrap();
+        assert!(
+            ctx.lua
+                .load(r#"mdot.store.set("list", {})"#)
+                .exec()
+                .is_err()
+        );
+
+        let store = Store::load(&Store::path(&root)).unwrap();
+        assert_eq!(
+            store.values.get("theme"),
+            Some(&Stored::String("gruvbox\tdark".to_string()))
+        );
+        assert_eq!(store.values.get("runs"), Some(&Stored::Integer(3)));
+        assert_eq!(store.values.get("scale"), Some(&Stored::Number(1.5)));
+        assert_eq!(store.values.get("prompted"), Some(&Stored::Boolean(true)));
+        assert_eq!(store.values.len(), 4);
+
+        let ctx = Context::builder()
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        api::install(&ctx.lua, &ctx.platform).unwrap();
+        install(&ctx.lua, &root).unwrap();
+        let runs: i64 = ctx.lua.load(r#"mdot.store.get("runs")"#).eval().unwrap();
+        assert_eq!(runs, 3);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,721 @@
+use crate::config::Config;
+use crate::platform::Platform;
+use crate::{Package, limits, ordered_pairs};
+use log::warn;
+use mlua::{Function, Lua, Table, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Template variables keyed by their dotted path, e.g. `vars.font.size`.
+pub type Vars = BTreeMap<String, String>;
+
+/// Directory of per-host variable files, `hosts/<hostname>.lua`.
+pub const HOSTS_DIR: &str = "hosts";
+/// Machine-local variable overrides in the repo root.
+pub const LOCAL_FILE: &str = "local.lua";
+
+/// Flattens a Lua table into `out`, prefixing every key with `prefix`.
+pub fn flatten(prefix: &str, tbl: &Table, out: &mut Vars) {
+    for (key, value) in ordered_pairs(tbl, prefix) {
+        let key = match key {
+            Value::String(s) => format!("{}.{}", prefix, s.to_string_lossy()),
+            Value::Integer(i) => format!("{}.{}", prefix, i),
+            k => {
+                warn!("variable key {:?} in '{}' is ignored", k, prefix);
+                continue;
+            }
+        };
+        match value {
+            Value::Table(tbl) => flatten(&key, &tbl, out),
+            Value::String(s) => {
+                out.insert(key, s.to_string_lossy());
+            }
+            Value::Integer(i) => {
+                out.insert(key, i.to_string());
+            }
+            Value::Number(n) => {
+                out.insert(key, n.to_string());
+            }
+            Value::Boolean(b) => {
+                out.insert(key, b.to_string());
+            }
+            v => warn!("variable '{}' has unsupported type {}", key, v.type_name()),
+        }
+    }
+}
+
+/// Where a variable got its value, from lowest to highest precedence.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layer {
+    /// `mdot.vars` in the config.
+    Default,
+    /// The package's own `vars`.
+    Package,
+    /// `vars` of the active profile.
+    Profile(String),
+    /// `hosts/<hostname>.lua` in the repo.
+    Host(String),
+    /// `local.lua` in the repo, meant to stay out of version control.
+    Local,
+    /// `--set` on the command line.
+    Cli,
+    Fact,
+    Builtin,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layer::Default => write!(f, "mdot.vars"),
+            Layer::Package => write!(f, "package vars"),
+            Layer::Profile(name) => write!(f, "profile '{}'", name),
+            Layer::Host(hostname) => write!(f, "{}/{}.lua", HOSTS_DIR, hostname),
+            Layer::Local => write!(f, "{}", LOCAL_FILE),
+            Layer::Cli => write!(f, "--set"),
+            Layer::Fact => write!(f, "fact"),
+            Layer::Builtin => write!(f, "builtin"),
+        }
+    }
+}
+
+/// Every variable with its final value and the layer that supplied it.
+/// Without a package, only the variables shared by all packages.
+pub fn layered_vars(
+    config: &Config,
+    pkg: Option<&Package>,
+    platform: &Platform,
+) -> BTreeMap<String, (String, Layer)> {
+    let mut out = BTreeMap::new();
+    let mut apply = |layer: &Layer, vars: &Vars| {
+        for (key, value) in vars {
+            out.insert(key.clone(), (value.clone(), layer.clone()));
+        }
+    };
+    apply(&Layer::Default, &config.vars);
+    if let Some(pkg) = pkg {
+        apply(&Layer::Package, &pkg.vars);
+    }
+    for (layer, vars) in &config.var_layers {
+        apply(layer, vars);
+    }
+    apply(&Layer::Fact, &config.facts);
+    let mut builtins = Vars::from([("hostname".to_string(), platform.hostname.clone())]);
+    if let Some(pkg) = pkg {
+        builtins.insert("name".to_string(), pkg.name.clone());
+    }
+    apply(&Layer::Builtin, &builtins);
+    out
+}
+
+/// Variables visible to a package, as resolved by `layered_vars`.
+pub fn package_vars(config: &Config, pkg: &Package, platform: &Platform) -> Vars {
+    layered_vars(config, Some(pkg), platform)
+        .into_iter()
+        .map(|(key, (value, _))| (key, value))
+        .collect()
+}
+
+/// How the templates of `pkg` are rendered unless an entry says otherwise.
+pub fn package_settings(config: &Config, pkg: &Package) -> Settings {
+    Settings {
+        engine: pkg
+            .template_engine
+            .unwrap_or(config.options.template_engine),
+        delims: pkg.template_delims.clone(),
+        ..Settings::default()
+    }
+}
+
+/// What placeholders mean, set with `template_engine`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Engine {
+    /// `{{ vars.font | default("mono") }}`.
+    #[default]
+    Builtin,
+    /// `<%= vars.font %>` inserts the value of a Lua expression and
+    /// `<% for i = 1, 3 do %>` runs Lua code, etlua style.
+    Lua,
+}
+
+impl Engine {
+    pub fn parse(path: &str, name: &str) -> Self {
+        match name {
+            "builtin" => Engine::Builtin,
+            "lua" => Engine::Lua,
+            name => fatal!("'{}' must be \"builtin\" or \"lua\", got '{}'", path, name),
+        }
+    }
+}
+
+/// How a template file is rendered.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    /// Undefined variables render as nothing instead of failing.
+    pub lenient: bool,
+    pub engine: Engine,
+    /// What opens and closes a placeholder instead of those of the engine.
+    pub delims: Option<(String, String)>,
+}
+
+impl Settings {
+    /// What opens and closes a placeholder: `{{` and `}}` for the builtin
+    /// engine, `<%` and `%>` for Lua, unless set.
+    pub fn delims(&self) -> (&str, &str) {
+        match (&self.delims, self.engine) {
+            (Some((open, close)), _) => (open, close),
+            (None, Engine::Builtin) => ("{{", "}}"),
+            (None, Engine::Lua) => ("<%", "%>"),
+        }
+    }
+}
+
+/// Parses `delims = { "[[", "]]" }` at `path`.
+pub fn delims_from_value(path: &str, value: &Value) -> (String, String) {
+    let delims = Package::extract_strings(path, value);
+    match delims.as_slice() {
+        [open, close] if !open.is_empty() && !close.is_empty() => (open.clone(), close.clone()),
+        _ => fatal!(
+            "'{}' expects an opening and a closing delimiter, got {:?}",
+            path,
+            delims
+        ),
+    }
+}
+
+/// Reads the quoted argument of a filter, `("value")` or `('value')`,
+/// returning it and what follows.
+fn string_arg(s: &str) -> Option<(String, &str)> {
+    let s = s.strip_prefix('(')?.trim_start();
+    let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let end = s[1..].find(quote)? + 1;
+    let rest = s[end + 1..].trim_start().strip_prefix(')')?;
+    Some((s[1..end].to_string(), rest))
+}
+
+/// Evaluates what is between `{{` and `}}`: a variable, optionally
+/// followed by `| default("value")` for when it is undefined.
+fn eval(expr: &str, vars: &Vars, settings: &Settings) -> Result<String, String> {
+    let (key, mut rest) = expr.split_at(expr.find('|').unwrap_or(expr.len()));
+    let key = key.trim();
+    let mut value = vars.get(key).cloned();
+    while let Some(filter) = rest.trim_start().strip_prefix('|') {
+        let filter = filter.trim_start();
+        let (name, args) = filter.split_at(
+            filter
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(filter.len()),
+        );
+        match name {
+            "default" => {
+                let (fallback, tail) = string_arg(args.trim_start()).ok_or_else(|| {
+                    format!(
+                        "'default' expects a quoted string, e.g. default(\"x\"), in '{}'",
+                        expr.trim()
+                    )
+                })?;
+                value = value.or(Some(fallback));
+                rest = tail;
+            }
+            "" => return Err(format!("missing filter after '|' in '{}'", expr.trim())),
+            name => return Err(format!("unknown filter '{}'", name)),
+        }
+    }
+    if !rest.trim().is_empty() {
+        return Err(format!("unexpected '{}' in '{}'", rest.trim(), expr.trim()));
+    }
+    match value {
+        Some(value) => Ok(value),
+        None if settings.lenient => Ok(String::new()),
+        None => Err(format!("undefined variable '{}'", key)),
+    }
+}
+
+/// A placeholder: where it starts and ends in the input, the expression
+/// in it, and whether it trims the whitespace before or after it.
+struct Tag<'a> {
+    start: usize,
+    end: usize,
+    expr: &'a str,
+    trim_before: bool,
+    trim_after: bool,
+}
+
+/// The first placeholder of `input` from `from`, or where an unterminated
+/// one starts.
+fn next_tag<'a>(
+    input: &'a str,
+    from: usize,
+    settings: &Settings,
+) -> Option<Result<Tag<'a>, usize>> {
+    let (open, close) = settings.delims();
+    let start = from + input[from..].find(open)?;
+    let after = start + open.len();
+    let Some(end) = input[after..].find(close).map(|i| after + i) else {
+        return Some(Err(start));
+    };
+    let mut expr = &input[after..end];
+    let trim_before = expr.starts_with('-');
+    if trim_before {
+        expr = &expr[1..];
+    }
+    let trim_after = expr.ends_with('-');
+    if trim_after {
+        expr = &expr[..expr.len() - 1];
+    }
+    Some(Ok(Tag {
+        start,
+        end: end + close.len(),
+        expr,
+        trim_before,
+        trim_after,
+    }))
+}
+
+/// A part of a template, the expressions and errors with their offset.
+enum Piece<'a> {
+    Text(&'a str),
+    Expr(usize, &'a str),
+    Error(usize, String),
+}
+
+/// Pushes `text`, without its leading whitespace when the placeholder
+/// before it ends with `-`.
+fn push_text<'a>(pieces: &mut Vec<Piece<'a>>, text: &'a str, trim: &mut bool) {
+    let text = match std::mem::take(trim) {
+        true => text.trim_start(),
+        false => text,
+    };
+    pieces.push(Piece::Text(text));
+}
+
+/// Splits `input` at its placeholders. `{{-` and `-}}` trim the
+/// whitespace before and after, and what is between `{{ raw }}` and
+/// `{{ endraw }}` is kept as is.
+fn pieces<'a>(input: &'a str, settings: &Settings) -> Vec<Piece<'a>> {
+    let mut pieces = Vec::new();
+    let mut trim = false;
+    let mut pos = 0;
+    let tag = |pieces: &mut Vec<Piece<'a>>, tag: &Tag<'a>, pos: &mut usize, trim: &mut bool| {
+        push_text(pieces, &input[*pos..tag.start], trim);
+        if tag.trim_before
+            && let Some(Piece::Text(text)) = pieces.last_mut()
+        {
+            *text = text.trim_end();
+        }
+        *trim = tag.trim_after;
+        *pos = tag.end;
+    };
+    loop {
+        let open = match next_tag(input, pos, settings) {
+            None => break,
+            Some(Ok(open)) => open,
+            Some(Err(start)) => {
+                push_text(&mut pieces, &input[pos..start], &mut trim);
+                let err = format!("unterminated '{}'", settings.delims().0);
+                pieces.push(Piece::Error(start, err));
+                return pieces;
+            }
+        };
+        tag(&mut pieces, &open, &mut pos, &mut trim);
+        match open.expr.trim() {
+            "raw" => {
+                let mut from = pos;
+                let close = loop {
+                    match next_tag(input, from, settings) {
+                        Some(Ok(close)) if close.expr.trim() == "endraw" => break Some(close),
+                        Some(Ok(Tag { start, .. })) | Some(Err(start)) => {
+                            from = start + settings.delims().0.len()
+                        }
+                        None => break None,
+                    }
+                };
+                let Some(close) = close else {
+                    let err = "unterminated 'raw' block".to_string();
+                    pieces.push(Piece::Error(open.start, err));
+                    return pieces;
+                };
+                tag(&mut pieces, &close, &mut pos, &mut trim);
+            }
+            "endraw" => pieces.push(Piece::Error(
+                open.start,
+                "'endraw' without 'raw'".to_string(),
+            )),
+            _ => pieces.push(Piece::Expr(open.start, open.expr)),
+        }
+    }
+    push_text(&mut pieces, &input[pos..], &mut trim);
+    pieces
+}
+
+/// Substitutes every `{{ key }}` placeholder in `input`. Undefined
+/// variables are an error unless a `default` filter covers them.
+pub fn render(input: &str, vars: &Vars) -> Result<String, String> {
+    render_with(input, vars, &Settings::default())
+}
+
+/// Builds the scope of Lua templates from the variables and whether they
+/// are lenient: `vars.font` for the `vars.font` variable, Lua's globals,
+/// and `get(key, default)` for variables that may be undefined. Returns
+/// the scope, the function outputting a value and the table collecting
+/// the output.
+const LUA_PRELUDE: &str = r#"
+local vars, lenient = ...
+local function scope(prefix)
+    return setmetatable({}, { __index = function(_, key)
+        if prefix == "" and _G[key] ~= nil then return _G[key] end
+        if lenient then return nil end
+        error("undefined variable '" .. prefix .. tostring(key) .. "'", 2)
+    end })
+end
+local env = scope("")
+for key, value in pairs(vars) do
+    local parts = {}
+    for part in key:gmatch("[^.]+") do parts[#parts + 1] = tonumber(part) or part end
+    local tbl, prefix = env, ""
+    for i = 1, #parts - 1 do
+        prefix = prefix .. parts[i] .. "."
+        local inner = rawget(tbl, parts[i])
+        if inner == nil then
+            inner = scope(prefix)
+            rawset(tbl, parts[i], inner)
+        end
+        tbl = inner
+    end
+    rawset(tbl, parts[#parts], value)
+end
+rawset(env, "get", function(key, default)
+    local value = vars[key]
+    if value == nil then return default end
+    return value
+end)
+local out = {}
+local function emit(value)
+    if value == nil then
+        if lenient then return end
+        error("the expression is nil", 2)
+    end
+    out[#out + 1] = tostring(value)
+end
+return env, emit, out
+"#;
+
+/// The line and message of an error of a Lua template.
+fn lua_error(err: &mlua::Error) -> (usize, String) {
+    let err = err.to_string();
+    let first = err.lines().next().unwrap_or_default();
+    if let Some((_, at)) = first.split_once("template:")
+        && let Some((line, message)) = at.split_once(": ")
+        && let Ok(line) = line.parse()
+    {
+        return (line, message.to_string());
+    }
+    (0, first.to_string())
+}
+
+/// Renders `input` with the Lua engine, compiling it to a chunk whose
+/// lines are those of the template so that errors point at them.
+fn render_lua(input: &str, vars: &Vars, settings: &Settings) -> Result<String, (usize, String)> {
+    let line = |offset: usize| input[..offset].matches('\n').count() + 1;
+    let mut texts = Vec::new();
+    let mut code = String::from("local _text, _emit = ... ");
+    let mut lines = 1;
+    for piece in pieces(input, settings) {
+        match piece {
+            Piece::Text(text) => {
+                texts.push(text);
+                code.push_str(&format!("_emit(_text[{}]) ", texts.len()));
+            }
+            Piece::Expr(offset, expr) => {
+                while lines < line(offset) {
+                    code.push('\n');
+                    lines += 1;
+                }
+                match expr.trim_start().strip_prefix('=') {
+                    Some(expr) => code.push_str(&format!("_emit(({})) ", expr)),
+                    None => code.push_str(&format!("{} ", expr)),
+                }
+                lines += expr.matches('\n').count();
+            }
+            Piece::Error(offset, err) => return Err((line(offset), err)),
+        }
+    }
+    let lua = Lua::new();
+    let prelude = |err: mlua::Error| (0, err.to_string());
+    limits::watch(&lua).map_err(prelude)?;
+    let vars = lua
+        .create_table_from(vars.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .map_err(prelude)?;
+    let (env, emit, out): (Table, Function, Table) = lua
+        .load(LUA_PRELUDE)
+        .set_name("=prelude")
+        .call((vars, settings.lenient))
+        .map_err(prelude)?;
+    let chunk = lua
+        .load(code)
+        .set_name("=template")
+        .set_environment(env)
+        .into_function()
+        .map_err(|err| lua_error(&err))?;
+    limits::budgeted(|| chunk.call::<()>((texts, emit))).map_err(|err| lua_error(&err))?;
+    out.sequence_values::<String>()
+        .collect::<mlua::Result<Vec<_>>>()
+        .map(|parts| parts.concat())
+        .map_err(prelude)
+}
+
+/// `render` with the `settings` of a template file.
+pub fn render_with(input: &str, vars: &Vars, settings: &Settings) -> Result<String, String> {
+    if settings.engine == Engine::Lua {
+        return render_lua(input, vars, settings)
+            .map_err(|(line, err)| format!("line {}: {}", line, err));
+    }
+    let mut out = String::with_capacity(input.len());
+    for piece in pieces(input, settings) {
+        let value = match piece {
+            Piece::Text(text) => Ok(text.to_string()),
+            Piece::Expr(_, expr) => eval(expr, vars, settings),
+            Piece::Error(_, err) => Err(err),
+        };
+        out.push_str(&value.map_err(|err| format!("{} in '{}'", err, input))?);
+    }
+    Ok(out)
+}
+
+/// Every problem `render_with` could stop at in `input`, with its 1-based
+/// line: undefined variables, bad filters, unterminated placeholders and
+/// raw blocks.
+pub fn check(input: &str, vars: &Vars, settings: &Settings) -> Vec<(usize, String)> {
+    if settings.engine == Engine::Lua {
+        return render_lua(input, vars, settings)
+            .err()
+            .into_iter()
+            .collect();
+    }
+    let line = |offset: usize| input[..offset].matches('\n').count() + 1;
+    let mut problems = Vec::new();
+    for piece in pieces(input, settings) {
+        match piece {
+            Piece::Text(_) => (),
+            Piece::Expr(offset, expr) => {
+                if let Err(err) = eval(expr, vars, settings) {
+                    problems.push((line(offset), err));
+                }
+            }
+            Piece::Error(offset, err) => problems.push((line(offset), err)),
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let vars = Vars::from([
+            ("name".to_string(), "kitty".to_string()),
+            ("vars.theme".to_string(), "gruvbox".to_string()),
+        ]);
+        assert_eq!(
+            render("~/.config/{{name}}/{{ vars.theme }}.conf", &vars),
+            Ok("~/.config/kitty/gruvbox.conf".to_string())
+        );
+        assert!(render("{{ vars.missing }}", &vars).is_err());
+        assert!(render("{{ name", &vars).is_err());
+    }
+
+    #[test]
+    fn test_default_and_lenient() {
+        let vars = Vars::from([("name".to_string(), "kitty".to_string())]);
+        assert_eq!(
+            render(
+                r#"{{ vars.font | default("mono") }} {{name|default('x')}}"#,
+                &vars
+            ),
+            Ok("mono kitty".to_string())
+        );
+        assert_eq!(
+            render(
+                r#"{{ vars.a | default("") }}|{{ vars.b|default('a | b') }}"#,
+                &vars
+            ),
+            Ok("|a | b".to_string())
+        );
+        let lenient = Settings {
+            lenient: true,
+            ..Settings::default()
+        };
+        assert_eq!(
+            render_with("[{{ vars.missing }}]", &vars, &lenient),
+            Ok("[]".to_string())
+        );
+        assert_eq!(
+            check("{{ name | upper }}\n{{ name | default(x) }}\n{{ name | }}", &vars, &lenient),
+            [
+                (1, "unknown filter 'upper'".to_string()),
+                (
+                    2,
+                    "'default' expects a quoted string, e.g. default(\"x\"), in 'name | default(x)'"
+                        .to_string()
+                ),
+                (3, "missing filter after '|' in 'name |'".to_string()),
+            ]
+        );
+        assert_eq!(
+            check(r#"{{ name default("x") }}"#, &vars, &Settings::default()),
+            [(1, "undefined variable 'name default(\"x\")'".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_trim_and_raw() {
+        let vars = Vars::from([("name".to_string(), "kitty".to_string())]);
+        assert_eq!(
+            render("a  {{- name -}}\n  b\n{{ name }} c", &vars),
+            Ok("akittyb\nkitty c".to_string())
+        );
+        assert_eq!(
+            render(
+                "{{ raw -}}\n{{ .Branch }} {{- x }}\n{{- endraw }} {{ name }}",
+                &vars
+            ),
+            Ok("{{ .Branch }} {{- x }} kitty".to_string())
+        );
+        let delims = Settings {
+            delims: Some(("<%".to_string(), "%>".to_string())),
+            ..Settings::default()
+        };
+        assert_eq!(
+            render_with("{{ x }} <% name %> <%- name %>", &vars, &delims),
+            Ok("{{ x }} kittykitty".to_string())
+        );
+        assert_eq!(
+            check("{{ raw }}\n{{ x", &vars, &Settings::default()),
+            [(1, "unterminated 'raw' block".to_string())]
+        );
+        assert_eq!(
+            check("{{ endraw }}\n<% x", &vars, &delims),
+            [(2, "unterminated '<%'".to_string())]
+        );
+        assert_eq!(
+            check("{{ endraw }}", &vars, &Settings::default()),
+            [(1, "'endraw' without 'raw'".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_lua_engine() {
+        let vars = Vars::from([
+            ("name".to_string(), "kitty".to_string()),
+            ("vars.font.size".to_string(), "11".to_string()),
+            ("vars.list.1".to_string(), "a".to_string()),
+        ]);
+        let lua = Settings {
+            engine: Engine::Lua,
+            ..Settings::default()
+        };
+        assert_eq!(
+            render_with(
+                "<%= name:upper() %> <%= vars.font.size + 1 %> <%= vars.list[1] %>\n\
+                 <% for i = 1, 3 do -%>\n{{ <%= i %> }}\n<%- end %>\n\
+                 <%= get(\"vars.theme\", \"dark\") %>",
+                &vars,
+                &lua
+            ),
+            Ok("KITTY 12 a\n{{ 1 }}{{ 2 }}{{ 3 }}\ndark".to_string())
+        );
+        assert_eq!(
+            check(
+                "a\n<% if true then %>\n<%= vars.font.family %>\n<% end %>",
+                &vars,
+                &lua
+            ),
+            [(3, "undefined variable 'vars.font.family'".to_string())]
+        );
+        let syntax = check("a\n\n<% if %>", &vars, &lua);
+        assert_eq!(syntax.len(), 1);
+        assert_eq!(syntax[0].0, 3);
+        assert_eq!(
+            render_with("<%= missing %>", &vars, &lua),
+            Err("line 1: undefined variable 'missing'".to_string())
+        );
+        let lenient = Settings {
+            lenient: true,
+            ..lua.clone()
+        };
+        assert_eq!(
+            render_with("[<%= vars.missing %>]", &vars, &lenient),
+            Ok("[]".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let vars = Vars::from([("name".to_string(), "kitty".to_string())]);
+        let strict = Settings::default();
+        assert_eq!(check("{{ name }}\n{{name}}", &vars, &strict), []);
+        assert_eq!(
+            check(
+                "a {{ vars.font }}\n\nb {{ name }} {{ vars.size }}\n{{ name",
+                &vars,
+                &strict
+            ),
+            [
+                (1, "undefined variable 'vars.font'".to_string()),
+                (3, "undefined variable 'vars.size'".to_string()),
+                (4, "unterminated '{{'".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_layered_vars() {
+        use crate::{Context, config, setup_logger};
+        use std::{env, fs};
+        let _ = setup_logger();
+        let root = env::temp_dir().join(format!("mdot-layers-{}", std::process::id()));
+        fs::create_dir_all(root.join(HOSTS_DIR)).unwrap();
+        fs::write(
+            root.join("main.lua"),
+            r#"
+            mdot.vars = { theme = "nord", font = "mono", size = 10, gap = 4 }
+            mdot.profiles.work = { "kitty", vars = { theme = "light" } }
+            return { { "kitty", vars = { theme = "dark", size = 11, gap = 8 } } }
+            "#,
+        )
+        .unwrap();
+        let mut ctx = Context::builder()
+            .entry(root.join("main.lua"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        fs::write(
+            root.join(HOSTS_DIR)
+                .join(format!("{}.lua", ctx.platform.hostname)),
+            "return { font = 'iosevka', size = 12 }",
+        )
+        .unwrap();
+        fs::write(root.join(LOCAL_FILE), "return { size = 14 }").unwrap();
+        ctx.profile = Some("work".to_string());
+        ctx.overrides = Vars::from([("vars.gap".to_string(), "2".to_string())]);
+        let config = config::load(&ctx);
+
+        let vars = layered_vars(&config, Some(&config.packages[0]), &ctx.platform);
+        let layer = |key: &str| (vars[key].0.as_str(), vars[key].1.to_string());
+        assert_eq!(layer("vars.theme"), ("light", "profile 'work'".to_string()));
+        assert_eq!(
+            layer("vars.font"),
+            ("iosevka", format!("hosts/{}.lua", ctx.platform.hostname))
+        );
+        assert_eq!(layer("vars.size"), ("14", "local.lua".to_string()));
+        assert_eq!(layer("vars.gap"), ("2", "--set".to_string()));
+        assert_eq!(layer("name"), ("kitty", "builtin".to_string()));
+        let global = layered_vars(&config, None, &ctx.platform);
+        assert_eq!(global["vars.gap"].0, "2");
+        assert_eq!(global["vars.theme"].0, "light");
+        assert!(!global.contains_key("name"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use crate::deploy::expand_tilde;
+use log::info;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The config tmux reads at startup: `$XDG_CONFIG_HOME/tmux/tmux.conf`,
+/// `~/.config/tmux/tmux.conf` or `~/.tmux.conf`, the first that exists.
+fn default_conf() -> Option<PathBuf> {
+    let home = dirs::home_dir()?;
+    let xdg = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from);
+    xdg.into_iter()
+        .chain([home.join(".config")])
+        .map(|dir| dir.join("tmux/tmux.conf"))
+        .chain([home.join(".tmux.conf")])
+        .find(|conf| conf.exists())
+}
+
+/// The directory of the sockets of the tmux servers of this user,
+/// `$TMUX_TMPDIR/tmux-UID` or `/tmp/tmux-UID`.
+fn socket_dir() -> Option<PathBuf> {
+    let uid = fs::metadata(dirs::home_dir()?).ok()?.uid();
+    let tmp = env::var_os("TMUX_TMPDIR").map_or_else(|| PathBuf::from("/tmp"), PathBuf::from);
+    Some(tmp.join(format!("tmux-{}", uid)))
+}
+
+/// The server sockets in `dir`, each named after the `-L` name of its
+/// server.
+fn sockets(dir: &Path) -> Vec<PathBuf> {
+    let mut sockets: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_socket()))
+        .map(|entry| entry.path())
+        .collect();
+    sockets.sort();
+    sockets
+}
+
+/// Sources `conf` in the server at `socket`, returning the errors tmux
+/// reports, `None` when no server is running there.
+fn source_in(socket: &Path, conf: &Path) -> io::Result<Option<Vec<String>>> {
+    let running = Command::new("tmux")
+        .arg("-S")
+        .arg(socket)
+        .arg("has-session")
+        .stderr(Stdio::null())
+        .status()?;
+    if !running.success() {
+        return Ok(None);
+    }
+    let out = Command::new("tmux")
+        .arg("-S")
+        .arg(socket)
+        .arg("source-file")
+        .arg(conf)
+        .stdin(Stdio::null())
+        .output()?;
+    // Errors parsing the file come on stdout, those running it on stderr.
+    let output = [out.stdout, out.stderr].concat();
+    let errors: Vec<String> = String::from_utf8_lossy(&output)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+    match (out.status.success(), errors.is_empty()) {
+        (false, true) => Ok(Some(vec![format!("tmux exited with {}", out.status)])),
+        _ => Ok(Some(errors)),
+    }
+}
+
+/// Sources `conf`, or the config tmux reads by default, once in every
+/// running tmux server, returning the errors of each server by the name
+/// of its socket.
+pub fn reload(conf: Option<&Path>) -> Result<(), String> {
+    let conf = match conf.map(expand_tilde).or_else(default_conf) {
+        Some(conf) => conf,
+        None => return Err("no tmux.conf to source".to_string()),
+    };
+    let mut running = 0;
+    let mut errors = Vec::new();
+    for socket in socket_dir().map(|dir| sockets(&dir)).unwrap_or_default() {
+        let server = socket.file_name().unwrap_or_default().to_string_lossy();
+        match source_in(&socket, &conf) {
+            Ok(None) => continue,
+            Ok(Some(found)) if found.is_empty() => (),
+            Ok(Some(found)) => errors.push(format!("server {}: {}", server, found.join("; "))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("tmux is not installed");
+                return Ok(());
+            }
+            Err(err) => errors.push(format!("server {}: {}", server, err)),
+        }
+        running += 1;
+    }
+    if running == 0 {
+        info!("tmux is not running");
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join("\n")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tmux::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_sockets() {
+        let dir = env::temp_dir().join(format!("mdot-tmux-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let _default = UnixListener::bind(dir.join("default")).unwrap();
+        let _work = UnixListener::bind(dir.join("work")).unwrap();
+        fs::write(dir.join("notes"), "").unwrap();
+        assert_eq!(sockets(&dir), [dir.join("default"), dir.join("work")]);
+        assert!(sockets(&dir.join("missing")).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
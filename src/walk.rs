@@ -0,0 +1,282 @@
+use crate::config::Options;
+use globset::Glob;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, WalkBuilder, WalkState};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const IGNORE_FILE: &str = ".mdotignore";
+
+/// Exclusion rules of a package: its `excludes` field and `.mdotignore`,
+/// followed by the repo-root `.mdotignore`.
+///
+/// Everything uses gitignore syntax. Package rules take precedence, so a
+/// package can re-include (`!pattern`) a path the repo root ignores.
+#[derive(Debug, Clone)]
+pub struct Excludes {
+    package: Gitignore,
+    root: Gitignore,
+    dir: PathBuf,
+}
+
+fn add_ignore_file(builder: &mut GitignoreBuilder, path: &Path) -> Result<(), ignore::Error> {
+    match path.is_file().then(|| builder.add(path)).flatten() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+impl Excludes {
+    /// Builds the rules for the package in `dir`, merging `excludes` with
+    /// the package and repo-root ignore files.
+    pub fn load(repo: &Path, dir: &Path, patterns: &[PathBuf]) -> Result<Self, ignore::Error> {
+        let mut builder = GitignoreBuilder::new(dir);
+        builder.add_line(None, IGNORE_FILE)?;
+        for pattern in patterns {
+            builder.add_line(None, &pattern.to_string_lossy())?;
+        }
+        add_ignore_file(&mut builder, &dir.join(IGNORE_FILE))?;
+
+        let mut root = GitignoreBuilder::new(repo);
+        add_ignore_file(&mut root, &repo.join(IGNORE_FILE))?;
+        Ok(Self {
+            package: builder.build()?,
+            root: root.build()?,
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Whether `rel` (relative to the package directory) or any of its
+    /// parent directories is excluded.
+    pub fn is_excluded(&self, rel: &Path, is_dir: bool) -> bool {
+        let path = self.dir.join(rel);
+        match self.package.matched_path_or_any_parents(&path, is_dir) {
+            Match::Ignore(_) => true,
+            Match::Whitelist(_) => false,
+            Match::None => self
+                .root
+                .matched_path_or_any_parents(&path, is_dir)
+                .is_ignore(),
+        }
+    }
+}
+
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '[', '{'])
+}
+
+fn walker(dir: &Path, options: &Options) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(dir);
+    builder
+        .standard_filters(false)
+        .follow_links(options.follow_symlinks);
+    builder
+}
+
+/// Runs `walker` to completion over several threads, returning the files
+/// it yields relative to `base` in file name order. Fails on symlink
+/// cycles and on paths nested deeper than `options.max_depth` below
+/// `base`, reporting the first such path.
+fn collect(base: &Path, walker: WalkBuilder, options: &Options) -> io::Result<Vec<PathBuf>> {
+    let files = Mutex::new(Vec::new());
+    let errors = Mutex::new(Vec::new());
+    let too_deep = Mutex::new(Vec::new());
+    walker.build_parallel().run(|| {
+        Box::new(|entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    errors.lock().unwrap().push(err.to_string());
+                    return WalkState::Continue;
+                }
+            };
+            let rel = entry.path().strip_prefix(base).unwrap();
+            if rel.components().count() > options.max_depth {
+                too_deep.lock().unwrap().push(entry.path().to_path_buf());
+                return WalkState::Skip;
+            } else if entry.depth() > 0 && !entry.file_type().is_some_and(|t| t.is_dir()) {
+                files.lock().unwrap().push(rel.to_path_buf());
+            }
+            WalkState::Continue
+        })
+    });
+    if let Some(err) = errors.into_inner().unwrap().into_iter().min() {
+        return Err(io::Error::other(err));
+    }
+    if let Some(path) = too_deep.into_inner().unwrap().into_iter().min() {
+        return Err(io::Error::other(format!(
+            "{} is nested deeper than mdot.options.max_depth ({})",
+            path.display(),
+            options.max_depth
+        )));
+    }
+    let mut files = files.into_inner().unwrap();
+    files.sort();
+    Ok(files)
+}
+
+/// Lists files below `dir` as paths relative to `base`, skipping excluded
+/// entries, and git-ignored ones when `options.gitignore` is set. Symlinks
+/// are returned as files unless `options.follow_symlinks` is set.
+pub fn files(
+    base: &Path,
+    dir: &Path,
+    excludes: &Excludes,
+    options: &Options,
+) -> io::Result<Vec<PathBuf>> {
+    let filter = excludes.clone();
+    let filter_base = base.to_path_buf();
+    let mut walker = walker(dir, options);
+    walker
+        .git_ignore(options.gitignore)
+        .git_exclude(options.gitignore)
+        .parents(options.gitignore)
+        .require_git(false)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            let rel = entry.path().strip_prefix(&filter_base).unwrap();
+            entry.depth() == 0 || !filter.is_excluded(rel, is_dir)
+        });
+    collect(base, walker, options)
+}
+
+/// Lists every file below `dir` relative to it, ignoring excludes.
+pub fn all_files(dir: &Path, options: &Options) -> io::Result<Vec<PathBuf>> {
+    collect(dir, walker(dir, options), options)
+}
+
+/// Counts the files below `dir`, ignoring excludes.
+pub fn count_files(dir: &Path, options: &Options) -> io::Result<usize> {
+    Ok(all_files(dir, options)?.len())
+}
+
+/// Whether any file below `dir` is left out by `files`.
+pub fn has_excluded(
+    base: &Path,
+    dir: &Path,
+    excludes: &Excludes,
+    options: &Options,
+) -> io::Result<bool> {
+    Ok(files(base, dir, excludes, options)?.len() != count_files(dir, options)?)
+}
+
+/// Filters `files` down to the ones matching the glob `pattern`.
+pub fn glob(pattern: &Path, files: Vec<PathBuf>) -> Result<Vec<PathBuf>, String> {
+    let matcher = Glob::new(&pattern.to_string_lossy())
+        .map_err(|err| err.to_string())?
+        .compile_matcher();
+    Ok(files.into_iter().filter(|f| matcher.is_match(f)).collect())
+}
+
+/// The leading components of `pattern` that contain no glob syntax.
+pub fn glob_base(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|c| !is_glob(Path::new(c.as_os_str())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn test_excludes() {
+        let excludes = Excludes::load(
+            Path::new("/repo"),
+            Path::new("/repo/pkg"),
+            &[
+                PathBuf::from("README.md"),
+                PathBuf::from("*.bak"),
+                PathBuf::from("secrets/**"),
+            ],
+        )
+        .unwrap();
+        assert!(excludes.is_excluded(Path::new("README.md"), false));
+        assert!(excludes.is_excluded(Path::new("nested/README.md"), false));
+        assert!(excludes.is_excluded(Path::new("config/init.lua.bak"), false));
+        assert!(excludes.is_excluded(Path::new("secrets/token"), false));
+        assert!(excludes.is_excluded(Path::new(IGNORE_FILE), false));
+        assert!(!excludes.is_excluded(Path::new("config/init.lua"), false));
+        assert!(!excludes.is_excluded(Path::new("secrets"), true));
+    }
+
+    #[test]
+    fn test_ignore_files() {
+        let repo = env::temp_dir().join(format!("mdot-ignore-{}", std::process::id()));
+        let dir = repo.join("pkg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(repo.join(IGNORE_FILE), "*.bak\nnotes.txt\n").unwrap();
+        fs::write(dir.join(IGNORE_FILE), "!keep.bak\nbuild/\n").unwrap();
+
+        let excludes = Excludes::load(&repo, &dir, &[PathBuf::from("README.md")]).unwrap();
+        assert!(excludes.is_excluded(Path::new("README.md"), false));
+        assert!(excludes.is_excluded(Path::new("old.bak"), false));
+        assert!(excludes.is_excluded(Path::new("notes.txt"), false));
+        assert!(excludes.is_excluded(Path::new("build/out.o"), false));
+        assert!(!excludes.is_excluded(Path::new("keep.bak"), false));
+        assert!(!excludes.is_excluded(Path::new("build"), false));
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_gitignore() {
+        let repo = env::temp_dir().join(format!("mdot-gitignore-{}", std::process::id()));
+        let dir = repo.join("pkg");
+        fs::create_dir_all(dir.join("target/debug")).unwrap();
+        fs::write(repo.join(".gitignore"), "local.lua\n").unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n*.o\n").unwrap();
+        for file in ["init.lua", "local.lua", "main.o", "target/debug/out"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let excludes = Excludes::load(&repo, &dir, &[]).unwrap();
+
+        let options = Options::default();
+        assert_eq!(files(&dir, &dir, &excludes, &options).unwrap().len(), 5);
+
+        let options = Options {
+            gitignore: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            files(&dir, &dir, &excludes, &options).unwrap(),
+            [PathBuf::from(".gitignore"), PathBuf::from("init.lua")]
+        );
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_symlink_loop_and_depth() {
+        let dir = env::temp_dir().join(format!("mdot-loop-{}", std::process::id()));
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/file"), "").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("a/b/loop")).unwrap();
+        let excludes = Excludes::load(&dir, &dir, &[]).unwrap();
+
+        let options = Options::default();
+        assert_eq!(files(&dir, &dir, &excludes, &options).unwrap().len(), 2);
+
+        let options = Options {
+            follow_symlinks: true,
+            ..Options::default()
+        };
+        let err = files(&dir, &dir, &excludes, &options).unwrap_err();
+        assert!(err.to_string().contains("loop"), "{}", err);
+
+        let options = Options {
+            max_depth: 2,
+            ..Options::default()
+        };
+        let err = files(&dir, &dir, &excludes, &options).unwrap_err();
+        assert!(err.to_string().contains("a/b/file"), "{}", err);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_glob_base() {
+        assert_eq!(glob_base(Path::new("lua/*.lua")), PathBuf::from("lua"));
+        assert_eq!(glob_base(Path::new("**/*.conf")), PathBuf::new());
+    }
+}
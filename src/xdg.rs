@@ -0,0 +1,120 @@
+use mlua::{Lua, Result as LuaResult, Table};
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Dotfiles in `$HOME` whose apps also read them from an XDG location,
+/// with that location relative to `$XDG_CONFIG_HOME`.
+const KNOWN: &[(&str, &str)] = &[
+    (".alacritty.toml", "alacritty/alacritty.toml"),
+    (".alacritty.yml", "alacritty/alacritty.yml"),
+    (".gitconfig", "git/config"),
+    (".ideavimrc", "ideavim/ideavimrc"),
+    (".mpdconf", "mpd/mpd.conf"),
+    (".muttrc", "mutt/muttrc"),
+    (".nanorc", "nano/nanorc"),
+    (".ncmpcpp", "ncmpcpp"),
+    (".newsboat", "newsboat"),
+    (".tigrc", "tig/config"),
+    (".tmux.conf", "tmux/tmux.conf"),
+    (".vim", "vim"),
+    (".vimrc", "vim/vimrc"),
+    (".wezterm.lua", "wezterm/wezterm.lua"),
+];
+
+fn base(var: &str, fallback: &str) -> PathBuf {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| dirs::home_dir().unwrap_or_default().join(fallback))
+}
+
+pub fn config_home() -> PathBuf {
+    base("XDG_CONFIG_HOME", ".config")
+}
+
+pub fn data_home() -> PathBuf {
+    base("XDG_DATA_HOME", ".local/share")
+}
+
+pub fn state_home() -> PathBuf {
+    base("XDG_STATE_HOME", ".local/state")
+}
+
+pub fn cache_home() -> PathBuf {
+    base("XDG_CACHE_HOME", ".cache")
+}
+
+/// Where `target` should go instead, when it is a top-level dotfile in
+/// `home` of an app that supports XDG paths.
+pub fn suggestion(target: &Path, home: &Path) -> Option<PathBuf> {
+    if target.parent() != Some(home) {
+        return None;
+    }
+    let name = target.file_name()?.to_str()?;
+    KNOWN
+        .iter()
+        .find(|(dotfile, _)| *dotfile == name)
+        .map(|(_, xdg)| config_home().join(xdg))
+}
+
+/// Registers `mdot.xdg.config(app)`, `data(app)`, `state(app)` and
+/// `cache(app)`, returning the app's directory below the XDG base, or the
+/// base itself when `app` is left out.
+pub fn install(lua: &Lua) -> LuaResult<()> {
+    let mdot: Table = lua.globals().get("mdot")?;
+    let xdg = lua.create_table()?;
+    let dirs = [
+        ("config", config_home as fn() -> PathBuf),
+        ("data", data_home),
+        ("state", state_home),
+        ("cache", cache_home),
+    ];
+    for (name, dir) in dirs {
+        xdg.set(
+            name,
+            lua.create_function(move |_, app: Option<String>| {
+                let path = match app {
+                    Some(app) => dir().join(app),
+                    None => dir(),
+                };
+                Ok(path.display().to_string())
+            })?,
+        )?;
+    }
+    mdot.set("xdg", xdg)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_xdg() {
+        let home = Path::new("/home/me");
+        assert_eq!(
+            xdg::suggestion(&home.join(".tmux.conf"), home),
+            Some(xdg::config_home().join("tmux/tmux.conf"))
+        );
+        assert_eq!(xdg::suggestion(&home.join(".bashrc"), home), None);
+        assert_eq!(
+            xdg::suggestion(&home.join(".config/.tmux.conf"), home),
+            None
+        );
+
+        let root = env::temp_dir().join(format!("mdot-xdg-{}", std::process::id()));
+        let ctx = Context::builder()
+            .config_dir(root.join("config"))
+            .state_dir(root.join("state"))
+            .data_dir(root.join("data"))
+            .cache_dir(root.join("cache"))
+            .build();
+        api::install(&ctx.lua, &ctx.platform).unwrap();
+        xdg::install(&ctx.lua).unwrap();
+        let path: String = ctx.lua.load("mdot.xdg.config('kitty')").eval().unwrap();
+        assert_eq!(PathBuf::from(path), xdg::config_home().join("kitty"));
+        let path: String = ctx.lua.load("mdot.xdg.data()").eval().unwrap();
+        assert_eq!(PathBuf::from(path), xdg::data_home());
+    }
+}
@@ -0,0 +1,53 @@
+use mdot::{ActionProvider, Context, Package};
+use mlua::Value;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::rc::Rc;
+
+/// Records the packages it ran for.
+#[derive(Default)]
+struct Secrets {
+    runs: RefCell<Vec<String>>,
+}
+
+impl ActionProvider for Secrets {
+    fn name(&self) -> &str {
+        "secrets"
+    }
+
+    fn run(&self, _ctx: &Context, pkg: &Package, args: &Value) -> Result<(), String> {
+        let vault: String = args
+            .as_table()
+            .and_then(|args| args.get("vault").ok())
+            .ok_or("expected a vault")?;
+        self.runs
+            .borrow_mut()
+            .push(format!("{} {}", pkg.name(), vault));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_deploy_with_provider() {
+    let root = env::temp_dir().join(format!("mdot-embed-{}", std::process::id()));
+    fs::create_dir_all(root.join("repo")).unwrap();
+    fs::write(
+        root.join("repo/main.lua"),
+        r#"return { { "work", actions = { secrets = { vault = "corp" } } } }"#,
+    )
+    .unwrap();
+    let secrets = Rc::new(Secrets::default());
+    let ctx = Context::builder()
+        .entry(root.join("repo/main.lua"))
+        .state_dir(root.join("state"))
+        .data_dir(root.join("data"))
+        .cache_dir(root.join("cache"))
+        .action(secrets.clone())
+        .build();
+
+    mdot::main_with(ctx, ["mdot", "deploy", "--no-install"]).unwrap();
+    assert_eq!(*secrets.runs.borrow(), ["work corp"]);
+    assert!(root.join("state/state").exists());
+    fs::remove_dir_all(root).unwrap();
+}